@@ -0,0 +1,99 @@
+// Constrained sampling for candle-based inference: ConstrainedLogitsProcessor
+// wraps candle_transformers' LogitsProcessor, so that
+//   let next_token = logits_processor.sample(&logits)?;
+// in a generation loop only produces tokens allowed by the recognizer.
+
+use candle_core::{Device, Error, Result, Tensor};
+use candle_transformers::generation::LogitsProcessor;
+use toktrie::{Recognizer, SimpleVob, TokEnv, TokenId};
+
+/// Additive bias for logits of `size` elements (the model's vocabulary size,
+/// which may be larger than the mask): 0.0 for allowed tokens, -inf otherwise.
+pub fn mask_tensor(mask: &SimpleVob, size: usize, device: &Device) -> Result<Tensor> {
+    let mut bias = vec![0.0f32; size];
+    mask.apply_to_logits(&mut bias, f32::NEG_INFINITY);
+    Tensor::from_vec(bias, size, device)
+}
+
+/// Set logits of disallowed tokens to -inf. The mask is applied along the last dimension
+/// of `logits`, which can have any float dtype and device.
+pub fn apply_mask(logits: &Tensor, mask: &SimpleVob) -> Result<Tensor> {
+    let size = logits.dims().last().copied().unwrap_or(0);
+    let bias = mask_tensor(mask, size, logits.device())?.to_dtype(logits.dtype())?;
+    logits.broadcast_add(&bias)
+}
+
+/// LogitsProcessor restricted to tokens allowed by a recognizer.
+/// Sampled tokens are appended to the recognizer; once a stop token
+/// is sampled, is_finished() returns true.
+pub struct ConstrainedLogitsProcessor<R: Recognizer> {
+    inner: LogitsProcessor,
+    tok_env: TokEnv,
+    rec: R,
+    mask: SimpleVob,
+    finished: bool,
+}
+
+impl<R: Recognizer> ConstrainedLogitsProcessor<R> {
+    pub fn new(inner: LogitsProcessor, tok_env: TokEnv, rec: R) -> Self {
+        let mask = tok_env.tok_trie().alloc_token_set();
+        ConstrainedLogitsProcessor {
+            inner,
+            tok_env,
+            rec,
+            mask,
+            finished: false,
+        }
+    }
+
+    pub fn recognizer(&self) -> &R {
+        &self.rec
+    }
+
+    pub fn recognizer_mut(&mut self) -> &mut R {
+        &mut self.rec
+    }
+
+    /// Whether a stop token was sampled.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Mask of tokens allowed by the recognizer in its current state.
+    pub fn compute_mask(&mut self) -> &SimpleVob {
+        self.tok_env
+            .tok_trie()
+            .compute_bias(&mut self.rec, &mut self.mask);
+        &self.mask
+    }
+
+    /// Sample the next token from `logits` (of shape [vocab_size]),
+    /// considering only tokens allowed by the recognizer, and append it.
+    /// Fails if no token is allowed, or after a stop token was sampled.
+    pub fn sample(&mut self, logits: &Tensor) -> Result<TokenId> {
+        if self.finished {
+            return Err(Error::msg("stop token already sampled"));
+        }
+        self.compute_mask();
+        if self.mask.is_zero() {
+            return Err(Error::msg("no tokens allowed by the recognizer"));
+        }
+        let logits = apply_mask(logits, &self.mask)?;
+        let tok = self.inner.sample(&logits)?;
+        self.append_token(tok)?;
+        Ok(tok)
+    }
+
+    /// Append a token produced outside of sample(), e.g., from the prompt
+    /// or a forced (fast-forward) token.
+    pub fn append_token(&mut self, tok: TokenId) -> Result<()> {
+        let trie = self.tok_env.tok_trie();
+        if trie.is_stop_token(tok) {
+            self.finished = true;
+            Ok(())
+        } else {
+            trie.append_token(&mut self.rec, tok)
+                .map_err(|e| Error::msg(format!("{:#}", e)))
+        }
+    }
+}
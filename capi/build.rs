@@ -0,0 +1,21 @@
+// Generates the C header into OUT_DIR. The checked-in toktrie.h is only
+// rewritten on request: TOKTRIE_UPDATE_HEADER=1 cargo build
+// (the header_up_to_date test fails when it is stale).
+fn main() {
+    let crate_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    let out_dir = std::env::var("OUT_DIR").unwrap();
+    println!("cargo:rerun-if-changed=src/lib.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    println!("cargo:rerun-if-env-changed=TOKTRIE_UPDATE_HEADER");
+    let config = cbindgen::Config::from_file(format!("{}/cbindgen.toml", crate_dir))
+        .expect("invalid cbindgen.toml");
+    let bindings = cbindgen::Builder::new()
+        .with_crate(&crate_dir)
+        .with_config(config)
+        .generate()
+        .expect("unable to generate C header");
+    bindings.write_to_file(format!("{}/toktrie.h", out_dir));
+    if std::env::var_os("TOKTRIE_UPDATE_HEADER").is_some() {
+        bindings.write_to_file(format!("{}/toktrie.h", crate_dir));
+    }
+}
@@ -0,0 +1,328 @@
+// C API over an opaque trie handle; the header (toktrie.h) is generated by cbindgen
+// (see build.rs for regenerating the checked-in copy).
+// Functions never unwind into C: errors and panics are turned into a failure result,
+// with the message available from toktrie_last_error() on the same thread.
+// Tokenization is greedy (longest match in the trie), not the tokenizer's own BPE
+// or Unigram algorithm, so token sequences can differ from what HF tokenizers produce
+// (they still decode to the same bytes).
+
+use std::{
+    cell::RefCell,
+    ffi::{c_char, CStr, CString},
+    ptr, slice,
+};
+
+use anyhow::{anyhow, Result};
+use toktrie::{
+    catch_step,
+    recognizer::{JsonSyntax, RegexDfa},
+    PreTokenizer, Recognizer, SpecialToken, TokTrie, TokenId,
+};
+
+/// Token trie loaded from a tokenizer.json file.
+pub struct TokTrieHandle {
+    trie: TokTrie,
+    pre: Option<PreTokenizer>,
+}
+
+/// Constraint on the generated bytes, used to compute token masks.
+pub struct TokTrieRecognizer {
+    rec: Box<dyn Recognizer>,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn set_error(msg: &str) {
+    let msg = CString::new(msg.replace('\0', "\\0")).unwrap();
+    LAST_ERROR.with(|e| *e.borrow_mut() = msg);
+}
+
+// Run f, storing the error message (if any) for toktrie_last_error().
+fn guard<T>(f: impl FnOnce() -> Result<T>) -> Option<T> {
+    match catch_step(0, f) {
+        Ok(v) => Some(v),
+        Err(e) => {
+            set_error(&e.message);
+            None
+        }
+    }
+}
+
+unsafe fn as_slice<'a, T>(data: *const T, len: usize) -> Result<&'a [T]> {
+    if len == 0 {
+        Ok(&[])
+    } else if data.is_null() {
+        Err(anyhow!("null pointer with non-zero length"))
+    } else {
+        Ok(slice::from_raw_parts(data, len))
+    }
+}
+
+unsafe fn as_ref<'a, T>(p: *const T) -> Result<&'a T> {
+    p.as_ref().ok_or_else(|| anyhow!("null handle"))
+}
+
+// Copy as much of src as fits into out; return the full length of src.
+unsafe fn copy_out<T: Copy>(src: &[T], out: *mut T, out_len: usize) -> usize {
+    let n = std::cmp::min(src.len(), out_len);
+    if n > 0 && !out.is_null() {
+        ptr::copy_nonoverlapping(src.as_ptr(), out, n);
+    }
+    src.len()
+}
+
+/// Message of the last error on the current thread (empty if none).
+/// The pointer is valid until the next failing call on this thread.
+#[no_mangle]
+pub extern "C" fn toktrie_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ptr())
+}
+
+/// Load a trie from the contents of a HuggingFace tokenizer.json file.
+/// Returns NULL on error.
+///
+/// # Safety
+/// data has to point to len readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn toktrie_new_from_tokenizer_json(
+    data: *const u8,
+    len: usize,
+) -> *mut TokTrieHandle {
+    guard(|| {
+        let data = as_slice(data, len)?;
+        Ok(Box::into_raw(Box::new(TokTrieHandle {
+            trie: TokTrie::from_tokenizer_json(data)?,
+            pre: PreTokenizer::from_tokenizer_json(data)?,
+        })))
+    })
+    .unwrap_or(ptr::null_mut())
+}
+
+/// Free a trie returned by toktrie_new_from_tokenizer_json(); NULL is ignored.
+///
+/// # Safety
+/// h has to be a valid handle, not used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn toktrie_free(h: *mut TokTrieHandle) {
+    if !h.is_null() {
+        drop(Box::from_raw(h));
+    }
+}
+
+/// Number of tokens in the vocabulary; 0 if h is NULL.
+///
+/// # Safety
+/// h has to be a valid handle or NULL.
+#[no_mangle]
+pub unsafe extern "C" fn toktrie_vocab_size(h: *const TokTrieHandle) -> u32 {
+    guard(|| Ok(as_ref(h)?.trie.vocab_size() as u32)).unwrap_or(0)
+}
+
+/// The end-of-sequence token; UINT32_MAX if h is NULL.
+///
+/// # Safety
+/// h has to be a valid handle or NULL.
+#[no_mangle]
+pub unsafe extern "C" fn toktrie_eos_token(h: *const TokTrieHandle) -> u32 {
+    guard(|| Ok(as_ref(h)?.trie.eos_token())).unwrap_or(u32::MAX)
+}
+
+/// Number of uint32_t words in a token mask (bit i of word i/32 is token i); 0 if h is NULL.
+///
+/// # Safety
+/// h has to be a valid handle or NULL.
+#[no_mangle]
+pub unsafe extern "C" fn toktrie_mask_words(h: *const TokTrieHandle) -> usize {
+    guard(|| Ok(as_ref(h)?.trie.vocab_size().div_ceil(32))).unwrap_or(0)
+}
+
+/// Tokenize bytes: pre-tokenization (if the tokenizer.json uses a known pattern),
+/// followed by greedy (longest-match) tokenization of each piece.
+/// This is not the tokenizer's own algorithm (e.g., BPE merges), so the tokens
+/// can differ from the ones HF tokenizers would produce, but they decode to the same bytes.
+/// Writes up to out_len tokens to out and returns the total number of tokens;
+/// if it is larger than out_len, call again with a bigger buffer.
+/// Returns -1 on error.
+///
+/// # Safety
+/// h has to be a valid handle, data has to point to len readable bytes,
+/// and out to out_len writable tokens.
+#[no_mangle]
+pub unsafe extern "C" fn toktrie_tokenize(
+    h: *const TokTrieHandle,
+    data: *const u8,
+    len: usize,
+    out: *mut u32,
+    out_len: usize,
+) -> isize {
+    guard(|| {
+        let h = as_ref(h)?;
+        let data = as_slice(data, len)?;
+        let tokens = match h.pre {
+            Some(pre) => h.trie.greedy_tokenize_split(data, pre),
+            None => h.trie.greedy_tokenize(data),
+        };
+        Ok(copy_out(&tokens, out, out_len) as isize)
+    })
+    .unwrap_or(-1)
+}
+
+/// Decode tokens to bytes (special tokens are skipped).
+/// Writes up to out_len bytes to out and returns the total number of bytes;
+/// if it is larger than out_len, call again with a bigger buffer.
+/// Returns -1 on error (e.g., a token out of range).
+///
+/// # Safety
+/// h has to be a valid handle, tokens has to point to num_tokens tokens,
+/// and out to out_len writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn toktrie_decode(
+    h: *const TokTrieHandle,
+    tokens: *const u32,
+    num_tokens: usize,
+    out: *mut u8,
+    out_len: usize,
+) -> isize {
+    guard(|| {
+        let h = as_ref(h)?;
+        let tokens = as_slice(tokens, num_tokens)?;
+        h.trie.check_tokens(tokens)?;
+        Ok(copy_out(&h.trie.decode(tokens), out, out_len) as isize)
+    })
+    .unwrap_or(-1)
+}
+
+fn new_recognizer(rec: impl Recognizer + 'static) -> *mut TokTrieRecognizer {
+    Box::into_raw(Box::new(TokTrieRecognizer { rec: Box::new(rec) }))
+}
+
+/// Recognizer for bytes matching given regex (whole match); NULL on error.
+///
+/// # Safety
+/// pattern has to be a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn toktrie_recognizer_new_regex(
+    pattern: *const c_char,
+) -> *mut TokTrieRecognizer {
+    guard(|| {
+        if pattern.is_null() {
+            return Err(anyhow!("null pattern"));
+        }
+        let pattern = CStr::from_ptr(pattern).to_str()?;
+        Ok(new_recognizer(RegexDfa::new(pattern)?.to_recognizer()))
+    })
+    .unwrap_or(ptr::null_mut())
+}
+
+/// Recognizer for a single well-formed JSON value, nested up to max_depth levels.
+#[no_mangle]
+pub extern "C" fn toktrie_recognizer_new_json(max_depth: usize) -> *mut TokTrieRecognizer {
+    new_recognizer(JsonSyntax::new(max_depth).to_recognizer())
+}
+
+/// Free a recognizer; NULL is ignored.
+///
+/// # Safety
+/// rec has to be a valid recognizer, not used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn toktrie_recognizer_free(rec: *mut TokTrieRecognizer) {
+    if !rec.is_null() {
+        drop(Box::from_raw(rec));
+    }
+}
+
+/// Compute the set of tokens allowed by the recognizer in its current state.
+/// mask has to hold toktrie_mask_words() words. Returns 0 on success, -1 on error.
+///
+/// # Safety
+/// h and rec have to be valid, and mask has to point to mask_words writable words.
+#[no_mangle]
+pub unsafe extern "C" fn toktrie_compute_mask(
+    h: *const TokTrieHandle,
+    rec: *mut TokTrieRecognizer,
+    mask: *mut u32,
+    mask_words: usize,
+) -> i32 {
+    guard(|| {
+        let h = as_ref(h)?;
+        let rec = rec.as_mut().ok_or_else(|| anyhow!("null recognizer"))?;
+        let needed = h.trie.vocab_size().div_ceil(32);
+        if mask.is_null() || mask_words < needed {
+            return Err(anyhow!("mask needs {} words", needed));
+        }
+        let mut set = h.trie.alloc_token_set();
+        h.trie.compute_bias(&mut rec.rec, &mut set);
+        copy_out(&set.as_slice()[..needed], mask, needed);
+        Ok(0)
+    })
+    .unwrap_or(-1)
+}
+
+/// Advance the recognizer by given token. Returns 0 on success,
+/// -1 if the token is not allowed (the recognizer is then unchanged).
+///
+/// # Safety
+/// h and rec have to be valid.
+#[no_mangle]
+pub unsafe extern "C" fn toktrie_append_token(
+    h: *const TokTrieHandle,
+    rec: *mut TokTrieRecognizer,
+    token: u32,
+) -> i32 {
+    guard(|| {
+        let h = as_ref(h)?;
+        let rec = rec.as_mut().ok_or_else(|| anyhow!("null recognizer"))?;
+        h.trie.check_tokens(&[token as TokenId])?;
+        if !h.trie.token_allowed(&mut rec.rec, token) {
+            return Err(anyhow!("token {} not allowed", h.trie.token_dbg(token)));
+        }
+        h.trie.append_token(&mut rec.rec, token)?;
+        Ok(0)
+    })
+    .unwrap_or(-1)
+}
+
+/// Check if the recognizer accepts end of sequence in its current state (1 if so, 0 otherwise).
+///
+/// # Safety
+/// rec has to be valid.
+#[no_mangle]
+pub unsafe extern "C" fn toktrie_recognizer_is_accepting(rec: *mut TokTrieRecognizer) -> i32 {
+    match rec.as_mut() {
+        Some(rec) => rec.rec.special_allowed(SpecialToken::EndOfSentence) as i32,
+        None => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_up_to_date() {
+        let generated = include_str!(concat!(env!("OUT_DIR"), "/toktrie.h"));
+        let checked_in = include_str!("../toktrie.h");
+        assert!(
+            generated == checked_in,
+            "toktrie.h is stale, regenerate with: TOKTRIE_UPDATE_HEADER=1 cargo build"
+        );
+    }
+
+    #[test]
+    fn null_handles() {
+        unsafe {
+            assert_eq!(toktrie_vocab_size(ptr::null()), 0);
+            assert_eq!(toktrie_eos_token(ptr::null()), u32::MAX);
+            assert_eq!(toktrie_mask_words(ptr::null()), 0);
+            let mut out = [0u32; 4];
+            assert_eq!(
+                toktrie_tokenize(ptr::null(), b"a".as_ptr(), 1, out.as_mut_ptr(), 4),
+                -1
+            );
+            let err = CStr::from_ptr(toktrie_last_error()).to_str().unwrap();
+            assert!(err.contains("null"), "{err}");
+        }
+    }
+}
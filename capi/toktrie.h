@@ -0,0 +1,164 @@
+#ifndef TOKTRIE_H
+#define TOKTRIE_H
+
+/* Generated by cbindgen from capi/src/lib.rs; do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Token trie loaded from a tokenizer.json file.
+ */
+typedef struct TokTrieHandle TokTrieHandle;
+
+/**
+ * Constraint on the generated bytes, used to compute token masks.
+ */
+typedef struct TokTrieRecognizer TokTrieRecognizer;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Message of the last error on the current thread (empty if none).
+ * The pointer is valid until the next failing call on this thread.
+ */
+const char *toktrie_last_error(void);
+
+/**
+ * Load a trie from the contents of a HuggingFace tokenizer.json file.
+ * Returns NULL on error.
+ *
+ * # Safety
+ * data has to point to len readable bytes.
+ */
+struct TokTrieHandle *toktrie_new_from_tokenizer_json(const uint8_t *data, size_t len);
+
+/**
+ * Free a trie returned by toktrie_new_from_tokenizer_json(); NULL is ignored.
+ *
+ * # Safety
+ * h has to be a valid handle, not used afterwards.
+ */
+void toktrie_free(struct TokTrieHandle *h);
+
+/**
+ * Number of tokens in the vocabulary; 0 if h is NULL.
+ *
+ * # Safety
+ * h has to be a valid handle or NULL.
+ */
+uint32_t toktrie_vocab_size(const struct TokTrieHandle *h);
+
+/**
+ * The end-of-sequence token; UINT32_MAX if h is NULL.
+ *
+ * # Safety
+ * h has to be a valid handle or NULL.
+ */
+uint32_t toktrie_eos_token(const struct TokTrieHandle *h);
+
+/**
+ * Number of uint32_t words in a token mask (bit i of word i/32 is token i); 0 if h is NULL.
+ *
+ * # Safety
+ * h has to be a valid handle or NULL.
+ */
+size_t toktrie_mask_words(const struct TokTrieHandle *h);
+
+/**
+ * Tokenize bytes: pre-tokenization (if the tokenizer.json uses a known pattern),
+ * followed by greedy (longest-match) tokenization of each piece.
+ * This is not the tokenizer's own algorithm (e.g., BPE merges), so the tokens
+ * can differ from the ones HF tokenizers would produce, but they decode to the same bytes.
+ * Writes up to out_len tokens to out and returns the total number of tokens;
+ * if it is larger than out_len, call again with a bigger buffer.
+ * Returns -1 on error.
+ *
+ * # Safety
+ * h has to be a valid handle, data has to point to len readable bytes,
+ * and out to out_len writable tokens.
+ */
+ptrdiff_t toktrie_tokenize(const struct TokTrieHandle *h,
+                           const uint8_t *data,
+                           size_t len,
+                           uint32_t *out,
+                           size_t out_len);
+
+/**
+ * Decode tokens to bytes (special tokens are skipped).
+ * Writes up to out_len bytes to out and returns the total number of bytes;
+ * if it is larger than out_len, call again with a bigger buffer.
+ * Returns -1 on error (e.g., a token out of range).
+ *
+ * # Safety
+ * h has to be a valid handle, tokens has to point to num_tokens tokens,
+ * and out to out_len writable bytes.
+ */
+ptrdiff_t toktrie_decode(const struct TokTrieHandle *h,
+                         const uint32_t *tokens,
+                         size_t num_tokens,
+                         uint8_t *out,
+                         size_t out_len);
+
+/**
+ * Recognizer for bytes matching given regex (whole match); NULL on error.
+ *
+ * # Safety
+ * pattern has to be a NUL-terminated string.
+ */
+struct TokTrieRecognizer *toktrie_recognizer_new_regex(const char *pattern);
+
+/**
+ * Recognizer for a single well-formed JSON value, nested up to max_depth levels.
+ */
+struct TokTrieRecognizer *toktrie_recognizer_new_json(size_t max_depth);
+
+/**
+ * Free a recognizer; NULL is ignored.
+ *
+ * # Safety
+ * rec has to be a valid recognizer, not used afterwards.
+ */
+void toktrie_recognizer_free(struct TokTrieRecognizer *rec);
+
+/**
+ * Compute the set of tokens allowed by the recognizer in its current state.
+ * mask has to hold toktrie_mask_words() words. Returns 0 on success, -1 on error.
+ *
+ * # Safety
+ * h and rec have to be valid, and mask has to point to mask_words writable words.
+ */
+int32_t toktrie_compute_mask(const struct TokTrieHandle *h,
+                             struct TokTrieRecognizer *rec,
+                             uint32_t *mask,
+                             size_t mask_words);
+
+/**
+ * Advance the recognizer by given token. Returns 0 on success,
+ * -1 if the token is not allowed (the recognizer is then unchanged).
+ *
+ * # Safety
+ * h and rec have to be valid.
+ */
+int32_t toktrie_append_token(const struct TokTrieHandle *h,
+                             struct TokTrieRecognizer *rec,
+                             uint32_t token);
+
+/**
+ * Check if the recognizer accepts end of sequence in its current state (1 if so, 0 otherwise).
+ *
+ * # Safety
+ * rec has to be valid.
+ */
+int32_t toktrie_recognizer_is_accepting(struct TokTrieRecognizer *rec);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* TOKTRIE_H */
@@ -0,0 +1,224 @@
+// Command-line tool for inspecting tokenizers and the trie, e.g.:
+//   toktrie tokenize --tokenizer tokenizer.json "Hello</s>"
+//   toktrie decode --tokenizer tokenizer.json --ids 1,2,3
+//   toktrie inspect --tokenizer tokenizer.json
+//   toktrie mask --tokenizer tokenizer.json --regex "[0-9]+"
+
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use clap::{Args, Parser, Subcommand};
+use toktrie::{recognizer::RegexDfa, Recognizer, SpecialToken, TokEnv, TokenId};
+use toktrie_hf_tokenizers::HfTokenizerEnv;
+
+#[derive(Parser)]
+#[command(name = "toktrie", about = "Inspect tokenizers and token tries")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Args)]
+struct TokenizerArg {
+    /// tokenizer.json file, or HuggingFace model name
+    #[arg(long, short)]
+    tokenizer: String,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Tokenize text and list the tokens.
+    Tokenize {
+        #[command(flatten)]
+        tok: TokenizerArg,
+        /// Recognize special token names (like </s>) in the text.
+        #[arg(long)]
+        special: bool,
+        /// Also show greedy (longest-match) tokenization with the trie.
+        #[arg(long)]
+        greedy: bool,
+        text: String,
+    },
+    /// Decode a list of token ids.
+    Decode {
+        #[command(flatten)]
+        tok: TokenizerArg,
+        /// Comma-separated token ids.
+        #[arg(long, value_delimiter = ',', num_args = 1..)]
+        ids: Vec<TokenId>,
+        /// Skip special tokens.
+        #[arg(long)]
+        skip_special: bool,
+    },
+    /// Vocabulary statistics and special tokens.
+    Inspect {
+        #[command(flatten)]
+        tok: TokenizerArg,
+    },
+    /// Count the tokens allowed by a regex.
+    Mask {
+        #[command(flatten)]
+        tok: TokenizerArg,
+        #[arg(long)]
+        regex: String,
+        /// Text already generated, to be consumed by the regex first.
+        #[arg(long, default_value = "")]
+        prefix: String,
+        /// Number of allowed tokens to list.
+        #[arg(long, default_value_t = 20)]
+        show: usize,
+    },
+}
+
+fn load(arg: &TokenizerArg) -> Result<TokEnv> {
+    let env = if Path::new(&arg.tokenizer).exists() {
+        HfTokenizerEnv::from_file(&arg.tokenizer)?
+    } else {
+        HfTokenizerEnv::from_name(&arg.tokenizer, None)?
+    };
+    Ok(env.to_env())
+}
+
+fn print_tokens(env: &TokEnv, tokens: &[TokenId]) {
+    let trie = env.tok_trie();
+    println!("{:?}", tokens);
+    for &t in tokens {
+        println!("{:>8}  {}", t, trie.token_dbg(t));
+    }
+    println!("{} tokens", tokens.len());
+}
+
+fn tokenize(tok: &TokenizerArg, special: bool, greedy: bool, text: &str) -> Result<()> {
+    let env = load(tok)?;
+    let tokens = if special {
+        env.tokenize_special(text)
+    } else {
+        env.tokenize(text)
+    };
+    print_tokens(&env, &tokens);
+    if greedy {
+        let trie = env.tok_trie();
+        let g = if special {
+            trie.tokenize_with_special(text.as_bytes(), |s| trie.greedy_tokenize(s))
+        } else {
+            trie.greedy_tokenize(text.as_bytes())
+        };
+        println!("\ngreedy:");
+        print_tokens(&env, &g);
+        if g != tokens {
+            println!("greedy tokenization differs");
+        }
+    }
+    Ok(())
+}
+
+fn decode(tok: &TokenizerArg, ids: &[TokenId], skip_special: bool) -> Result<()> {
+    let env = load(tok)?;
+    env.tok_trie().check_tokens(ids)?;
+    print_tokens(&env, ids);
+    let bytes = env.decode_bytes_ext(ids, !skip_special);
+    println!("{:?}", String::from_utf8_lossy(&bytes));
+    Ok(())
+}
+
+fn inspect(tok: &TokenizerArg) -> Result<()> {
+    let env = load(tok)?;
+    let trie = env.tok_trie();
+    let info = trie.info();
+    let n = trie.vocab_size() as TokenId;
+
+    let special: Vec<TokenId> = (0..n).filter(|&t| trie.is_special_token(t)).collect();
+    let empty = (0..n)
+        .filter(|&t| trie.token(t).is_empty() && !trie.is_special_token(t))
+        .count();
+    let regular = n as usize - special.len() - empty;
+    let avg_len = (0..n)
+        .filter(|&t| !trie.is_special_token(t))
+        .map(|t| trie.token(t).len())
+        .sum::<usize>() as f64
+        / std::cmp::max(regular, 1) as f64;
+
+    println!("vocab size: {}", n);
+    println!("regular tokens: {}", regular);
+    println!("special tokens: {}", special.len());
+    println!("empty tokens: {}", empty);
+    println!("max token length: {} bytes", trie.max_token_len());
+    println!("avg token length: {:.2} bytes", avg_len);
+    println!(
+        "single-byte tokens: {}/256",
+        (0..=255u8)
+            .filter(|&b| trie.token_id_at_bytes(&[b]).is_some())
+            .count()
+    );
+
+    println!("\nroles:");
+    for role in [
+        SpecialToken::EndOfSentence,
+        SpecialToken::BeginningOfSentence,
+        SpecialToken::Padding,
+        SpecialToken::Unknown,
+        SpecialToken::EndOfTurn,
+    ] {
+        if let Some(t) = info.role_token(role) {
+            println!("  {:?}: {} {}", role, t, trie.token_dbg(t));
+        }
+    }
+    for (role, t) in info.extra_role_tokens() {
+        println!("  {:?}: {} {}", role, t, trie.token_dbg(t));
+    }
+    println!("stop tokens: {}", trie.tokens_dbg(trie.stop_tokens()));
+
+    println!("\nspecial tokens:");
+    for t in special {
+        println!("{:>8}  {}", t, trie.token_dbg(t));
+    }
+    Ok(())
+}
+
+fn mask(tok: &TokenizerArg, regex: &str, prefix: &str, show: usize) -> Result<()> {
+    let env = load(tok)?;
+    let trie = env.tok_trie();
+    let mut rec = RegexDfa::new(regex)?.to_recognizer();
+    for (idx, &b) in prefix.as_bytes().iter().enumerate() {
+        if !rec.try_push_byte(b) {
+            return Err(anyhow!("prefix not allowed by regex at byte {}", idx));
+        }
+    }
+    rec.collapse();
+    let mut set = trie.alloc_token_set();
+    trie.compute_bias(&mut rec, &mut set);
+    let allowed: Vec<TokenId> = (0..trie.vocab_size() as TokenId)
+        .filter(|&t| set.is_allowed(t))
+        .collect();
+    println!("{} of {} tokens allowed", allowed.len(), trie.vocab_size());
+    for &t in allowed.iter().take(show) {
+        println!("{:>8}  {}", t, trie.token_dbg(t));
+    }
+    if allowed.len() > show {
+        println!("...");
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    match Cli::parse().command {
+        Command::Tokenize {
+            tok,
+            special,
+            greedy,
+            text,
+        } => tokenize(&tok, special, greedy, &text),
+        Command::Decode {
+            tok,
+            ids,
+            skip_special,
+        } => decode(&tok, &ids, skip_special),
+        Command::Inspect { tok } => inspect(&tok),
+        Command::Mask {
+            tok,
+            regex,
+            prefix,
+            show,
+        } => mask(&tok, &regex, &prefix, show),
+    }
+}
@@ -0,0 +1,118 @@
+// Benchmarks of trie building, mask computation, tokenization and mask operations.
+// Real vocabularies are read from $TOKTRIE_BENCH_DIR/{cl100k,llama2,llama3}.json
+// (HuggingFace tokenizer.json files) when present; a synthetic vocabulary is always used.
+// See benches/README.md.
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use toktrie::{
+    recognizer::{JsonSyntax, RegexDfa},
+    SimpleVob, TestTokEnv, TokTrie, TokenizerEnv,
+};
+
+const VOCABS: &[&str] = &["cl100k", "llama2", "llama3"];
+
+// Deterministic vocabulary of ~32k lowercase words, half with a leading space,
+// roughly matching the shape of BPE vocabularies.
+fn synthetic_trie() -> TokTrie {
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    let mut words = vec![];
+    for i in 0..32_000 {
+        let len = 2 + (next() % 7) as usize;
+        let mut w: String = (0..len)
+            .map(|_| (b'a' + (next() % 26) as u8) as char)
+            .collect();
+        if i % 2 == 0 {
+            w.insert(0, ' ');
+        }
+        words.push(w);
+    }
+    let words: Vec<&str> = words.iter().map(|w| w.as_str()).collect();
+    TestTokEnv::with_words(&words).tok_trie().clone()
+}
+
+fn load_vocabs() -> Vec<(String, TokTrie)> {
+    let mut r = vec![("synthetic".to_string(), synthetic_trie())];
+    if let Ok(dir) = std::env::var("TOKTRIE_BENCH_DIR") {
+        for name in VOCABS {
+            let path = format!("{}/{}.json", dir, name);
+            match TokTrie::from_tokenizer_json_file(&path) {
+                Ok(trie) => r.push((name.to_string(), trie)),
+                Err(e) => eprintln!("skipping {}: {}", name, e),
+            }
+        }
+    }
+    r
+}
+
+// Mostly-ASCII text with some punctuation, numbers and multi-byte characters.
+fn sample_text() -> String {
+    let para = "The quick brown fox jumps over the lazy dog, 12345 times! \
+                {\"name\": \"Zoë\", \"age\": 42, \"tags\": [\"a\", \"b\"]}\n\
+                Naïve café owners serve crème brûlée at 7:30pm — über lecker. ";
+    para.repeat(64)
+}
+
+fn bench_vocab(c: &mut Criterion, name: &str, trie: &TokTrie) {
+    let mut g = c.benchmark_group(name);
+
+    let words: Vec<Vec<u8>> = (0..trie.vocab_size() as u32)
+        .map(|t| trie.token(t).to_vec())
+        .collect();
+    g.bench_function("build", |b| {
+        b.iter(|| TokTrie::from(black_box(trie.info()), black_box(&words)))
+    });
+
+    let mut mask = trie.alloc_token_set();
+    let mut regex = RegexDfa::new("[a-zA-Z ]+[0-9]*").unwrap().to_recognizer();
+    g.bench_function("compute_bias_regex", |b| {
+        b.iter(|| trie.compute_bias(&mut regex, &mut mask))
+    });
+
+    let mut json = JsonSyntax::new(JsonSyntax::MAX_DEPTH).to_recognizer();
+    trie.append_tokens(&mut json, &trie.greedy_tokenize(b"{\"name\": \""))
+        .unwrap();
+    g.bench_function("compute_bias_json_string", |b| {
+        b.iter(|| trie.compute_bias(&mut json, &mut mask))
+    });
+
+    let text = sample_text();
+    g.bench_function("greedy_tokenize", |b| {
+        b.iter(|| trie.greedy_tokenize(black_box(text.as_bytes())))
+    });
+
+    let n = trie.vocab_size();
+    let a = SimpleVob::from_token_ids((0..n as u32).step_by(3), n);
+    let other = SimpleVob::from_token_ids((0..n as u32).step_by(5), n);
+    let mut dst = a.clone();
+    g.bench_function("mask_or_and_sub", |b| {
+        b.iter(|| {
+            dst.or(black_box(&other));
+            dst.and(black_box(&a));
+            dst.sub(black_box(&other));
+        })
+    });
+    g.bench_function("mask_num_set", |b| b.iter(|| black_box(&a).num_set()));
+    let mut logits = vec![0.0f32; n];
+    g.bench_function("mask_apply_to", |b| {
+        b.iter(|| a.apply_to(black_box(&mut logits)))
+    });
+
+    g.finish();
+}
+
+fn benches(c: &mut Criterion) {
+    for (name, trie) in load_vocabs() {
+        bench_vocab(c, &name, &trie);
+    }
+}
+
+criterion_group!(toktrie_benches, benches);
+criterion_main!(toktrie_benches);
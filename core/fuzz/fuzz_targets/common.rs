@@ -0,0 +1,18 @@
+// Shared vocabulary for the fuzz targets: all bytes (which split multi-byte
+// UTF-8 characters), some ASCII words and JSON punctuation, a few
+// multi-byte characters, and the special token <|end|>.
+
+use std::sync::OnceLock;
+
+use toktrie::TestTokEnv;
+
+const WORDS: &[&str] = &[
+    "he", "hel", "hello", " hello", "world", " world", "wor", "ld", "12", "123", "0.5", "e+",
+    "{\"", "\":", "\": ", "\",", "\"}", "[\"", "\"]", "{}", "[]", "\\\"", "\\u", "true", "false",
+    "null", "  ", "\n", "\n\n", "é", "ü", "€", "😀", "ab", "abc", "bc", "cd",
+];
+
+pub fn env() -> &'static TestTokEnv {
+    static ENV: OnceLock<TestTokEnv> = OnceLock::new();
+    ENV.get_or_init(|| TestTokEnv::with_words(WORDS))
+}
@@ -0,0 +1,87 @@
+// Random bytes pushed into recognizers, checking the token mask at every step.
+// The first input byte selects the recognizer; for the grammar recognizer,
+// the grammar text is read up to the first zero byte.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use toktrie::{
+    recognizer::{Cfg, JsonSyntax, NumberRange, OneOf, RegexDfa, Substring},
+    Recognizer, TokTrie, TokenizerEnv,
+};
+
+mod common;
+
+const REGEXES: &[&str] = &[
+    "[a-z ]+[0-9]*",
+    "(hello|world)( (hello|world))*",
+    "\"([^\"\\\\]|\\\\.)*\"",
+    "[😀é€]{1,3}x?",
+];
+
+const GRAMMAR: &str = r#"
+start ::= value
+value ::= "[" ( value ( "," value )* )? "]" | [0-9]+ | "\"" [a-z]* "\""
+"#;
+
+fn check_steps(trie: &TokTrie, rec: &mut impl Recognizer, bytes: &[u8]) {
+    let mut mask = trie.alloc_token_set();
+    for &b in bytes {
+        mask.set_all(false);
+        trie.compute_bias(rec, &mut mask);
+        for tok in 0..trie.vocab_size() as u32 {
+            if !trie.is_special_token(tok) {
+                assert_eq!(
+                    mask.is_allowed(tok),
+                    trie.token_allowed(rec, tok),
+                    "token {}",
+                    trie.token_dbg(tok)
+                );
+            }
+        }
+        if rec.try_push_byte_ext(b).is_ok() {
+            rec.collapse();
+        }
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let trie = common::env().tok_trie();
+    let Some((&sel, data)) = data.split_first() else {
+        return;
+    };
+    match sel % 8 {
+        0..4 => {
+            let mut rec = RegexDfa::new(REGEXES[sel as usize % 4])
+                .unwrap()
+                .to_recognizer();
+            check_steps(trie, &mut rec, data);
+        }
+        4 => check_steps(trie, &mut JsonSyntax::new(4).to_recognizer(), data),
+        5 => check_steps(
+            trie,
+            &mut Cfg::parse(GRAMMAR).unwrap().to_recognizer(),
+            data,
+        ),
+        6 => {
+            let mut rec = OneOf::new(&["hello", "help", "world"]).to_recognizer();
+            check_steps(trie, &mut rec, data);
+            let mut rec = Substring::new("hello world").to_recognizer();
+            check_steps(trie, &mut rec, data);
+            let mut rec = NumberRange::decimal(-12.5, 300.0, 2).to_recognizer();
+            check_steps(trie, &mut rec, data);
+        }
+        _ => {
+            let (grammar, data) = match data.iter().position(|&b| b == 0) {
+                Some(p) => (&data[..p], &data[p + 1..]),
+                None => (data, &[][..]),
+            };
+            let Ok(grammar) = std::str::from_utf8(grammar) else {
+                return;
+            };
+            if let Ok(cfg) = Cfg::parse(grammar) {
+                check_steps(trie, &mut cfg.to_recognizer(), data);
+            }
+        }
+    }
+});
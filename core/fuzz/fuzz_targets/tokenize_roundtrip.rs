@@ -0,0 +1,41 @@
+// Arbitrary bytes through tokenization and back.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use toktrie::{StreamDecoder, TokenizerEnv};
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    let env = common::env();
+    let trie = env.tok_trie();
+
+    let toks = trie.greedy_tokenize(data);
+    assert_eq!(trie.decode_raw(&toks), data);
+    assert_eq!(trie.greedy_count(data), toks.len());
+    trie.check_tokens(&toks).unwrap();
+
+    // decoding token by token gives the same text as decoding everything at once
+    let mut dec = StreamDecoder::new();
+    let mut text = String::new();
+    for &t in &toks {
+        text.push_str(&dec.push_token(trie, t));
+    }
+    text.push_str(&dec.flush());
+    assert_eq!(text, String::from_utf8_lossy(&trie.decode(&toks)));
+
+    // token spans are contiguous
+    let spans = env.tokenize_bytes_with_offsets(data);
+    assert_eq!(spans.len(), toks.len());
+    let mut pos = 0;
+    for (_, r) in spans {
+        assert_eq!(r.start, pos);
+        pos = r.end;
+    }
+
+    // text with special token markers; unknown names are dropped
+    let toks = env.tokenize_bytes_marker(data);
+    trie.check_tokens(&toks).unwrap();
+    let _ = trie.decode(&toks);
+});
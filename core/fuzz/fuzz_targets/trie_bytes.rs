@@ -0,0 +1,59 @@
+// Deserialization of corrupted tries: try_from_bytes() must either fail
+// or return a trie that is consistent with its token table.
+// With an even first byte, the rest of the input is a list of
+// (offset, xor) byte pairs patched into a valid serialized trie.
+
+#![no_main]
+
+use std::sync::OnceLock;
+
+use libfuzzer_sys::fuzz_target;
+use toktrie::{recognizer::RegexDfa, TokTrie, TokenizerEnv};
+
+mod common;
+
+fn valid_bytes() -> &'static [u8] {
+    static BYTES: OnceLock<Vec<u8>> = OnceLock::new();
+    BYTES.get_or_init(|| common::env().tok_trie().serialize())
+}
+
+fn check(trie: &TokTrie) {
+    let n = trie.vocab_size() as u32;
+    for tok in 0..n {
+        let bytes = trie.token(tok);
+        if bytes.is_empty() {
+            continue;
+        }
+        let t2 = trie.token_id(bytes).unwrap();
+        assert_eq!(trie.token(t2), bytes);
+    }
+    assert!(trie.eos_token() < n);
+
+    let again = TokTrie::try_from_bytes(&trie.serialize()).unwrap();
+    assert_eq!(again.serialize(), trie.serialize());
+
+    let mut mask = trie.alloc_token_set();
+    let mut rec = RegexDfa::new("[a-z\"{}]+").unwrap().to_recognizer();
+    trie.compute_bias(&mut rec, &mut mask);
+    let _ = trie.sorted_tokens();
+    let _ = trie.trie_stats();
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&sel, data)) = data.split_first() else {
+        return;
+    };
+    let bytes = if sel % 2 == 0 {
+        let mut bytes = valid_bytes().to_vec();
+        for p in data.chunks_exact(3) {
+            let off = u16::from_le_bytes([p[0], p[1]]) as usize % bytes.len();
+            bytes[off] ^= p[2];
+        }
+        bytes
+    } else {
+        data.to_vec()
+    };
+    if let Ok(trie) = TokTrie::try_from_bytes(&bytes) {
+        check(&trie);
+    }
+});
@@ -1,9 +1,11 @@
-use std::mem::size_of;
+use core::mem::size_of;
 
 use anyhow::{anyhow, Result};
 use bytemuck::{NoUninit, Pod as PodTrait};
 use bytemuck_derive::{Pod, Zeroable};
 
+use crate::prelude::*;
+
 #[derive(Clone, Copy, PartialEq, Eq, Debug, Zeroable, Pod)]
 #[repr(C)]
 pub struct U32Pair(pub u32, pub u32);
@@ -23,6 +25,14 @@ pub fn vec_from_bytes<T: PodTrait>(bytes: &[u8]) -> Vec<T> {
     bytemuck::cast_slice(bytes).to_vec()
 }
 
+/// Like vec_from_bytes(), but bytes don't have to be aligned.
+pub fn vec_from_unaligned_bytes<T: PodTrait>(bytes: &[u8]) -> Vec<T> {
+    assert!(bytes.len().is_multiple_of(size_of::<T>()));
+    let mut r = vec![T::zeroed(); bytes.len() / size_of::<T>()];
+    bytemuck::cast_slice_mut(&mut r).copy_from_slice(bytes);
+    r
+}
+
 pub fn limit_str(s: &str, max_len: usize) -> String {
     limit_bytes(s.as_bytes(), max_len)
 }
@@ -55,3 +65,94 @@ pub fn from_hex_string(s: &str) -> Result<Vec<u8>> {
     }
     Ok(result)
 }
+
+// GPT-2 byte-level alphabet: printable bytes map to the same code point,
+// the remaining 68 bytes (0x00-0x20, 0x7F-0xA0, 0xAD) map to U+0100 onwards, in order.
+// Thus space is 'Ġ' (U+0120) and newline is 'Ċ' (U+010A).
+
+fn is_byte_level_self_mapped(b: u32) -> bool {
+    matches!(b, 0x21..=0x7E | 0xA1..=0xAC | 0xAE..=0xFF)
+}
+
+/// Map a byte to its character in the byte-level alphabet.
+pub fn byte_level_char(b: u8) -> char {
+    let b = b as u32;
+    let c = if is_byte_level_self_mapped(b) {
+        b
+    } else if b <= 0x20 {
+        0x100 + b
+    } else if b <= 0xA0 {
+        0x100 + 33 + (b - 0x7F)
+    } else {
+        0x100 + 67
+    };
+    char::from_u32(c).unwrap()
+}
+
+/// Map a character of the byte-level alphabet back to its byte.
+pub fn byte_level_byte(c: char) -> Option<u8> {
+    let c = c as u32;
+    if is_byte_level_self_mapped(c) {
+        Some(c as u8)
+    } else if (0x100..0x100 + 33).contains(&c) {
+        Some((c - 0x100) as u8)
+    } else if (0x100 + 33..0x100 + 67).contains(&c) {
+        Some((c - 0x100 - 33 + 0x7F) as u8)
+    } else if c == 0x100 + 67 {
+        Some(0xAD)
+    } else {
+        None
+    }
+}
+
+/// Decode a byte-level token string (like "Ġhello") to raw bytes.
+/// Returns None if the string has characters outside of the alphabet.
+pub fn byte_level_decode(s: &str) -> Option<Vec<u8>> {
+    s.chars().map(byte_level_byte).collect()
+}
+
+/// Encode raw bytes as a byte-level token string.
+pub fn byte_level_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| byte_level_char(b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_level_alphabet() {
+        // bytes_to_unicode() from GPT-2's encoder.py
+        let mut n = 0;
+        for b in 0..=255u8 {
+            let expected = if is_byte_level_self_mapped(b as u32) {
+                b as u32
+            } else {
+                n += 1;
+                0x100 + n - 1
+            };
+            let c = byte_level_char(b);
+            assert_eq!(c as u32, expected, "byte {b:#x}");
+            assert_eq!(byte_level_byte(c), Some(b));
+        }
+        assert_eq!(n, 68);
+
+        assert_eq!(byte_level_char(b' '), 'Ġ');
+        assert_eq!(byte_level_char(b'\n'), 'Ċ');
+        assert_eq!(byte_level_char(0xAD), 'Ń');
+        assert_eq!(byte_level_byte('Ń'), Some(0xAD));
+        assert_eq!(byte_level_byte('ń'), None);
+        assert_eq!(byte_level_byte('\u{20AC}'), None);
+    }
+
+    #[test]
+    fn byte_level_roundtrip() {
+        let all: Vec<u8> = (0..=255).collect();
+        let s = byte_level_encode(&all);
+        assert_eq!(s.chars().count(), 256);
+        assert_eq!(byte_level_decode(&s), Some(all));
+        assert_eq!(byte_level_encode(b"\nhello world"), "Ċhello\u{120}world");
+        assert_eq!(byte_level_decode("Ġhello").unwrap(), b" hello");
+        assert_eq!(byte_level_decode("hello\u{20AC}"), None);
+    }
+}
@@ -0,0 +1,44 @@
+use std::time::{Duration, Instant};
+
+/// Time budget for a step, so that expensive work (e.g., computing a precise mask)
+/// can check how much time is left, and fall back to something cheaper
+/// instead of running over the limit.
+#[derive(Clone, Copy, Debug)]
+pub struct Deadline {
+    start: Instant,
+    end: Instant,
+}
+
+impl Deadline {
+    pub fn new(budget: Duration) -> Self {
+        let start = Instant::now();
+        Deadline {
+            start,
+            end: start + budget,
+        }
+    }
+
+    pub fn from_micros(micros: u64) -> Self {
+        Self::new(Duration::from_micros(micros))
+    }
+
+    /// Microseconds since the deadline was created (monotonic).
+    pub fn elapsed_micros(&self) -> u64 {
+        self.start.elapsed().as_micros() as u64
+    }
+
+    /// Zero once the deadline has passed.
+    pub fn time_remaining(&self) -> Duration {
+        self.end.saturating_duration_since(Instant::now())
+    }
+
+    pub fn is_expired(&self) -> bool {
+        Instant::now() >= self.end
+    }
+
+    /// Check if there is at least given fraction (0.0 to 1.0) of the budget left.
+    pub fn has_fraction_left(&self, fraction: f64) -> bool {
+        let total = self.end - self.start;
+        self.time_remaining().as_secs_f64() >= total.as_secs_f64() * fraction
+    }
+}
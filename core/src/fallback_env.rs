@@ -0,0 +1,116 @@
+use anyhow::{bail, Result};
+
+use crate::prelude::*;
+use crate::{TokEnv, TokTrie, TokenId, TokenizerEnv};
+
+/// TokenizerEnv that tokenizes with the primary env, and when it fails
+/// (try_tokenize_bytes() returns an error, e.g., for input it can't encode),
+/// splits the input into valid UTF-8 runs and the bytes in between:
+/// the runs are tokenized with the primary env again, and the other bytes
+/// (and runs the primary env still fails on) with the fallback env.
+/// Only primary envs overriding try_tokenize_bytes() can fail; the default
+/// implementation never does, so with such a primary env the fallback is never used.
+/// Both envs have to use the same vocabulary; the trie of the primary env is used.
+pub struct FallbackTokEnv {
+    primary: TokEnv,
+    fallback: TokEnv,
+}
+
+impl FallbackTokEnv {
+    pub fn new(primary: TokEnv, fallback: TokEnv) -> Result<Self> {
+        let n0 = primary.tok_trie().vocab_size();
+        let n1 = fallback.tok_trie().vocab_size();
+        if n0 != n1 {
+            bail!("vocab size mismatch: primary {} vs fallback {}", n0, n1);
+        }
+        Ok(FallbackTokEnv { primary, fallback })
+    }
+
+    pub fn primary(&self) -> &TokEnv {
+        &self.primary
+    }
+
+    pub fn fallback(&self) -> &TokEnv {
+        &self.fallback
+    }
+
+    fn tokenize_spans(&self, s: &[u8]) -> Result<Vec<TokenId>> {
+        let mut r = Vec::new();
+        for chunk in s.utf8_chunks() {
+            let valid = chunk.valid().as_bytes();
+            if !valid.is_empty() {
+                match self.primary.try_tokenize_bytes(valid) {
+                    Ok(toks) => r.extend_from_slice(&toks),
+                    Err(_) => r.extend_from_slice(&self.fallback.try_tokenize_bytes(valid)?),
+                }
+            }
+            if !chunk.invalid().is_empty() {
+                r.extend_from_slice(&self.fallback.try_tokenize_bytes(chunk.invalid())?);
+            }
+        }
+        Ok(r)
+    }
+}
+
+impl TokenizerEnv for FallbackTokEnv {
+    fn tok_trie(&self) -> &TokTrie {
+        self.primary.tok_trie()
+    }
+
+    fn tokenize_bytes(&self, s: &[u8]) -> Vec<TokenId> {
+        self.try_tokenize_bytes(s)
+            .unwrap_or_else(|_| self.fallback.tokenize_bytes(s))
+    }
+
+    fn try_tokenize_bytes(&self, s: &[u8]) -> Result<Vec<TokenId>> {
+        self.primary
+            .try_tokenize_bytes(s)
+            .or_else(|_| self.tokenize_spans(s))
+    }
+
+    fn tokenize_is_canonical(&self) -> bool {
+        self.primary.tokenize_is_canonical() && self.fallback.tokenize_is_canonical()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TestTokEnv;
+    use alloc::sync::Arc;
+
+    // tokenizes into single bytes, and fails on invalid UTF-8
+    struct Utf8Only(TestTokEnv);
+
+    impl TokenizerEnv for Utf8Only {
+        fn tok_trie(&self) -> &TokTrie {
+            self.0.tok_trie()
+        }
+
+        fn tokenize_bytes(&self, s: &[u8]) -> Vec<TokenId> {
+            self.try_tokenize_bytes(s).unwrap()
+        }
+
+        fn try_tokenize_bytes(&self, s: &[u8]) -> Result<Vec<TokenId>> {
+            core::str::from_utf8(s)?;
+            Ok(s.iter().map(|&b| b as TokenId).collect())
+        }
+    }
+
+    #[test]
+    fn fallback_per_span() {
+        let env = FallbackTokEnv::new(
+            Arc::new(Utf8Only(TestTokEnv::with_words(&["ab"]))),
+            Arc::new(TestTokEnv::with_words(&["ab"])),
+        )
+        .unwrap();
+        let ab = env.tok_trie().token_id(b"ab").unwrap();
+        assert_eq!(env.tokenize_bytes(b"abab"), vec![97, 98, 97, 98]);
+        assert_eq!(
+            env.tokenize_bytes(b"ab\xFFab\xC3"),
+            vec![97, 98, 255, 97, 98, 0xC3]
+        );
+        assert_eq!(env.tokenize_bytes(b"\xFFab\xFF"), vec![255, 97, 98, 255]);
+        assert_ne!(env.tokenize_bytes(b"\xFFab"), vec![255, ab]);
+    }
+}
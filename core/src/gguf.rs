@@ -0,0 +1,333 @@
+// Loader for the tokenizer metadata embedded in GGUF model files.
+// See https://github.com/ggerganov/ggml/blob/master/docs/gguf.md
+// Only the metadata key-value section is read; tensor data is never touched.
+
+use std::io::{BufReader, Read};
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::{
+    sentencepiece::{TOKEN_TYPE_CONTROL, TOKEN_TYPE_UNKNOWN, TOKEN_TYPE_USER_DEFINED},
+    tokenizer_json::{token_to_bytes, DecoderKind},
+    FxHashMap, SpecialToken, TokRxInfo, TokTrie, TokenId,
+};
+
+const GGUF_MAGIC: u32 = 0x46554747; // "GGUF"
+
+// sanity limit for string and array lengths
+const MAX_LEN: u64 = 1 << 30;
+// arrays of arrays are allowed, but nothing we read nests deeper
+const MAX_DEPTH: usize = 4;
+// buffers grow as data is read, so allocation is bounded by the input size
+// and not by the (untrusted) lengths
+const MAX_PREALLOC: usize = 4096;
+
+#[derive(Debug, Clone)]
+enum GgufValue {
+    Int(i64),
+    String(String),
+    Array(Vec<GgufValue>),
+    // floats and bools are not needed for the vocabulary
+    Other,
+}
+
+impl GgufValue {
+    fn as_int(&self) -> Option<i64> {
+        match self {
+            GgufValue::Int(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            GgufValue::String(s) => Some(s.as_str()),
+            _ => None,
+        }
+    }
+
+    fn as_array(&self) -> Option<&[GgufValue]> {
+        match self {
+            GgufValue::Array(a) => Some(a.as_slice()),
+            _ => None,
+        }
+    }
+}
+
+struct GgufReader<R: Read> {
+    inner: R,
+    version: u32,
+}
+
+impl<R: Read> GgufReader<R> {
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut buf = [0u8; N];
+        self.inner.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes()?))
+    }
+
+    // lengths and counts are u32 in GGUF v1, u64 later
+    fn len(&mut self) -> Result<u64> {
+        let len = if self.version == 1 {
+            self.u32()? as u64
+        } else {
+            self.u64()?
+        };
+        if len > MAX_LEN {
+            bail!("GGUF length too large: {}", len);
+        }
+        Ok(len)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.len()?;
+        let mut buf = Vec::with_capacity(core::cmp::min(len as usize, MAX_PREALLOC));
+        (&mut self.inner).take(len).read_to_end(&mut buf)?;
+        if buf.len() as u64 != len {
+            bail!("unexpected end of GGUF data");
+        }
+        // some vocabularies have invalid UTF-8 in token strings
+        Ok(String::from_utf8(buf)
+            .unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).to_string()))
+    }
+
+    fn value(&mut self, tp: u32, depth: usize) -> Result<GgufValue> {
+        let v = match tp {
+            0 => GgufValue::Int(u8::from_le_bytes(self.bytes()?) as i64),
+            1 => GgufValue::Int(i8::from_le_bytes(self.bytes()?) as i64),
+            2 => GgufValue::Int(u16::from_le_bytes(self.bytes()?) as i64),
+            3 => GgufValue::Int(i16::from_le_bytes(self.bytes()?) as i64),
+            4 => GgufValue::Int(u32::from_le_bytes(self.bytes()?) as i64),
+            5 => GgufValue::Int(i32::from_le_bytes(self.bytes()?) as i64),
+            6 => {
+                self.bytes::<4>()?;
+                GgufValue::Other
+            }
+            7 => {
+                self.bytes::<1>()?;
+                GgufValue::Other
+            }
+            8 => GgufValue::String(self.string()?),
+            9 => {
+                if depth >= MAX_DEPTH {
+                    bail!("GGUF arrays nested too deep");
+                }
+                let elt_tp = self.u32()?;
+                let len = self.len()?;
+                let mut arr = Vec::with_capacity(core::cmp::min(len as usize, MAX_PREALLOC));
+                for _ in 0..len {
+                    arr.push(self.value(elt_tp, depth + 1)?);
+                }
+                GgufValue::Array(arr)
+            }
+            10 => GgufValue::Int(u64::from_le_bytes(self.bytes()?) as i64),
+            11 => GgufValue::Int(i64::from_le_bytes(self.bytes()?)),
+            12 => {
+                self.bytes::<8>()?;
+                GgufValue::Other
+            }
+            _ => bail!("unknown GGUF value type: {}", tp),
+        };
+        Ok(v)
+    }
+
+    fn metadata(mut self) -> Result<FxHashMap<String, GgufValue>> {
+        let magic = self.u32()?;
+        if magic != GGUF_MAGIC {
+            bail!("not a GGUF file (magic {:#x})", magic);
+        }
+        self.version = self.u32()?;
+        if self.version == 0 || self.version > 3 {
+            bail!("unsupported GGUF version: {}", self.version);
+        }
+        let _num_tensors = self.len()?;
+        let num_kv = self.len()?;
+        let mut res = FxHashMap::default();
+        for _ in 0..num_kv {
+            let key = self.string()?;
+            let tp = self.u32()?;
+            let value = self.value(tp, 0)?;
+            res.insert(key, value);
+        }
+        Ok(res)
+    }
+}
+
+impl TokTrie {
+    /// Build a trie from the tokenizer metadata (tokenizer.ggml.*) of a GGUF file.
+    /// Both SentencePiece ("llama") and byte-level BPE ("gpt2") vocabularies are supported.
+    /// Control and unknown tokens are prefixed with SPECIAL_TOKEN_MARKER.
+    /// Token scores are not needed for the trie and are ignored.
+    pub fn from_gguf(reader: impl Read) -> Result<Self> {
+        let meta = GgufReader {
+            inner: reader,
+            version: 0,
+        }
+        .metadata()?;
+
+        let get = |key: &str| meta.get(&format!("tokenizer.ggml.{}", key));
+
+        let kind = match get("model").and_then(|v| v.as_str()) {
+            Some("llama") | Some("t5") => DecoderKind::ByteFallback { space_ch: '▁' },
+            Some("gpt2") => DecoderKind::ByteLevel,
+            Some(m) => bail!("unsupported GGUF tokenizer model: {:?}", m),
+            None => bail!("missing tokenizer.ggml.model"),
+        };
+        let tokens = get("tokens")
+            .and_then(|v| v.as_array())
+            .ok_or_else(|| anyhow!("missing tokenizer.ggml.tokens"))?;
+        let token_types = get("token_type").and_then(|v| v.as_array());
+
+        let vocab_size = tokens.len() as u32;
+        let special_id = |key: &str| {
+            get(key)
+                .and_then(|v| v.as_int())
+                .filter(|&id| id >= 0 && (id as u32) < vocab_size)
+                .map(|id| id as TokenId)
+        };
+
+        let mut info = TokRxInfo::new(vocab_size, special_id("eos_token_id").unwrap_or(0));
+        info.tok_bos = special_id("bos_token_id");
+        info.tok_unk = special_id("unknown_token_id");
+        info.tok_pad = special_id("padding_token_id");
+        info.tok_end_of_turn = special_id("eot_token_id");
+        if let Some(id) = special_id("eom_token_id") {
+            info.add_stop_token(id)?;
+        }
+        for (key, role) in [
+            ("fim_pre_token_id", SpecialToken::FimPrefix),
+            ("fim_mid_token_id", SpecialToken::FimMiddle),
+            ("fim_suf_token_id", SpecialToken::FimSuffix),
+        ] {
+            if let Some(id) = special_id(key) {
+                info.set_role_token(role, id);
+            }
+        }
+
+        let mut words = Vec::with_capacity(tokens.len());
+        for (idx, tok) in tokens.iter().enumerate() {
+            let name = tok
+                .as_str()
+                .ok_or_else(|| anyhow!("invalid token at {}", idx))?;
+            let tp = token_types
+                .and_then(|t| t.get(idx))
+                .and_then(|v| v.as_int())
+                .unwrap_or(1) as i32;
+            let bytes = match tp {
+                TOKEN_TYPE_CONTROL | TOKEN_TYPE_UNKNOWN => {
+                    let mut bytes = name.as_bytes().to_vec();
+                    bytes.insert(0, TokTrie::SPECIAL_TOKEN_MARKER);
+                    bytes
+                }
+                TOKEN_TYPE_USER_DEFINED => name.as_bytes().to_vec(),
+                _ => token_to_bytes(&kind, name)
+                    .map_err(|e| anyhow!("error: {} for {:?}", e, name))?,
+            };
+            words.push(bytes);
+        }
+
+        Ok(TokTrie::from(&info, &words))
+    }
+
+    /// Build a trie from the tokenizer metadata of a GGUF file on disk.
+    pub fn from_gguf_file(path: &str) -> Result<Self> {
+        let file =
+            std::fs::File::open(path).map_err(|e| anyhow!("error opening {}: {}", path, e))?;
+        Self::from_gguf(BufReader::new(file))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(s: &str) -> Vec<u8> {
+        let mut r = (s.len() as u64).to_le_bytes().to_vec();
+        r.extend_from_slice(s.as_bytes());
+        r
+    }
+
+    fn gguf(kvs: &[(&str, u32, Vec<u8>)]) -> Vec<u8> {
+        let mut r = GGUF_MAGIC.to_le_bytes().to_vec();
+        r.extend_from_slice(&3u32.to_le_bytes());
+        r.extend_from_slice(&0u64.to_le_bytes());
+        r.extend_from_slice(&(kvs.len() as u64).to_le_bytes());
+        for (key, tp, value) in kvs {
+            r.extend_from_slice(&string(key));
+            r.extend_from_slice(&tp.to_le_bytes());
+            r.extend_from_slice(value);
+        }
+        r
+    }
+
+    fn string_array(items: &[&str]) -> Vec<u8> {
+        let mut r = 8u32.to_le_bytes().to_vec();
+        r.extend_from_slice(&(items.len() as u64).to_le_bytes());
+        for s in items {
+            r.extend_from_slice(&string(s));
+        }
+        r
+    }
+
+    #[test]
+    fn reads_vocab() {
+        let data = gguf(&[
+            ("tokenizer.ggml.model", 8, string("gpt2")),
+            ("tokenizer.ggml.tokens", 9, string_array(&["a", "b", "ab"])),
+            (
+                "tokenizer.ggml.eos_token_id",
+                4,
+                1u32.to_le_bytes().to_vec(),
+            ),
+        ]);
+        let trie = TokTrie::from_gguf(data.as_slice()).unwrap();
+        assert_eq!(trie.vocab_size(), 3);
+        assert_eq!(trie.token(2), b"ab");
+        assert_eq!(trie.info().tok_eos, 1);
+    }
+
+    #[test]
+    fn rejects_bad_lengths_and_nesting() {
+        // lengths way past the end of the data
+        let mut huge_string = (MAX_LEN - 1).to_le_bytes().to_vec();
+        huge_string.extend_from_slice(b"abc");
+        let mut huge_array = 0u32.to_le_bytes().to_vec();
+        huge_array.extend_from_slice(&(MAX_LEN - 1).to_le_bytes());
+        huge_array.extend_from_slice(&[1, 2, 3]);
+        for (tp, value) in [(8, huge_string), (9, huge_array)] {
+            let data = gguf(&[("x", tp, value)]);
+            assert!(TokTrie::from_gguf(data.as_slice()).is_err());
+        }
+
+        let nested = |depth: usize| {
+            let mut value = vec![];
+            for _ in 0..depth {
+                value.extend_from_slice(&9u32.to_le_bytes());
+                value.extend_from_slice(&1u64.to_le_bytes());
+            }
+            value.extend_from_slice(&0u32.to_le_bytes());
+            value.extend_from_slice(&0u64.to_le_bytes());
+            gguf(&[("x", 9, value)])
+        };
+        let ok = GgufReader {
+            inner: nested(MAX_DEPTH - 1).as_slice(),
+            version: 0,
+        }
+        .metadata();
+        assert!(ok.is_ok());
+        let err = GgufReader {
+            inner: nested(MAX_DEPTH).as_slice(),
+            version: 0,
+        }
+        .metadata();
+        assert!(err.unwrap_err().to_string().contains("nested"));
+    }
+}
@@ -1,16 +1,94 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
 use serde::{Deserialize, Serialize};
 
+use crate::prelude::*;
+
 pub mod bytes;
+#[cfg(feature = "std")]
+mod deadline;
+mod fallback_env;
+#[cfg(feature = "std")]
+mod gguf;
+#[cfg(feature = "llamacpp")]
+mod llamacpp;
+mod logit_bias;
+mod mask_cache;
+mod math;
+mod normalizer;
+mod pretokenizer;
+mod program;
 pub mod recognizer;
+#[cfg(feature = "std")]
+mod record_env;
 pub mod rng;
+mod rwkv;
+mod sentencepiece;
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+mod simd;
+mod step_error;
+mod stop_sequence;
+mod stream_decoder;
+mod substring;
 mod svob;
+mod tekken;
+mod test_env;
+#[cfg(feature = "std")]
+mod thread_local_env;
+mod tok_error;
+mod tokenizer_json;
 mod toktree;
+#[cfg(feature = "std")]
+mod trie_cache;
+mod wire;
 
+#[cfg(feature = "std")]
+pub use deadline::Deadline;
+pub use fallback_env::FallbackTokEnv;
+#[cfg(feature = "llamacpp")]
+pub use llamacpp::{LlamaCppTokEnv, LlamaVocab};
+pub use logit_bias::LogitBias;
+pub use mask_cache::{MaskCache, MaskCacheStats};
+pub use normalizer::{NormalizedTokEnv, Normalizer, NormalizerStep};
+pub use pretokenizer::PreTokenizer;
+pub use program::{Program, Stage};
+#[cfg(feature = "std")]
+pub use record_env::{RecordingTokEnv, ReplayTokEnv, TokCall, TokLog};
+#[cfg(feature = "std")]
+pub use step_error::catch_step;
+pub use step_error::StepError;
+pub use stop_sequence::{StopMatch, StopSequenceMatcher};
+pub use stream_decoder::StreamDecoder;
+pub use substring::SubstringIndex;
 pub use svob::{SimpleVob, SimpleVobIter};
+pub use tekken::TekkenTokenizerEnv;
+pub use test_env::TestTokEnv;
+#[cfg(feature = "std")]
+pub use thread_local_env::{Encoder, ThreadLocalTokEnv};
+pub use tok_error::TokError;
 pub use toktree::{
-    Recognizer, SpecialToken, TokEnv, TokEnvWithTrie, TokRxInfo, TokTrie, TokenId, TokenizerEnv,
-    TrieNode,
+    AddedToken, MarkerDetection, MarkerOptions, Recognizer, Rejection, SpecialToken,
+    SpecialTokenEscape, TokEnv, TokEnvWithTrie, TokRxInfo, TokTrie, TokenId, TokenizerEnv,
+    TrieNode, TrieStats, UnknownMarker,
 };
+pub use wire::WireFormat;
+
+// Parts of the std prelude that core doesn't have, for no_std builds.
+mod prelude {
+    pub use alloc::{
+        boxed::Box,
+        format,
+        string::{String, ToString},
+        vec,
+        vec::Vec,
+    };
+}
+
+// std::collections::HashMap is not available without std.
+pub(crate) type FxHashMap<K, V> = hashbrown::HashMap<K, V, rustc_hash::FxBuildHasher>;
+pub(crate) type FxHashSet<K> = hashbrown::HashSet<K, rustc_hash::FxBuildHasher>;
 
 /// Defines what is allowed in Branch
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
@@ -30,6 +108,87 @@ pub struct InferenceCapabilities {
     /// More than one branch is allowed.
     #[serde(default)]
     pub fork: bool,
+
+    /// Branch::pre_splice is supported.
+    #[serde(default)]
+    pub splice_and_sample: bool,
+
+    /// Branch::top_p, top_k and repetition_penalty are supported.
+    #[serde(default)]
+    pub sampling_overrides: bool,
+
+    /// WireFormat::Postcard is supported.
+    #[serde(default)]
+    pub postcard: bool,
+}
+
+/// Version of the controller interface (StepArg, Branch, etc.).
+/// Bumped on incompatible changes; compatible additions are announced
+/// through InferenceCapabilities instead.
+pub const ABI_VERSION: u32 = 1;
+
+pub fn check_abi_version(version: u32) -> anyhow::Result<()> {
+    if version != ABI_VERSION {
+        anyhow::bail!(
+            "ABI version mismatch: got {}, expected {}",
+            version,
+            ABI_VERSION
+        );
+    }
+    Ok(())
+}
+
+impl InferenceCapabilities {
+    pub const FF_TOKENS: u32 = 1 << 0;
+    pub const CONDITIONAL_FF_TOKENS: u32 = 1 << 1;
+    pub const BACKTRACK: u32 = 1 << 2;
+    pub const FORK: u32 = 1 << 3;
+    pub const SPLICE_AND_SAMPLE: u32 = 1 << 4;
+    pub const SAMPLING_OVERRIDES: u32 = 1 << 5;
+    pub const POSTCARD: u32 = 1 << 6;
+
+    /// Bitmask for exchanging capabilities over the ABI; unknown bits are ignored by from_bits().
+    pub fn to_bits(&self) -> u32 {
+        [
+            (Self::FF_TOKENS, self.ff_tokens),
+            (Self::CONDITIONAL_FF_TOKENS, self.conditional_ff_tokens),
+            (Self::BACKTRACK, self.backtrack),
+            (Self::FORK, self.fork),
+            (Self::SPLICE_AND_SAMPLE, self.splice_and_sample),
+            (Self::SAMPLING_OVERRIDES, self.sampling_overrides),
+            (Self::POSTCARD, self.postcard),
+        ]
+        .iter()
+        .filter(|(_, v)| *v)
+        .fold(0, |acc, (bit, _)| acc | bit)
+    }
+
+    pub fn from_bits(bits: u32) -> Self {
+        InferenceCapabilities {
+            ff_tokens: bits & Self::FF_TOKENS != 0,
+            conditional_ff_tokens: bits & Self::CONDITIONAL_FF_TOKENS != 0,
+            backtrack: bits & Self::BACKTRACK != 0,
+            fork: bits & Self::FORK != 0,
+            splice_and_sample: bits & Self::SPLICE_AND_SAMPLE != 0,
+            sampling_overrides: bits & Self::SAMPLING_OVERRIDES != 0,
+            postcard: bits & Self::POSTCARD != 0,
+        }
+    }
+
+    /// Capabilities supported by both sides.
+    pub fn intersect(&self, other: &InferenceCapabilities) -> Self {
+        Self::from_bits(self.to_bits() & other.to_bits())
+    }
+}
+
+/// Log-probabilities reported by the engine for the sampled token.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SampledLogprobs {
+    /// Log-probability of the sampled token.
+    pub logprob: f32,
+    /// Most likely alternatives (token, log-probability), most likely first.
+    #[serde(default)]
+    pub top_k: Vec<(TokenId, f32)>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -42,6 +201,9 @@ pub struct StepArg {
     pub tokens: Vec<TokenId>,
     /// The token that was sampled (after applying the mask), before any splicing.
     pub sampled: Option<TokenId>,
+    /// Log-probabilities of the sampled token, if the engine reports them.
+    #[serde(default)]
+    pub logprobs: Option<SampledLogprobs>,
 }
 
 impl StepArg {
@@ -50,6 +212,7 @@ impl StepArg {
             backtrack: 0,
             tokens: vec![],
             sampled: None,
+            logprobs: None,
         }
     }
 
@@ -68,6 +231,7 @@ impl StepArg {
             backtrack: s.backtrack,
             tokens: s.ff_tokens.clone(),
             sampled,
+            logprobs: None,
         }
     }
 
@@ -76,8 +240,14 @@ impl StepArg {
             backtrack: 0,
             tokens: vec![tok],
             sampled: Some(tok),
+            logprobs: None,
         }
     }
+
+    pub fn with_logprobs(mut self, logprob: f32, top_k: Vec<(TokenId, f32)>) -> Self {
+        self.logprobs = Some(SampledLogprobs { logprob, top_k });
+        self
+    }
 }
 
 /*
@@ -130,6 +300,31 @@ impl Splice {
             ff_tokens,
         }
     }
+
+    /// Single splice with the effect of applying self and then next.
+    /// Backtracking in next first removes tokens appended by self.
+    pub fn then(&self, next: &Splice) -> Splice {
+        let mut ff_tokens = self.ff_tokens.clone();
+        let dropped = core::cmp::min(next.backtrack as usize, ff_tokens.len());
+        ff_tokens.truncate(ff_tokens.len() - dropped);
+        ff_tokens.extend_from_slice(&next.ff_tokens);
+        Splice {
+            when_sampled: next.when_sampled.clone(),
+            backtrack: self.backtrack + next.backtrack - dropped as u32,
+            ff_tokens,
+        }
+    }
+}
+
+/// Why generation stopped, and the final result, passed on to the API caller.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct StopInfo {
+    /// Machine-readable reason, e.g., "grammar_complete" or "max_tokens".
+    #[serde(default)]
+    pub reason: Option<String>,
+    /// Final result of the controller, e.g., extracted captures.
+    #[serde(default, with = "wire::json_value")]
+    pub payload: Option<serde_json::Value>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -139,6 +334,22 @@ pub struct Branch<S> {
     pub sample_mask: Option<S>,
     /// Override temperature for sampling. It may or may not be sticky.
     pub temperature: Option<f32>,
+    /// Override nucleus sampling threshold for this step.
+    #[serde(default)]
+    pub top_p: Option<f32>,
+    /// Override the number of most likely tokens to sample from for this step.
+    #[serde(default)]
+    pub top_k: Option<u32>,
+    /// Override repetition penalty for this step.
+    #[serde(default)]
+    pub repetition_penalty: Option<f32>,
+    /// Applied before sampling: backtrack and append tokens, and then sample
+    /// the next token with sample_mask, all in one step.
+    #[serde(default)]
+    pub pre_splice: Option<Splice>,
+    /// Only for stop branches (see stop_with()).
+    #[serde(default)]
+    pub stop_info: Option<StopInfo>,
     /// Describes what to do after sampling.
     /// If no sampling, there should be exactly one splice, with empty `when_sampled`.
     pub splices: Vec<Splice>,
@@ -149,6 +360,11 @@ impl<S: Clone> Clone for Branch<S> {
         Branch {
             sample_mask: self.sample_mask.clone(),
             temperature: self.temperature,
+            top_p: self.top_p,
+            top_k: self.top_k,
+            repetition_penalty: self.repetition_penalty,
+            pre_splice: self.pre_splice.clone(),
+            stop_info: self.stop_info.clone(),
             splices: self.splices.clone(),
         }
     }
@@ -162,6 +378,11 @@ impl<S> Branch<S> {
         Branch {
             sample_mask: self.sample_mask.as_ref().map(f),
             temperature: self.temperature,
+            top_p: self.top_p,
+            top_k: self.top_k,
+            repetition_penalty: self.repetition_penalty,
+            pre_splice: self.pre_splice.clone(),
+            stop_info: self.stop_info.clone(),
             splices: self.splices.clone(),
         }
     }
@@ -172,14 +393,20 @@ impl<S> Branch<S> {
             .find(|s| s.when_sampled.is_empty() || s.when_sampled.contains(&sampled))
     }
 
+    /// Overall effect of the step, when given token is sampled (including pre_splice).
     pub fn spliced(&self, sampled: TokenId) -> Splice {
-        self.find_splice(sampled)
-            .cloned()
-            .unwrap_or_else(|| Splice {
+        let s = self.find_splice(sampled);
+        match (&self.pre_splice, s) {
+            // backtrack of s includes the sampled token
+            (Some(pre), Some(s)) => pre.then(&Splice::tokens(vec![sampled])).then(s),
+            (Some(pre), None) => pre.then(&Splice::tokens(vec![sampled])),
+            (None, Some(s)) => s.clone(),
+            (None, None) => Splice {
                 when_sampled: vec![],
                 backtrack: 0,
                 ff_tokens: vec![sampled],
-            })
+            },
+        }
     }
 
     pub fn unconditional_splice(&self) -> Option<&Splice> {
@@ -193,28 +420,53 @@ impl<S> Branch<S> {
     pub fn has_backtrack(&self) -> bool {
         let max_bt = if self.sample_mask.is_none() { 0 } else { 1 };
         self.splices.iter().any(|s| s.backtrack > max_bt)
+            || self.pre_splice.as_ref().is_some_and(|s| s.backtrack > 0)
     }
 
     pub fn has_ff_tokens(&self) -> bool {
         self.splices.len() > 0
+            || self
+                .pre_splice
+                .as_ref()
+                .is_some_and(|s| !s.ff_tokens.is_empty())
     }
 
     pub fn stop() -> Self {
         Branch {
             sample_mask: None,
             temperature: None,
+            top_p: None,
+            top_k: None,
+            repetition_penalty: None,
+            pre_splice: None,
+            stop_info: None,
             splices: vec![],
         }
     }
 
+    pub fn stop_with(reason: &str, payload: Option<serde_json::Value>) -> Self {
+        Branch {
+            stop_info: Some(StopInfo {
+                reason: Some(reason.to_string()),
+                payload,
+            }),
+            ..Self::stop()
+        }
+    }
+
     pub fn is_stop(&self) -> bool {
-        self.sample_mask.is_none() && self.splices.is_empty()
+        self.sample_mask.is_none() && self.splices.is_empty() && self.pre_splice.is_none()
     }
 
     pub fn splice(backtrack: u32, ff_tokens: Vec<TokenId>) -> Self {
         Branch {
             sample_mask: None,
             temperature: None,
+            top_p: None,
+            top_k: None,
+            repetition_penalty: None,
+            pre_splice: None,
+            stop_info: None,
             splices: vec![Splice {
                 when_sampled: vec![],
                 backtrack,
@@ -231,9 +483,159 @@ impl<S> Branch<S> {
         Branch {
             sample_mask: Some(set),
             temperature,
+            top_p: None,
+            top_k: None,
+            repetition_penalty: None,
+            pre_splice: None,
+            stop_info: None,
             splices: vec![],
         }
     }
+
+    /// Backtrack and append ff_tokens, and then sample with the given set,
+    /// saving a round-trip compared to a splice step followed by a sample step.
+    pub fn splice_and_sample(
+        backtrack: u32,
+        ff_tokens: Vec<TokenId>,
+        set: S,
+        temperature: Option<f32>,
+    ) -> Self {
+        Branch {
+            pre_splice: Some(Splice {
+                when_sampled: vec![],
+                backtrack,
+                ff_tokens,
+            }),
+            ..Self::sample(set, temperature)
+        }
+    }
+
+    pub fn with_top_p(mut self, top_p: f32) -> Self {
+        self.top_p = Some(top_p);
+        self
+    }
+
+    pub fn with_top_k(mut self, top_k: u32) -> Self {
+        self.top_k = Some(top_k);
+        self
+    }
+
+    pub fn with_repetition_penalty(mut self, penalty: f32) -> Self {
+        self.repetition_penalty = Some(penalty);
+        self
+    }
+
+    /// True if any of the sampling parameters is overridden.
+    pub fn has_sampling_overrides(&self) -> bool {
+        self.temperature.is_some()
+            || self.top_p.is_some()
+            || self.top_k.is_some()
+            || self.repetition_penalty.is_some()
+    }
 }
 
 pub type StepResult = Branch<SimpleVob>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(tokens: &[TokenId], s: &Splice) -> Vec<TokenId> {
+        let mut res = tokens.to_vec();
+        StepArg::from_splice(s, None).save_tokens(&mut res);
+        res
+    }
+
+    fn splice(backtrack: u32, ff_tokens: &[TokenId]) -> Splice {
+        Splice {
+            when_sampled: vec![],
+            backtrack,
+            ff_tokens: ff_tokens.to_vec(),
+        }
+    }
+
+    #[test]
+    fn splice_then() {
+        let base = [10, 11, 12, 13];
+        let cases = [
+            (splice(1, &[1, 2, 3]), splice(2, &[4]), splice(1, &[1, 4])),
+            // backtracks past everything the first one appended
+            (splice(1, &[1, 2, 3]), splice(5, &[4]), splice(3, &[4])),
+            (splice(0, &[]), splice(2, &[4, 5]), splice(2, &[4, 5])),
+            (splice(2, &[1]), splice(0, &[]), splice(2, &[1])),
+            (splice(0, &[1, 2]), splice(2, &[]), splice(0, &[])),
+        ];
+        for (a, b, expected) in &cases {
+            let ab = a.then(b);
+            assert_eq!(
+                (ab.backtrack, &ab.ff_tokens),
+                (expected.backtrack, &expected.ff_tokens)
+            );
+            assert_eq!(apply(&base, &ab), apply(&apply(&base, a), b));
+        }
+        let mut cond = splice(1, &[7]);
+        cond.when_sampled = vec![5];
+        assert_eq!(splice(0, &[1]).then(&cond).when_sampled, [5]);
+    }
+
+    #[test]
+    fn splice_and_sample() {
+        let mask = SimpleVob::alloc_ones(10);
+        let mut branch = Branch::splice_and_sample(1, vec![7], mask, None);
+        assert!(branch.has_backtrack());
+        assert!(branch.has_ff_tokens());
+        branch.splices.push(Splice {
+            when_sampled: vec![5],
+            backtrack: 1,
+            ff_tokens: vec![8, 9],
+        });
+        let base = [1, 2, 3];
+        let s = branch.spliced(5);
+        assert_eq!((s.backtrack, &s.ff_tokens[..]), (1, &[7, 8, 9][..]));
+        assert_eq!(apply(&base, &s), [1, 2, 7, 8, 9]);
+        let s = branch.spliced(6);
+        assert_eq!((s.backtrack, &s.ff_tokens[..]), (1, &[7, 6][..]));
+        assert_eq!(apply(&base, &s), [1, 2, 7, 6]);
+
+        let plain = Branch::sample(SimpleVob::alloc_ones(10), None);
+        assert!(!plain.has_backtrack());
+        let s = plain.spliced(4);
+        assert_eq!((s.backtrack, &s.ff_tokens[..]), (0, &[4][..]));
+    }
+
+    #[test]
+    fn capability_bits() {
+        let all = InferenceCapabilities {
+            ff_tokens: true,
+            conditional_ff_tokens: true,
+            backtrack: true,
+            fork: true,
+            splice_and_sample: true,
+            sampling_overrides: true,
+            postcard: true,
+        };
+        assert_eq!(all.to_bits(), (1 << 7) - 1);
+        // unknown bits from a newer peer are ignored
+        assert_eq!(
+            InferenceCapabilities::from_bits(u32::MAX).to_bits(),
+            all.to_bits()
+        );
+        assert_eq!(InferenceCapabilities::default().to_bits(), 0);
+        for bit in 0..7 {
+            let caps = InferenceCapabilities::from_bits(1 << bit);
+            assert_eq!(caps.to_bits(), 1 << bit);
+        }
+
+        let host = InferenceCapabilities::from_bits(
+            InferenceCapabilities::BACKTRACK | InferenceCapabilities::FF_TOKENS,
+        );
+        let ctrl = InferenceCapabilities::from_bits(
+            InferenceCapabilities::FF_TOKENS | InferenceCapabilities::POSTCARD,
+        );
+        let both = host.intersect(&ctrl);
+        assert!(both.ff_tokens && !both.backtrack && !both.postcard);
+
+        assert!(check_abi_version(ABI_VERSION).is_ok());
+        assert!(check_abi_version(ABI_VERSION + 1).is_err());
+    }
+}
@@ -0,0 +1,208 @@
+// TokenizerEnv over a llama.cpp vocabulary, through the llama.cpp C API.
+// The final binary has to link against llama.cpp (libllama).
+
+use core::ffi::c_char;
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::prelude::*;
+use crate::{TokRxInfo, TokTrie, TokenId, TokenizerEnv};
+
+/// Opaque llama.cpp vocabulary (struct llama_vocab), as returned by llama_model_get_vocab().
+#[repr(C)]
+pub struct LlamaVocab {
+    _private: [u8; 0],
+}
+
+// enum llama_token_attr
+const LLAMA_TOKEN_ATTR_UNKNOWN: u32 = 1 << 0;
+const LLAMA_TOKEN_ATTR_CONTROL: u32 = 1 << 3;
+
+extern "C" {
+    fn llama_vocab_n_tokens(vocab: *const LlamaVocab) -> i32;
+    fn llama_vocab_eos(vocab: *const LlamaVocab) -> i32;
+    fn llama_vocab_bos(vocab: *const LlamaVocab) -> i32;
+    fn llama_vocab_is_eog(vocab: *const LlamaVocab, token: i32) -> bool;
+    fn llama_vocab_get_attr(vocab: *const LlamaVocab, token: i32) -> u32;
+    fn llama_token_to_piece(
+        vocab: *const LlamaVocab,
+        token: i32,
+        buf: *mut c_char,
+        length: i32,
+        lstrip: i32,
+        special: bool,
+    ) -> i32;
+    fn llama_tokenize(
+        vocab: *const LlamaVocab,
+        text: *const c_char,
+        text_len: i32,
+        tokens: *mut i32,
+        n_tokens_max: i32,
+        add_special: bool,
+        parse_special: bool,
+    ) -> i32;
+}
+
+/// TokenizerEnv sharing the vocabulary of a model loaded by llama.cpp.
+/// The trie is built from the token pieces (control tokens are special),
+/// and llama.cpp's own llama_tokenize() is used for tokenization.
+pub struct LlamaCppTokEnv {
+    vocab: *const LlamaVocab,
+    tok_trie: TokTrie,
+}
+
+// llama_vocab is not modified after the model is loaded, and llama.cpp
+// allows tokenization from multiple threads.
+unsafe impl Send for LlamaCppTokEnv {}
+unsafe impl Sync for LlamaCppTokEnv {}
+
+fn token_piece(vocab: *const LlamaVocab, tok: i32) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; 64];
+    loop {
+        let n = unsafe {
+            llama_token_to_piece(
+                vocab,
+                tok,
+                buf.as_mut_ptr() as *mut c_char,
+                buf.len() as i32,
+                0,
+                true,
+            )
+        };
+        if n >= 0 {
+            buf.truncate(n as usize);
+            return Ok(buf);
+        }
+        let len = required_len(n, buf.len())
+            .ok_or_else(|| anyhow!("llama_token_to_piece() failed for token {}", tok))?;
+        buf.resize(len, 0);
+    }
+}
+
+// a negative result of llama.cpp functions filling a buffer is minus the required size;
+// None if that doesn't make the buffer bigger (and so retrying wouldn't help)
+fn required_len(n: i32, curr_len: usize) -> Option<usize> {
+    let len = n.unsigned_abs() as usize;
+    if n < 0 && len > curr_len && len <= i32::MAX as usize {
+        Some(len)
+    } else {
+        None
+    }
+}
+
+impl LlamaCppTokEnv {
+    /// Build from a llama_vocab handle.
+    ///
+    /// # Safety
+    /// vocab has to be valid (typically from llama_model_get_vocab())
+    /// and the model has to outlive the returned env.
+    pub unsafe fn from_vocab(vocab: *const LlamaVocab) -> Result<Self> {
+        if vocab.is_null() {
+            bail!("null llama_vocab");
+        }
+        let n_vocab = llama_vocab_n_tokens(vocab);
+        if n_vocab <= 0 {
+            bail!("empty llama_vocab");
+        }
+        let id = |t: i32| {
+            if t >= 0 && t < n_vocab {
+                Some(t as TokenId)
+            } else {
+                None
+            }
+        };
+
+        let mut info = TokRxInfo::new(n_vocab as u32, id(llama_vocab_eos(vocab)).unwrap_or(0));
+        info.tok_bos = id(llama_vocab_bos(vocab));
+
+        let mut words = Vec::with_capacity(n_vocab as usize);
+        for tok in 0..n_vocab {
+            let mut bytes = token_piece(vocab, tok)?;
+            let attr = llama_vocab_get_attr(vocab, tok);
+            if attr & (LLAMA_TOKEN_ATTR_CONTROL | LLAMA_TOKEN_ATTR_UNKNOWN) != 0 {
+                if let Ok(name) = core::str::from_utf8(&bytes) {
+                    info.assign_role_by_name(name, tok as TokenId)?;
+                }
+                bytes.insert(0, TokTrie::SPECIAL_TOKEN_MARKER);
+            }
+            if llama_vocab_is_eog(vocab, tok) && tok as TokenId != info.tok_eos {
+                info.add_stop_token(tok as TokenId)?;
+            }
+            words.push(bytes);
+        }
+
+        Ok(LlamaCppTokEnv {
+            vocab,
+            tok_trie: TokTrie::from(&info, &words),
+        })
+    }
+
+    fn llama_tokenize(&self, s: &str) -> Result<Vec<TokenId>> {
+        if s.len() >= i32::MAX as usize {
+            bail!("input too long for llama_tokenize()");
+        }
+        let mut tokens: Vec<i32> = vec![0; s.len() + 1];
+        loop {
+            let n = unsafe {
+                llama_tokenize(
+                    self.vocab,
+                    s.as_ptr() as *const c_char,
+                    s.len() as i32,
+                    tokens.as_mut_ptr(),
+                    tokens.len() as i32,
+                    false,
+                    false,
+                )
+            };
+            if n >= 0 {
+                let tokens = tokens
+                    .get(..n as usize)
+                    .ok_or_else(|| anyhow!("llama_tokenize() returned too many tokens"))?;
+                return Ok(tokens.iter().map(|&t| t as TokenId).collect());
+            }
+            let len =
+                required_len(n, tokens.len()).ok_or_else(|| anyhow!("llama_tokenize() failed"))?;
+            tokens.resize(len, 0);
+        }
+    }
+}
+
+impl TokenizerEnv for LlamaCppTokEnv {
+    fn tok_trie(&self) -> &TokTrie {
+        &self.tok_trie
+    }
+
+    fn tokenize_bytes(&self, s: &[u8]) -> Vec<TokenId> {
+        self.try_tokenize_bytes(s)
+            .unwrap_or_else(|_| self.tok_trie.greedy_tokenize(s))
+    }
+
+    fn try_tokenize_bytes(&self, s: &[u8]) -> Result<Vec<TokenId>> {
+        let mut err = None;
+        let r = self.tok_trie.tokenize_with_greedy_fallback(s, |s| {
+            self.llama_tokenize(s).unwrap_or_else(|e| {
+                err = Some(e);
+                vec![]
+            })
+        });
+        match err {
+            Some(e) => Err(e),
+            None => Ok(r),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn required_len_checks_bounds() {
+        assert_eq!(required_len(-100, 64), Some(100));
+        assert_eq!(required_len(-64, 64), None);
+        assert_eq!(required_len(-10, 64), None);
+        assert_eq!(required_len(5, 64), None);
+        assert_eq!(required_len(i32::MIN, 64), None);
+        assert_eq!(required_len(-i32::MAX, 64), Some(i32::MAX as usize));
+    }
+}
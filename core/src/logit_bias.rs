@@ -0,0 +1,141 @@
+// Export of token masks as the logit_bias parameter of OpenAI-compatible APIs,
+// for remote models that don't support constraints otherwise.
+// The APIs limit the number of entries (300 for OpenAI); masks are sent as a list
+// of allowed or banned tokens, whichever fits, and truncated with a warning otherwise.
+
+use alloc::collections::BTreeMap;
+
+use crate::prelude::*;
+use crate::{SimpleVob, TokTrie, TokenId};
+
+/// Token biases for the logit_bias request parameter.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LogitBias {
+    /// Bias (-100 to 100) by token id.
+    pub bias: BTreeMap<TokenId, i32>,
+    /// Set when the mask couldn't be represented within the entry limit.
+    pub warning: Option<String>,
+}
+
+impl LogitBias {
+    /// Maximum number of logit_bias entries accepted by the OpenAI API.
+    pub const OPENAI_LIMIT: usize = 300;
+    /// Bias of allowed tokens; 100 results in exclusive selection of the biased tokens.
+    pub const ALLOW: i32 = 100;
+    /// Bias of banned tokens.
+    pub const BAN: i32 = -100;
+
+    /// The logit_bias object, like {"1734": 100, "3763": 100}.
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::Value::Object(
+            self.bias
+                .iter()
+                .map(|(tok, bias)| (tok.to_string(), (*bias).into()))
+                .collect(),
+        )
+    }
+}
+
+impl TokTrie {
+    /// logit_bias with at most `limit` entries (see LogitBias::OPENAI_LIMIT) equivalent to the mask:
+    /// allowed tokens get LogitBias::ALLOW if there are at most `limit` of them,
+    /// otherwise disallowed tokens get LogitBias::BAN.
+    /// Only tokens with bytes are banned; ids without them (e.g., padding up to
+    /// the model's vocabulary size) can't be sampled anyway.
+    /// If neither fits, only the first `limit` allowed tokens are included (so that the
+    /// output still satisfies the mask), and the warning is set.
+    pub fn to_openai_logit_bias(&self, mask: &SimpleVob, limit: usize) -> LogitBias {
+        let num_allowed = mask.num_set();
+        let mut banned = vec![];
+        mask.iter_unset_entries(|idx| {
+            if !self.token(idx as TokenId).is_empty() {
+                banned.push(idx as TokenId);
+            }
+        });
+        let mut res = LogitBias::default();
+        if num_allowed == 0 {
+            res.warning = Some("no tokens allowed; logit_bias can't express that".to_string());
+        } else if num_allowed <= limit {
+            mask.iter_set_entries(|idx| {
+                res.bias.insert(idx as TokenId, LogitBias::ALLOW);
+            });
+        } else if banned.len() <= limit {
+            res.bias = banned.iter().map(|&tok| (tok, LogitBias::BAN)).collect();
+        } else {
+            for tok in mask.iter_set().take(limit) {
+                res.bias.insert(tok, LogitBias::ALLOW);
+            }
+            res.warning = Some(format!(
+                "{} tokens allowed and {} banned, over the limit of {}; only the first {} allowed tokens are included",
+                num_allowed,
+                banned.len(),
+                limit,
+                limit
+            ));
+        }
+        res
+    }
+
+    /// logit_bias (with LogitBias::OPENAI_LIMIT) restricting the next token to ones
+    /// that can start one of the `strings`, i.e., tokens equal to a prefix of a string.
+    /// Typically used to pick one of several options (e.g., ["yes", "no"]) with a remote model,
+    /// extending the prompt with the sampled token and repeating as needed.
+    pub fn logit_bias_for_strings(&self, strings: &[&str]) -> LogitBias {
+        let mut mask = self.alloc_token_set();
+        for s in strings {
+            let bytes = s.as_bytes();
+            for len in 1..=bytes.len() {
+                if let Some(tok) = self.token_id(&bytes[..len]) {
+                    mask.allow_token(tok);
+                }
+            }
+        }
+        self.apply_duplicates(&mut mask);
+        self.to_openai_logit_bias(&mask, LogitBias::OPENAI_LIMIT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TokRxInfo;
+
+    #[test]
+    fn bans_only_real_tokens() {
+        // 256 bytes, "yes", "no", two padding tokens without bytes
+        let mut words: Vec<Vec<u8>> = (0..=255u8).map(|b| vec![b]).collect();
+        words.extend([b"yes".to_vec(), b"no".to_vec(), vec![], vec![]]);
+        let trie = TokTrie::from(&TokRxInfo::new(words.len() as u32, 0), &words);
+
+        let mut mask = trie.alloc_token_set();
+        mask.set_all(true);
+        mask.disallow_token(b'x' as TokenId);
+        mask.disallow_token(257);
+        mask.disallow_token(258);
+        mask.disallow_token(259);
+        let bias = trie.to_openai_logit_bias(&mask, 10);
+        assert_eq!(bias.warning, None);
+        assert_eq!(
+            bias.bias.into_iter().collect::<Vec<_>>(),
+            vec![(b'x' as TokenId, LogitBias::BAN), (257, LogitBias::BAN)]
+        );
+
+        let bias = trie.logit_bias_for_strings(&["yes", "no"]);
+        assert_eq!(
+            bias.bias.keys().copied().collect::<Vec<_>>(),
+            vec![b'n' as TokenId, b'y' as TokenId, 256, 257]
+        );
+        let json = bias.to_json();
+        assert_eq!(json["256"], LogitBias::ALLOW);
+
+        let bias = trie.to_openai_logit_bias(&trie.alloc_token_set(), 10);
+        assert!(bias.bias.is_empty() && bias.warning.is_some());
+        let mut half = trie.alloc_token_set();
+        for tok in 0..128 {
+            half.allow_token(tok);
+        }
+        let bias = trie.to_openai_logit_bias(&half, 10);
+        assert_eq!(bias.bias.len(), 10);
+        assert!(bias.warning.is_some());
+    }
+}
@@ -0,0 +1,159 @@
+use alloc::collections::VecDeque;
+
+use crate::{FxHashMap, SimpleVob};
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MaskCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+impl MaskCacheStats {
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+}
+
+/// LRU cache of token masks, keyed by a caller-provided fingerprint of the recognizer state.
+/// The fingerprint has to capture everything the mask depends on.
+pub struct MaskCache {
+    capacity: usize,
+    tick: u64,
+    entries: FxHashMap<u64, (SimpleVob, u64)>,
+    // (key, tick) for each use; only the entry with the tick
+    // of the key's last use is current, the others are skipped
+    queue: VecDeque<(u64, u64)>,
+    stats: MaskCacheStats,
+}
+
+impl MaskCache {
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0);
+        MaskCache {
+            capacity,
+            tick: 0,
+            entries: FxHashMap::default(),
+            queue: VecDeque::new(),
+            stats: MaskCacheStats::default(),
+        }
+    }
+
+    fn touch(&mut self, key: u64) {
+        self.queue.push_back((key, self.tick));
+        if self.queue.len() > 2 * self.capacity + 16 {
+            let entries = &self.entries;
+            self.queue
+                .retain(|(k, t)| entries.get(k).is_some_and(|e| e.1 == *t));
+        }
+    }
+
+    pub fn get(&mut self, key: u64) -> Option<&SimpleVob> {
+        self.tick += 1;
+        match self.entries.get_mut(&key) {
+            Some(e) => {
+                self.stats.hits += 1;
+                e.1 = self.tick;
+                self.touch(key);
+                Some(&self.entries[&key].0)
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn insert(&mut self, key: u64, mask: SimpleVob) {
+        self.tick += 1;
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(&key) {
+            while let Some((k, t)) = self.queue.pop_front() {
+                if self.entries.get(&k).is_some_and(|e| e.1 == t) {
+                    self.entries.remove(&k);
+                    self.stats.evictions += 1;
+                    break;
+                }
+            }
+        }
+        self.entries.insert(key, (mask, self.tick));
+        self.touch(key);
+    }
+
+    /// Return the cached mask for key, computing and caching it if missing.
+    pub fn get_or_compute(&mut self, key: u64, compute: impl FnOnce() -> SimpleVob) -> &SimpleVob {
+        if self.get(key).is_none() {
+            self.insert(key, compute());
+        }
+        &self.entries[&key].0
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn stats(&self) -> &MaskCacheStats {
+        &self.stats
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = MaskCacheStats::default();
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.queue.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mask(n: usize) -> SimpleVob {
+        let mut v = SimpleVob::alloc(64);
+        v.allow_token(n as u32);
+        v
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = MaskCache::new(3);
+        for k in 0..3 {
+            cache.insert(k, mask(k as usize));
+        }
+        // many hits on 0 and 2, so 1 is the oldest
+        for _ in 0..100 {
+            assert!(cache.get(0).is_some());
+            assert!(cache.get(2).is_some());
+        }
+        cache.insert(3, mask(3));
+        assert_eq!(cache.len(), 3);
+        assert!(cache.get(1).is_none());
+        cache.insert(4, mask(4));
+        assert!(cache.get(0).is_none());
+        assert_eq!(cache.get(2), Some(&mask(2)));
+        assert_eq!(cache.get(3), Some(&mask(3)));
+        assert_eq!(cache.get(4), Some(&mask(4)));
+        assert_eq!(cache.stats().evictions, 2);
+        // the use queue stays bounded
+        assert!(cache.queue.len() <= 2 * cache.capacity() + 16);
+
+        let computed = cache.get_or_compute(5, || mask(5)).clone();
+        assert_eq!(computed, mask(5));
+        assert_eq!(cache.get_or_compute(5, || unreachable!()), &mask(5));
+        assert_eq!(cache.len(), 3);
+    }
+}
@@ -0,0 +1,42 @@
+// Float functions that core doesn't provide; libm is used for no_std builds,
+// the std versions otherwise (so that results don't change with std).
+
+#[cfg(feature = "std")]
+mod imp {
+    pub fn exp(x: f64) -> f64 {
+        x.exp()
+    }
+
+    pub fn ln(x: f64) -> f64 {
+        x.ln()
+    }
+
+    pub fn powi(x: f64, n: i32) -> f64 {
+        x.powi(n)
+    }
+
+    pub fn round(x: f64) -> f64 {
+        x.round()
+    }
+}
+
+#[cfg(not(feature = "std"))]
+mod imp {
+    pub fn exp(x: f64) -> f64 {
+        libm::exp(x)
+    }
+
+    pub fn ln(x: f64) -> f64 {
+        libm::log(x)
+    }
+
+    pub fn powi(x: f64, n: i32) -> f64 {
+        libm::pow(x, n as f64)
+    }
+
+    pub fn round(x: f64) -> f64 {
+        libm::round(x)
+    }
+}
+
+pub(crate) use imp::*;
@@ -0,0 +1,520 @@
+use core::ops::Range;
+
+use anyhow::{anyhow, bail, Result};
+use serde_json::Value;
+
+use crate::prelude::*;
+use crate::{TokEnv, TokTrie, TokenId, TokenizerEnv};
+
+/// Single step of a Normalizer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NormalizerStep {
+    Nfc,
+    Nfd,
+    Nfkc,
+    Nfkd,
+    Lowercase,
+    /// Remove combining marks after canonical decomposition (é -> e).
+    StripAccents,
+    Strip {
+        left: bool,
+        right: bool,
+    },
+    Replace {
+        pattern: String,
+        content: String,
+    },
+    /// Remove control characters and turn all whitespace into plain spaces (BERT).
+    CleanText,
+    /// Surround CJK ideographs with spaces (BERT).
+    PadChinese,
+}
+
+impl NormalizerStep {
+    /// Check if this build supports the step (Unicode forms need the "normalization" feature).
+    pub fn is_supported(&self) -> bool {
+        let needs_tables = matches!(
+            self,
+            NormalizerStep::Nfc
+                | NormalizerStep::Nfd
+                | NormalizerStep::Nfkc
+                | NormalizerStep::Nfkd
+                | NormalizerStep::StripAccents
+        );
+        !needs_tables || cfg!(feature = "normalization")
+    }
+
+    #[cfg(feature = "normalization")]
+    fn apply_unicode(&self, s: &str) -> String {
+        use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+        match self {
+            NormalizerStep::Nfc => s.nfc().collect(),
+            NormalizerStep::Nfd => s.nfd().collect(),
+            NormalizerStep::Nfkc => s.nfkc().collect(),
+            NormalizerStep::Nfkd => s.nfkd().collect(),
+            NormalizerStep::StripAccents => s.nfd().filter(|&c| !is_combining_mark(c)).collect(),
+            _ => unreachable!(),
+        }
+    }
+
+    #[cfg(not(feature = "normalization"))]
+    fn apply_unicode(&self, _s: &str) -> String {
+        panic!("normalization feature not enabled")
+    }
+
+    // Unicode forms on text split after each starter, merging pieces
+    // that don't normalize independently (e.g., a base char and a combining mark for NFC)
+    #[cfg(feature = "normalization")]
+    fn apply_unicode_aligned(&self, chars: Vec<AlignedChar>) -> Vec<AlignedChar> {
+        use unicode_normalization::char::canonical_combining_class;
+        let text = |seg: &Range<usize>| chars[seg.clone()].iter().map(|c| c.0).collect::<String>();
+        let mut segs: Vec<Range<usize>> = vec![];
+        for (i, c) in chars.iter().enumerate() {
+            match segs.last_mut() {
+                Some(seg) if canonical_combining_class(c.0) != 0 => seg.end = i + 1,
+                _ => segs.push(i..i + 1),
+            }
+        }
+        let mut merged: Vec<Range<usize>> = vec![];
+        for seg in segs {
+            if let Some(prev) = merged.last_mut() {
+                let (a, b) = (text(prev), text(&seg));
+                let ab = self.apply_unicode(&format!("{}{}", a, b));
+                if ab != format!("{}{}", self.apply_unicode(&a), self.apply_unicode(&b)) {
+                    prev.end = seg.end;
+                    continue;
+                }
+            }
+            merged.push(seg);
+        }
+        let mut res = vec![];
+        for seg in merged {
+            let span = chars[seg.start].1.start..chars[seg.end - 1].1.end;
+            res.extend(
+                self.apply_unicode(&text(&seg))
+                    .chars()
+                    .map(|c| (c, span.clone())),
+            );
+        }
+        res
+    }
+
+    #[cfg(not(feature = "normalization"))]
+    fn apply_unicode_aligned(&self, _chars: Vec<AlignedChar>) -> Vec<AlignedChar> {
+        panic!("normalization feature not enabled")
+    }
+
+    // apply() with the span of the input each char comes from
+    fn apply_aligned(&self, mut chars: Vec<AlignedChar>) -> Vec<AlignedChar> {
+        match self {
+            NormalizerStep::Strip { left, right } => {
+                if *right {
+                    while chars.last().is_some_and(|c| c.0.is_whitespace()) {
+                        chars.pop();
+                    }
+                }
+                if *left {
+                    let n = chars.iter().take_while(|c| c.0.is_whitespace()).count();
+                    chars.drain(..n);
+                }
+                chars
+            }
+            NormalizerStep::Replace { pattern, content } => {
+                replace_aligned(&chars, pattern, content)
+            }
+            NormalizerStep::Lowercase | NormalizerStep::CleanText | NormalizerStep::PadChinese => {
+                let mut buf = [0u8; 4];
+                chars
+                    .into_iter()
+                    .flat_map(|(c, span)| {
+                        let r = self.apply(c.encode_utf8(&mut buf));
+                        r.chars().map(|c| (c, span.clone())).collect::<Vec<_>>()
+                    })
+                    .collect()
+            }
+            _ => self.apply_unicode_aligned(chars),
+        }
+    }
+
+    fn apply(&self, s: &str) -> String {
+        match self {
+            // char by char, like HuggingFace tokenizers (no final sigma rule)
+            NormalizerStep::Lowercase => s.chars().flat_map(char::to_lowercase).collect(),
+            NormalizerStep::Strip { left, right } => {
+                let s = if *left { s.trim_start() } else { s };
+                let s = if *right { s.trim_end() } else { s };
+                s.to_string()
+            }
+            NormalizerStep::Replace { pattern, content } => s.replace(pattern, content),
+            NormalizerStep::CleanText => s
+                .chars()
+                .filter(|&c| !(c == '\0' || c == '\u{FFFD}' || is_bert_control(c)))
+                .map(|c| if c.is_whitespace() { ' ' } else { c })
+                .collect(),
+            NormalizerStep::PadChinese => {
+                let mut r = String::with_capacity(s.len());
+                for c in s.chars() {
+                    if is_cjk(c) {
+                        r.push(' ');
+                        r.push(c);
+                        r.push(' ');
+                    } else {
+                        r.push(c);
+                    }
+                }
+                r
+            }
+            _ => self.apply_unicode(s),
+        }
+    }
+}
+
+// normalized char with the span of the original input it comes from
+type AlignedChar = (char, Range<usize>);
+
+// like str::replace(); replacements come from the span of the matched text
+fn replace_aligned(chars: &[AlignedChar], pattern: &str, content: &str) -> Vec<AlignedChar> {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let mut res = vec![];
+    let mut i = 0;
+    loop {
+        let matches = chars.len() - i >= pattern.len()
+            && chars[i..i + pattern.len()]
+                .iter()
+                .zip(&pattern)
+                .all(|(a, b)| a.0 == *b);
+        if matches {
+            let span = match pattern.len() {
+                // empty pattern matches between chars
+                0 => {
+                    let pos = match chars.get(i) {
+                        Some(c) => c.1.start,
+                        None => chars.last().map_or(0, |c| c.1.end),
+                    };
+                    pos..pos
+                }
+                n => chars[i].1.start..chars[i + n - 1].1.end,
+            };
+            res.extend(content.chars().map(|c| (c, span.clone())));
+            if !pattern.is_empty() {
+                i += pattern.len();
+                continue;
+            }
+        }
+        match chars.get(i) {
+            Some(c) => res.push(c.clone()),
+            None => break,
+        }
+        i += 1;
+    }
+    res
+}
+
+fn is_bert_control(c: char) -> bool {
+    !matches!(c, '\t' | '\n' | '\r') && c.is_control()
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c as u32,
+        0x4E00..=0x9FFF
+        | 0x3400..=0x4DBF
+        | 0x20000..=0x2A6DF
+        | 0x2A700..=0x2B73F
+        | 0x2B740..=0x2B81F
+        | 0x2B820..=0x2CEAF
+        | 0xF900..=0xFAFF
+        | 0x2F800..=0x2FA1F)
+}
+
+/// Text normalization applied before tokenization, as configured
+/// in the "normalizer" section of HuggingFace tokenizer.json.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Normalizer {
+    steps: Vec<NormalizerStep>,
+}
+
+impl Normalizer {
+    pub fn new(steps: Vec<NormalizerStep>) -> Result<Self> {
+        if let Some(s) = steps.iter().find(|s| !s.is_supported()) {
+            bail!("{:?} normalizer requires the normalization feature", s);
+        }
+        Ok(Normalizer { steps })
+    }
+
+    pub fn steps(&self) -> &[NormalizerStep] {
+        &self.steps
+    }
+
+    /// True if normalize() doesn't change anything.
+    pub fn is_identity(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Parse the "normalizer" section of tokenizer.json (null means no normalization).
+    /// Prepend, and Replace producing the Metaspace character, are skipped:
+    /// they implement the Metaspace space handling, while tokens in the trie use plain spaces,
+    /// and text is tokenized as a continuation (without a leading space).
+    /// Precompiled (SentencePiece character map) and regex Replace are not supported.
+    pub fn from_json(json: &Value) -> Result<Self> {
+        let mut steps = vec![];
+        add_steps(json, &mut steps)?;
+        Self::new(steps)
+    }
+
+    /// Normalizer configured in tokenizer.json file contents.
+    pub fn from_tokenizer_json(bytes: &[u8]) -> Result<Self> {
+        let json: Value = serde_json::from_slice(bytes)?;
+        Self::from_json(&json["normalizer"])
+    }
+
+    pub fn normalize(&self, s: &str) -> String {
+        let mut r = s.to_string();
+        for step in &self.steps {
+            r = step.apply(&r);
+        }
+        r
+    }
+
+    /// Like normalize_bytes(), but also returns the span of s
+    /// each byte of the result comes from.
+    pub fn normalize_bytes_with_offsets(&self, s: &[u8]) -> (Vec<u8>, Vec<Range<usize>>) {
+        let valid = match core::str::from_utf8(s) {
+            Ok(_) => s.len(),
+            Err(e) => e.valid_up_to(),
+        };
+        let prefix = core::str::from_utf8(&s[..valid]).unwrap();
+        let mut chars: Vec<AlignedChar> = prefix
+            .char_indices()
+            .map(|(i, c)| (c, i..i + c.len_utf8()))
+            .collect();
+        for step in &self.steps {
+            chars = step.apply_aligned(chars);
+        }
+        let mut bytes = Vec::with_capacity(s.len());
+        let mut spans = Vec::with_capacity(s.len());
+        let mut buf = [0u8; 4];
+        for (c, span) in chars {
+            let b = c.encode_utf8(&mut buf).as_bytes();
+            bytes.extend_from_slice(b);
+            spans.extend(b.iter().map(|_| span.clone()));
+        }
+        bytes.extend_from_slice(&s[valid..]);
+        spans.extend((valid..s.len()).map(|i| i..i + 1));
+        (bytes, spans)
+    }
+
+    /// Normalize the valid UTF-8 prefix of s; trailing bytes
+    /// (e.g., an incomplete character) are kept as is.
+    pub fn normalize_bytes(&self, s: &[u8]) -> Vec<u8> {
+        if self.is_identity() {
+            return s.to_vec();
+        }
+        let valid = match core::str::from_utf8(s) {
+            Ok(_) => s.len(),
+            Err(e) => e.valid_up_to(),
+        };
+        let prefix = core::str::from_utf8(&s[..valid]).unwrap();
+        let mut r = self.normalize(prefix).into_bytes();
+        r.extend_from_slice(&s[valid..]);
+        r
+    }
+}
+
+fn add_steps(json: &Value, steps: &mut Vec<NormalizerStep>) -> Result<()> {
+    let tp = match json {
+        Value::Null => return Ok(()),
+        _ => json["type"]
+            .as_str()
+            .ok_or_else(|| anyhow!("normalizer without type: {}", json))?,
+    };
+    match tp {
+        "Sequence" => {
+            for n in json["normalizers"].as_array().into_iter().flatten() {
+                add_steps(n, steps)?;
+            }
+        }
+        "NFC" => steps.push(NormalizerStep::Nfc),
+        "NFD" => steps.push(NormalizerStep::Nfd),
+        "NFKC" => steps.push(NormalizerStep::Nfkc),
+        "NFKD" => steps.push(NormalizerStep::Nfkd),
+        "Lowercase" => steps.push(NormalizerStep::Lowercase),
+        "StripAccents" => steps.push(NormalizerStep::StripAccents),
+        "Strip" => steps.push(NormalizerStep::Strip {
+            left: json["strip_left"].as_bool().unwrap_or(true),
+            right: json["strip_right"].as_bool().unwrap_or(true),
+        }),
+        "Prepend" => {}
+        "Replace" => {
+            let pattern = json["pattern"]["String"]
+                .as_str()
+                .ok_or_else(|| anyhow!("only string patterns supported in Replace: {}", json))?;
+            let content = json["content"].as_str().unwrap_or("");
+            if content != "\u{2581}" {
+                steps.push(NormalizerStep::Replace {
+                    pattern: pattern.to_string(),
+                    content: content.to_string(),
+                });
+            }
+        }
+        "BertNormalizer" => {
+            let lowercase = json["lowercase"].as_bool().unwrap_or(true);
+            if json["clean_text"].as_bool().unwrap_or(true) {
+                steps.push(NormalizerStep::CleanText);
+            }
+            if json["handle_chinese_chars"].as_bool().unwrap_or(true) {
+                steps.push(NormalizerStep::PadChinese);
+            }
+            // null means the same as lowercase
+            if json["strip_accents"].as_bool().unwrap_or(lowercase) {
+                steps.push(NormalizerStep::StripAccents);
+            }
+            if lowercase {
+                steps.push(NormalizerStep::Lowercase);
+            }
+        }
+        _ => bail!("unsupported normalizer: {}", tp),
+    }
+    Ok(())
+}
+
+/// TokenizerEnv that normalizes text before passing it to the base tokenizer.
+/// Special tokens (see tokenize_special()) are not normalized.
+pub struct NormalizedTokEnv {
+    base_env: TokEnv,
+    normalizer: Normalizer,
+}
+
+impl NormalizedTokEnv {
+    pub fn new(base_env: TokEnv, normalizer: Normalizer) -> Self {
+        Self {
+            base_env,
+            normalizer,
+        }
+    }
+
+    pub fn normalizer(&self) -> &Normalizer {
+        &self.normalizer
+    }
+}
+
+impl TokenizerEnv for NormalizedTokEnv {
+    fn tok_trie(&self) -> &TokTrie {
+        self.base_env.tok_trie()
+    }
+
+    fn tokenize_bytes(&self, s: &[u8]) -> Vec<TokenId> {
+        self.base_env
+            .tokenize_bytes(&self.normalizer.normalize_bytes(s))
+    }
+
+    fn count_tokens(&self, s: &[u8]) -> usize {
+        self.base_env
+            .count_tokens(&self.normalizer.normalize_bytes(s))
+    }
+
+    fn try_tokenize_bytes(&self, s: &[u8]) -> Result<Vec<TokenId>> {
+        self.base_env
+            .try_tokenize_bytes(&self.normalizer.normalize_bytes(s))
+    }
+
+    /// Spans are mapped back to s; tokens from the same normalized text
+    /// (e.g., "f" and "i" from "ﬁ" with NFKC) share its span.
+    fn tokenize_bytes_with_offsets(&self, s: &[u8]) -> Vec<(TokenId, Range<usize>)> {
+        let (norm, spans) = self.normalizer.normalize_bytes_with_offsets(s);
+        self.base_env
+            .tokenize_bytes_with_offsets(&norm)
+            .into_iter()
+            .map(|(tok, r)| {
+                let span = if r.start < r.end {
+                    spans[r.start].start..spans[r.end - 1].end
+                } else {
+                    let pos = spans.get(r.start).map_or(s.len(), |sp| sp.start);
+                    pos..pos
+                };
+                (tok, span)
+            })
+            .collect()
+    }
+
+    fn tokenize_is_canonical(&self) -> bool {
+        self.base_env.tokenize_is_canonical()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TestTokEnv;
+    use alloc::sync::Arc;
+
+    fn spans(steps: Vec<NormalizerStep>, s: &str) -> Vec<(String, Range<usize>)> {
+        let normalizer = Normalizer::new(steps).unwrap();
+        let (norm, _) = normalizer.normalize_bytes_with_offsets(s.as_bytes());
+        assert_eq!(norm, normalizer.normalize(s).as_bytes());
+        let env = NormalizedTokEnv::new(Arc::new(TestTokEnv::with_words(&["ab"])), normalizer);
+        env.tokenize_bytes_with_offsets(s.as_bytes())
+            .into_iter()
+            .map(|(tok, r)| (env.tok_trie().token_dbg(tok), r))
+            .collect()
+    }
+
+    fn tok(s: &str, r: Range<usize>) -> (String, Range<usize>) {
+        (format!("{:?}", s), r)
+    }
+
+    #[test]
+    fn offsets_map_to_input() {
+        let replace = NormalizerStep::Replace {
+            pattern: "ab".to_string(),
+            content: "X".to_string(),
+        };
+        assert_eq!(
+            spans(vec![NormalizerStep::Lowercase, replace], "AbcAB"),
+            vec![tok("X", 0..2), tok("c", 2..3), tok("X", 3..5)]
+        );
+        assert_eq!(
+            spans(
+                vec![NormalizerStep::Strip {
+                    left: true,
+                    right: true
+                }],
+                "  AB "
+            ),
+            vec![tok("A", 2..3), tok("B", 3..4)]
+        );
+        assert_eq!(
+            spans(vec![NormalizerStep::Lowercase], "ABx"),
+            vec![tok("ab", 0..2), tok("x", 2..3)]
+        );
+    }
+
+    #[cfg(feature = "normalization")]
+    #[test]
+    fn offsets_with_unicode_forms() {
+        // U+FB01 LATIN SMALL LIGATURE FI
+        assert_eq!(
+            spans(vec![NormalizerStep::Nfkc], "\u{FB01}x"),
+            vec![tok("f", 0..3), tok("i", 0..3), tok("x", 3..4)]
+        );
+        let s = spans(vec![NormalizerStep::StripAccents], "e\u{301}ab");
+        assert_eq!(s, vec![tok("e", 0..3), tok("ab", 3..5)]);
+        // composed e with acute is two byte tokens, both from "e" and the mark
+        let s = spans(vec![NormalizerStep::Nfc], "xe\u{301}");
+        assert_eq!(s.len(), 3);
+        assert_eq!(s[0].1, 0..1);
+        assert_eq!(s[1].1, 1..4);
+        assert_eq!(s[2].1, 1..4);
+    }
+
+    #[test]
+    fn unsupported_steps_error() {
+        let precompiled = serde_json::json!({"type": "Precompiled", "precompiled_charsmap": ""});
+        assert!(Normalizer::from_json(&precompiled).is_err());
+        let regex = serde_json::json!({
+            "type": "Replace",
+            "pattern": {"Regex": " +"},
+            "content": " "
+        });
+        assert!(Normalizer::from_json(&regex).is_err());
+    }
+}
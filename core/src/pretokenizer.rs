@@ -0,0 +1,364 @@
+// Hand-written matchers for the standard pre-tokenization regexes.
+// The patterns use lookahead, which regex-automata doesn't support,
+// so each alternative is implemented directly, in the order of the regex
+// (leftmost-first semantics).
+// \p{L} and \p{N} use the regex-syntax Unicode tables,
+// and \s is char::is_whitespace() (the same White_Space property).
+
+use core::ops::Range;
+
+use anyhow::{bail, Result};
+use regex_syntax::hir::{Class, HirKind};
+use serde_json::Value;
+
+use crate::prelude::*;
+use crate::{TokTrie, TokenId};
+
+const GPT2_PATTERN: &str =
+    r"'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+";
+const CL100K_PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+// cl100k written with explicit case alternatives, as found in some tokenizer.json files
+const CL100K_PATTERN_NOCASE: &str = r"(?:'[sS]|'[tT]|'[rR][eE]|'[vV][eE]|'[mM]|'[lL][lL]|'[dD])|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+
+/// Splitting of text into pieces that are tokenized independently,
+/// following the regexes used by common BPE tokenizers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PreTokenizer {
+    /// GPT-2 (also used by ByteLevel pre-tokenizer with use_regex).
+    Gpt2,
+    /// GPT-4 cl100k_base.
+    Cl100k,
+    /// Llama 3 (same pattern as cl100k).
+    Llama3,
+}
+
+impl PreTokenizer {
+    /// The regex this pre-tokenizer implements.
+    pub fn pattern(&self) -> &'static str {
+        match self {
+            PreTokenizer::Gpt2 => GPT2_PATTERN,
+            PreTokenizer::Cl100k | PreTokenizer::Llama3 => CL100K_PATTERN,
+        }
+    }
+
+    /// Recognize one of the supported regexes.
+    pub fn from_pattern(pattern: &str) -> Option<Self> {
+        match pattern {
+            GPT2_PATTERN => Some(PreTokenizer::Gpt2),
+            CL100K_PATTERN | CL100K_PATTERN_NOCASE => Some(PreTokenizer::Cl100k),
+            _ => None,
+        }
+    }
+
+    /// Parse the "pre_tokenizer" section of tokenizer.json.
+    /// Returns None if there is no regex splitting (e.g., Metaspace or null).
+    pub fn from_json(json: &Value) -> Result<Option<Self>> {
+        match json["type"].as_str() {
+            None => Ok(None),
+            Some("ByteLevel") => {
+                if json["use_regex"].as_bool().unwrap_or(true) {
+                    Ok(Some(PreTokenizer::Gpt2))
+                } else {
+                    Ok(None)
+                }
+            }
+            Some("Split") => {
+                let pattern = match json["pattern"]["Regex"].as_str() {
+                    Some(p) => p,
+                    None => return Ok(None),
+                };
+                match Self::from_pattern(pattern) {
+                    Some(p) => Ok(Some(p)),
+                    None => bail!("unsupported pre-tokenizer regex: {:?}", pattern),
+                }
+            }
+            Some("Sequence") => {
+                let mut res = None;
+                for p in json["pretokenizers"].as_array().into_iter().flatten() {
+                    // the first regex decides; ByteLevel after Split usually has use_regex=false
+                    if let Some(p) = Self::from_json(p)? {
+                        res = res.or(Some(p));
+                    }
+                }
+                Ok(res)
+            }
+            Some("Metaspace") => Ok(None),
+            Some(tp) => bail!("unsupported pre-tokenizer: {}", tp),
+        }
+    }
+
+    /// Pre-tokenizer configured in tokenizer.json file contents.
+    pub fn from_tokenizer_json(bytes: &[u8]) -> Result<Option<Self>> {
+        let json: Value = serde_json::from_slice(bytes)?;
+        Self::from_json(&json["pre_tokenizer"])
+    }
+
+    /// Split s into pieces; the pieces cover all of s.
+    pub fn split<'a>(&self, s: &'a str) -> Vec<&'a str> {
+        self.split_ranges(s).into_iter().map(|r| &s[r]).collect()
+    }
+
+    /// Byte ranges of pieces of s.
+    /// Invalid UTF-8 sequences are returned as separate pieces.
+    pub fn split_bytes(&self, s: &[u8]) -> Vec<Range<usize>> {
+        let mut res = vec![];
+        let mut off = 0;
+        for chunk in s.utf8_chunks() {
+            let valid = chunk.valid();
+            res.extend(
+                self.split_ranges(valid)
+                    .into_iter()
+                    .map(|r| r.start + off..r.end + off),
+            );
+            off += valid.len();
+            let invalid = chunk.invalid().len();
+            if invalid > 0 {
+                res.push(off..off + invalid);
+                off += invalid;
+            }
+        }
+        res
+    }
+
+    fn split_ranges(&self, s: &str) -> Vec<Range<usize>> {
+        with_char_classes(|cc| self.split_ranges_with(cc, s))
+    }
+
+    fn split_ranges_with(&self, cc: &CharClasses, s: &str) -> Vec<Range<usize>> {
+        let chars: Vec<(usize, char)> = s.char_indices().collect();
+        let mut res = vec![];
+        let mut i = 0;
+        while i < chars.len() {
+            let n = match self {
+                PreTokenizer::Gpt2 => gpt2_match(cc, &chars[i..]),
+                PreTokenizer::Cl100k | PreTokenizer::Llama3 => cl100k_match(cc, &chars[i..]),
+            };
+            debug_assert!(n > 0);
+            let end = chars.get(i + n).map_or(s.len(), |c| c.0);
+            res.push(chars[i].0..end);
+            i += n;
+        }
+        res
+    }
+}
+
+// \p{L} and \p{N}, as sorted ranges
+struct CharClasses {
+    letter: Vec<(char, char)>,
+    number: Vec<(char, char)>,
+}
+
+impl CharClasses {
+    fn new() -> Self {
+        CharClasses {
+            letter: class_ranges(r"\p{L}"),
+            number: class_ranges(r"\p{N}"),
+        }
+    }
+
+    fn is_letter(&self, c: char) -> bool {
+        if c.is_ascii() {
+            c.is_ascii_alphabetic()
+        } else {
+            in_ranges(&self.letter, c)
+        }
+    }
+
+    fn is_number(&self, c: char) -> bool {
+        if c.is_ascii() {
+            c.is_ascii_digit()
+        } else {
+            in_ranges(&self.number, c)
+        }
+    }
+
+    fn is_other(&self, c: char) -> bool {
+        !c.is_whitespace() && !self.is_letter(c) && !self.is_number(c)
+    }
+}
+
+fn class_ranges(pattern: &str) -> Vec<(char, char)> {
+    match regex_syntax::parse(pattern).unwrap().into_kind() {
+        HirKind::Class(Class::Unicode(cls)) => {
+            cls.ranges().iter().map(|r| (r.start(), r.end())).collect()
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn in_ranges(ranges: &[(char, char)], c: char) -> bool {
+    ranges
+        .binary_search_by(|&(lo, hi)| {
+            if hi < c {
+                core::cmp::Ordering::Less
+            } else if lo > c {
+                core::cmp::Ordering::Greater
+            } else {
+                core::cmp::Ordering::Equal
+            }
+        })
+        .is_ok()
+}
+
+// the tables are built once with std, and on every split without it
+#[cfg(feature = "std")]
+fn with_char_classes<T>(f: impl FnOnce(&CharClasses) -> T) -> T {
+    static CLASSES: std::sync::OnceLock<CharClasses> = std::sync::OnceLock::new();
+    f(CLASSES.get_or_init(CharClasses::new))
+}
+
+#[cfg(not(feature = "std"))]
+fn with_char_classes<T>(f: impl FnOnce(&CharClasses) -> T) -> T {
+    f(&CharClasses::new())
+}
+
+fn is_newline(c: char) -> bool {
+    c == '\r' || c == '\n'
+}
+
+// number of leading chars satisfying f
+fn count(s: &[(usize, char)], f: impl Fn(char) -> bool) -> usize {
+    s.iter().take_while(|c| f(c.1)).count()
+}
+
+// ' ?' followed by one or more chars satisfying f
+fn opt_space_then(s: &[(usize, char)], f: impl Fn(char) -> bool) -> usize {
+    let sp = (s[0].1 == ' ') as usize;
+    match count(&s[sp..], f) {
+        0 => 0,
+        n => sp + n,
+    }
+}
+
+fn contraction(s: &[(usize, char)], ignore_case: bool) -> usize {
+    if s[0].1 != '\'' {
+        return 0;
+    }
+    let lower = |i: usize| {
+        s.get(i).map(|c| {
+            if ignore_case {
+                c.1.to_ascii_lowercase()
+            } else {
+                c.1
+            }
+        })
+    };
+    match (lower(1), lower(2)) {
+        (Some('r'), Some('e')) | (Some('v'), Some('e')) | (Some('l'), Some('l')) => 3,
+        (Some('s' | 't' | 'm' | 'd'), _) => 2,
+        _ => 0,
+    }
+}
+
+// \s+(?!\S) followed by \s+
+fn whitespace(s: &[(usize, char)]) -> usize {
+    let n = count(s, char::is_whitespace);
+    if n > 1 && n < s.len() {
+        // leave the last space to be attached to the following word
+        n - 1
+    } else {
+        n
+    }
+}
+
+fn gpt2_match(cc: &CharClasses, s: &[(usize, char)]) -> usize {
+    let n = contraction(s, false);
+    if n > 0 {
+        return n;
+    }
+    for f in [
+        CharClasses::is_letter,
+        CharClasses::is_number,
+        CharClasses::is_other,
+    ] {
+        let n = opt_space_then(s, |c| f(cc, c));
+        if n > 0 {
+            return n;
+        }
+    }
+    whitespace(s)
+}
+
+fn cl100k_match(cc: &CharClasses, s: &[(usize, char)]) -> usize {
+    let n = contraction(s, true);
+    if n > 0 {
+        return n;
+    }
+
+    // [^\r\n\p{L}\p{N}]?\p{L}+
+    let c0 = s[0].1;
+    if !is_newline(c0) && !cc.is_letter(c0) && !cc.is_number(c0) {
+        let n = count(&s[1..], |c| cc.is_letter(c));
+        if n > 0 {
+            return n + 1;
+        }
+    }
+    let n = count(s, |c| cc.is_letter(c));
+    if n > 0 {
+        return n;
+    }
+
+    // \p{N}{1,3}
+    let n = count(s, |c| cc.is_number(c));
+    if n > 0 {
+        return core::cmp::min(n, 3);
+    }
+
+    // ?[^\s\p{L}\p{N}]+[\r\n]*
+    let n = opt_space_then(s, |c| cc.is_other(c));
+    if n > 0 {
+        return n + count(&s[n..], is_newline);
+    }
+
+    // \s*[\r\n]+
+    let ws = count(s, char::is_whitespace);
+    if let Some(last_nl) = s[..ws].iter().rposition(|c| is_newline(c.1)) {
+        return last_nl + 1;
+    }
+
+    whitespace(s)
+}
+
+impl TokTrie {
+    /// Like greedy_tokenize(), but tokenizes each piece of the pre-tokenizer separately,
+    /// so that tokens don't cross the boundaries the reference tokenizer uses.
+    pub fn greedy_tokenize_split(&self, bytes: &[u8], pre: PreTokenizer) -> Vec<TokenId> {
+        let mut r = Vec::new();
+        for range in pre.split_bytes(bytes) {
+            r.extend_from_slice(&self.greedy_tokenize(&bytes[range]));
+        }
+        r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unicode_letters_and_numbers() {
+        let cc = CharClasses::new();
+        // combining marks are alphabetic, but not letters
+        for c in ['\u{0345}', '\u{093F}'] {
+            assert!(c.is_alphabetic());
+            assert!(!cc.is_letter(c) && cc.is_other(c));
+        }
+        for c in ['a', 'Z', '\u{00E9}', '\u{0915}', '\u{4E2D}'] {
+            assert!(cc.is_letter(c) && !cc.is_number(c));
+        }
+        // letter numbers (Roman numerals) and other numbers
+        for c in ['7', '\u{216B}', '\u{00B2}', '\u{0967}'] {
+            assert!(cc.is_number(c) && !cc.is_letter(c));
+        }
+
+        let pre = PreTokenizer::Gpt2;
+        assert_eq!(
+            pre.split("\u{0915}\u{093F} 12"),
+            ["\u{0915}", "\u{093F}", " 12"]
+        );
+        assert_eq!(
+            PreTokenizer::Cl100k.split("caf\u{00E9}\u{216B}12345"),
+            ["caf\u{00E9}", "\u{216B}12", "345"]
+        );
+    }
+}
@@ -0,0 +1,376 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::prelude::*;
+use crate::{
+    recognizer::OneOf, Branch, Recognizer, SpecialToken, StepArg, StepResult, TokenId, TokenizerEnv,
+};
+
+enum StageKind {
+    Text(String),
+    Gen(Box<dyn Recognizer + Send>),
+}
+
+/// Part of a Program: either forced text, or generation constrained by a recognizer.
+pub struct Stage {
+    kind: StageKind,
+    name: Option<String>,
+    max_tokens: Option<usize>,
+}
+
+impl Stage {
+    /// Text forced as-is (tokenized separately from the surrounding stages).
+    pub fn text(text: &str) -> Self {
+        Self::new(StageKind::Text(text.to_string()))
+    }
+
+    /// Generation constrained by given recognizer. The stage ends when EOS is sampled,
+    /// or when the recognizer doesn't allow any more bytes.
+    pub fn gen(rec: impl Recognizer + Send + 'static) -> Self {
+        Self::new(StageKind::Gen(Box::new(rec)))
+    }
+
+    /// Generation of text matching given regex.
+    #[cfg(feature = "regex")]
+    pub fn regex(rx: &str) -> Result<Self> {
+        Ok(Self::gen(
+            crate::recognizer::RegexDfa::new(rx)?.to_recognizer(),
+        ))
+    }
+
+    /// Generation of one of the options.
+    pub fn choice(options: &[&str]) -> Self {
+        Self::gen(OneOf::new(options).to_recognizer())
+    }
+
+    /// Record the output of this stage under given name, see Program::captures().
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// End the stage after given number of tokens.
+    pub fn with_max_tokens(mut self, max_tokens: usize) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+
+    fn new(kind: StageKind) -> Self {
+        Stage {
+            kind,
+            name: None,
+            max_tokens: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct ProgramState {
+    idx: usize,
+    output: Vec<u8>,
+    num_tokens: usize,
+    captures: Vec<(String, Vec<u8>)>,
+    pending_ff: Vec<TokenId>,
+    pending_backtrack: bool,
+    expected_backtrack: u32,
+    // Recognizer::save_state() of stages from idx on (empty for text stages)
+    stages: Vec<Vec<u8>>,
+}
+
+/// Runs a sequence of stages, one step at a time, taking care of forcing text,
+/// computing masks, and moving to the next stage.
+/// Once all stages are done, it stops, with the named outputs as the stop payload.
+pub struct Program {
+    stages: Vec<Stage>,
+    idx: usize,
+    // output and number of tokens of the current stage
+    output: Vec<u8>,
+    num_tokens: usize,
+    captures: Vec<(String, Vec<u8>)>,
+    // tokens we forced, expected at the start of the next StepArg
+    pending_ff: Vec<TokenId>,
+    // EOS was sampled to end a stage, and needs to be removed
+    pending_backtrack: bool,
+    // backtrack we asked for, expected in the next StepArg
+    expected_backtrack: u32,
+}
+
+impl Program {
+    pub fn new(stages: Vec<Stage>) -> Self {
+        Program {
+            stages,
+            idx: 0,
+            output: vec![],
+            num_tokens: 0,
+            captures: vec![],
+            pending_ff: vec![],
+            pending_backtrack: false,
+            expected_backtrack: 0,
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.idx >= self.stages.len()
+    }
+
+    /// Outputs of named stages that are done.
+    pub fn captures(&self) -> &[(String, Vec<u8>)] {
+        &self.captures
+    }
+
+    /// Checkpoint of the whole program, to be passed to restore_state() when backtracking.
+    /// All recognizers of the remaining stages have to support save_state().
+    pub fn save_state(&mut self) -> Result<Vec<u8>> {
+        let mut stages = vec![];
+        for stage in &mut self.stages[self.idx..] {
+            stages.push(match &mut stage.kind {
+                StageKind::Text(_) => vec![],
+                StageKind::Gen(rec) => rec.save_state()?,
+            });
+        }
+        let state = ProgramState {
+            idx: self.idx,
+            output: self.output.clone(),
+            num_tokens: self.num_tokens,
+            captures: self.captures.clone(),
+            pending_ff: self.pending_ff.clone(),
+            pending_backtrack: self.pending_backtrack,
+            expected_backtrack: self.expected_backtrack,
+            stages,
+        };
+        Ok(serde_json::to_vec(&state)?)
+    }
+
+    /// Restore a checkpoint made by save_state() of this program (or its earlier incarnation).
+    pub fn restore_state(&mut self, data: &[u8]) -> Result<()> {
+        let state: ProgramState = serde_json::from_slice(data)?;
+        if state.idx + state.stages.len() != self.stages.len() {
+            bail!("invalid Program state");
+        }
+        for (stage, data) in self.stages[state.idx..].iter_mut().zip(&state.stages) {
+            if let StageKind::Gen(rec) = &mut stage.kind {
+                rec.restore_state(data)?;
+            }
+        }
+        self.idx = state.idx;
+        self.output = state.output;
+        self.num_tokens = state.num_tokens;
+        self.captures = state.captures;
+        self.pending_ff = state.pending_ff;
+        self.pending_backtrack = state.pending_backtrack;
+        self.expected_backtrack = state.expected_backtrack;
+        Ok(())
+    }
+
+    fn finish_stage(&mut self) {
+        if let Some(name) = &self.stages[self.idx].name {
+            self.captures
+                .push((name.clone(), core::mem::take(&mut self.output)));
+        }
+        self.output.clear();
+        self.num_tokens = 0;
+        self.idx += 1;
+    }
+
+    fn apply_tokens(&mut self, env: &dyn TokenizerEnv, arg: &StepArg) -> Result<()> {
+        if arg.backtrack != self.expected_backtrack {
+            bail!("Program doesn't support backtracking");
+        }
+        self.expected_backtrack = 0;
+        let mut tokens = &arg.tokens[..];
+        if !self.pending_ff.is_empty() {
+            if !tokens.starts_with(&self.pending_ff) {
+                bail!("forced tokens were not appended");
+            }
+            tokens = &tokens[self.pending_ff.len()..];
+            self.pending_ff.clear();
+        }
+        let trie = env.tok_trie();
+        for &tok in tokens {
+            if self.is_done() {
+                bail!("token after the end of the program");
+            }
+            let stage = &mut self.stages[self.idx];
+            let rec = match &mut stage.kind {
+                StageKind::Gen(rec) => rec,
+                StageKind::Text(_) => bail!("token not expected in text stage"),
+            };
+            if trie.is_stop_token(tok) {
+                // EOS ends the stage; it's only kept at the very end
+                self.finish_stage();
+                self.pending_backtrack = !self.is_done();
+                continue;
+            }
+            trie.append_token(rec, tok)?;
+            self.output.extend_from_slice(trie.token(tok));
+            self.num_tokens += 1;
+        }
+        Ok(())
+    }
+
+    /// Process the tokens appended since the previous step, and decide what to do next.
+    pub fn step(&mut self, env: &dyn TokenizerEnv, arg: &StepArg) -> Result<StepResult> {
+        self.apply_tokens(env, arg)?;
+        let trie = env.tok_trie();
+        let backtrack = self.pending_backtrack as u32;
+        while !self.is_done() {
+            let stage = &mut self.stages[self.idx];
+            match &mut stage.kind {
+                StageKind::Text(text) => {
+                    let tokens = env.tokenize(text);
+                    self.output = text.as_bytes().to_vec();
+                    self.finish_stage();
+                    self.pending_ff = tokens.clone();
+                    self.pending_backtrack = false;
+                    self.expected_backtrack = backtrack;
+                    return Ok(Branch::splice(backtrack, tokens));
+                }
+                StageKind::Gen(rec) => {
+                    let at_max = stage.max_tokens.is_some_and(|m| self.num_tokens >= m);
+                    let only_eos = rec.no_bytes_allowed();
+                    if at_max || only_eos {
+                        if !at_max && !rec.special_allowed(SpecialToken::EndOfSentence) {
+                            bail!("stage can't be completed");
+                        }
+                        self.finish_stage();
+                        continue;
+                    }
+                    let mut mask = trie.alloc_token_set();
+                    trie.compute_bias(rec, &mut mask);
+                    self.pending_backtrack = false;
+                    self.expected_backtrack = backtrack;
+                    return Ok(if backtrack > 0 {
+                        Branch::splice_and_sample(backtrack, vec![], mask, None)
+                    } else {
+                        Branch::sample(mask, None)
+                    });
+                }
+            }
+        }
+        let payload = serde_json::Value::Object(
+            self.captures
+                .iter()
+                .map(|(k, v)| {
+                    (
+                        k.clone(),
+                        serde_json::Value::String(String::from_utf8_lossy(v).to_string()),
+                    )
+                })
+                .collect(),
+        );
+        Ok(Branch::stop_with("done", Some(payload)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TestTokEnv;
+
+    fn env() -> TestTokEnv {
+        TestTokEnv::with_words(&["Q: ", "yes", "yak", "es"])
+    }
+
+    fn program() -> Program {
+        Program::new(vec![
+            Stage::text("Q: "),
+            Stage::choice(&["yes", "yak", "y"]).with_name("a"),
+            Stage::text("!"),
+        ])
+    }
+
+    fn payload(res: &StepResult) -> serde_json::Value {
+        assert!(res.is_stop());
+        res.stop_info.as_ref().unwrap().payload.clone().unwrap()
+    }
+
+    // host side of a step: apply the splice for sampled token (if any)
+    fn next_arg(res: &StepResult, sampled: Option<TokenId>) -> StepArg {
+        match sampled {
+            Some(t) => {
+                assert!(res.sample_mask.as_ref().unwrap().is_allowed(t));
+                StepArg::from_splice(&res.spliced(t), Some(t))
+            }
+            None => StepArg::from_splice(res.unconditional_splice().unwrap(), None),
+        }
+    }
+
+    #[test]
+    fn stages() {
+        let env = env();
+        let trie = env.tok_trie();
+        let tok = |s| env.token(s).unwrap();
+        let mut p = program();
+        let res = p.step(&env, &StepArg::empty()).unwrap();
+        assert_eq!(res.unconditional_splice().unwrap().ff_tokens, [tok("Q: ")]);
+
+        let res = p.step(&env, &next_arg(&res, None)).unwrap();
+        let mask = res.sample_mask.as_ref().unwrap();
+        assert_eq!(env.allowed_names(mask), ["\"y\"", "\"yes\"", "\"yak\""]);
+
+        let res = p.step(&env, &next_arg(&res, Some(tok("yes")))).unwrap();
+        // the choice is complete, so "!" is forced right away
+        assert_eq!(res.unconditional_splice().unwrap().ff_tokens, [tok("!")]);
+        let res = p.step(&env, &next_arg(&res, None)).unwrap();
+        assert_eq!(payload(&res), serde_json::json!({"a": "yes"}));
+        assert!(p.is_done());
+
+        // EOS ends the stage early, and is backtracked over
+        let mut p = program();
+        let res = p.step(&env, &StepArg::empty()).unwrap();
+        let res = p.step(&env, &next_arg(&res, None)).unwrap();
+        let res = p.step(&env, &next_arg(&res, Some(tok("y")))).unwrap();
+        let eos = trie.eos_token();
+        let res = p.step(&env, &next_arg(&res, Some(eos))).unwrap();
+        let splice = res.unconditional_splice().unwrap();
+        assert_eq!(
+            (splice.backtrack, &splice.ff_tokens[..]),
+            (1, &[tok("!")][..])
+        );
+        let res = p.step(&env, &next_arg(&res, None)).unwrap();
+        assert_eq!(payload(&res), serde_json::json!({"a": "y"}));
+
+        // the host didn't append forced tokens
+        let mut p = program();
+        p.step(&env, &StepArg::empty()).unwrap();
+        assert!(p.step(&env, &StepArg::empty()).is_err());
+    }
+
+    #[test]
+    fn checkpoint_and_restore() {
+        let env = env();
+        let tok = |s| env.token(s).unwrap();
+        let mut p = program();
+        let res = p.step(&env, &StepArg::empty()).unwrap();
+        let res = p.step(&env, &next_arg(&res, None)).unwrap();
+        let res = p.step(&env, &next_arg(&res, Some(tok("y")))).unwrap();
+        let checkpoint = p.save_state().unwrap();
+        let at_y = res.clone();
+
+        let res = p.step(&env, &next_arg(&res, Some(tok("es")))).unwrap();
+        let res = p.step(&env, &next_arg(&res, None)).unwrap();
+        assert_eq!(payload(&res), serde_json::json!({"a": "yes"}));
+        assert_eq!(p.captures().len(), 1);
+
+        // go back to just after "y", and take the other branch
+        p.restore_state(&checkpoint).unwrap();
+        assert!(!p.is_done());
+        assert!(p.captures().is_empty());
+        let res = p.step(&env, &next_arg(&at_y, Some(tok("a")))).unwrap();
+        let mask = res.sample_mask.as_ref().unwrap();
+        assert_eq!(env.allowed_names(mask), ["\"k\""]);
+        let res = p.step(&env, &next_arg(&res, Some(tok("k")))).unwrap();
+        let res = p.step(&env, &next_arg(&res, None)).unwrap();
+        assert_eq!(payload(&res), serde_json::json!({"a": "yak"}));
+
+        // a fresh program can pick up the checkpoint, but a different one can't
+        let mut p2 = program();
+        p2.restore_state(&checkpoint).unwrap();
+        let res = p2.step(&env, &next_arg(&at_y, Some(tok("es")))).unwrap();
+        let res = p2.step(&env, &next_arg(&res, None)).unwrap();
+        assert_eq!(payload(&res), serde_json::json!({"a": "yes"}));
+        let mut other = Program::new(vec![Stage::text("x")]);
+        assert!(other.restore_state(&checkpoint).is_err());
+        assert!(other.restore_state(b"garbage").is_err());
+    }
+}
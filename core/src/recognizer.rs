@@ -1,5 +1,45 @@
-use crate::toktree::{Recognizer, SpecialToken};
-use std::fmt::Debug;
+use crate::prelude::*;
+use crate::{
+    toktree::{Recognizer, SpecialToken},
+    FxHashMap, SimpleVob, TokTrie, TokenId,
+};
+use anyhow::{bail, Result};
+use core::fmt::Debug;
+
+mod blob;
+mod cfg;
+mod combinators;
+mod fuzzy;
+mod json;
+#[cfg(feature = "regex")]
+mod json_schema;
+mod limits;
+mod number;
+mod one_of;
+#[cfg(feature = "regex")]
+mod regex;
+mod substring;
+mod token_level;
+mod unicode;
+
+pub use cfg::{Cfg, CfgRecognizer};
+pub use combinators::{And, Capture, Not, Or, Repeat, Seq};
+pub use fuzzy::FuzzyMatch;
+pub use json::{JsonRecognizer, JsonState, JsonSyntax};
+#[cfg(feature = "regex")]
+pub use json_schema::{json_schema_recognizer, json_schema_to_regex};
+pub use limits::{WithLengthLimit, WithTokenLimit};
+pub use number::{NumberRange, NumberRangeRecognizer, NumberState};
+pub use one_of::{OneOf, OneOfRecognizer};
+#[cfg(feature = "regex")]
+pub use regex::{RegexDfa, RegexRecognizer};
+pub use substring::{Substring, SubstringRecognizer};
+pub use token_level::{
+    AllowTokens, BanTokens, ForceTokens, NoRepeatNgram, TokenConstraint, TokenLevel,
+};
+pub use unicode::{
+    CaseInsensitive, CaseInsensitiveRecognizer, CharClass, CharClassRecognizer, CharClassState,
+};
 
 pub trait FunctionalRecognizer<S: Copy> {
     /// Initial state
@@ -12,6 +52,30 @@ pub trait FunctionalRecognizer<S: Copy> {
     fn get_error(&self, _state: S) -> Option<String> {
         None
     }
+    /// See Recognizer::all_bytes_allowed().
+    fn all_bytes_allowed(&self, _state: S) -> bool {
+        false
+    }
+    /// See Recognizer::no_bytes_allowed().
+    fn no_bytes_allowed(&self, _state: S) -> bool {
+        false
+    }
+    /// Weight of appending given byte in given state (see Recognizer::byte_weight()).
+    fn byte_weight(&self, _state: S, _byte: u8) -> f32 {
+        0.0
+    }
+    /// See Recognizer::state_description().
+    fn state_description(&self, _state: S) -> Option<String> {
+        None
+    }
+    /// See Recognizer::save_state().
+    fn save_state(&self, _state: S) -> Result<Vec<u8>> {
+        bail!("save_state() not supported by this recognizer")
+    }
+    /// See Recognizer::restore_state().
+    fn restore_state(&self, _data: &[u8]) -> Result<S> {
+        bail!("restore_state() not supported by this recognizer")
+    }
 }
 
 #[derive(Clone)]
@@ -43,10 +107,15 @@ impl<S: Copy, R: FunctionalRecognizer<S>> StackRecognizer<S, R> {
     pub fn recognizer_mut(&mut self) -> &mut R {
         &mut self.rec
     }
+
+    /// State at the top of the stack.
+    pub fn state(&self) -> S {
+        self.stack[self.stack_ptr]
+    }
 }
 
 impl<S: Copy + Debug, R: FunctionalRecognizer<S>> Debug for StackRecognizer<S, R> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("StackRecognizer")
             .field("top", &self.stack[self.stack_ptr])
             .finish()
@@ -77,6 +146,32 @@ impl<S: Copy + Debug, R: FunctionalRecognizer<S>> Recognizer for StackRecognizer
         self.rec.get_error(self.stack[self.stack_ptr])
     }
 
+    fn all_bytes_allowed(&mut self) -> bool {
+        self.rec.all_bytes_allowed(self.stack[self.stack_ptr])
+    }
+
+    fn no_bytes_allowed(&mut self) -> bool {
+        self.rec.no_bytes_allowed(self.stack[self.stack_ptr])
+    }
+
+    fn byte_weight(&mut self, byte: u8) -> f32 {
+        self.rec.byte_weight(self.stack[self.stack_ptr], byte)
+    }
+
+    fn state_description(&mut self) -> Option<String> {
+        self.rec.state_description(self.stack[self.stack_ptr])
+    }
+
+    fn save_state(&mut self) -> Result<Vec<u8>> {
+        self.rec.save_state(self.stack[self.stack_ptr])
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> Result<()> {
+        self.stack[0] = self.rec.restore_state(state)?;
+        self.stack_ptr = 0;
+        Ok(())
+    }
+
     #[inline(always)]
     fn try_push_byte(&mut self, byte: u8) -> bool {
         match self.rec.try_append(self.stack[self.stack_ptr], byte) {
@@ -105,4 +200,318 @@ impl FunctionalRecognizer<()> for AnythingGoes {
     fn special_allowed(&self, _state: (), _tok: SpecialToken) -> bool {
         true
     }
+
+    fn all_bytes_allowed(&self, _state: ()) -> bool {
+        true
+    }
+
+    fn save_state(&self, _state: ()) -> Result<Vec<u8>> {
+        Ok(vec![])
+    }
+
+    fn restore_state(&self, _data: &[u8]) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Recognizer with states numbered 0..num_states(), like a DFA.
+/// Such recognizers can have their token masks precomputed with PrecomputedMasks.
+pub trait EnumerableRecognizer: FunctionalRecognizer<u32> {
+    fn num_states(&self) -> usize;
+}
+
+// walks the trie from a fixed state of an EnumerableRecognizer
+struct StateWalker<'a, R: EnumerableRecognizer> {
+    rec: &'a R,
+    stack: Vec<u32>,
+}
+
+impl<R: EnumerableRecognizer> StateWalker<'_, R> {
+    fn top(&self) -> u32 {
+        *self.stack.last().unwrap()
+    }
+}
+
+impl<R: EnumerableRecognizer> Recognizer for StateWalker<'_, R> {
+    fn pop_bytes(&mut self, num: usize) {
+        self.stack.truncate(self.stack.len() - num);
+    }
+
+    fn collapse(&mut self) {
+        let top = self.top();
+        self.stack.clear();
+        self.stack.push(top);
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        self.rec.special_allowed(self.top(), tok)
+    }
+
+    fn trie_finished(&mut self) {
+        // walks of a subtree leave the bytes of the last branch on the stack
+        self.stack.truncate(1);
+    }
+
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        match self.rec.try_append(self.top(), byte) {
+            Some(state) => {
+                self.stack.push(state);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn all_bytes_allowed(&mut self) -> bool {
+        self.rec.all_bytes_allowed(self.top())
+    }
+
+    fn no_bytes_allowed(&mut self) -> bool {
+        self.rec.no_bytes_allowed(self.top())
+    }
+
+    fn byte_weight(&mut self, byte: u8) -> f32 {
+        self.rec.byte_weight(self.top(), byte)
+    }
+
+    fn get_error(&mut self) -> Option<String> {
+        self.rec.get_error(self.top())
+    }
+
+    fn state_description(&mut self) -> Option<String> {
+        self.rec.state_description(self.top())
+    }
+}
+
+// allows every token; used to list the tokens of a trie subtree
+struct AllBytes;
+
+impl Recognizer for AllBytes {
+    fn pop_bytes(&mut self, _num: usize) {}
+
+    fn collapse(&mut self) {}
+
+    fn special_allowed(&mut self, _tok: SpecialToken) -> bool {
+        true
+    }
+
+    fn trie_finished(&mut self) {}
+
+    fn try_push_byte(&mut self, _byte: u8) -> bool {
+        true
+    }
+}
+
+// tokens set in toks (with their duplicates), leaving toks empty
+fn take_tokens(trie: &TokTrie, toks: &mut SimpleVob) -> Vec<TokenId> {
+    trie.apply_duplicates(toks);
+    let tokens: Vec<TokenId> = toks.iter_set().collect();
+    for &t in &tokens {
+        toks.disallow_token(t);
+    }
+    tokens
+}
+
+/// Token masks of all states of an EnumerableRecognizer, computed up front,
+/// so that computing the mask at runtime is a few table lookups instead of a trie walk.
+///
+/// The trie is split into the subtrees of the first byte of the token.
+/// For every (state, subtree) pair, a bit records whether the whole subtree is allowed;
+/// such subtrees are filled in from a single token list per subtree, shared by all states.
+/// Subtrees that are only partially allowed store the tokens allowed in the state after
+/// the first byte; these lists are shared between states reaching the same state after
+/// the same byte, so memory use is proportional to the number of distinct such pairs,
+/// not to num_states() * vocab_size().
+pub struct PrecomputedMasks {
+    vocab_size: usize,
+    // all tokens (including duplicates) of the subtree of each first byte
+    subtree_tokens: Vec<Vec<TokenId>>,
+    // bit state * 256 + byte is set when the whole subtree of byte is allowed in state
+    full_subtrees: SimpleVob,
+    // allowed tokens for each distinct (byte, state after byte), when not the whole subtree
+    partial: Vec<Vec<TokenId>>,
+    // indices into partial for each state
+    states: Vec<Vec<u32>>,
+    // states allowing EndOfSentence and EndOfTurn
+    eos_allowed: SimpleVob,
+    eot_allowed: SimpleVob,
+    // EOS and tok_stop
+    eos_tokens: Vec<TokenId>,
+    eot_token: Option<TokenId>,
+}
+
+impl PrecomputedMasks {
+    const FULL: u32 = u32::MAX;
+
+    pub fn new(trie: &TokTrie, rec: &impl EnumerableRecognizer) -> Self {
+        let num_states = rec.num_states();
+        let mut toks = trie.alloc_token_set();
+        let subtree_tokens: Vec<Vec<TokenId>> = (0..=255u8)
+            .map(|b| {
+                trie.add_bias(&mut AllBytes, &mut toks, &[b]);
+                take_tokens(trie, &mut toks)
+            })
+            .collect();
+
+        let mut subtree_idx = FxHashMap::default();
+        let mut partial = vec![];
+        let mut full_subtrees = SimpleVob::alloc(num_states * 256);
+        let mut eos_allowed = SimpleVob::alloc(num_states);
+        let mut eot_allowed = SimpleVob::alloc(num_states);
+        let mut walker = StateWalker { rec, stack: vec![] };
+
+        let states = (0..num_states as u32)
+            .map(|state| {
+                if rec.special_allowed(state, SpecialToken::EndOfSentence) {
+                    eos_allowed.allow_token(state);
+                }
+                if rec.special_allowed(state, SpecialToken::EndOfTurn) {
+                    eot_allowed.allow_token(state);
+                }
+                let mut res = vec![];
+                if rec.no_bytes_allowed(state) {
+                    return res;
+                }
+                for b in 0..=255u8 {
+                    let next = match rec.try_append(state, b) {
+                        Some(next) => next,
+                        None => continue,
+                    };
+                    let idx = *subtree_idx.entry((b, next)).or_insert_with(|| {
+                        walker.stack.clear();
+                        walker.stack.push(next);
+                        trie.add_bias(&mut walker, &mut toks, &[b]);
+                        let tokens = take_tokens(trie, &mut toks);
+                        if tokens.len() == subtree_tokens[b as usize].len() {
+                            Self::FULL
+                        } else {
+                            partial.push(tokens);
+                            partial.len() as u32 - 1
+                        }
+                    });
+                    if idx == Self::FULL {
+                        full_subtrees.set(state as usize * 256 + b as usize, true);
+                    } else if !partial[idx as usize].is_empty() {
+                        res.push(idx);
+                    }
+                }
+                res
+            })
+            .collect();
+
+        let info = trie.info();
+        PrecomputedMasks {
+            vocab_size: trie.vocab_size(),
+            subtree_tokens,
+            full_subtrees,
+            partial,
+            states,
+            eos_allowed,
+            eot_allowed,
+            eos_tokens: core::iter::once(info.tok_eos)
+                .chain(info.extra_stop_tokens())
+                .collect(),
+            eot_token: info.tok_end_of_turn,
+        }
+    }
+
+    pub fn num_states(&self) -> usize {
+        self.states.len()
+    }
+
+    /// Whether all tokens starting with byte are allowed in state.
+    pub fn subtree_allowed(&self, state: u32, byte: u8) -> bool {
+        self.full_subtrees.get(state as usize * 256 + byte as usize)
+    }
+
+    /// Mask for given state; same as what TokTrie::compute_bias() would return.
+    pub fn mask(&self, state: u32) -> SimpleVob {
+        let mut logits = SimpleVob::alloc(self.vocab_size);
+        self.compute_bias(state, &mut logits);
+        logits
+    }
+
+    pub fn compute_bias(&self, state: u32, logits: &mut SimpleVob) {
+        logits.set_all(false);
+        for b in 0..=255u8 {
+            if self.subtree_allowed(state, b) {
+                for &tok in &self.subtree_tokens[b as usize] {
+                    logits.allow_token(tok);
+                }
+            }
+        }
+        for &idx in &self.states[state as usize] {
+            for &tok in &self.partial[idx as usize] {
+                logits.allow_token(tok);
+            }
+        }
+        if self.eos_allowed.is_allowed(state) {
+            for &tok in &self.eos_tokens {
+                logits.allow_token(tok);
+            }
+        }
+        if let Some(tok) = self.eot_token {
+            if self.eot_allowed.is_allowed(state) {
+                logits.allow_token(tok);
+            }
+        }
+    }
+}
+
+// pushes s byte by byte, like sampling single-byte tokens
+#[cfg(test)]
+pub(crate) fn push_str(r: &mut impl Recognizer, s: &str) -> bool {
+    for &b in s.as_bytes() {
+        if !r.try_push_byte(b) {
+            return false;
+        }
+        r.collapse();
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{TestTokEnv, TokenizerEnv};
+
+    // returns the number of (state, subtree) pairs with the whole subtree allowed
+    fn check_precomputed(trie: &TokTrie, rec: impl EnumerableRecognizer + Clone) -> usize {
+        let masks = PrecomputedMasks::new(trie, &rec);
+        let mut num_full = 0;
+        assert_eq!(masks.num_states(), rec.num_states());
+        let mut expected = trie.alloc_token_set();
+        let mut logits = trie.alloc_token_set();
+        for state in 0..rec.num_states() as u32 {
+            let mut r = StackRecognizer::from(rec.clone());
+            r.restore_state(&rec.save_state(state).unwrap()).unwrap();
+            trie.compute_bias(&mut r, &mut expected);
+            masks.compute_bias(state, &mut logits);
+            let exp: Vec<TokenId> = expected.iter_set().collect();
+            assert_eq!(logits.iter_set().collect::<Vec<_>>(), exp, "state {state}");
+            assert_eq!(masks.mask(state).iter_set().collect::<Vec<_>>(), exp);
+            for b in 0..=255u8 {
+                let mut subtree = (0..trie.vocab_size() as TokenId)
+                    .filter(|&t| trie.token(t).first() == Some(&b))
+                    .peekable();
+                let full = subtree.peek().is_some() && subtree.all(|t| expected.is_allowed(t));
+                assert_eq!(
+                    masks.subtree_allowed(state, b),
+                    full,
+                    "state {state} byte {b}"
+                );
+                num_full += full as usize;
+            }
+        }
+        num_full
+    }
+
+    #[test]
+    fn precomputed_masks_match_compute_bias() {
+        let env = TestTokEnv::with_words(&["ab", "abc", "ba", "cab", "b", "Ab", "aB"]);
+        let trie = env.tok_trie();
+        // after "ab", the single-byte subtree of "d" is allowed as a whole
+        assert!(check_precomputed(trie, OneOf::new(&["abc", "abd", "b", "cab", ""])) > 0);
+        check_precomputed(trie, CaseInsensitive::new("abca"));
+    }
 }
@@ -0,0 +1,104 @@
+// Little-endian encoding of recognizer state for save_state()/restore_state().
+
+use anyhow::{bail, Result};
+
+use crate::prelude::*;
+
+#[derive(Default)]
+pub(crate) struct BlobWriter {
+    data: Vec<u8>,
+}
+
+impl BlobWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn u8(mut self, v: u8) -> Self {
+        self.data.push(v);
+        self
+    }
+
+    pub fn u32(mut self, v: u32) -> Self {
+        self.data.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    pub fn u128(mut self, v: u128) -> Self {
+        self.data.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    /// Length-prefixed byte string.
+    pub fn bytes(mut self, v: &[u8]) -> Self {
+        self = self.u32(v.len() as u32);
+        self.data.extend_from_slice(v);
+        self
+    }
+
+    pub fn captures(mut self, caps: &[(String, Vec<u8>)]) -> Self {
+        self = self.u32(caps.len() as u32);
+        for (name, bytes) in caps {
+            self = self.bytes(name.as_bytes()).bytes(bytes);
+        }
+        self
+    }
+}
+
+pub(crate) struct BlobReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> BlobReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        BlobReader { data }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.data.len() < n {
+            bail!("recognizer state truncated");
+        }
+        let (res, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(res)
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u128(&mut self) -> Result<u128> {
+        Ok(u128::from_le_bytes(self.take(16)?.try_into().unwrap()))
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8]> {
+        let n = self.u32()? as usize;
+        self.take(n)
+    }
+
+    pub fn captures(&mut self) -> Result<Vec<(String, Vec<u8>)>> {
+        let n = self.u32()?;
+        let mut res = Vec::new();
+        for _ in 0..n {
+            let name = String::from_utf8(self.bytes()?.to_vec())?;
+            res.push((name, self.bytes()?.to_vec()));
+        }
+        Ok(res)
+    }
+
+    /// Fail if there is any data left.
+    pub fn finish(&self) -> Result<()> {
+        if !self.data.is_empty() {
+            bail!("trailing data in recognizer state");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,830 @@
+use anyhow::{bail, Result};
+
+use super::blob::{BlobReader, BlobWriter};
+use crate::prelude::*;
+use crate::toktree::{Recognizer, SpecialToken};
+use crate::{FxHashMap, FxHashSet};
+
+type ByteSet = [u32; 8];
+
+fn byteset_add(set: &mut ByteSet, b: u8) {
+    set[b as usize / 32] |= 1 << (b % 32);
+}
+
+fn byteset_has(set: &ByteSet, b: u8) -> bool {
+    set[b as usize / 32] & (1 << (b % 32)) != 0
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Symbol {
+    Bytes(ByteSet),
+    NonTerminal(u32),
+}
+
+#[derive(Clone, Debug)]
+struct Rule {
+    lhs: u32,
+    rhs: Vec<Symbol>,
+}
+
+#[derive(Debug)]
+enum Expr {
+    Seq(Vec<Expr>),
+    Alt(Vec<Expr>),
+    Bytes(ByteSet),
+    Ref(String, usize),
+    Opt(Box<Expr>),
+    Star(Box<Expr>),
+    Plus(Box<Expr>),
+}
+
+#[derive(Debug, PartialEq)]
+enum Tok {
+    Ident(String),
+    Define,
+    Bar,
+    LParen,
+    RParen,
+    Question,
+    Star,
+    Plus,
+    Str(Vec<u8>),
+    Class(ByteSet),
+}
+
+fn parse_escape(chars: &mut core::iter::Peekable<core::str::CharIndices>) -> Result<u8> {
+    let c = match chars.next() {
+        Some((_, c)) => c,
+        None => bail!("unterminated escape"),
+    };
+    Ok(match c {
+        'n' => b'\n',
+        't' => b'\t',
+        'r' => b'\r',
+        'x' => {
+            let hex: String = (0..2)
+                .filter_map(|_| chars.next().map(|(_, c)| c))
+                .collect();
+            match u8::from_str_radix(&hex, 16) {
+                Ok(b) => b,
+                Err(_) => bail!("invalid \\x escape: {:?}", hex),
+            }
+        }
+        c if c.is_ascii_punctuation() => c as u8,
+        _ => bail!("invalid escape: \\{}", c),
+    })
+}
+
+// next char of a string or class literal, as UTF-8 bytes
+fn literal_bytes(
+    chars: &mut core::iter::Peekable<core::str::CharIndices>,
+    c: char,
+) -> Result<Vec<u8>> {
+    if c == '\\' {
+        Ok(vec![parse_escape(chars)?])
+    } else {
+        Ok(c.to_string().into_bytes())
+    }
+}
+
+fn lex(text: &str) -> Result<Vec<(Tok, usize)>> {
+    let mut res = Vec::new();
+    let mut line = 1;
+    let mut chars = text.char_indices().peekable();
+    while let Some((pos, c)) = chars.next() {
+        let tok = match c {
+            '\n' => {
+                line += 1;
+                continue;
+            }
+            _ if c.is_whitespace() => continue,
+            '#' => {
+                while chars.next_if(|(_, c)| *c != '\n').is_some() {}
+                continue;
+            }
+            '|' => Tok::Bar,
+            '(' => Tok::LParen,
+            ')' => Tok::RParen,
+            '?' => Tok::Question,
+            '*' => Tok::Star,
+            '+' => Tok::Plus,
+            ':' => {
+                if text[pos..].starts_with("::=") {
+                    chars.next();
+                    chars.next();
+                    Tok::Define
+                } else {
+                    bail!("line {}: expecting '::='", line);
+                }
+            }
+            '"' => {
+                let mut s = Vec::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, '\n')) | None => bail!("line {}: unterminated string", line),
+                        Some((_, c)) => s.extend(
+                            literal_bytes(&mut chars, c)
+                                .map_err(|e| anyhow::anyhow!("line {}: {}", line, e))?,
+                        ),
+                    }
+                }
+                Tok::Str(s)
+            }
+            '[' => {
+                let negate = chars.next_if(|(_, c)| *c == '^').is_some();
+                let mut set = [0; 8];
+                let mut prev: Option<u8> = None;
+                loop {
+                    let c = match chars.next() {
+                        Some((_, ']')) => break,
+                        Some((_, '\n')) | None => {
+                            bail!("line {}: unterminated character class", line)
+                        }
+                        Some((_, c)) => c,
+                    };
+                    if c == '-' && prev.is_some() && chars.peek().is_some_and(|(_, c)| *c != ']') {
+                        let (_, c2) = chars.next().unwrap();
+                        let hi = literal_bytes(&mut chars, c2)
+                            .map_err(|e| anyhow::anyhow!("line {}: {}", line, e))?;
+                        let lo = prev.take().unwrap();
+                        if hi.len() != 1 || hi[0] < lo {
+                            bail!("line {}: invalid range in character class", line);
+                        }
+                        for b in lo..=hi[0] {
+                            byteset_add(&mut set, b);
+                        }
+                        continue;
+                    }
+                    let bytes = literal_bytes(&mut chars, c)
+                        .map_err(|e| anyhow::anyhow!("line {}: {}", line, e))?;
+                    if bytes.len() != 1 {
+                        bail!(
+                            "line {}: only single-byte characters allowed in class",
+                            line
+                        );
+                    }
+                    byteset_add(&mut set, bytes[0]);
+                    prev = Some(bytes[0]);
+                }
+                if negate {
+                    for w in set.iter_mut() {
+                        *w = !*w;
+                    }
+                }
+                Tok::Class(set)
+            }
+            _ if c.is_ascii_alphanumeric() || c == '_' => {
+                let mut name = c.to_string();
+                while let Some((_, c)) =
+                    chars.next_if(|(_, c)| c.is_ascii_alphanumeric() || *c == '_' || *c == '-')
+                {
+                    name.push(c);
+                }
+                Tok::Ident(name)
+            }
+            _ => bail!("line {}: unexpected character {:?}", line, c),
+        };
+        res.push((tok, line));
+    }
+    Ok(res)
+}
+
+struct Parser {
+    toks: Vec<(Tok, usize)>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Tok> {
+        self.toks.get(self.pos).map(|(t, _)| t)
+    }
+
+    fn line(&self) -> usize {
+        self.toks
+            .get(self.pos)
+            .or(self.toks.last())
+            .map_or(0, |(_, l)| *l)
+    }
+
+    fn at_rule_start(&self) -> bool {
+        matches!(self.peek(), Some(Tok::Ident(_)))
+            && matches!(self.toks.get(self.pos + 1), Some((Tok::Define, _)))
+    }
+
+    fn rule(&mut self) -> Result<(String, Expr)> {
+        if !self.at_rule_start() {
+            bail!("line {}: expecting 'name ::='", self.line());
+        }
+        let name = match &self.toks[self.pos].0 {
+            Tok::Ident(n) => n.clone(),
+            _ => unreachable!(),
+        };
+        self.pos += 2;
+        Ok((name, self.alts()?))
+    }
+
+    fn alts(&mut self) -> Result<Expr> {
+        let mut alts = vec![self.seq()?];
+        while self.peek() == Some(&Tok::Bar) {
+            self.pos += 1;
+            alts.push(self.seq()?);
+        }
+        Ok(if alts.len() == 1 {
+            alts.pop().unwrap()
+        } else {
+            Expr::Alt(alts)
+        })
+    }
+
+    fn seq(&mut self) -> Result<Expr> {
+        let mut items = Vec::new();
+        loop {
+            let line = self.line();
+            let mut e = match self.peek() {
+                Some(Tok::Ident(_)) if !self.at_rule_start() => match &self.toks[self.pos].0 {
+                    Tok::Ident(n) => Expr::Ref(n.clone(), line),
+                    _ => unreachable!(),
+                },
+                Some(Tok::Str(s)) => Expr::Seq(
+                    s.iter()
+                        .map(|&b| {
+                            let mut set = [0; 8];
+                            byteset_add(&mut set, b);
+                            Expr::Bytes(set)
+                        })
+                        .collect(),
+                ),
+                Some(Tok::Class(set)) => Expr::Bytes(*set),
+                Some(Tok::LParen) => {
+                    self.pos += 1;
+                    let e = self.alts()?;
+                    if self.peek() != Some(&Tok::RParen) {
+                        bail!("line {}: expecting ')'", self.line());
+                    }
+                    e
+                }
+                _ => break,
+            };
+            self.pos += 1;
+            loop {
+                e = match self.peek() {
+                    Some(Tok::Question) => Expr::Opt(Box::new(e)),
+                    Some(Tok::Star) => Expr::Star(Box::new(e)),
+                    Some(Tok::Plus) => Expr::Plus(Box::new(e)),
+                    _ => break,
+                };
+                self.pos += 1;
+            }
+            items.push(e);
+        }
+        Ok(Expr::Seq(items))
+    }
+}
+
+/// Context-free grammar over bytes.
+///
+/// The grammar format is a list of rules `name ::= alternatives`, where alternatives
+/// are separated by `|` and consist of nonterminal names, "string" literals,
+/// byte classes like `[a-z_]` or `[^"\\]`, and parenthesized groups,
+/// optionally followed by `?`, `*` or `+`. `#` starts a comment.
+/// The first rule defines the start symbol; a name defined more than once gets
+/// the alternatives of all definitions.
+///
+/// ```text
+/// start ::= expr
+/// expr  ::= term ("+" term)*
+/// term  ::= [0-9]+ | "(" expr ")"
+/// ```
+#[derive(Clone, Debug)]
+pub struct Cfg {
+    names: Vec<String>,
+    rules: Vec<Rule>,
+    rules_by_lhs: Vec<Vec<u32>>,
+    nullable: Vec<bool>,
+}
+
+impl Cfg {
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut parser = Parser {
+            toks: lex(text)?,
+            pos: 0,
+        };
+        let mut defs = Vec::new();
+        while parser.peek().is_some() {
+            defs.push(parser.rule()?);
+        }
+        if defs.is_empty() {
+            bail!("grammar has no rules");
+        }
+
+        let mut cfg = Cfg {
+            names: Vec::new(),
+            rules: Vec::new(),
+            rules_by_lhs: Vec::new(),
+            nullable: Vec::new(),
+        };
+        let mut ids = FxHashMap::default();
+        for (name, _) in &defs {
+            if !ids.contains_key(name) {
+                ids.insert(name.clone(), cfg.add_nonterminal(name.clone()));
+            }
+        }
+        for (name, expr) in &defs {
+            let lhs = ids[name];
+            match expr {
+                Expr::Alt(alts) => {
+                    for alt in alts {
+                        let rhs = cfg.flatten(&ids, lhs, alt)?;
+                        cfg.add_rule(lhs, rhs);
+                    }
+                }
+                _ => {
+                    let rhs = cfg.flatten(&ids, lhs, expr)?;
+                    cfg.add_rule(lhs, rhs);
+                }
+            }
+        }
+        cfg.compute_nullable();
+        Ok(cfg)
+    }
+
+    pub fn to_recognizer(self) -> CfgRecognizer {
+        CfgRecognizer::new(self)
+    }
+
+    pub fn num_rules(&self) -> usize {
+        self.rules.len()
+    }
+
+    fn add_nonterminal(&mut self, name: String) -> u32 {
+        self.names.push(name);
+        self.rules_by_lhs.push(Vec::new());
+        (self.names.len() - 1) as u32
+    }
+
+    fn add_rule(&mut self, lhs: u32, rhs: Vec<Symbol>) {
+        self.rules_by_lhs[lhs as usize].push(self.rules.len() as u32);
+        self.rules.push(Rule { lhs, rhs });
+    }
+
+    // fresh nonterminal for a group or repetition inside of the rule for parent
+    fn helper(&mut self, parent: u32) -> u32 {
+        let name = format!("{}#{}", self.names[parent as usize], self.names.len());
+        self.add_nonterminal(name)
+    }
+
+    fn flatten(&mut self, ids: &FxHashMap<String, u32>, lhs: u32, e: &Expr) -> Result<Vec<Symbol>> {
+        let nt = match e {
+            Expr::Bytes(set) => return Ok(vec![Symbol::Bytes(*set)]),
+            Expr::Ref(name, line) => match ids.get(name) {
+                Some(id) => return Ok(vec![Symbol::NonTerminal(*id)]),
+                None => bail!("line {}: undefined nonterminal {:?}", line, name),
+            },
+            Expr::Seq(items) => {
+                let mut res = Vec::new();
+                for item in items {
+                    res.extend(self.flatten(ids, lhs, item)?);
+                }
+                return Ok(res);
+            }
+            Expr::Alt(alts) => {
+                let nt = self.helper(lhs);
+                for alt in alts {
+                    let rhs = self.flatten(ids, lhs, alt)?;
+                    self.add_rule(nt, rhs);
+                }
+                nt
+            }
+            // N ::= | X
+            Expr::Opt(inner) => {
+                let nt = self.helper(lhs);
+                let rhs = self.flatten(ids, lhs, inner)?;
+                self.add_rule(nt, vec![]);
+                self.add_rule(nt, rhs);
+                nt
+            }
+            // N ::= | N X  or  N ::= X | N X
+            Expr::Star(inner) | Expr::Plus(inner) => {
+                let nt = self.helper(lhs);
+                let rhs = self.flatten(ids, lhs, inner)?;
+                let mut rec = vec![Symbol::NonTerminal(nt)];
+                rec.extend_from_slice(&rhs);
+                self.add_rule(
+                    nt,
+                    if matches!(e, Expr::Star(_)) {
+                        vec![]
+                    } else {
+                        rhs
+                    },
+                );
+                self.add_rule(nt, rec);
+                nt
+            }
+        };
+        Ok(vec![Symbol::NonTerminal(nt)])
+    }
+
+    fn compute_nullable(&mut self) {
+        self.nullable = vec![false; self.names.len()];
+        loop {
+            let mut changed = false;
+            for r in &self.rules {
+                if !self.nullable[r.lhs as usize]
+                    && r.rhs.iter().all(|s| match s {
+                        Symbol::NonTerminal(n) => self.nullable[*n as usize],
+                        Symbol::Bytes(_) => false,
+                    })
+                {
+                    self.nullable[r.lhs as usize] = true;
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct Item {
+    rule: u32,
+    dot: u32,
+    origin: u32,
+}
+
+#[derive(Clone, Debug)]
+struct EarleySet {
+    start: usize,
+    allowed: ByteSet,
+    accepting: bool,
+}
+
+/// Earley parser for a Cfg, consuming one byte at a time.
+/// Each pushed byte adds an Earley set, and pop_bytes() drops them,
+/// so it can be used with TokTrie::compute_bias() directly.
+/// EOS is allowed once the output so far is a complete sentence of the grammar.
+#[derive(Clone)]
+pub struct CfgRecognizer {
+    cfg: Cfg,
+    items: Vec<Item>,
+    sets: Vec<EarleySet>,
+    // index of the set that was on top of the stack when collapse() was last called
+    base: usize,
+    seen: FxHashSet<Item>,
+}
+
+impl CfgRecognizer {
+    pub fn new(cfg: Cfg) -> Self {
+        let mut r = CfgRecognizer {
+            cfg,
+            items: Vec::new(),
+            sets: Vec::new(),
+            base: 0,
+            seen: FxHashSet::default(),
+        };
+        r.reset();
+        r
+    }
+
+    pub fn reset(&mut self) {
+        self.items.clear();
+        self.sets.clear();
+        self.base = 0;
+        self.start_set();
+        for k in 0..self.cfg.rules_by_lhs[0].len() {
+            let rule = self.cfg.rules_by_lhs[0][k];
+            self.add(Item {
+                rule,
+                dot: 0,
+                origin: 0,
+            });
+        }
+        self.close();
+    }
+
+    pub fn cfg(&self) -> &Cfg {
+        &self.cfg
+    }
+
+    /// Check if the output so far is a complete sentence of the grammar.
+    pub fn is_accepting(&self) -> bool {
+        self.sets.last().unwrap().accepting
+    }
+
+    fn next_symbol(&self, item: Item) -> Option<Symbol> {
+        self.cfg.rules[item.rule as usize]
+            .rhs
+            .get(item.dot as usize)
+            .copied()
+    }
+
+    fn start_set(&mut self) {
+        self.seen.clear();
+        self.sets.push(EarleySet {
+            start: self.items.len(),
+            allowed: [0; 8],
+            accepting: false,
+        });
+    }
+
+    fn add(&mut self, item: Item) {
+        if self.seen.insert(item) {
+            self.items.push(item);
+        }
+    }
+
+    // predict and complete items of the top set until fixpoint
+    fn close(&mut self) {
+        let set_idx = self.sets.len() - 1;
+        let mut allowed = [0; 8];
+        let mut accepting = false;
+        let mut i = self.sets[set_idx].start;
+        while i < self.items.len() {
+            let item = self.items[i];
+            i += 1;
+            match self.next_symbol(item) {
+                Some(Symbol::Bytes(set)) => {
+                    for (a, s) in allowed.iter_mut().zip(set.iter()) {
+                        *a |= s;
+                    }
+                }
+                Some(Symbol::NonTerminal(n)) => {
+                    for k in 0..self.cfg.rules_by_lhs[n as usize].len() {
+                        let rule = self.cfg.rules_by_lhs[n as usize][k];
+                        self.add(Item {
+                            rule,
+                            dot: 0,
+                            origin: set_idx as u32,
+                        });
+                    }
+                    // nullable nonterminals complete immediately (Aycock-Horspool)
+                    if self.cfg.nullable[n as usize] {
+                        self.add(Item {
+                            dot: item.dot + 1,
+                            ..item
+                        });
+                    }
+                }
+                None => {
+                    let lhs = self.cfg.rules[item.rule as usize].lhs;
+                    if lhs == 0 && item.origin == 0 {
+                        accepting = true;
+                    }
+                    // when origin == set_idx, the nonterminal is nullable and handled above
+                    if item.origin as usize != set_idx {
+                        let origin = &self.sets[item.origin as usize];
+                        let end = self.sets[item.origin as usize + 1].start;
+                        for j in origin.start..end {
+                            let parent = self.items[j];
+                            if self.next_symbol(parent) == Some(Symbol::NonTerminal(lhs)) {
+                                self.add(Item {
+                                    dot: parent.dot + 1,
+                                    ..parent
+                                });
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        let set = &mut self.sets[set_idx];
+        set.allowed = allowed;
+        set.accepting = accepting;
+    }
+}
+
+impl Recognizer for CfgRecognizer {
+    fn pop_bytes(&mut self, num: usize) {
+        if num == 0 {
+            return;
+        }
+        let len = self.sets.len() - num;
+        self.items.truncate(self.sets[len].start);
+        self.sets.truncate(len);
+    }
+
+    fn collapse(&mut self) {
+        // earlier sets are still referenced by item origins, so they are kept
+        self.base = self.sets.len() - 1;
+    }
+
+    fn byte_allowed(&mut self, byte: u8) -> bool {
+        byteset_has(&self.sets.last().unwrap().allowed, byte)
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        match tok {
+            SpecialToken::EndOfSentence => self.is_accepting(),
+            _ => false,
+        }
+    }
+
+    fn trie_finished(&mut self) {
+        self.pop_bytes(self.sets.len() - 1 - self.base);
+    }
+
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        let top = self.sets.len() - 1;
+        if !byteset_has(&self.sets[top].allowed, byte) {
+            return false;
+        }
+        let (start, end) = (self.sets[top].start, self.items.len());
+        self.start_set();
+        for i in start..end {
+            let item = self.items[i];
+            if let Some(Symbol::Bytes(set)) = self.next_symbol(item) {
+                if byteset_has(&set, byte) {
+                    self.add(Item {
+                        dot: item.dot + 1,
+                        ..item
+                    });
+                }
+            }
+        }
+        self.close();
+        true
+    }
+
+    fn no_bytes_allowed(&mut self) -> bool {
+        self.sets.last().unwrap().allowed.iter().all(|w| *w == 0)
+    }
+
+    /// Lists the rules expecting a byte, at the current position.
+    fn state_description(&mut self) -> Option<String> {
+        let set = self.sets.last().unwrap();
+        let mut names: Vec<&str> = Vec::new();
+        for item in &self.items[set.start..] {
+            if let Some(Symbol::Bytes(_)) = self.next_symbol(*item) {
+                let lhs = self.cfg.rules[item.rule as usize].lhs;
+                // helper nonterminals are named after their rule
+                let name = self.cfg.names[lhs as usize].split('#').next().unwrap();
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+        Some(format!(
+            "at byte {} in rules: {}",
+            self.sets.len() - 1,
+            names.join(", ")
+        ))
+    }
+
+    /// The state includes all Earley sets so far, so it grows with the output.
+    fn save_state(&mut self) -> Result<Vec<u8>> {
+        let mut w = BlobWriter::new().u32(self.sets.len() as u32);
+        for set in &self.sets {
+            w = w.u32(set.start as u32).u8(set.accepting as u8);
+            for a in set.allowed {
+                w = w.u32(a);
+            }
+        }
+        w = w.u32(self.items.len() as u32);
+        for item in &self.items {
+            w = w.u32(item.rule).u32(item.dot).u32(item.origin);
+        }
+        Ok(w.finish())
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> Result<()> {
+        let mut r = BlobReader::new(state);
+        let mut sets = Vec::new();
+        for _ in 0..r.u32()? {
+            let start = r.u32()? as usize;
+            let accepting = r.u8()? != 0;
+            let mut allowed = [0; 8];
+            for a in allowed.iter_mut() {
+                *a = r.u32()?;
+            }
+            if sets.last().is_some_and(|s: &EarleySet| s.start > start) {
+                bail!("invalid CFG state");
+            }
+            sets.push(EarleySet {
+                start,
+                allowed,
+                accepting,
+            });
+        }
+        let mut items = Vec::new();
+        for _ in 0..r.u32()? {
+            let item = Item {
+                rule: r.u32()?,
+                dot: r.u32()?,
+                origin: r.u32()?,
+            };
+            if item.rule as usize >= self.cfg.rules.len()
+                || item.dot as usize > self.cfg.rules[item.rule as usize].rhs.len()
+                || item.origin as usize >= sets.len()
+            {
+                bail!("invalid CFG state");
+            }
+            items.push(item);
+        }
+        r.finish()?;
+        if sets.is_empty() || sets.last().unwrap().start > items.len() {
+            bail!("invalid CFG state");
+        }
+        self.sets = sets;
+        self.items = items;
+        self.base = self.sets.len() - 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recognizer::push_str as push;
+    use crate::{TestTokEnv, TokenizerEnv};
+
+    const EXPR: &str = r#"
+        start ::= expr
+        expr  ::= term ("+" term)*
+        term  ::= [0-9]+ | "(" expr ")"  # nested
+    "#;
+
+    fn accepts(grammar: &str, s: &str) -> bool {
+        let mut r = Cfg::parse(grammar).unwrap().to_recognizer();
+        push(&mut r, s) && r.is_accepting()
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(Cfg::parse("").is_err());
+        assert!(Cfg::parse("# only a comment").is_err());
+        assert!(Cfg::parse("start ::= other").is_err());
+        assert!(Cfg::parse("start ::= (\"a\"").is_err());
+        assert!(Cfg::parse("start ::= \"a").is_err());
+        assert!(Cfg::parse("\"a\" ::= \"b\"").is_err());
+        assert!(Cfg::parse(EXPR).is_ok());
+    }
+
+    #[test]
+    fn accepts_sentences() {
+        for s in ["1", "12+3", "(1+(2))+3", "((7))"] {
+            assert!(accepts(EXPR, s), "{s}");
+        }
+        for s in ["", "+", "1+", "(1", "1)", "a"] {
+            assert!(!accepts(EXPR, s), "{s}");
+        }
+        // left recursion, nullable rules, and repeated definitions
+        let g = r#"
+            start ::= list
+            list  ::= list "a" | empty
+            empty ::=
+            start ::= "b"?
+        "#;
+        for s in ["", "a", "aaa", "b"] {
+            assert!(accepts(g, s), "{s}");
+        }
+        assert!(!accepts(g, "ab"));
+        assert!(accepts("start ::= [^a-y]+", "z{\u{e9}"));
+        assert!(!accepts("start ::= [^a-y]+", "zb"));
+    }
+
+    #[test]
+    fn bias_matches_slow() {
+        let env = TestTokEnv::with_words(&["12", "+(", "(1", "1+", "))", "+1)", "ab"]);
+        let mut r = Cfg::parse(EXPR).unwrap().to_recognizer();
+        for prefix in ["", "(", "(1", "(1+2", "(1+2)", "1+"] {
+            r.reset();
+            assert!(push(&mut r, prefix));
+            env.check_bias(&mut r).unwrap();
+        }
+        r.reset();
+        let mut mask = env.tok_trie().alloc_token_set();
+        env.tok_trie().compute_bias(&mut r, &mut mask);
+        assert_eq!(
+            env.allowed_names(&mask),
+            [
+                "\"(\"", "\"0\"", "\"1\"", "\"2\"", "\"3\"", "\"4\"", "\"5\"", "\"6\"", "\"7\"",
+                "\"8\"", "\"9\"", "\"12\"", "\"(1\"", "\"1+\""
+            ]
+        );
+    }
+
+    #[test]
+    fn state_roundtrip() {
+        let cfg = Cfg::parse(EXPR).unwrap();
+        let mut r = cfg.clone().to_recognizer();
+        assert!(push(&mut r, "(1+"));
+        let state = r.save_state().unwrap();
+
+        let mut r2 = cfg.to_recognizer();
+        r2.restore_state(&state).unwrap();
+        assert_eq!(r2.save_state().unwrap(), state);
+        assert!(!r2.try_push_byte(b')'));
+        assert!(push(&mut r2, "2)"));
+        assert!(r2.is_accepting());
+
+        assert!(r2.restore_state(&state[..state.len() - 1]).is_err());
+        let mut bad = state.clone();
+        // rule index of the last item
+        let n = bad.len();
+        bad[n - 12..n - 8].copy_from_slice(&1000u32.to_le_bytes());
+        assert!(r2.restore_state(&bad).is_err());
+        assert_eq!(r2.state_description().unwrap(), "at byte 5 in rules: expr");
+    }
+}
@@ -0,0 +1,1055 @@
+// Combinators running several recognizers side by side.
+//
+// Each combinator counts the bytes pushed since the last collapse() (depth),
+// and each sub-recognizer (Child) records how deep it got; a child that rejected
+// a byte stays behind and comes back to life when the combinator pops back to its depth.
+// On collapse(), children that are behind are dropped for good, and children that
+// accept any continuation (all_bytes_allowed() and EOS) are replaced by a constant "true",
+// so they no longer need to be pushed.
+
+use anyhow::{bail, Result};
+
+use super::blob::{BlobReader, BlobWriter};
+use crate::prelude::*;
+use crate::toktree::{Recognizer, SpecialToken};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Status {
+    Live,
+    True,
+    False,
+}
+
+#[derive(Clone)]
+struct Child<R> {
+    rec: R,
+    status: Status,
+    // depth of the combinator at which this child was started
+    born: usize,
+    // depth of the combinator this child got to
+    depth: usize,
+    // captures of the output before this child was started (Seq and Repeat threads)
+    prefix: Vec<(String, Vec<u8>)>,
+}
+
+impl<R: Recognizer> Child<R> {
+    fn new(rec: R, born: usize) -> Self {
+        Child {
+            rec,
+            status: Status::Live,
+            born,
+            depth: born,
+            prefix: vec![],
+        }
+    }
+
+    fn with_prefix(mut self, prefix: Vec<(String, Vec<u8>)>) -> Self {
+        self.prefix = prefix;
+        self
+    }
+
+    fn is_live(&self, depth: usize) -> bool {
+        self.status == Status::Live && self.depth == depth
+    }
+
+    fn is_true(&self) -> bool {
+        self.status == Status::True
+    }
+
+    fn is_false(&self, depth: usize) -> bool {
+        !self.is_true() && !self.is_live(depth)
+    }
+
+    // the child is consumed (has nothing more to accept) when it accepts everything
+    fn is_universal(&mut self) -> bool {
+        self.rec.all_bytes_allowed() && self.rec.special_allowed(SpecialToken::EndOfSentence)
+    }
+
+    fn push(&mut self, depth: usize, byte: u8) -> bool {
+        match self.status {
+            Status::True => true,
+            Status::Live if self.depth == depth && self.rec.try_push_byte(byte) => {
+                self.depth += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn truncate(&mut self, depth: usize) {
+        if self.status == Status::Live && self.depth > depth {
+            self.rec.pop_bytes(self.depth - depth);
+            self.depth = depth;
+        }
+    }
+
+    fn collapse(&mut self, depth: usize, allow_true: bool) {
+        if self.is_live(depth) {
+            self.rec.collapse();
+            self.born = 0;
+            self.depth = 0;
+            // captures would not be updated anymore
+            if allow_true && self.is_universal() && self.rec.captures().is_empty() {
+                self.status = Status::True;
+            }
+        } else if self.status == Status::Live {
+            self.status = Status::False;
+        }
+    }
+
+    fn trie_started(&mut self) {
+        if self.status == Status::Live {
+            self.rec.trie_started();
+        }
+    }
+
+    // called once the combinator popped back to depth 0
+    fn trie_finished(&mut self) {
+        if self.status == Status::Live {
+            self.rec.trie_finished();
+        }
+    }
+
+    fn special_allowed(&mut self, depth: usize, tok: SpecialToken) -> bool {
+        match self.status {
+            Status::True => true,
+            Status::Live if self.depth == depth => self.rec.special_allowed(tok),
+            _ => false,
+        }
+    }
+
+    fn all_bytes_allowed(&mut self, depth: usize) -> bool {
+        self.is_true() || (self.is_live(depth) && self.rec.all_bytes_allowed())
+    }
+
+    fn no_bytes_allowed(&mut self, depth: usize) -> bool {
+        self.is_false(depth) || (self.is_live(depth) && self.rec.no_bytes_allowed())
+    }
+
+    fn captures(&mut self, depth: usize) -> Vec<(String, Vec<u8>)> {
+        let mut res = self.prefix.clone();
+        if self.is_live(depth) || self.is_true() {
+            res.extend(self.rec.captures());
+        }
+        res
+    }
+
+    fn get_error(&mut self, depth: usize) -> Option<String> {
+        if self.is_live(depth) {
+            self.rec.get_error()
+        } else {
+            None
+        }
+    }
+
+    fn state_description(&mut self, depth: usize) -> Option<String> {
+        if self.is_live(depth) {
+            self.rec.state_description()
+        } else {
+            None
+        }
+    }
+
+    fn save(&mut self, depth: usize, w: BlobWriter) -> Result<BlobWriter> {
+        let w = w.captures(&self.prefix);
+        Ok(if self.is_true() {
+            w.u8(1)
+        } else if self.is_live(depth) {
+            w.u8(0).bytes(&self.rec.save_state()?)
+        } else {
+            w.u8(2)
+        })
+    }
+
+    // restore as if collapsed
+    fn restore(&mut self, r: &mut BlobReader) -> Result<()> {
+        self.prefix = r.captures()?;
+        self.born = 0;
+        self.depth = 0;
+        self.status = match r.u8()? {
+            0 => {
+                self.rec.restore_state(r.bytes()?)?;
+                Status::Live
+            }
+            1 => Status::True,
+            2 => Status::False,
+            s => bail!("invalid recognizer status {}", s),
+        };
+        Ok(())
+    }
+}
+
+// descriptions of the children, separated by "; "
+fn join_descriptions(descs: impl IntoIterator<Item = Option<String>>) -> Option<String> {
+    let descs: Vec<String> = descs.into_iter().flatten().collect();
+    if descs.is_empty() {
+        None
+    } else {
+        Some(descs.join("; "))
+    }
+}
+
+/// Output has to be allowed by both recognizers.
+#[derive(Clone)]
+pub struct And<A, B> {
+    a: Child<A>,
+    b: Child<B>,
+    depth: usize,
+}
+
+impl<A: Recognizer, B: Recognizer> And<A, B> {
+    pub fn new(a: A, b: B) -> Self {
+        And {
+            a: Child::new(a, 0),
+            b: Child::new(b, 0),
+            depth: 0,
+        }
+    }
+}
+
+impl<A: Recognizer, B: Recognizer> Recognizer for And<A, B> {
+    fn pop_bytes(&mut self, num: usize) {
+        self.depth -= num;
+        self.a.truncate(self.depth);
+        self.b.truncate(self.depth);
+    }
+
+    fn collapse(&mut self) {
+        self.a.collapse(self.depth, true);
+        self.b.collapse(self.depth, true);
+        self.depth = 0;
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        self.a.special_allowed(self.depth, tok) && self.b.special_allowed(self.depth, tok)
+    }
+
+    fn trie_started(&mut self) {
+        self.a.trie_started();
+        self.b.trie_started();
+    }
+
+    fn trie_finished(&mut self) {
+        self.pop_bytes(self.depth);
+        self.a.trie_finished();
+        self.b.trie_finished();
+    }
+
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        if self.a.push(self.depth, byte) && self.b.push(self.depth, byte) {
+            self.depth += 1;
+            true
+        } else {
+            self.a.truncate(self.depth);
+            false
+        }
+    }
+
+    fn all_bytes_allowed(&mut self) -> bool {
+        self.a.all_bytes_allowed(self.depth) && self.b.all_bytes_allowed(self.depth)
+    }
+
+    fn no_bytes_allowed(&mut self) -> bool {
+        self.a.no_bytes_allowed(self.depth) || self.b.no_bytes_allowed(self.depth)
+    }
+
+    fn get_error(&mut self) -> Option<String> {
+        self.a
+            .get_error(self.depth)
+            .or_else(|| self.b.get_error(self.depth))
+    }
+
+    fn captures(&mut self) -> Vec<(String, Vec<u8>)> {
+        let mut res = self.a.captures(self.depth);
+        res.extend(self.b.captures(self.depth));
+        res
+    }
+
+    fn state_description(&mut self) -> Option<String> {
+        join_descriptions([
+            self.a.state_description(self.depth),
+            self.b.state_description(self.depth),
+        ])
+    }
+
+    fn save_state(&mut self) -> Result<Vec<u8>> {
+        let w = self.a.save(self.depth, BlobWriter::new())?;
+        Ok(self.b.save(self.depth, w)?.finish())
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> Result<()> {
+        let mut r = BlobReader::new(state);
+        self.a.restore(&mut r)?;
+        self.b.restore(&mut r)?;
+        r.finish()?;
+        self.depth = 0;
+        Ok(())
+    }
+}
+
+/// Output has to be allowed by at least one of the recognizers.
+#[derive(Clone)]
+pub struct Or<A, B> {
+    a: Child<A>,
+    b: Child<B>,
+    depth: usize,
+}
+
+impl<A: Recognizer, B: Recognizer> Or<A, B> {
+    pub fn new(a: A, b: B) -> Self {
+        Or {
+            a: Child::new(a, 0),
+            b: Child::new(b, 0),
+            depth: 0,
+        }
+    }
+}
+
+impl<A: Recognizer, B: Recognizer> Recognizer for Or<A, B> {
+    fn pop_bytes(&mut self, num: usize) {
+        self.depth -= num;
+        self.a.truncate(self.depth);
+        self.b.truncate(self.depth);
+    }
+
+    fn collapse(&mut self) {
+        self.a.collapse(self.depth, true);
+        self.b.collapse(self.depth, true);
+        self.depth = 0;
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        self.a.special_allowed(self.depth, tok) || self.b.special_allowed(self.depth, tok)
+    }
+
+    fn trie_started(&mut self) {
+        self.a.trie_started();
+        self.b.trie_started();
+    }
+
+    fn trie_finished(&mut self) {
+        self.pop_bytes(self.depth);
+        self.a.trie_finished();
+        self.b.trie_finished();
+    }
+
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        let ok_a = self.a.push(self.depth, byte);
+        let ok_b = self.b.push(self.depth, byte);
+        if ok_a || ok_b {
+            self.depth += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn all_bytes_allowed(&mut self) -> bool {
+        self.a.all_bytes_allowed(self.depth) || self.b.all_bytes_allowed(self.depth)
+    }
+
+    fn no_bytes_allowed(&mut self) -> bool {
+        self.a.no_bytes_allowed(self.depth) && self.b.no_bytes_allowed(self.depth)
+    }
+
+    fn get_error(&mut self) -> Option<String> {
+        self.a
+            .get_error(self.depth)
+            .or_else(|| self.b.get_error(self.depth))
+    }
+
+    /// Captures of the first recognizer still matching.
+    fn captures(&mut self) -> Vec<(String, Vec<u8>)> {
+        if self.a.is_false(self.depth) {
+            self.b.captures(self.depth)
+        } else {
+            self.a.captures(self.depth)
+        }
+    }
+
+    fn state_description(&mut self) -> Option<String> {
+        join_descriptions([
+            self.a.state_description(self.depth),
+            self.b.state_description(self.depth),
+        ])
+    }
+
+    fn save_state(&mut self) -> Result<Vec<u8>> {
+        let w = self.a.save(self.depth, BlobWriter::new())?;
+        Ok(self.b.save(self.depth, w)?.finish())
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> Result<()> {
+        let mut r = BlobReader::new(state);
+        self.a.restore(&mut r)?;
+        self.b.restore(&mut r)?;
+        r.finish()?;
+        self.depth = 0;
+        Ok(())
+    }
+}
+
+/// Output must not be accepted by the recognizer; EOS is allowed when the inner
+/// recognizer doesn't allow it.
+/// A byte is disallowed only when the inner recognizer would accept any continuation
+/// after it (see Recognizer::all_bytes_allowed()), so e.g. Not(Substring("bad"))
+/// stops the output just before it would contain "bad".
+#[derive(Clone)]
+pub struct Not<A> {
+    a: Child<A>,
+    depth: usize,
+}
+
+impl<A: Recognizer> Not<A> {
+    pub fn new(a: A) -> Self {
+        Not {
+            a: Child::new(a, 0),
+            depth: 0,
+        }
+    }
+}
+
+impl<A: Recognizer> Recognizer for Not<A> {
+    fn pop_bytes(&mut self, num: usize) {
+        self.depth -= num;
+        self.a.truncate(self.depth);
+    }
+
+    fn collapse(&mut self) {
+        self.a.collapse(self.depth, true);
+        self.depth = 0;
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        !self.a.special_allowed(self.depth, tok)
+    }
+
+    fn trie_started(&mut self) {
+        self.a.trie_started();
+    }
+
+    fn trie_finished(&mut self) {
+        self.pop_bytes(self.depth);
+        self.a.trie_finished();
+    }
+
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        if self.a.is_live(self.depth) {
+            if self.a.push(self.depth, byte) && self.a.is_universal() {
+                self.a.truncate(self.depth);
+                return false;
+            }
+        } else if self.a.is_true() {
+            return false;
+        }
+        self.depth += 1;
+        true
+    }
+
+    fn all_bytes_allowed(&mut self) -> bool {
+        self.a.is_false(self.depth)
+    }
+
+    fn no_bytes_allowed(&mut self) -> bool {
+        self.a.is_true()
+    }
+
+    fn state_description(&mut self) -> Option<String> {
+        self.a
+            .state_description(self.depth)
+            .map(|d| format!("not: {}", d))
+    }
+
+    fn save_state(&mut self) -> Result<Vec<u8>> {
+        Ok(self.a.save(self.depth, BlobWriter::new())?.finish())
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> Result<()> {
+        let mut r = BlobReader::new(state);
+        self.a.restore(&mut r)?;
+        r.finish()?;
+        self.depth = 0;
+        Ok(())
+    }
+}
+
+/// Output of the first recognizer (up to a point where it allows EOS),
+/// followed by the output of the second one.
+/// Every time the first recognizer allows EOS, a fresh copy of the second one is started,
+/// so the second recognizer should be passed in its initial state.
+#[derive(Clone)]
+pub struct Seq<A, B> {
+    a: Child<A>,
+    b: B,
+    threads: Vec<Child<B>>,
+    depth: usize,
+}
+
+impl<A: Recognizer, B: Recognizer + Clone> Seq<A, B> {
+    pub fn new(a: A, b: B) -> Self {
+        let mut r = Seq {
+            a: Child::new(a, 0),
+            b,
+            threads: Vec::new(),
+            depth: 0,
+        };
+        r.spawn();
+        r
+    }
+
+    fn spawn(&mut self) {
+        if self.a.is_live(self.depth)
+            && self.a.rec.special_allowed(SpecialToken::EndOfSentence)
+            && !self.threads.iter().any(|t| t.born == self.depth)
+        {
+            let prefix = self.a.rec.captures();
+            self.threads
+                .push(Child::new(self.b.clone(), self.depth).with_prefix(prefix));
+        }
+    }
+}
+
+impl<A: Recognizer, B: Recognizer + Clone> Recognizer for Seq<A, B> {
+    fn pop_bytes(&mut self, num: usize) {
+        self.depth -= num;
+        let depth = self.depth;
+        self.threads.retain(|t| t.born <= depth);
+        self.a.truncate(depth);
+        for t in self.threads.iter_mut() {
+            t.truncate(depth);
+        }
+    }
+
+    fn collapse(&mut self) {
+        let depth = self.depth;
+        self.a.collapse(depth, false);
+        self.threads.retain(|t| t.is_live(depth));
+        for t in self.threads.iter_mut() {
+            t.collapse(depth, false);
+        }
+        self.depth = 0;
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        let depth = self.depth;
+        self.threads
+            .iter_mut()
+            .any(|t| t.special_allowed(depth, tok))
+    }
+
+    fn trie_started(&mut self) {
+        self.a.trie_started();
+        for t in self.threads.iter_mut() {
+            t.trie_started();
+        }
+    }
+
+    fn trie_finished(&mut self) {
+        self.pop_bytes(self.depth);
+        self.a.trie_finished();
+        for t in self.threads.iter_mut() {
+            t.trie_finished();
+        }
+    }
+
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        let depth = self.depth;
+        let mut ok = self.a.push(depth, byte);
+        for t in self.threads.iter_mut() {
+            ok |= t.push(depth, byte);
+        }
+        if ok {
+            self.depth += 1;
+            self.spawn();
+        }
+        ok
+    }
+
+    fn all_bytes_allowed(&mut self) -> bool {
+        let depth = self.depth;
+        self.threads.iter_mut().any(|t| t.all_bytes_allowed(depth))
+    }
+
+    fn no_bytes_allowed(&mut self) -> bool {
+        let depth = self.depth;
+        self.a.no_bytes_allowed(depth) && self.threads.iter_mut().all(|t| t.no_bytes_allowed(depth))
+    }
+
+    /// Captures of the earliest started second recognizer that allows EOS, or is live,
+    /// or of the first recognizer if none is running yet.
+    fn captures(&mut self) -> Vec<(String, Vec<u8>)> {
+        let depth = self.depth;
+        let idx = self
+            .threads
+            .iter_mut()
+            .position(|t| t.special_allowed(depth, SpecialToken::EndOfSentence))
+            .or_else(|| self.threads.iter().position(|t| t.is_live(depth)));
+        match idx {
+            Some(idx) => self.threads[idx].captures(depth),
+            None => self.a.captures(depth),
+        }
+    }
+
+    fn state_description(&mut self) -> Option<String> {
+        let depth = self.depth;
+        let first = self.a.state_description(depth);
+        join_descriptions(
+            core::iter::once(first)
+                .chain(self.threads.iter_mut().map(|t| t.state_description(depth))),
+        )
+    }
+
+    fn save_state(&mut self) -> Result<Vec<u8>> {
+        let depth = self.depth;
+        let mut w = self.a.save(depth, BlobWriter::new())?;
+        let live = self.threads.iter().filter(|t| t.is_live(depth)).count();
+        w = w.u32(live as u32);
+        for t in self.threads.iter_mut().filter(|t| t.is_live(depth)) {
+            w = t.save(depth, w)?;
+        }
+        Ok(w.finish())
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> Result<()> {
+        let mut r = BlobReader::new(state);
+        self.a.restore(&mut r)?;
+        let mut threads = Vec::new();
+        for _ in 0..r.u32()? {
+            let mut t = Child::new(self.b.clone(), 0);
+            t.restore(&mut r)?;
+            threads.push(t);
+        }
+        r.finish()?;
+        self.threads = threads;
+        self.depth = 0;
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+struct RepeatThread<A> {
+    // number of repetitions completed before this one
+    count: usize,
+    // whether this repetition consumed any bytes before the last collapse()
+    consumed: bool,
+    child: Child<A>,
+}
+
+impl<A: Recognizer> RepeatThread<A> {
+    fn is_fresh(&self) -> bool {
+        !self.consumed && self.child.depth == self.child.born
+    }
+
+    // the output so far consists of at least min complete repetitions
+    fn allows_eos(&mut self, depth: usize, min: usize) -> bool {
+        self.child.is_live(depth)
+            && ((self.is_fresh() && self.count >= min)
+                || (self.count + 1 >= min
+                    && self
+                        .child
+                        .special_allowed(depth, SpecialToken::EndOfSentence)))
+    }
+}
+
+/// Between min and max (inclusive; None is unbounded) repetitions of the output
+/// of given recognizer, each ending where it allows EOS.
+/// The recognizer should be passed in its initial state.
+#[derive(Clone)]
+pub struct Repeat<A> {
+    a: A,
+    min: usize,
+    max: Option<usize>,
+    threads: Vec<RepeatThread<A>>,
+    depth: usize,
+}
+
+impl<A: Recognizer + Clone> Repeat<A> {
+    pub fn new(a: A, min: usize, max: Option<usize>) -> Self {
+        assert!(max.is_none_or(|m| m >= min && m > 0));
+        let child = Child::new(a.clone(), 0);
+        Repeat {
+            a,
+            min,
+            max,
+            threads: vec![RepeatThread {
+                count: 0,
+                consumed: false,
+                child,
+            }],
+            depth: 0,
+        }
+    }
+
+    fn spawn(&mut self) {
+        let depth = self.depth;
+        let mut spawned = Vec::new();
+        for t in self.threads.iter_mut() {
+            let count = t.count + 1;
+            if t.child.is_live(depth)
+                && !t.is_fresh()
+                && self.max.is_none_or(|m| count < m)
+                && t.child.rec.special_allowed(SpecialToken::EndOfSentence)
+            {
+                spawned.push((count, t.child.captures(depth)));
+            }
+        }
+        for (count, prefix) in spawned {
+            if !self
+                .threads
+                .iter()
+                .any(|t| t.child.born == depth && t.count == count)
+            {
+                self.threads.push(RepeatThread {
+                    count,
+                    consumed: false,
+                    child: Child::new(self.a.clone(), depth).with_prefix(prefix),
+                });
+            }
+        }
+    }
+}
+
+impl<A: Recognizer + Clone> Recognizer for Repeat<A> {
+    fn pop_bytes(&mut self, num: usize) {
+        self.depth -= num;
+        let depth = self.depth;
+        self.threads.retain(|t| t.child.born <= depth);
+        for t in self.threads.iter_mut() {
+            t.child.truncate(depth);
+        }
+    }
+
+    fn collapse(&mut self) {
+        let depth = self.depth;
+        self.threads.retain(|t| t.child.is_live(depth));
+        for t in self.threads.iter_mut() {
+            t.consumed |= t.child.depth > t.child.born;
+            t.child.collapse(depth, false);
+        }
+        self.depth = 0;
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        let (depth, min) = (self.depth, self.min);
+        self.threads.iter_mut().any(|t| match tok {
+            SpecialToken::EndOfSentence => t.allows_eos(depth, min),
+            _ => t.child.special_allowed(depth, tok),
+        })
+    }
+
+    fn trie_started(&mut self) {
+        for t in self.threads.iter_mut() {
+            t.child.trie_started();
+        }
+    }
+
+    fn trie_finished(&mut self) {
+        self.pop_bytes(self.depth);
+        for t in self.threads.iter_mut() {
+            t.child.trie_finished();
+        }
+    }
+
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        let depth = self.depth;
+        let mut ok = false;
+        for t in self.threads.iter_mut() {
+            ok |= t.child.push(depth, byte);
+        }
+        if ok {
+            self.depth += 1;
+            self.spawn();
+        }
+        ok
+    }
+
+    fn no_bytes_allowed(&mut self) -> bool {
+        let depth = self.depth;
+        self.threads
+            .iter_mut()
+            .all(|t| t.child.no_bytes_allowed(depth))
+    }
+
+    /// Captures of all repetitions so far (of the earliest started thread
+    /// that allows EOS, or is live).
+    fn captures(&mut self) -> Vec<(String, Vec<u8>)> {
+        let (depth, min) = (self.depth, self.min);
+        let idx = self
+            .threads
+            .iter_mut()
+            .position(|t| t.allows_eos(depth, min))
+            .or_else(|| self.threads.iter().position(|t| t.child.is_live(depth)));
+        match idx {
+            Some(idx) => self.threads[idx].child.captures(depth),
+            None => vec![],
+        }
+    }
+
+    fn state_description(&mut self) -> Option<String> {
+        let depth = self.depth;
+        join_descriptions(
+            self.threads
+                .iter_mut()
+                .map(|t| t.child.state_description(depth)),
+        )
+    }
+
+    fn save_state(&mut self) -> Result<Vec<u8>> {
+        let depth = self.depth;
+        let live = self
+            .threads
+            .iter()
+            .filter(|t| t.child.is_live(depth))
+            .count();
+        let mut w = BlobWriter::new().u32(live as u32);
+        for t in self.threads.iter_mut().filter(|t| t.child.is_live(depth)) {
+            let consumed = t.consumed || t.child.depth > t.child.born;
+            w = w.u32(t.count as u32).u8(consumed as u8);
+            w = t.child.save(depth, w)?;
+        }
+        Ok(w.finish())
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> Result<()> {
+        let mut r = BlobReader::new(state);
+        let mut threads = Vec::new();
+        for _ in 0..r.u32()? {
+            let count = r.u32()? as usize;
+            let consumed = r.u8()? != 0;
+            let mut child = Child::new(self.a.clone(), 0);
+            child.restore(&mut r)?;
+            threads.push(RepeatThread {
+                count,
+                consumed,
+                child,
+            });
+        }
+        r.finish()?;
+        self.threads = threads;
+        self.depth = 0;
+        Ok(())
+    }
+}
+
+/// Records the output of given recognizer under given name, see Recognizer::captures().
+/// Typically used inside of Seq or Repeat, to extract fields of the output.
+#[derive(Clone)]
+pub struct Capture<A> {
+    name: String,
+    a: A,
+    bytes: Vec<u8>,
+    // length of bytes at the last collapse()
+    committed: usize,
+}
+
+impl<A: Recognizer> Capture<A> {
+    pub fn new(name: &str, a: A) -> Self {
+        Capture {
+            name: name.to_string(),
+            a,
+            bytes: vec![],
+            committed: 0,
+        }
+    }
+}
+
+impl<A: Recognizer> Recognizer for Capture<A> {
+    fn pop_bytes(&mut self, num: usize) {
+        self.a.pop_bytes(num);
+        self.bytes.truncate(self.bytes.len() - num);
+    }
+
+    fn collapse(&mut self) {
+        self.a.collapse();
+        self.committed = self.bytes.len();
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        self.a.special_allowed(tok)
+    }
+
+    fn trie_started(&mut self) {
+        self.a.trie_started();
+    }
+
+    fn trie_finished(&mut self) {
+        self.a.trie_finished();
+        self.bytes.truncate(self.committed);
+    }
+
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        if self.a.try_push_byte(byte) {
+            self.bytes.push(byte);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn all_bytes_allowed(&mut self) -> bool {
+        self.a.all_bytes_allowed()
+    }
+
+    fn no_bytes_allowed(&mut self) -> bool {
+        self.a.no_bytes_allowed()
+    }
+
+    fn byte_weight(&mut self, byte: u8) -> f32 {
+        self.a.byte_weight(byte)
+    }
+
+    fn get_error(&mut self) -> Option<String> {
+        self.a.get_error()
+    }
+
+    fn captures(&mut self) -> Vec<(String, Vec<u8>)> {
+        let mut res = vec![(self.name.clone(), self.bytes.clone())];
+        res.extend(self.a.captures());
+        res
+    }
+
+    fn state_description(&mut self) -> Option<String> {
+        let inner = self.a.state_description();
+        Some(match inner {
+            Some(d) => format!("in {}: {}", self.name, d),
+            None => format!("in {}", self.name),
+        })
+    }
+
+    fn save_state(&mut self) -> Result<Vec<u8>> {
+        Ok(BlobWriter::new()
+            .bytes(&self.bytes)
+            .bytes(&self.a.save_state()?)
+            .finish())
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> Result<()> {
+        let mut r = BlobReader::new(state);
+        let bytes = r.bytes()?.to_vec();
+        self.a.restore_state(r.bytes()?)?;
+        r.finish()?;
+        self.committed = bytes.len();
+        self.bytes = bytes;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recognizer::{push_str, NumberRange, OneOf, Substring};
+    use crate::{TestTokEnv, TokenizerEnv};
+
+    fn accepts(mut r: impl Recognizer, s: &str) -> bool {
+        push_str(&mut r, s) && r.special_allowed(SpecialToken::EndOfSentence)
+    }
+
+    fn env() -> TestTokEnv {
+        TestTokEnv::with_words(&["ab", "ba", "abab", "bad", "12", "cat", "ca", "xab"])
+    }
+
+    // checks compute_bias() against the slow reference after each prefix of s
+    fn check_prefixes(mut r: impl Recognizer, s: &str) {
+        let env = env();
+        env.check_bias(&mut r).unwrap();
+        for &b in s.as_bytes() {
+            assert!(r.try_push_byte(b));
+            r.collapse();
+            env.check_bias(&mut r).unwrap();
+        }
+    }
+
+    fn one_of(options: &[&str]) -> impl Recognizer + Clone {
+        OneOf::new(options).to_recognizer()
+    }
+
+    #[test]
+    fn and_or() {
+        let and = || {
+            And::new(
+                Substring::new("ab").to_recognizer(),
+                one_of(&["xab", "b", "abab"]),
+            )
+        };
+        assert!(accepts(and(), "xab"));
+        assert!(accepts(and(), "abab"));
+        assert!(!accepts(and(), "b"));
+        check_prefixes(and(), "aba");
+
+        let or = || {
+            Or::new(
+                one_of(&["cat"]),
+                NumberRange::integer(1, 20).to_recognizer(),
+            )
+        };
+        assert!(accepts(or(), "cat"));
+        assert!(accepts(or(), "12"));
+        assert!(!accepts(or(), "21"));
+        assert!(!accepts(or(), "ca"));
+        check_prefixes(or(), "ca");
+        check_prefixes(or(), "1");
+    }
+
+    #[test]
+    fn not() {
+        let not = || Not::new(Substring::new("bad").to_recognizer());
+        assert!(accepts(not(), "goodba"));
+        assert!(accepts(not(), ""));
+        let mut r = not();
+        assert!(push_str(&mut r, "ba"));
+        assert!(!r.try_push_byte(b'd'));
+        check_prefixes(not(), "aba");
+    }
+
+    #[test]
+    fn seq_and_repeat() {
+        let seq = || Seq::new(one_of(&["a", "ab"]), one_of(&["c", "bc"]));
+        for s in ["ac", "abc", "abbc"] {
+            assert!(accepts(seq(), s), "{s}");
+        }
+        assert!(!accepts(seq(), "ab"));
+        check_prefixes(seq(), "ab");
+
+        let rep = || Repeat::new(one_of(&["ab", "b"]), 1, Some(2));
+        for s in ["ab", "b", "abab", "bb", "abb"] {
+            assert!(accepts(rep(), s), "{s}");
+        }
+        for s in ["", "bbb", "ababab", "a"] {
+            assert!(!accepts(rep(), s), "{s}");
+        }
+        check_prefixes(rep(), "abab");
+        let unbounded = Repeat::new(one_of(&["ab"]), 0, None);
+        assert!(accepts(unbounded.clone(), ""));
+        assert!(accepts(unbounded, "ababababab"));
+    }
+
+    #[test]
+    fn captures_and_state() {
+        let rec = || {
+            Seq::new(
+                Capture::new("animal", one_of(&["cat", "ca"])),
+                Capture::new("n", NumberRange::integer(0, 99).to_recognizer()),
+            )
+        };
+        let mut r = rec();
+        assert!(push_str(&mut r, "cat1"));
+        let state = r.save_state().unwrap();
+        assert!(push_str(&mut r, "2"));
+        let caps = r.captures();
+        assert!(caps.contains(&("animal".to_string(), b"cat".to_vec())));
+        assert!(caps.contains(&("n".to_string(), b"12".to_vec())));
+
+        let mut r2 = rec();
+        r2.restore_state(&state).unwrap();
+        assert_eq!(r2.save_state().unwrap(), state);
+        assert!(push_str(&mut r2, "7"));
+        assert!(r2.captures().contains(&("n".to_string(), b"17".to_vec())));
+        assert!(r2.restore_state(&state[..state.len() - 1]).is_err());
+
+        // trie walks don't leak into captures
+        let env = env();
+        let mut mask = env.tok_trie().alloc_token_set();
+        env.tok_trie().compute_bias(&mut r2, &mut mask);
+        assert!(r2.captures().contains(&("n".to_string(), b"17".to_vec())));
+    }
+}
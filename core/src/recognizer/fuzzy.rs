@@ -0,0 +1,202 @@
+use anyhow::{bail, Result};
+
+use super::blob::{BlobReader, BlobWriter};
+use crate::prelude::*;
+use crate::toktree::{Recognizer, SpecialToken};
+
+/// Recognizer for byte strings within given Levenshtein distance of a target
+/// (insertions, deletions and substitutions of single bytes all count as one edit).
+/// Useful for forcing near-verbatim quotes, while tolerating small differences
+/// in whitespace or punctuation.
+/// Each stack element is a row of the edit distance table: the distance between
+/// the output so far and each prefix of the target, capped at max_edits + 1.
+#[derive(Clone)]
+pub struct FuzzyMatch {
+    target: Vec<u8>,
+    max_edits: u8,
+    // stack of rows, each of target.len() + 1 entries
+    rows: Vec<u8>,
+}
+
+impl FuzzyMatch {
+    pub fn new(target: &str, max_edits: u8) -> Self {
+        assert!(max_edits < u8::MAX - 1);
+        let cap = max_edits + 1;
+        let rows = (0..=target.len())
+            .map(|i| core::cmp::min(i, cap as usize) as u8)
+            .collect();
+        FuzzyMatch {
+            target: target.as_bytes().to_vec(),
+            max_edits,
+            rows,
+        }
+    }
+
+    fn width(&self) -> usize {
+        self.target.len() + 1
+    }
+
+    fn top(&self) -> &[u8] {
+        &self.rows[self.rows.len() - self.width()..]
+    }
+
+    /// Edit distance between the output so far and the whole target,
+    /// or None if it is more than max_edits.
+    pub fn distance(&self) -> Option<u8> {
+        let d = *self.top().last().unwrap();
+        if d <= self.max_edits {
+            Some(d)
+        } else {
+            None
+        }
+    }
+}
+
+impl Recognizer for FuzzyMatch {
+    fn pop_bytes(&mut self, num: usize) {
+        let len = self.rows.len() - num * self.width();
+        self.rows.truncate(len);
+    }
+
+    fn collapse(&mut self) {
+        let start = self.rows.len() - self.width();
+        self.rows.drain(0..start);
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        match tok {
+            SpecialToken::EndOfSentence => self.distance().is_some(),
+            _ => false,
+        }
+    }
+
+    fn trie_finished(&mut self) {
+        self.rows.truncate(self.width());
+    }
+
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        let w = self.width();
+        let cap = self.max_edits + 1;
+        let prev = self.rows.len() - w;
+        let mut best = self.rows[prev] + 1;
+        self.rows.push(core::cmp::min(best, cap));
+        for i in 1..w {
+            let subst = self.rows[prev + i - 1] + (self.target[i - 1] != byte) as u8;
+            let insert = self.rows[prev + i] + 1;
+            let delete = self.rows[prev + w + i - 1] + 1;
+            let d = core::cmp::min(core::cmp::min(subst, insert), core::cmp::min(delete, cap));
+            best = core::cmp::min(best, d);
+            self.rows.push(d);
+        }
+        if best <= self.max_edits {
+            true
+        } else {
+            self.rows.truncate(prev + w);
+            false
+        }
+    }
+
+    fn no_bytes_allowed(&mut self) -> bool {
+        // a byte can be inserted anywhere below max_edits, or match the next byte of the target
+        let row = self.top();
+        let n = self.target.len();
+        !row.iter()
+            .enumerate()
+            .any(|(i, &d)| d < self.max_edits || (d == self.max_edits && i < n))
+    }
+
+    fn state_description(&mut self) -> Option<String> {
+        Some(format!(
+            "within {} edits of {:?}",
+            self.max_edits,
+            String::from_utf8_lossy(&self.target)
+        ))
+    }
+
+    fn save_state(&mut self) -> Result<Vec<u8>> {
+        Ok(BlobWriter::new().bytes(self.top()).finish())
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> Result<()> {
+        let mut r = BlobReader::new(state);
+        let row = r.bytes()?;
+        r.finish()?;
+        if row.len() != self.width() || row.iter().any(|&d| d > self.max_edits + 1) {
+            bail!("invalid FuzzyMatch state");
+        }
+        self.rows = row.to_vec();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recognizer::push_str;
+    use crate::TestTokEnv;
+
+    fn levenshtein(a: &[u8], b: &[u8]) -> usize {
+        let mut row: Vec<usize> = (0..=b.len()).collect();
+        for (i, &x) in a.iter().enumerate() {
+            let mut next = vec![i + 1];
+            for (j, &y) in b.iter().enumerate() {
+                next.push(
+                    (row[j] + (x != y) as usize)
+                        .min(row[j + 1] + 1)
+                        .min(next[j] + 1),
+                );
+            }
+            row = next;
+        }
+        row[b.len()]
+    }
+
+    #[test]
+    fn matches_reference() {
+        let target = b"abca";
+        let fm = FuzzyMatch::new("abca", 1);
+        let mut strings = vec![vec![]];
+        for _ in 0..6 {
+            let mut next = vec![];
+            for s in &strings {
+                let mut r = fm.clone();
+                assert!(push_str(&mut r, core::str::from_utf8(s).unwrap()));
+                let dist = levenshtein(s, target);
+                assert_eq!(r.distance(), (dist <= 1).then_some(dist as u8), "{:?}", s);
+                for b in [b'a', b'b', b'c', b'x'] {
+                    let mut s2 = s.clone();
+                    s2.push(b);
+                    // viable if within max_edits of some prefix of the target
+                    let viable = (0..=target.len()).any(|j| levenshtein(&s2, &target[..j]) <= 1);
+                    assert_eq!(r.try_push_byte(b), viable, "{:?}", s2);
+                    if viable {
+                        r.pop_bytes(1);
+                        next.push(s2);
+                    }
+                }
+                assert_eq!(r.no_bytes_allowed(), next.iter().all(|n| !n.starts_with(s)));
+            }
+            strings = next;
+        }
+    }
+
+    #[test]
+    fn bias_and_state() {
+        let env = TestTokEnv::with_words(&["hel", "hello", "helo", " world", "wrld", "xx"]);
+        let fm = FuzzyMatch::new("hello world", 2);
+        for prefix in ["", "h", "helo", "hello w", "hallo wor"] {
+            let mut r = fm.clone();
+            assert!(push_str(&mut r, prefix), "{prefix}");
+            env.check_bias(&mut r).unwrap();
+        }
+        let mut r = fm.clone();
+        assert!(push_str(&mut r, "helo wo"));
+        let state = r.save_state().unwrap();
+        let mut r2 = fm.clone();
+        r2.restore_state(&state).unwrap();
+        assert!(push_str(&mut r2, "rl"));
+        assert_eq!(r2.distance(), Some(2));
+        assert!(!push_str(&mut r2, "xx"));
+        assert!(r2.restore_state(&state[..state.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,560 @@
+use anyhow::{bail, Result};
+
+use super::{
+    blob::{BlobReader, BlobWriter},
+    FunctionalRecognizer, StackRecognizer,
+};
+use crate::prelude::*;
+use crate::SpecialToken;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum NumMode {
+    Minus,
+    Zero,
+    Int,
+    Dot,
+    Frac,
+    Exp,
+    ExpSign,
+    ExpDigits,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode {
+    /// Expecting a value.
+    Value,
+    /// After '['.
+    ArrValueOrEnd,
+    /// After '{'.
+    ObjKeyOrEnd,
+    /// After ',' in an object.
+    ObjKey,
+    /// After object key.
+    Colon,
+    /// After a complete value inside of an object or array.
+    AfterValue,
+    /// After the complete top-level value.
+    Done,
+    /// Inside of a string; esc is 0 (none), 1 (after backslash),
+    /// or 2..=5 (esc - 1 hex digits of \uXXXX left); utf8 is number of continuation bytes left,
+    /// or 4..=7 after E0, ED, F0, F4, where the next byte has a restricted range (see utf8_next()).
+    Str {
+        key: bool,
+        esc: u8,
+        utf8: u8,
+    },
+    Num(NumMode),
+    /// Inside of true/false/null.
+    Lit {
+        idx: u8,
+        pos: u8,
+    },
+}
+
+const LITERALS: [&[u8]; 3] = [b"true", b"false", b"null"];
+
+const NUM_MODES: [NumMode; 8] = [
+    NumMode::Minus,
+    NumMode::Zero,
+    NumMode::Int,
+    NumMode::Dot,
+    NumMode::Frac,
+    NumMode::Exp,
+    NumMode::ExpSign,
+    NumMode::ExpDigits,
+];
+
+/// State of JsonSyntax; nesting is kept as a bit stack (1 for object, 0 for array).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct JsonState {
+    mode: Mode,
+    depth: u8,
+    stack: u128,
+}
+
+/// Recognizer for a single well-formed JSON value, optionally surrounded by whitespace.
+/// EOS is allowed once the value is complete.
+#[derive(Clone)]
+pub struct JsonSyntax {
+    max_depth: usize,
+}
+
+pub type JsonRecognizer = StackRecognizer<JsonState, JsonSyntax>;
+
+fn is_ws(b: u8) -> bool {
+    matches!(b, b' ' | b'\t' | b'\n' | b'\r')
+}
+
+// Allowed range of the next continuation byte in given Mode::Str utf8 state,
+// and the state after it. The restricted ranges exclude overlong encodings,
+// surrogates, and code points above U+10FFFF (Unicode Table 3-7).
+fn utf8_next(utf8: u8) -> (u8, u8, u8) {
+    match utf8 {
+        4 => (0xA0, 0xBF, 1), // E0
+        5 => (0x80, 0x9F, 1), // ED
+        6 => (0x90, 0xBF, 2), // F0
+        7 => (0x80, 0x8F, 2), // F4
+        n => (0x80, 0xBF, n - 1),
+    }
+}
+
+impl JsonSyntax {
+    pub const MAX_DEPTH: usize = 128;
+
+    /// max_depth is the maximum nesting of objects and arrays;
+    /// values above MAX_DEPTH are clamped to it.
+    pub fn new(max_depth: usize) -> Self {
+        JsonSyntax {
+            max_depth: core::cmp::min(max_depth, Self::MAX_DEPTH),
+        }
+    }
+
+    pub fn to_recognizer(self) -> JsonRecognizer {
+        StackRecognizer::from(self)
+    }
+
+    fn with_mode(st: JsonState, mode: Mode) -> JsonState {
+        JsonState { mode, ..st }
+    }
+
+    fn in_object(st: JsonState) -> bool {
+        st.depth > 0 && (st.stack >> (st.depth - 1)) & 1 != 0
+    }
+
+    fn value_done(st: JsonState) -> JsonState {
+        Self::with_mode(
+            st,
+            if st.depth == 0 {
+                Mode::Done
+            } else {
+                Mode::AfterValue
+            },
+        )
+    }
+
+    fn push(&self, st: JsonState, is_obj: bool, mode: Mode) -> Option<JsonState> {
+        if st.depth as usize >= self.max_depth {
+            return None;
+        }
+        let bit = 1u128 << st.depth;
+        let stack = if is_obj {
+            st.stack | bit
+        } else {
+            st.stack & !bit
+        };
+        Some(JsonState {
+            mode,
+            depth: st.depth + 1,
+            stack,
+        })
+    }
+
+    fn pop(st: JsonState) -> JsonState {
+        Self::value_done(JsonState {
+            depth: st.depth - 1,
+            ..st
+        })
+    }
+
+    fn value_start(&self, st: JsonState, b: u8) -> Option<JsonState> {
+        let mode = match b {
+            b'{' => return self.push(st, true, Mode::ObjKeyOrEnd),
+            b'[' => return self.push(st, false, Mode::ArrValueOrEnd),
+            b'"' => Mode::Str {
+                key: false,
+                esc: 0,
+                utf8: 0,
+            },
+            b'-' => Mode::Num(NumMode::Minus),
+            b'0' => Mode::Num(NumMode::Zero),
+            b'1'..=b'9' => Mode::Num(NumMode::Int),
+            b't' => Mode::Lit { idx: 0, pos: 1 },
+            b'f' => Mode::Lit { idx: 1, pos: 1 },
+            b'n' => Mode::Lit { idx: 2, pos: 1 },
+            _ => return None,
+        };
+        Some(Self::with_mode(st, mode))
+    }
+
+    fn after_value(st: JsonState, b: u8) -> Option<JsonState> {
+        if is_ws(b) {
+            return Some(st);
+        }
+        if st.mode == Mode::Done {
+            return None;
+        }
+        let in_obj = Self::in_object(st);
+        match b {
+            b',' if in_obj => Some(Self::with_mode(st, Mode::ObjKey)),
+            b',' => Some(Self::with_mode(st, Mode::Value)),
+            b'}' if in_obj => Some(Self::pop(st)),
+            b']' if !in_obj => Some(Self::pop(st)),
+            _ => None,
+        }
+    }
+
+    fn string_byte(st: JsonState, key: bool, esc: u8, utf8: u8, b: u8) -> Option<JsonState> {
+        let str_mode = |esc, utf8| Some(Self::with_mode(st, Mode::Str { key, esc, utf8 }));
+        if utf8 > 0 {
+            let (lo, hi, next) = utf8_next(utf8);
+            return if (lo..=hi).contains(&b) {
+                str_mode(0, next)
+            } else {
+                None
+            };
+        }
+        match esc {
+            0 => match b {
+                b'"' if key => Some(Self::with_mode(st, Mode::Colon)),
+                b'"' => Some(Self::value_done(st)),
+                b'\\' => str_mode(1, 0),
+                0x00..=0x1F => None,
+                0x20..=0x7F => str_mode(0, 0),
+                0xC2..=0xDF => str_mode(0, 1),
+                0xE0 => str_mode(0, 4),
+                0xED => str_mode(0, 5),
+                0xE1..=0xEF => str_mode(0, 2),
+                0xF0 => str_mode(0, 6),
+                0xF4 => str_mode(0, 7),
+                0xF1..=0xF3 => str_mode(0, 3),
+                _ => None,
+            },
+            1 => match b {
+                b'"' | b'\\' | b'/' | b'b' | b'f' | b'n' | b'r' | b't' => str_mode(0, 0),
+                b'u' => str_mode(5, 0),
+                _ => None,
+            },
+            _ => {
+                if b.is_ascii_hexdigit() {
+                    str_mode(if esc == 2 { 0 } else { esc - 1 }, 0)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    fn number_byte(st: JsonState, n: NumMode, b: u8) -> Option<JsonState> {
+        let num = |n| Some(Self::with_mode(st, Mode::Num(n)));
+        match (n, b) {
+            (NumMode::Minus, b'0') => num(NumMode::Zero),
+            (NumMode::Minus, b'1'..=b'9') => num(NumMode::Int),
+            (NumMode::Int, b'0'..=b'9') => num(NumMode::Int),
+            (NumMode::Zero | NumMode::Int, b'.') => num(NumMode::Dot),
+            (NumMode::Dot | NumMode::Frac, b'0'..=b'9') => num(NumMode::Frac),
+            (NumMode::Zero | NumMode::Int | NumMode::Frac, b'e' | b'E') => num(NumMode::Exp),
+            (NumMode::Exp, b'+' | b'-') => num(NumMode::ExpSign),
+            (NumMode::Exp | NumMode::ExpSign | NumMode::ExpDigits, b'0'..=b'9') => {
+                num(NumMode::ExpDigits)
+            }
+            // the number is complete; the byte has to follow a value
+            (NumMode::Zero | NumMode::Int | NumMode::Frac | NumMode::ExpDigits, _) => {
+                Self::after_value(Self::value_done(st), b)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl FunctionalRecognizer<JsonState> for JsonSyntax {
+    fn initial(&self) -> JsonState {
+        JsonState {
+            mode: Mode::Value,
+            depth: 0,
+            stack: 0,
+        }
+    }
+
+    fn try_append(&self, st: JsonState, b: u8) -> Option<JsonState> {
+        match st.mode {
+            Mode::Value => {
+                if is_ws(b) {
+                    Some(st)
+                } else {
+                    self.value_start(st, b)
+                }
+            }
+            Mode::ArrValueOrEnd => match b {
+                _ if is_ws(b) => Some(st),
+                b']' => Some(Self::pop(st)),
+                _ => self.value_start(st, b),
+            },
+            Mode::ObjKeyOrEnd | Mode::ObjKey => match b {
+                _ if is_ws(b) => Some(st),
+                b'}' if st.mode == Mode::ObjKeyOrEnd => Some(Self::pop(st)),
+                b'"' => Some(Self::with_mode(
+                    st,
+                    Mode::Str {
+                        key: true,
+                        esc: 0,
+                        utf8: 0,
+                    },
+                )),
+                _ => None,
+            },
+            Mode::Colon => match b {
+                _ if is_ws(b) => Some(st),
+                b':' => Some(Self::with_mode(st, Mode::Value)),
+                _ => None,
+            },
+            Mode::AfterValue | Mode::Done => Self::after_value(st, b),
+            Mode::Str { key, esc, utf8 } => Self::string_byte(st, key, esc, utf8, b),
+            Mode::Num(n) => Self::number_byte(st, n, b),
+            Mode::Lit { idx, pos } => {
+                let lit = LITERALS[idx as usize];
+                if lit[pos as usize] != b {
+                    None
+                } else if pos as usize + 1 == lit.len() {
+                    Some(Self::value_done(st))
+                } else {
+                    Some(Self::with_mode(st, Mode::Lit { idx, pos: pos + 1 }))
+                }
+            }
+        }
+    }
+
+    fn special_allowed(&self, st: JsonState, tok: SpecialToken) -> bool {
+        match tok {
+            SpecialToken::EndOfSentence => match st.mode {
+                Mode::Done => true,
+                Mode::Num(NumMode::Zero | NumMode::Int | NumMode::Frac | NumMode::ExpDigits) => {
+                    st.depth == 0
+                }
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    fn state_description(&self, st: JsonState) -> Option<String> {
+        let what = match st.mode {
+            Mode::Value => "expecting JSON value",
+            Mode::ArrValueOrEnd => "after '['",
+            Mode::ObjKeyOrEnd => "after '{'",
+            Mode::ObjKey => "expecting object key",
+            Mode::Colon => "expecting ':'",
+            Mode::AfterValue if Self::in_object(st) => "after object member",
+            Mode::AfterValue => "after array element",
+            Mode::Done => "after complete JSON value",
+            Mode::Str { key: true, .. } => "in object key",
+            Mode::Str { .. } => "in string",
+            Mode::Num(_) => "in number",
+            Mode::Lit { .. } => "in literal",
+        };
+        Some(format!("{} at depth {}", what, st.depth))
+    }
+
+    fn save_state(&self, st: JsonState) -> Result<Vec<u8>> {
+        let w = BlobWriter::new();
+        let w = match st.mode {
+            Mode::Value => w.u8(0),
+            Mode::ArrValueOrEnd => w.u8(1),
+            Mode::ObjKeyOrEnd => w.u8(2),
+            Mode::ObjKey => w.u8(3),
+            Mode::Colon => w.u8(4),
+            Mode::AfterValue => w.u8(5),
+            Mode::Done => w.u8(6),
+            Mode::Str { key, esc, utf8 } => w.u8(7).u8(key as u8).u8(esc).u8(utf8),
+            Mode::Num(n) => w.u8(8).u8(n as u8),
+            Mode::Lit { idx, pos } => w.u8(9).u8(idx).u8(pos),
+        };
+        Ok(w.u8(st.depth).u128(st.stack).finish())
+    }
+
+    fn restore_state(&self, data: &[u8]) -> Result<JsonState> {
+        let mut r = BlobReader::new(data);
+        let mode = match r.u8()? {
+            0 => Mode::Value,
+            1 => Mode::ArrValueOrEnd,
+            2 => Mode::ObjKeyOrEnd,
+            3 => Mode::ObjKey,
+            4 => Mode::Colon,
+            5 => Mode::AfterValue,
+            6 => Mode::Done,
+            7 => {
+                let (key, esc, utf8) = (r.u8()? != 0, r.u8()?, r.u8()?);
+                if esc > 5 || utf8 > 7 {
+                    bail!("invalid JSON string state");
+                }
+                Mode::Str { key, esc, utf8 }
+            }
+            8 => match NUM_MODES.get(r.u8()? as usize) {
+                Some(n) => Mode::Num(*n),
+                None => bail!("invalid JSON number state"),
+            },
+            9 => {
+                let (idx, pos) = (r.u8()?, r.u8()?);
+                if idx as usize >= LITERALS.len() || pos as usize >= LITERALS[idx as usize].len() {
+                    bail!("invalid JSON literal state");
+                }
+                Mode::Lit { idx, pos }
+            }
+            m => bail!("invalid JSON mode {}", m),
+        };
+        let st = JsonState {
+            mode,
+            depth: r.u8()?,
+            stack: r.u128()?,
+        };
+        r.finish()?;
+        if st.depth as usize > self.max_depth {
+            bail!("invalid JSON depth {}", st.depth);
+        }
+        Ok(st)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::toktree::Recognizer;
+
+    fn accepts(s: &[u8]) -> bool {
+        let mut r = JsonSyntax::new(10).to_recognizer();
+        s.iter().all(|&b| r.try_push_byte(b)) && r.special_allowed(SpecialToken::EndOfSentence)
+    }
+
+    fn string_accepted(content: &[u8]) -> bool {
+        let mut s = b"\"".to_vec();
+        s.extend_from_slice(content);
+        s.push(b'"');
+        accepts(&s)
+    }
+
+    #[test]
+    fn utf8_in_strings() {
+        for s in [
+            "é",
+            "€",
+            "\u{FFFF}",
+            "\u{E000}",
+            "\u{D7FF}",
+            "𝄞",
+            "\u{10FFFF}",
+        ] {
+            assert!(string_accepted(s.as_bytes()), "{s:?}");
+        }
+        for s in [
+            &b"\xC0\x80"[..],    // overlong
+            b"\xE0\x80\x80",     // overlong
+            b"\xED\xA0\x80",     // surrogate
+            b"\xF0\x80\x80\x80", // overlong
+            b"\xF4\x90\x80\x80", // above U+10FFFF
+            b"\xF5\x80\x80\x80", // above U+10FFFF
+            b"\xE2\x82",         // truncated
+        ] {
+            assert!(!string_accepted(s), "{s:?}");
+        }
+        // every lead and second byte, with valid continuation bytes after them
+        for b0 in 0x80..=0xFFu8 {
+            for b1 in 0..=0xFFu8 {
+                for tail in [0x80u8, 0xBF] {
+                    let len = match b0 {
+                        0xC0..=0xDF => 2,
+                        0xE0..=0xEF => 3,
+                        _ => 4,
+                    };
+                    let mut s = vec![b0, b1];
+                    s.resize(len, tail);
+                    assert_eq!(
+                        string_accepted(&s),
+                        core::str::from_utf8(&s).is_ok(),
+                        "{s:x?}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn numbers() {
+        for s in [
+            "0", "-0", "7", "-12", "0.5", "10.25", "1e5", "1E+5", "-1.5e-3", "0e0", "[1,-0.0]",
+        ] {
+            assert!(accepts(s.as_bytes()), "{s}");
+        }
+        for s in [
+            "-",
+            "01",
+            "-01",
+            "1.",
+            "1.e5",
+            ".5",
+            "1e",
+            "1e+",
+            "1e-",
+            "+1",
+            "--1",
+            "0x10",
+            "1.5.",
+            "[1.]",
+            "[-]",
+            "{\"a\":1e}",
+        ] {
+            assert!(!accepts(s.as_bytes()), "{s}");
+        }
+    }
+
+    #[test]
+    fn utf8_restricted_second_bytes() {
+        // lowest and highest allowed second byte after E0, ED, F0 and F4
+        for s in [
+            &b"\xE0\xA0\x80"[..],
+            b"\xE0\xBF\xBF",
+            b"\xED\x80\x80",
+            b"\xED\x9F\xBF",
+            b"\xF0\x90\x80\x80",
+            b"\xF0\xBF\xBF\xBF",
+            b"\xF4\x80\x80\x80",
+            b"\xF4\x8F\xBF\xBF",
+        ] {
+            assert!(string_accepted(s), "{s:x?}");
+        }
+        for s in [
+            &b"\xE0\x9F\xBF"[..],
+            b"\xED\xBF\xBF",
+            b"\xF0\x8F\xBF\xBF",
+            b"\xF4\x90\x80\x80",
+            b"\xF4\xBF\xBF\xBF",
+        ] {
+            assert!(!string_accepted(s), "{s:x?}");
+        }
+    }
+
+    #[test]
+    fn depth_limit() {
+        let nested = |n: usize| {
+            let mut s = "[".repeat(n).into_bytes();
+            s.extend_from_slice("]".repeat(n).as_bytes());
+            s
+        };
+        let check = |max_depth: usize, s: &[u8]| {
+            let mut r = JsonSyntax::new(max_depth).to_recognizer();
+            s.iter().all(|&b| r.try_push_byte(b)) && r.special_allowed(SpecialToken::EndOfSentence)
+        };
+        assert!(check(3, &nested(3)));
+        assert!(!check(3, &nested(4)));
+        assert!(check(3, br#"{"a": [{"b": 1}]}"#));
+        assert!(!check(3, br#"{"a": [{"b": []}]}"#));
+        assert!(check(0, b" 1 "));
+        assert!(!check(0, b"[]"));
+        // clamped to MAX_DEPTH
+        assert!(check(usize::MAX, &nested(JsonSyntax::MAX_DEPTH)));
+        assert!(!check(usize::MAX, &nested(JsonSyntax::MAX_DEPTH + 1)));
+    }
+
+    #[test]
+    fn state_roundtrip() {
+        let syntax = JsonSyntax::new(4);
+        let mut st = syntax.initial();
+        for &b in br#"{"a\u00e9": [tr"# {
+            st = syntax.try_append(st, b).unwrap();
+            let data = syntax.save_state(st).unwrap();
+            assert_eq!(syntax.restore_state(&data).unwrap(), st);
+        }
+        assert!(JsonSyntax::new(1)
+            .restore_state(&syntax.save_state(st).unwrap())
+            .is_err());
+        assert!(syntax.restore_state(&[42]).is_err());
+    }
+}
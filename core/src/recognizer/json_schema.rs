@@ -0,0 +1,684 @@
+// Compiler from a subset of JSON Schema to a regex, matched with RegexDfa.
+// Supported: type (including arrays of types), enum, const, anyOf/oneOf,
+// properties/required (properties are emitted in the iteration order of serde_json::Map,
+// which is alphabetical by default; additional properties are not allowed),
+// items/minItems/maxItems, minLength/maxLength/pattern for strings (patterns match the whole
+// string value, and are applied to its JSON encoding),
+// minimum/maximum/exclusiveMinimum/exclusiveMaximum for integers (and non-negative integer bounds
+// for numbers), and local $refs (#/$defs/... or #/definitions/...) that are not recursive
+// (at most MAX_REF_DEPTH of them nested).
+// The output is compact JSON: a single optional space is allowed after ':' and ','.
+
+use anyhow::{anyhow, bail, Result};
+use regex_syntax::hir::{
+    Class, ClassUnicode, ClassUnicodeRange, Hir, HirKind, Literal, Look, Repetition,
+};
+use serde_json::Value;
+
+use super::{RegexDfa, RegexRecognizer};
+use crate::prelude::*;
+
+const MAX_REF_DEPTH: usize = 16;
+// arrays repeat the regex of their items, so nested arrays grow exponentially
+const MAX_REGEX_LEN: usize = 1 << 20;
+
+const STRING_CHAR: &str = r#"(?:[^"\\\x00-\x1F]|\\["\\/bfnrt]|\\u[0-9a-fA-F]{4})"#;
+const INT: &str = r"-?(?:0|[1-9][0-9]*)";
+const FRAC: &str = r"(?:\.[0-9]+)?";
+const EXP: &str = r"(?:[eE][+-]?[0-9]+)?";
+
+fn escape(s: &str) -> String {
+    let mut res = String::new();
+    for c in s.chars() {
+        if "\\.+*?()|[]{}^$#&-~".contains(c) {
+            res.push('\\');
+        }
+        res.push(c);
+    }
+    res
+}
+
+// JSON encoding of a character inside a string
+fn escape_json_char(c: char) -> String {
+    match c {
+        '"' => "\\\"".to_string(),
+        '\\' => "\\\\".to_string(),
+        '\n' => "\\n".to_string(),
+        '\r' => "\\r".to_string(),
+        '\t' => "\\t".to_string(),
+        '\u{08}' => "\\b".to_string(),
+        '\u{0C}' => "\\f".to_string(),
+        c if (c as u32) < 0x20 => format!("\\u{:04x}", c as u32),
+        c => c.to_string(),
+    }
+}
+
+// Rewrite a regex over string values into one over their JSON encoding (without quotes),
+// so that e.g. "." can't produce a raw '"' or newline. The pattern matches the whole value;
+// ^ and $ are ignored, and other assertions are not supported.
+fn escaped_pattern(pattern: &str) -> Result<String> {
+    let hir = regex_syntax::parse(pattern).map_err(|e| anyhow!("invalid pattern: {}", e))?;
+    Ok(escape_hir(&hir)?.to_string())
+}
+
+fn escape_hir(hir: &Hir) -> Result<Hir> {
+    Ok(match hir.kind() {
+        HirKind::Empty => Hir::empty(),
+        HirKind::Literal(Literal(bytes)) => {
+            let s = core::str::from_utf8(bytes)
+                .map_err(|_| anyhow!("unsupported: non-UTF-8 pattern"))?;
+            Hir::literal(
+                s.chars()
+                    .map(escape_json_char)
+                    .collect::<String>()
+                    .into_bytes(),
+            )
+        }
+        HirKind::Class(Class::Unicode(cls)) => {
+            let mut special = ClassUnicode::new(
+                [('\0', '\u{1F}'), ('"', '"'), ('\\', '\\')]
+                    .map(|(a, b)| ClassUnicodeRange::new(a, b)),
+            );
+            special.intersect(cls);
+            let mut plain = cls.clone();
+            plain.difference(&special);
+            let mut alts = vec![Hir::class(Class::Unicode(plain))];
+            for r in special.iter() {
+                for c in r.start()..=r.end() {
+                    alts.push(Hir::literal(escape_json_char(c).into_bytes()));
+                }
+            }
+            Hir::alternation(alts)
+        }
+        HirKind::Class(Class::Bytes(_)) => bail!("unsupported: byte classes in pattern"),
+        HirKind::Look(Look::Start | Look::End) => Hir::empty(),
+        HirKind::Look(look) => bail!("unsupported: {:?} in pattern", look),
+        HirKind::Repetition(rep) => Hir::repetition(Repetition {
+            sub: Box::new(escape_hir(&rep.sub)?),
+            ..rep.clone()
+        }),
+        HirKind::Capture(cap) => escape_hir(&cap.sub)?,
+        HirKind::Concat(subs) => Hir::concat(subs.iter().map(escape_hir).collect::<Result<_>>()?),
+        HirKind::Alternation(subs) => {
+            Hir::alternation(subs.iter().map(escape_hir).collect::<Result<_>>()?)
+        }
+    })
+}
+
+fn pow10(k: usize) -> u64 {
+    10u64.pow(k as u32)
+}
+
+fn num_digits(n: u64) -> usize {
+    n.to_string().len()
+}
+
+/// Regex alternatives for non-negative integers in [min, max], without leading zeros.
+fn uint_range(min: u64, max: u64) -> Vec<String> {
+    // split into blocks [start, start + 10^k - 1] not crossing digit-length boundaries,
+    // merging consecutive blocks that differ only in one digit
+    let mut res = Vec::new();
+    // (prefix, k, lo digit, hi digit)
+    let mut curr: Option<(String, usize, u8, u8)> = None;
+    let mut start = min;
+    while start <= max {
+        let mut k = 0;
+        while k + 1 < num_digits(start)
+            && start.is_multiple_of(pow10(k + 1))
+            && start + pow10(k + 1) - 1 <= max
+        {
+            k += 1;
+        }
+        let s = start.to_string();
+        let prefix = s[..s.len() - k - 1].to_string();
+        let digit = s.as_bytes()[s.len() - k - 1] - b'0';
+        match &mut curr {
+            Some((p, ck, _, hi)) if *p == prefix && *ck == k && *hi + 1 == digit => *hi = digit,
+            _ => {
+                if let Some(c) = curr.take() {
+                    res.push(c);
+                }
+                curr = Some((prefix, k, digit, digit));
+            }
+        }
+        match start.checked_add(pow10(k)) {
+            Some(next) => start = next,
+            None => break,
+        }
+    }
+    res.extend(curr);
+    res.into_iter()
+        .map(|(prefix, k, lo, hi)| {
+            let digit = if lo == hi {
+                lo.to_string()
+            } else {
+                format!("[{}-{}]", lo, hi)
+            };
+            let rest = match k {
+                0 => String::new(),
+                1 => "[0-9]".to_string(),
+                _ => format!("[0-9]{{{}}}", k),
+            };
+            format!("{}{}{}", prefix, digit, rest)
+        })
+        .collect()
+}
+
+/// Regex alternatives for non-negative integers >= min.
+fn uint_from(min: u64) -> Vec<String> {
+    let len = num_digits(min);
+    let mut res = uint_range(min, pow10(len) - 1);
+    res.push(format!("[1-9][0-9]{{{},}}", len));
+    res
+}
+
+/// Regex for integers in given range (inclusive; None means unbounded).
+pub(crate) fn int_range_regex(min: Option<i64>, max: Option<i64>) -> Result<String> {
+    if let (Some(a), Some(b)) = (min, max) {
+        if a > b {
+            bail!("empty integer range: {}..={}", a, b);
+        }
+    }
+    let mut alts = Vec::new();
+    // negative part; absolute values from lo up to -min
+    if min.is_none_or(|a| a < 0) {
+        let lo = match max {
+            Some(b) if b < 0 => b.unsigned_abs(),
+            _ => 1,
+        };
+        let neg = match min {
+            Some(a) => uint_range(lo, a.unsigned_abs()),
+            None => uint_from(lo),
+        };
+        alts.extend(neg.into_iter().map(|r| format!("-{}", r)));
+    }
+    // non-negative part
+    if max.is_none_or(|b| b >= 0) {
+        let lo = min.map_or(0, |a| core::cmp::max(a, 0) as u64);
+        let pos = match max {
+            Some(b) => uint_range(lo, b as u64),
+            None => uint_from(lo),
+        };
+        alts.extend(pos);
+    }
+    Ok(format!("(?:{})", alts.join("|")))
+}
+
+struct Compiler<'a> {
+    root: &'a Value,
+}
+
+fn as_int_bound(v: &Value, what: &str) -> Result<i64> {
+    match v.as_f64() {
+        Some(f) if f.abs() < 1e18 && f == (f as i64) as f64 => Ok(f as i64),
+        _ => bail!("unsupported {}: {}", what, v),
+    }
+}
+
+impl Compiler<'_> {
+    fn resolve(&self, reference: &str) -> Result<&Value> {
+        let path = reference
+            .strip_prefix("#/")
+            .ok_or_else(|| anyhow!("unsupported $ref: {}", reference))?;
+        let mut v = self.root;
+        for part in path.split('/') {
+            v = v
+                .get(part)
+                .ok_or_else(|| anyhow!("unresolved $ref: {}", reference))?;
+        }
+        Ok(v)
+    }
+
+    fn alternatives(&self, schemas: &Value, refs: usize) -> Result<String> {
+        let arr = schemas
+            .as_array()
+            .ok_or_else(|| anyhow!("expecting array in anyOf/oneOf"))?;
+        let alts = arr
+            .iter()
+            .map(|s| self.compile(s, refs))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(format!("(?:{})", alts.join("|")))
+    }
+
+    // refs is the number of $refs followed to get to schema
+    fn compile(&self, schema: &Value, refs: usize) -> Result<String> {
+        let res = self.compile_inner(schema, refs)?;
+        if res.len() > MAX_REGEX_LEN {
+            bail!("schema too large (nested arrays?)");
+        }
+        Ok(res)
+    }
+
+    fn compile_inner(&self, schema: &Value, refs: usize) -> Result<String> {
+        if schema == &Value::Bool(true) {
+            bail!("unsupported: unconstrained schema");
+        }
+        if let Some(r) = schema.get("$ref").and_then(|r| r.as_str()) {
+            if refs >= MAX_REF_DEPTH {
+                bail!("too many nested $refs (recursive $ref?)");
+            }
+            return self.compile(self.resolve(r)?, refs + 1);
+        }
+        if let Some(c) = schema.get("const") {
+            return Ok(escape(&c.to_string()));
+        }
+        if let Some(e) = schema.get("enum") {
+            let arr = e
+                .as_array()
+                .ok_or_else(|| anyhow!("expecting array in enum"))?;
+            let alts: Vec<String> = arr.iter().map(|v| escape(&v.to_string())).collect();
+            return Ok(format!("(?:{})", alts.join("|")));
+        }
+        if let Some(s) = schema.get("anyOf").or_else(|| schema.get("oneOf")) {
+            return self.alternatives(s, refs);
+        }
+
+        let tp = match schema.get("type") {
+            Some(Value::String(s)) => s.as_str(),
+            Some(Value::Array(types)) => {
+                let alts = types
+                    .iter()
+                    .map(|t| {
+                        let mut s = schema.clone();
+                        s["type"] = t.clone();
+                        self.compile(&s, refs)
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                return Ok(format!("(?:{})", alts.join("|")));
+            }
+            Some(t) => bail!("invalid type: {}", t),
+            None if schema.get("properties").is_some() => "object",
+            None if schema.get("items").is_some() => "array",
+            None => bail!("unsupported: schema without type"),
+        };
+
+        match tp {
+            "null" => Ok("null".to_string()),
+            "boolean" => Ok("(?:true|false)".to_string()),
+            "string" => self.string(schema),
+            "integer" => self.integer(schema),
+            "number" => self.number(schema),
+            "array" => self.array(schema, refs),
+            "object" => self.object(schema, refs),
+            _ => bail!("unsupported type: {}", tp),
+        }
+    }
+
+    fn string(&self, schema: &Value) -> Result<String> {
+        if let Some(p) = schema.get("pattern") {
+            let p = p.as_str().ok_or_else(|| anyhow!("invalid pattern"))?;
+            return Ok(format!("\"(?:{})\"", escaped_pattern(p)?));
+        }
+        let min = schema
+            .get("minLength")
+            .and_then(|v| v.as_u64())
+            .unwrap_or(0);
+        let rep = match schema.get("maxLength").and_then(|v| v.as_u64()) {
+            Some(max) => format!("{{{},{}}}", min, max),
+            None if min == 0 => "*".to_string(),
+            None => format!("{{{},}}", min),
+        };
+        Ok(format!("\"{}{}\"", STRING_CHAR, rep))
+    }
+
+    fn bounds(&self, schema: &Value) -> Result<(Option<i64>, Option<i64>)> {
+        let mut min = None;
+        let mut max = None;
+        if let Some(v) = schema.get("minimum") {
+            min = Some(as_int_bound(v, "minimum")?);
+        }
+        if let Some(v) = schema.get("exclusiveMinimum") {
+            min = Some(as_int_bound(v, "exclusiveMinimum")? + 1);
+        }
+        if let Some(v) = schema.get("maximum") {
+            max = Some(as_int_bound(v, "maximum")?);
+        }
+        if let Some(v) = schema.get("exclusiveMaximum") {
+            max = Some(as_int_bound(v, "exclusiveMaximum")? - 1);
+        }
+        Ok((min, max))
+    }
+
+    fn integer(&self, schema: &Value) -> Result<String> {
+        match self.bounds(schema)? {
+            (None, None) => Ok(INT.to_string()),
+            (min, max) => int_range_regex(min, max),
+        }
+    }
+
+    fn number(&self, schema: &Value) -> Result<String> {
+        let has_excl =
+            schema.get("exclusiveMinimum").is_some() || schema.get("exclusiveMaximum").is_some();
+        match self.bounds(schema)? {
+            (None, None) => Ok(format!("{}{}{}", INT, FRAC, EXP)),
+            (Some(min), max) if min >= 0 && !has_excl => {
+                // integer part in [min, max - 1] with any fraction, or exactly max
+                let mut alts = Vec::new();
+                match max {
+                    Some(max) if max == min => {}
+                    Some(max) => alts.push(format!(
+                        "{}{}",
+                        int_range_regex(Some(min), Some(max - 1))?,
+                        FRAC
+                    )),
+                    None => alts.push(format!("{}{}", int_range_regex(Some(min), None)?, FRAC)),
+                }
+                if let Some(max) = max {
+                    alts.push(format!("{}(?:\\.0+)?", max));
+                }
+                Ok(format!("(?:{})", alts.join("|")))
+            }
+            _ => bail!("unsupported number bounds (only non-negative inclusive integer bounds)"),
+        }
+    }
+
+    fn array(&self, schema: &Value, refs: usize) -> Result<String> {
+        let item = match schema.get("items") {
+            Some(items) => self.compile(items, refs)?,
+            None => bail!("unsupported: array without items"),
+        };
+        let min = schema.get("minItems").and_then(|v| v.as_u64()).unwrap_or(0);
+        let max = schema.get("maxItems").and_then(|v| v.as_u64());
+        if max == Some(0) {
+            return Ok(r"\[\]".to_string());
+        }
+        let rest = match max {
+            // a single item doesn't need to repeat its regex
+            Some(1) if min == 0 => return Ok(format!(r"\[(?:{})?\]", item)),
+            Some(1) => return Ok(format!(r"\[{}\]", item)),
+            Some(max) => format!("{{{},{}}}", min.saturating_sub(1), max - 1),
+            None => format!("{{{},}}", min.saturating_sub(1)),
+        };
+        let body = format!("{}(?:, ?{}){}", item, item, rest);
+        if min == 0 {
+            Ok(format!(r"\[(?:{})?\]", body))
+        } else {
+            Ok(format!(r"\[{}\]", body))
+        }
+    }
+
+    fn object(&self, schema: &Value, refs: usize) -> Result<String> {
+        let required: Vec<&str> = match schema.get("required") {
+            Some(r) => r
+                .as_array()
+                .ok_or_else(|| anyhow!("expecting array in required"))?
+                .iter()
+                .map(|v| v.as_str().ok_or_else(|| anyhow!("invalid required entry")))
+                .collect::<Result<_>>()?,
+            None => vec![],
+        };
+        let mut props = Vec::new();
+        if let Some(p) = schema.get("properties") {
+            let p = p
+                .as_object()
+                .ok_or_else(|| anyhow!("expecting object in properties"))?;
+            for (name, s) in p {
+                let val = self.compile(s, refs)?;
+                let key = escape(&Value::String(name.clone()).to_string());
+                props.push((
+                    format!("{}: ?{}", key, val),
+                    required.contains(&name.as_str()),
+                ));
+            }
+        }
+        for r in &required {
+            if schema["properties"].get(*r).is_none() {
+                bail!("required property {:?} not in properties", r);
+            }
+        }
+
+        let body = match props.iter().position(|(_, req)| *req) {
+            Some(first_req) => {
+                let mut body = String::new();
+                for (p, _) in &props[..first_req] {
+                    body.push_str(&format!("(?:{}, ?)?", p));
+                }
+                body.push_str(&props[first_req].0);
+                for (p, req) in &props[first_req + 1..] {
+                    body.push_str(&format!("(?:, ?{}){}", p, if *req { "" } else { "?" }));
+                }
+                body
+            }
+            None => {
+                // all optional; pick the first one present, followed by any of the later ones
+                let alts: Vec<String> = (0..props.len())
+                    .map(|i| {
+                        let mut alt = props[i].0.clone();
+                        for (p, _) in &props[i + 1..] {
+                            alt.push_str(&format!("(?:, ?{})?", p));
+                        }
+                        alt
+                    })
+                    .collect();
+                if alts.is_empty() {
+                    String::new()
+                } else {
+                    format!("(?:{})?", alts.join("|"))
+                }
+            }
+        };
+        Ok(format!(r"\{{{}\}}", body))
+    }
+}
+
+/// Compile a JSON Schema (see the list of supported features at the top of this file) to a regex.
+pub fn json_schema_to_regex(schema: &Value) -> Result<String> {
+    Compiler { root: schema }.compile(schema, 0)
+}
+
+impl RegexDfa {
+    pub fn from_json_schema(schema: &Value) -> Result<Self> {
+        RegexDfa::new(&json_schema_to_regex(schema)?)
+    }
+}
+
+/// Recognizer for JSON values matching the schema.
+pub fn json_schema_recognizer(schema: &Value) -> Result<RegexRecognizer> {
+    Ok(RegexDfa::from_json_schema(schema)?.to_recognizer())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recognizer::FunctionalRecognizer;
+    use serde_json::json;
+
+    fn accepts(dfa: &RegexDfa, s: &str) -> bool {
+        let mut state = dfa.initial();
+        for &b in s.as_bytes() {
+            match dfa.try_append(state, b) {
+                Some(next) => state = next,
+                None => return false,
+            }
+        }
+        dfa.is_accepting(state)
+    }
+
+    #[test]
+    fn string_pattern_is_json_escaped() {
+        let dfa =
+            RegexDfa::from_json_schema(&json!({"type": "string", "pattern": "^a.b$"})).unwrap();
+        assert!(accepts(&dfa, r#""axb""#));
+        assert!(accepts(&dfa, r#""a\"b""#));
+        assert!(accepts(&dfa, r#""a\u0001b""#));
+        assert!(!accepts(&dfa, "\"a\"b\""));
+        assert!(!accepts(&dfa, "\"a\u{1}b\""));
+        assert!(!accepts(&dfa, r#""a\nb""#));
+
+        let dfa =
+            RegexDfa::from_json_schema(&json!({"type": "string", "pattern": r#"say "\w+"\\"#}))
+                .unwrap();
+        assert!(accepts(&dfa, r#""say \"hi\"\\""#));
+        assert!(!accepts(&dfa, r#""say "hi"\""#));
+
+        for p in [r"\bword", "(?-u:\\xFF)"] {
+            assert!(json_schema_to_regex(&json!({"type": "string", "pattern": p})).is_err());
+        }
+    }
+
+    fn schema_dfa(schema: Value) -> RegexDfa {
+        RegexDfa::from_json_schema(&schema).unwrap()
+    }
+
+    #[test]
+    fn integer_ranges() {
+        for (min, max) in [
+            (Some(5), Some(123)),
+            (Some(-5), Some(-2)),
+            (Some(-17), Some(9)),
+            (Some(0), Some(0)),
+            (None, Some(10)),
+            (None, Some(-10)),
+            (Some(-10), None),
+            (Some(99), None),
+            (None, None),
+        ] {
+            let dfa = RegexDfa::new(&int_range_regex(min, max).unwrap()).unwrap();
+            for n in -1100i64..=1100 {
+                let expected = min.is_none_or(|a| n >= a) && max.is_none_or(|b| n <= b);
+                assert_eq!(
+                    accepts(&dfa, &n.to_string()),
+                    expected,
+                    "{n} in {min:?}..={max:?}"
+                );
+            }
+            for s in ["-0", "007", "", "-", "+5"] {
+                assert!(!accepts(&dfa, s), "{s:?} in {min:?}..={max:?}");
+            }
+        }
+        assert!(int_range_regex(Some(3), Some(2)).is_err());
+
+        assert_eq!(
+            uint_range(5, 123),
+            ["[5-9]", "[1-9][0-9]", "1[0-1][0-9]", "12[0-3]"]
+        );
+        assert_eq!(uint_range(0, 9), ["[0-9]"]);
+        assert_eq!(uint_range(u64::MAX, u64::MAX), [u64::MAX.to_string()]);
+
+        let dfa = schema_dfa(json!({"type": "integer", "exclusiveMinimum": 0, "maximum": 3}));
+        assert!(!accepts(&dfa, "0") && accepts(&dfa, "1") && accepts(&dfa, "3"));
+    }
+
+    #[test]
+    fn property_order() {
+        let int = json!({"type": "integer"});
+        let dfa = schema_dfa(json!({
+            "properties": {"a": int, "b": int, "c": int},
+            "required": ["b"]
+        }));
+        for s in [
+            r#"{"b":1}"#,
+            r#"{"a":1, "b":2}"#,
+            r#"{"a":1,"b":2,"c":3}"#,
+            r#"{"b": 1, "c": 2}"#,
+        ] {
+            assert!(accepts(&dfa, s), "{s}");
+        }
+        for s in [
+            "{}",
+            r#"{"a":1}"#,
+            r#"{"b":1,"a":2}"#,
+            r#"{"a":1,"c":2}"#,
+            r#"{"b":1,"b":2}"#,
+        ] {
+            assert!(!accepts(&dfa, s), "{s}");
+        }
+
+        let dfa =
+            schema_dfa(json!({"type": "object", "properties": {"a": int, "b": int, "c": int}}));
+        for s in ["{}", r#"{"c":1}"#, r#"{"a":1,"c":2}"#, r#"{"b":1,"c":2}"#] {
+            assert!(accepts(&dfa, s), "{s}");
+        }
+        for s in [
+            r#"{"c":1,"a":2}"#,
+            r#"{"a":1,"a":2}"#,
+            r#"{"a":1,}"#,
+            r#"{"d":1}"#,
+        ] {
+            assert!(!accepts(&dfa, s), "{s}");
+        }
+
+        assert!(
+            json_schema_to_regex(&json!({"properties": {"a": int}, "required": ["x"]})).is_err()
+        );
+    }
+
+    #[test]
+    fn array_lengths() {
+        let items = |min: Option<u64>, max: Option<u64>| {
+            let mut s = json!({"type": "array", "items": {"type": "integer"}});
+            if let Some(min) = min {
+                s["minItems"] = min.into();
+            }
+            if let Some(max) = max {
+                s["maxItems"] = max.into();
+            }
+            schema_dfa(s)
+        };
+        let list = |n: usize| format!("[{}]", vec!["1"; n].join(", "));
+        for (min, max) in [
+            (None, None),
+            (Some(1), Some(3)),
+            (Some(2), None),
+            (None, Some(0)),
+            (None, Some(1)),
+            (Some(1), Some(1)),
+            (Some(2), Some(2)),
+        ] {
+            let dfa = items(min, max);
+            for n in 0..6 {
+                let expected = n as u64 >= min.unwrap_or(0) && max.is_none_or(|m| n as u64 <= m);
+                assert_eq!(accepts(&dfa, &list(n)), expected, "{n} in {min:?}..{max:?}");
+            }
+        }
+        let dfa = items(None, None);
+        assert!(accepts(&dfa, "[1,2]") && !accepts(&dfa, "[1,]") && !accepts(&dfa, "[,]"));
+    }
+
+    #[test]
+    fn refs_and_nesting() {
+        // a chain of $refs, MAX_REF_DEPTH long
+        let mut defs = serde_json::Map::new();
+        for i in 0..MAX_REF_DEPTH {
+            defs.insert(
+                format!("d{i}"),
+                json!({"$ref": format!("#/$defs/d{}", i + 1)}),
+            );
+        }
+        defs.insert(format!("d{MAX_REF_DEPTH}"), json!({"type": "integer"}));
+        let mut schema = json!({"$defs": defs, "$ref": "#/$defs/d1"});
+        assert!(accepts(&schema_dfa(schema.clone()), "42"));
+        schema["$ref"] = json!("#/$defs/d0");
+        assert!(json_schema_to_regex(&schema).is_err());
+
+        let recursive = json!({
+            "$defs": {"node": {"type": "array", "items": {"$ref": "#/$defs/node"}}},
+            "$ref": "#/$defs/node"
+        });
+        assert!(json_schema_to_regex(&recursive).is_err());
+        assert!(json_schema_to_regex(&json!({"$ref": "#/$defs/missing"})).is_err());
+        assert!(json_schema_to_regex(&json!({"$ref": "http://example.com/s.json"})).is_err());
+
+        // plain nesting is not limited by MAX_REF_DEPTH
+        let n = 2 * MAX_REF_DEPTH;
+        let mut schema = json!({"type": "integer"});
+        for _ in 0..n {
+            schema = json!({"properties": {"a": schema}, "required": ["a"]});
+        }
+        let value = format!("{}7{}", r#"{"a":"#.repeat(n), "}".repeat(n));
+        assert!(accepts(&schema_dfa(schema), &value));
+        let mut schema = json!({"type": "integer"});
+        for _ in 0..n {
+            schema = json!({"type": "array", "items": schema, "maxItems": 1});
+        }
+        let value = format!("{}7{}", "[".repeat(n), "]".repeat(n));
+        assert!(accepts(&schema_dfa(schema), &value));
+
+        // but the size of the regex is
+        let mut schema = json!({"type": "integer"});
+        for _ in 0..n {
+            schema = json!({"type": "array", "items": schema});
+        }
+        assert!(json_schema_to_regex(&schema).is_err());
+    }
+}
@@ -0,0 +1,281 @@
+// Wrappers limiting the length of the output of a recognizer.
+//
+// At the maximum length, everything but EOS is forbidden, and EOS is allowed
+// even if the inner recognizer would not accept it (the output is cut short).
+// Below the minimum length, EOS is not allowed.
+
+use anyhow::{bail, Result};
+
+use super::blob::{BlobReader, BlobWriter};
+use crate::prelude::*;
+use crate::toktree::{Recognizer, SpecialToken};
+
+/// Limits the number of bytes of output of the inner recognizer.
+#[derive(Clone)]
+pub struct WithLengthLimit<A> {
+    a: A,
+    min_bytes: usize,
+    max_bytes: usize,
+    // bytes at the last collapse()
+    committed: usize,
+    // bytes pushed since then
+    depth: usize,
+}
+
+impl<A: Recognizer> WithLengthLimit<A> {
+    pub fn new(a: A, min_bytes: usize, max_bytes: usize) -> Self {
+        assert!(min_bytes <= max_bytes);
+        WithLengthLimit {
+            a,
+            min_bytes,
+            max_bytes,
+            committed: 0,
+            depth: 0,
+        }
+    }
+
+    /// Number of bytes in stack.top().
+    pub fn num_bytes(&self) -> usize {
+        self.committed + self.depth
+    }
+
+    fn at_max(&self) -> bool {
+        self.num_bytes() >= self.max_bytes
+    }
+}
+
+impl<A: Recognizer> Recognizer for WithLengthLimit<A> {
+    fn pop_bytes(&mut self, num: usize) {
+        self.a.pop_bytes(num);
+        self.depth -= num;
+    }
+
+    fn collapse(&mut self) {
+        self.a.collapse();
+        self.committed += self.depth;
+        self.depth = 0;
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        match tok {
+            SpecialToken::EndOfSentence => {
+                self.num_bytes() >= self.min_bytes && (self.at_max() || self.a.special_allowed(tok))
+            }
+            _ => !self.at_max() && self.a.special_allowed(tok),
+        }
+    }
+
+    fn trie_started(&mut self) {
+        self.a.trie_started();
+    }
+
+    fn trie_finished(&mut self) {
+        self.a.trie_finished();
+        self.depth = 0;
+    }
+
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        if !self.at_max() && self.a.try_push_byte(byte) {
+            self.depth += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn all_bytes_allowed(&mut self) -> bool {
+        // the limit will kick in at some point
+        false
+    }
+
+    fn no_bytes_allowed(&mut self) -> bool {
+        self.at_max() || self.a.no_bytes_allowed()
+    }
+
+    fn byte_weight(&mut self, byte: u8) -> f32 {
+        self.a.byte_weight(byte)
+    }
+
+    fn get_error(&mut self) -> Option<String> {
+        self.a.get_error()
+    }
+
+    fn captures(&mut self) -> Vec<(String, Vec<u8>)> {
+        self.a.captures()
+    }
+
+    fn state_description(&mut self) -> Option<String> {
+        if self.at_max() {
+            return Some(format!("at the limit of {} bytes", self.max_bytes));
+        }
+        self.a.state_description()
+    }
+
+    fn save_state(&mut self) -> Result<Vec<u8>> {
+        Ok(BlobWriter::new()
+            .u32(self.num_bytes() as u32)
+            .bytes(&self.a.save_state()?)
+            .finish())
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> Result<()> {
+        let mut r = BlobReader::new(state);
+        let committed = r.u32()? as usize;
+        if committed > self.max_bytes {
+            bail!("invalid WithLengthLimit state");
+        }
+        self.a.restore_state(r.bytes()?)?;
+        r.finish()?;
+        self.committed = committed;
+        self.depth = 0;
+        Ok(())
+    }
+}
+
+/// Limits the number of tokens of output of the inner recognizer.
+/// Tokens are counted on collapse(), which TokTrie::append_token() calls once per token;
+/// tokens it rejects (collapsing right after a failed byte) are not counted.
+#[derive(Clone)]
+pub struct WithTokenLimit<A> {
+    a: A,
+    min_tokens: usize,
+    max_tokens: usize,
+    num_tokens: usize,
+    // the last try_push_byte() failed
+    rejected: bool,
+}
+
+impl<A: Recognizer> WithTokenLimit<A> {
+    pub fn new(a: A, min_tokens: usize, max_tokens: usize) -> Self {
+        assert!(min_tokens <= max_tokens);
+        WithTokenLimit {
+            a,
+            min_tokens,
+            max_tokens,
+            num_tokens: 0,
+            rejected: false,
+        }
+    }
+
+    pub fn num_tokens(&self) -> usize {
+        self.num_tokens
+    }
+
+    fn at_max(&self) -> bool {
+        self.num_tokens >= self.max_tokens
+    }
+}
+
+impl<A: Recognizer> Recognizer for WithTokenLimit<A> {
+    fn pop_bytes(&mut self, num: usize) {
+        self.a.pop_bytes(num);
+        self.rejected = false;
+    }
+
+    fn collapse(&mut self) {
+        self.a.collapse();
+        if !self.rejected {
+            self.num_tokens += 1;
+        }
+        self.rejected = false;
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        match tok {
+            SpecialToken::EndOfSentence => {
+                self.num_tokens >= self.min_tokens && (self.at_max() || self.a.special_allowed(tok))
+            }
+            _ => !self.at_max() && self.a.special_allowed(tok),
+        }
+    }
+
+    fn trie_started(&mut self) {
+        self.a.trie_started();
+        self.rejected = false;
+    }
+
+    fn trie_finished(&mut self) {
+        self.a.trie_finished();
+        self.rejected = false;
+    }
+
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        let ok = !self.at_max() && self.a.try_push_byte(byte);
+        self.rejected = !ok;
+        ok
+    }
+
+    fn all_bytes_allowed(&mut self) -> bool {
+        !self.at_max() && self.a.all_bytes_allowed()
+    }
+
+    fn no_bytes_allowed(&mut self) -> bool {
+        self.at_max() || self.a.no_bytes_allowed()
+    }
+
+    fn byte_weight(&mut self, byte: u8) -> f32 {
+        self.a.byte_weight(byte)
+    }
+
+    fn get_error(&mut self) -> Option<String> {
+        self.a.get_error()
+    }
+
+    fn captures(&mut self) -> Vec<(String, Vec<u8>)> {
+        self.a.captures()
+    }
+
+    fn state_description(&mut self) -> Option<String> {
+        if self.at_max() {
+            return Some(format!("at the limit of {} tokens", self.max_tokens));
+        }
+        self.a.state_description()
+    }
+
+    fn save_state(&mut self) -> Result<Vec<u8>> {
+        Ok(BlobWriter::new()
+            .u32(self.num_tokens as u32)
+            .bytes(&self.a.save_state()?)
+            .finish())
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> Result<()> {
+        let mut r = BlobReader::new(state);
+        let num_tokens = r.u32()? as usize;
+        if num_tokens > self.max_tokens {
+            bail!("invalid WithTokenLimit state");
+        }
+        self.a.restore_state(r.bytes()?)?;
+        r.finish()?;
+        self.num_tokens = num_tokens;
+        self.rejected = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recognizer::OneOf;
+    use crate::{TestTokEnv, TokenizerEnv};
+
+    #[test]
+    fn token_limit_skips_rejected_tokens() {
+        let env = TestTokEnv::with_words(&["ab", "abab"]);
+        let trie = env.tok_trie();
+        let tok = |s: &str| env.token(s).unwrap();
+        let mut r = WithTokenLimit::new(OneOf::new(&["ababab"]).to_recognizer(), 0, 2);
+
+        assert!(trie.append_token(&mut r, tok("x")).is_err());
+        assert_eq!(r.num_tokens(), 0);
+        trie.append_token(&mut r, tok("ab")).unwrap();
+        assert_eq!(r.num_tokens(), 1);
+        assert!(trie.token_allowed(&mut r, tok("abab")));
+        trie.append_token(&mut r, tok("abab")).unwrap();
+        assert_eq!(r.num_tokens(), 2);
+        // at the limit, everything is rejected and still not counted
+        assert!(trie.append_token(&mut r, tok("ab")).is_err());
+        assert_eq!(r.num_tokens(), 2);
+        assert!(r.special_allowed(SpecialToken::EndOfSentence));
+    }
+}
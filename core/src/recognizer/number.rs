@@ -0,0 +1,341 @@
+use anyhow::{bail, Result};
+
+use super::{
+    blob::{BlobReader, BlobWriter},
+    FunctionalRecognizer, StackRecognizer,
+};
+use crate::prelude::*;
+use crate::{math, SpecialToken};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Phase {
+    Start,
+    Minus,
+    Int,
+    Dot,
+    Frac,
+}
+
+/// State of NumberRange; int and frac are the digits so far, with frac_digits digits after the dot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NumberState {
+    phase: Phase,
+    neg: bool,
+    int: u128,
+    frac: u128,
+    frac_digits: u32,
+}
+
+/// Recognizer for decimal numbers within an inclusive range, with at most
+/// `decimals` digits after the dot (none for integers).
+/// Leading zeros ("007") and "-0" are not allowed, and neither is "+" or an exponent;
+/// "1", "1.5" and "1.50" are all fine for decimals=2.
+/// Everything is computed on integers scaled by 10^decimals, so there are no rounding issues.
+#[derive(Clone)]
+pub struct NumberRange {
+    // both scaled by 10^decimals
+    min: i128,
+    max: i128,
+    decimals: u32,
+}
+
+pub type NumberRangeRecognizer = StackRecognizer<NumberState, NumberRange>;
+
+impl NumberRange {
+    pub const MAX_DECIMALS: u32 = 18;
+
+    /// Integers between min and max.
+    pub fn integer(min: i64, max: i64) -> Self {
+        assert!(min <= max);
+        NumberRange {
+            min: min as i128,
+            max: max as i128,
+            decimals: 0,
+        }
+    }
+
+    /// Numbers between min and max (rounded to given number of decimals).
+    pub fn decimal(min: f64, max: f64, decimals: u32) -> Self {
+        assert!(decimals <= Self::MAX_DECIMALS);
+        let scale = math::powi(10.0, decimals as i32);
+        let min = math::round(min * scale) as i128;
+        let max = math::round(max * scale) as i128;
+        assert!(min <= max);
+        NumberRange { min, max, decimals }
+    }
+
+    pub fn to_recognizer(self) -> NumberRangeRecognizer {
+        StackRecognizer::from(self)
+    }
+
+    fn scale(&self) -> u128 {
+        10u128.pow(self.decimals)
+    }
+
+    // range of allowed absolute (scaled) values for given sign
+    fn abs_range(&self, neg: bool) -> Option<(u128, u128)> {
+        let (lo, hi) = if neg {
+            // no "-0"
+            (core::cmp::max(1, -self.max), -self.min)
+        } else {
+            (core::cmp::max(0, self.min), self.max)
+        };
+        if lo <= hi {
+            Some((lo as u128, hi as u128))
+        } else {
+            None
+        }
+    }
+
+    // value of the digits so far, scaled
+    fn value(&self, st: &NumberState) -> Option<u128> {
+        let frac = st.frac * 10u128.pow(self.decimals - st.frac_digits);
+        st.int.checked_mul(self.scale())?.checked_add(frac)
+    }
+
+    // check if some completion of st is in range
+    fn viable(&self, st: &NumberState) -> bool {
+        if st.phase == Phase::Start {
+            return self.abs_range(false).is_some() || self.abs_range(true).is_some();
+        }
+        let (lo, hi) = match self.abs_range(st.neg) {
+            Some(r) => r,
+            None => return false,
+        };
+        let overlaps = |a: u128, b: u128| a <= hi && lo <= b;
+        let scale = self.scale();
+        match st.phase {
+            Phase::Start | Phase::Minus => true,
+            Phase::Int => {
+                // any number of additional integer digits, followed by any fraction
+                let mut span = 1u128;
+                loop {
+                    let first = match st.int.checked_mul(span * scale) {
+                        Some(v) if v <= hi => v,
+                        _ => return false,
+                    };
+                    if overlaps(first, first + span * scale - 1) {
+                        return true;
+                    }
+                    if st.int == 0 {
+                        return false;
+                    }
+                    span = match span.checked_mul(10) {
+                        Some(s) if s.checked_mul(scale).is_some() => s,
+                        _ => return false,
+                    };
+                }
+            }
+            Phase::Dot | Phase::Frac => match self.value(st) {
+                Some(v) => overlaps(v, v + 10u128.pow(self.decimals - st.frac_digits) - 1),
+                None => false,
+            },
+        }
+    }
+}
+
+impl FunctionalRecognizer<NumberState> for NumberRange {
+    fn initial(&self) -> NumberState {
+        NumberState {
+            phase: Phase::Start,
+            neg: false,
+            int: 0,
+            frac: 0,
+            frac_digits: 0,
+        }
+    }
+
+    fn try_append(&self, st: NumberState, byte: u8) -> Option<NumberState> {
+        let digit = if byte.is_ascii_digit() {
+            Some((byte - b'0') as u128)
+        } else {
+            None
+        };
+        let next = match (st.phase, byte, digit) {
+            (Phase::Start, b'-', _) => NumberState {
+                phase: Phase::Minus,
+                neg: true,
+                ..st
+            },
+            (Phase::Start | Phase::Minus, _, Some(d)) => NumberState {
+                phase: Phase::Int,
+                int: d,
+                ..st
+            },
+            // no leading zeros
+            (Phase::Int, _, Some(_)) if st.int == 0 => return None,
+            (Phase::Int, _, Some(d)) => NumberState {
+                int: st.int.checked_mul(10)?.checked_add(d)?,
+                ..st
+            },
+            (Phase::Int, b'.', _) if self.decimals > 0 => NumberState {
+                phase: Phase::Dot,
+                ..st
+            },
+            (Phase::Dot | Phase::Frac, _, Some(d)) if st.frac_digits < self.decimals => {
+                NumberState {
+                    phase: Phase::Frac,
+                    frac: st.frac * 10 + d,
+                    frac_digits: st.frac_digits + 1,
+                    ..st
+                }
+            }
+            _ => return None,
+        };
+        if self.viable(&next) {
+            Some(next)
+        } else {
+            None
+        }
+    }
+
+    fn special_allowed(&self, st: NumberState, tok: SpecialToken) -> bool {
+        match tok {
+            SpecialToken::EndOfSentence => {
+                matches!(st.phase, Phase::Int | Phase::Frac)
+                    && match (self.value(&st), self.abs_range(st.neg)) {
+                        (Some(v), Some((lo, hi))) => lo <= v && v <= hi,
+                        _ => false,
+                    }
+            }
+            _ => false,
+        }
+    }
+
+    fn no_bytes_allowed(&self, st: NumberState) -> bool {
+        (b'-'..=b'9').all(|b| self.try_append(st, b).is_none())
+    }
+
+    fn state_description(&self, _st: NumberState) -> Option<String> {
+        if self.decimals == 0 {
+            return Some(format!("integer between {} and {}", self.min, self.max));
+        }
+        let scale = self.scale() as f64;
+        Some(format!(
+            "number between {} and {} with at most {} decimals",
+            self.min as f64 / scale,
+            self.max as f64 / scale,
+            self.decimals
+        ))
+    }
+
+    fn save_state(&self, st: NumberState) -> Result<Vec<u8>> {
+        Ok(BlobWriter::new()
+            .u8(st.phase as u8)
+            .u8(st.neg as u8)
+            .u128(st.int)
+            .u128(st.frac)
+            .u32(st.frac_digits)
+            .finish())
+    }
+
+    fn restore_state(&self, data: &[u8]) -> Result<NumberState> {
+        let mut r = BlobReader::new(data);
+        let phase = match r.u8()? {
+            0 => Phase::Start,
+            1 => Phase::Minus,
+            2 => Phase::Int,
+            3 => Phase::Dot,
+            4 => Phase::Frac,
+            p => bail!("invalid NumberRange phase {}", p),
+        };
+        let st = NumberState {
+            phase,
+            neg: r.u8()? != 0,
+            int: r.u128()?,
+            frac: r.u128()?,
+            frac_digits: r.u32()?,
+        };
+        r.finish()?;
+        if st.frac_digits > self.decimals {
+            bail!("invalid NumberRange state");
+        }
+        Ok(st)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recognizer::push_str;
+    use crate::{Recognizer, TestTokEnv};
+
+    // value scaled by 10^decimals, if s is a well-formed number
+    fn parse(s: &str, decimals: u32) -> Option<i128> {
+        let (neg, s) = match s.strip_prefix('-') {
+            Some(s) => (true, s),
+            None => (false, s),
+        };
+        let (int, frac) = match s.split_once('.') {
+            Some((i, f)) => (i, Some(f)),
+            None => (s, None),
+        };
+        let digits = |d: &str| !d.is_empty() && d.bytes().all(|b| b.is_ascii_digit());
+        if !digits(int) || (int.len() > 1 && int.starts_with('0')) {
+            return None;
+        }
+        let mut v: i128 = int.parse::<i128>().ok()? * 10i128.pow(decimals);
+        if let Some(f) = frac {
+            if !digits(f) || f.len() > decimals as usize {
+                return None;
+            }
+            v += f.parse::<i128>().unwrap() * 10i128.pow(decimals - f.len() as u32);
+        }
+        if neg && v == 0 {
+            return None;
+        }
+        Some(if neg { -v } else { v })
+    }
+
+    fn strings(alphabet: &[u8], max_len: usize) -> Vec<String> {
+        let mut res = vec![String::new()];
+        let mut last = vec![String::new()];
+        for _ in 0..max_len {
+            last = last
+                .iter()
+                .flat_map(|s| alphabet.iter().map(move |&b| format!("{}{}", s, b as char)))
+                .collect();
+            res.extend(last.iter().cloned());
+        }
+        res
+    }
+
+    fn check_exhaustive(range: NumberRange, alphabet: &[u8], max_len: usize) {
+        let (min, max, decimals) = (range.min, range.max, range.decimals);
+        let rec = range.to_recognizer();
+        for s in strings(alphabet, max_len) {
+            let mut r = rec.clone();
+            let ok = push_str(&mut r, &s) && r.special_allowed(SpecialToken::EndOfSentence);
+            let expected = parse(&s, decimals).is_some_and(|v| min <= v && v <= max);
+            assert_eq!(ok, expected, "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn matches_reference() {
+        check_exhaustive(NumberRange::integer(-15, 123), b"-01235789", 4);
+        check_exhaustive(NumberRange::integer(7, 7), b"-078.", 3);
+        check_exhaustive(NumberRange::decimal(-1.5, 2.25, 2), b"-01259.", 5);
+        check_exhaustive(NumberRange::decimal(0.0, 0.5, 1), b"-0156.", 4);
+    }
+
+    #[test]
+    fn bias_and_state() {
+        let env = TestTokEnv::with_words(&["12", "-1", "0.", "1.5", "99", "100", "-"]);
+        let range = NumberRange::decimal(-10.0, 100.0, 1);
+        let mut r = range.clone().to_recognizer();
+        for prefix in ["", "-", "1", "10", "-1", "9.", "0.5"] {
+            let mut r = range.clone().to_recognizer();
+            assert!(push_str(&mut r, prefix), "{prefix}");
+            env.check_bias(&mut r).unwrap();
+        }
+        assert!(push_str(&mut r, "-9"));
+        let state = r.save_state().unwrap();
+        let mut r2 = range.to_recognizer();
+        r2.restore_state(&state).unwrap();
+        assert!(!r2.try_push_byte(b'9'));
+        assert!(push_str(&mut r2, ".5"));
+        assert!(r2.special_allowed(SpecialToken::EndOfSentence));
+        assert!(r2.restore_state(&state[1..]).is_err());
+    }
+}
@@ -0,0 +1,213 @@
+use anyhow::{bail, Result};
+
+use super::{
+    blob::{BlobReader, BlobWriter},
+    EnumerableRecognizer, FunctionalRecognizer, StackRecognizer,
+};
+use crate::prelude::*;
+use crate::SpecialToken;
+
+#[derive(Clone)]
+struct Node {
+    children: Vec<(u8, u32)>,
+    // index of the option ending here
+    option: Option<usize>,
+    // number of options going through this node
+    num_options: usize,
+}
+
+/// Recognizer for exactly one of given strings, followed by EOS.
+/// The state is a node in the prefix tree of the options.
+///
+/// When the output so far determines the rest of the string (e.g., only one option
+/// remains), forced_bytes() returns it, and TokTrie::compute_ff_tokens()
+/// turns it into tokens for Splice::ff_tokens.
+#[derive(Clone)]
+pub struct OneOf {
+    options: Vec<String>,
+    nodes: Vec<Node>,
+}
+
+pub type OneOfRecognizer = StackRecognizer<u32, OneOf>;
+
+impl OneOf {
+    pub fn new(options: &[&str]) -> Self {
+        let mut nodes = vec![Node {
+            children: Vec::new(),
+            option: None,
+            num_options: 0,
+        }];
+        for (idx, opt) in options.iter().enumerate() {
+            let mut n = 0;
+            nodes[0].num_options += 1;
+            for &b in opt.as_bytes() {
+                n = match nodes[n].children.iter().find(|(c, _)| *c == b) {
+                    Some((_, child)) => *child as usize,
+                    None => {
+                        let child = nodes.len();
+                        nodes.push(Node {
+                            children: Vec::new(),
+                            option: None,
+                            num_options: 0,
+                        });
+                        nodes[n].children.push((b, child as u32));
+                        child
+                    }
+                };
+                nodes[n].num_options += 1;
+            }
+            if nodes[n].option.is_none() {
+                nodes[n].option = Some(idx);
+            }
+        }
+        OneOf {
+            options: options.iter().map(|s| s.to_string()).collect(),
+            nodes,
+        }
+    }
+
+    pub fn to_recognizer(self) -> OneOfRecognizer {
+        StackRecognizer::from(self)
+    }
+
+    pub fn options(&self) -> &[String] {
+        &self.options
+    }
+
+    /// Number of options consistent with the output leading to given state.
+    pub fn num_remaining(&self, state: u32) -> usize {
+        self.nodes[state as usize].num_options
+    }
+
+    /// Index of the option that was output in full, if any.
+    pub fn selected(&self, state: u32) -> Option<usize> {
+        self.nodes[state as usize].option
+    }
+
+    /// Bytes that have to follow in given state, up to where the options diverge
+    /// or one of them ends.
+    pub fn forced_bytes(&self, state: u32) -> Vec<u8> {
+        let mut res = Vec::new();
+        let mut node = &self.nodes[state as usize];
+        while node.option.is_none() && node.children.len() == 1 {
+            let (b, child) = node.children[0];
+            res.push(b);
+            node = &self.nodes[child as usize];
+        }
+        res
+    }
+}
+
+impl FunctionalRecognizer<u32> for OneOf {
+    fn initial(&self) -> u32 {
+        0
+    }
+
+    fn try_append(&self, state: u32, byte: u8) -> Option<u32> {
+        self.nodes[state as usize]
+            .children
+            .iter()
+            .find(|(b, _)| *b == byte)
+            .map(|(_, child)| *child)
+    }
+
+    fn special_allowed(&self, state: u32, tok: SpecialToken) -> bool {
+        match tok {
+            SpecialToken::EndOfSentence => self.nodes[state as usize].option.is_some(),
+            _ => false,
+        }
+    }
+
+    fn no_bytes_allowed(&self, state: u32) -> bool {
+        self.nodes[state as usize].children.is_empty()
+    }
+
+    fn state_description(&self, state: u32) -> Option<String> {
+        Some(format!(
+            "{} of {} options remaining",
+            self.num_remaining(state),
+            self.options.len()
+        ))
+    }
+
+    fn save_state(&self, state: u32) -> Result<Vec<u8>> {
+        Ok(BlobWriter::new().u32(state).finish())
+    }
+
+    fn restore_state(&self, data: &[u8]) -> Result<u32> {
+        let mut r = BlobReader::new(data);
+        let state = r.u32()?;
+        r.finish()?;
+        if state as usize >= self.nodes.len() {
+            bail!("invalid OneOf state {}", state);
+        }
+        Ok(state)
+    }
+}
+
+impl EnumerableRecognizer for OneOf {
+    fn num_states(&self) -> usize {
+        self.nodes.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recognizer::push_str;
+    use crate::{Recognizer, TestTokEnv, TokenizerEnv};
+
+    #[test]
+    fn options_and_forced_bytes() {
+        let one_of = OneOf::new(&["yes", "no", "nope", "yes"]);
+        assert_eq!(one_of.num_remaining(0), 4);
+        assert_eq!(one_of.forced_bytes(0), b"");
+        let mut r = one_of.clone().to_recognizer();
+        assert!(push_str(&mut r, "y"));
+        let state = r.save_state().unwrap();
+        let state = one_of.restore_state(&state).unwrap();
+        assert_eq!(one_of.forced_bytes(state), b"es");
+        assert_eq!(one_of.num_remaining(state), 2);
+        assert!(push_str(&mut r, "es"));
+        assert!(r.special_allowed(SpecialToken::EndOfSentence));
+        assert!(r.no_bytes_allowed());
+
+        let mut r = one_of.clone().to_recognizer();
+        assert!(push_str(&mut r, "no"));
+        assert!(r.special_allowed(SpecialToken::EndOfSentence));
+        let node = one_of.restore_state(&r.save_state().unwrap()).unwrap();
+        assert_eq!(one_of.selected(node), Some(1));
+        assert_eq!(one_of.forced_bytes(node), b"");
+        assert!(!push_str(&mut r, "x"));
+        assert!(one_of.restore_state(&[0xff; 4]).is_err());
+        assert!(one_of.restore_state(&[0; 5]).is_err());
+
+        let empty = OneOf::new(&[]).to_recognizer();
+        let env = TestTokEnv::with_words(&[]);
+        let mut r = empty;
+        env.check_bias(&mut r).unwrap();
+        assert!(!r.special_allowed(SpecialToken::EndOfSentence));
+    }
+
+    #[test]
+    fn bias_and_ff_tokens() {
+        let env = TestTokEnv::with_words(&["yes", "ye", "no", "nope", "pe", "s"]);
+        let trie = env.tok_trie();
+        let one_of = OneOf::new(&["yes", "no", "nope"]);
+        for prefix in ["", "y", "n", "no", "nop"] {
+            let mut r = one_of.clone().to_recognizer();
+            assert!(push_str(&mut r, prefix));
+            env.check_bias(&mut r).unwrap();
+        }
+        let mut r = one_of.clone().to_recognizer();
+        assert!(push_str(&mut r, "y"));
+        let toks = trie.compute_ff_tokens(&mut r, |b| env.tokenize_bytes(b));
+        assert_eq!(trie.decode(&toks), b"es");
+        // the recognizer is left in its state
+        env.check_bias(&mut r).unwrap();
+        let mut r = one_of.to_recognizer();
+        assert!(push_str(&mut r, "n"));
+        let toks = trie.compute_ff_tokens(&mut r, |b| env.tokenize_bytes(b));
+        assert_eq!(trie.decode(&toks), b"o");
+    }
+}
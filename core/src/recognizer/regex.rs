@@ -0,0 +1,133 @@
+use anyhow::{anyhow, bail, Result};
+use regex_automata::{
+    dfa::{dense, Automaton, StartKind},
+    util::{primitives::StateID, start},
+    Anchored,
+};
+
+use super::{
+    blob::{BlobReader, BlobWriter},
+    FunctionalRecognizer, StackRecognizer,
+};
+use crate::prelude::*;
+use crate::{FxHashSet, SpecialToken};
+
+/// Regex compiled to a dense DFA, matched against the whole output (anchored at both ends).
+/// EOS is allowed once the output so far matches the regex.
+#[derive(Clone)]
+pub struct RegexDfa {
+    dfa: dense::DFA<Vec<u32>>,
+    start: StateID,
+    // states reachable from start (sorted), the only ones restore_state() accepts
+    states: Vec<StateID>,
+}
+
+pub type RegexRecognizer = StackRecognizer<StateID, RegexDfa>;
+
+impl RegexDfa {
+    pub fn new(pattern: &str) -> Result<Self> {
+        let dfa = dense::Builder::new()
+            .configure(dense::Config::new().start_kind(StartKind::Anchored))
+            // \z makes any bytes after a complete match lead to the dead state
+            .build(&format!("(?:{})\\z", pattern))
+            .map_err(|e| anyhow!("invalid regex {:?}: {}", pattern, e))?;
+        let start = dfa
+            .start_state(&start::Config::new().anchored(Anchored::Yes))
+            .map_err(|e| anyhow!("regex start state: {}", e))?;
+        let mut r = RegexDfa {
+            dfa,
+            start,
+            states: vec![],
+        };
+        r.states = r.reachable_states();
+        Ok(r)
+    }
+
+    fn reachable_states(&self) -> Vec<StateID> {
+        let mut seen = FxHashSet::default();
+        seen.insert(self.start);
+        let mut todo = vec![self.start];
+        while let Some(state) = todo.pop() {
+            for unit in self.dfa.byte_classes().representatives(..) {
+                if let Some(next) = unit.as_u8().and_then(|b| self.try_append(state, b)) {
+                    if seen.insert(next) {
+                        todo.push(next);
+                    }
+                }
+            }
+        }
+        let mut states: Vec<StateID> = seen.into_iter().collect();
+        states.sort();
+        states
+    }
+
+    pub fn to_recognizer(self) -> RegexRecognizer {
+        StackRecognizer::from(self)
+    }
+
+    /// Check if the output leading to `state` matches the regex.
+    pub fn is_accepting(&self, state: StateID) -> bool {
+        self.dfa.is_match_state(self.dfa.next_eoi_state(state))
+    }
+}
+
+impl FunctionalRecognizer<StateID> for RegexDfa {
+    fn initial(&self) -> StateID {
+        self.start
+    }
+
+    fn try_append(&self, state: StateID, byte: u8) -> Option<StateID> {
+        let next = self.dfa.next_state(state, byte);
+        if self.dfa.is_dead_state(next) || self.dfa.is_quit_state(next) {
+            None
+        } else {
+            Some(next)
+        }
+    }
+
+    fn special_allowed(&self, state: StateID, tok: SpecialToken) -> bool {
+        match tok {
+            SpecialToken::EndOfSentence => self.is_accepting(state),
+            _ => false,
+        }
+    }
+
+    fn no_bytes_allowed(&self, state: StateID) -> bool {
+        (0..=255).all(|b| self.try_append(state, b).is_none())
+    }
+
+    fn save_state(&self, state: StateID) -> Result<Vec<u8>> {
+        Ok(BlobWriter::new().u32(state.as_u32()).finish())
+    }
+
+    fn restore_state(&self, data: &[u8]) -> Result<StateID> {
+        let mut r = BlobReader::new(data);
+        let state = r.u32()?;
+        r.finish()?;
+        let state =
+            StateID::new(state as usize).map_err(|e| anyhow!("invalid regex state: {}", e))?;
+        if self.states.binary_search(&state).is_err() {
+            bail!("invalid regex state {}", state.as_u32());
+        }
+        Ok(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restore_state_checks_state() {
+        let dfa = RegexDfa::new("a[bc]*d").unwrap();
+        let state = dfa.try_append(dfa.initial(), b'a').unwrap();
+        let saved = dfa.save_state(state).unwrap();
+        assert_eq!(dfa.restore_state(&saved).unwrap(), state);
+
+        let max = dfa.states.iter().max().unwrap().as_u32();
+        for bad in [state.as_u32() + 1, max + dfa.dfa.stride() as u32, u32::MAX] {
+            let data = BlobWriter::new().u32(bad).finish();
+            assert!(dfa.restore_state(&data).is_err());
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use anyhow::{bail, Result};
+
+use super::{
+    blob::{BlobReader, BlobWriter},
+    FunctionalRecognizer, StackRecognizer,
+};
+use crate::prelude::*;
+use crate::SpecialToken;
+
+/// Recognizer for any output containing given byte string.
+/// The state is the length of the longest prefix of the needle the output ends with
+/// (KMP), or the needle length once it was found; EOS is allowed after that.
+#[derive(Clone)]
+pub struct Substring {
+    needle: Vec<u8>,
+    fail: Vec<u32>,
+}
+
+pub type SubstringRecognizer = StackRecognizer<u32, Substring>;
+
+impl Substring {
+    pub fn new(needle: &str) -> Self {
+        let needle = needle.as_bytes().to_vec();
+        let mut fail = vec![0u32; needle.len()];
+        let mut k = 0;
+        for i in 1..needle.len() {
+            while k > 0 && needle[i] != needle[k] {
+                k = fail[k - 1] as usize;
+            }
+            if needle[i] == needle[k] {
+                k += 1;
+            }
+            fail[i] = k as u32;
+        }
+        Substring { needle, fail }
+    }
+
+    pub fn to_recognizer(self) -> SubstringRecognizer {
+        StackRecognizer::from(self)
+    }
+
+    fn found(&self, state: u32) -> bool {
+        state as usize == self.needle.len()
+    }
+}
+
+impl FunctionalRecognizer<u32> for Substring {
+    fn initial(&self) -> u32 {
+        0
+    }
+
+    fn try_append(&self, state: u32, byte: u8) -> Option<u32> {
+        if self.found(state) {
+            return Some(state);
+        }
+        let mut k = state as usize;
+        while k > 0 && self.needle[k] != byte {
+            k = self.fail[k - 1] as usize;
+        }
+        if self.needle[k] == byte {
+            k += 1;
+        }
+        Some(k as u32)
+    }
+
+    fn special_allowed(&self, state: u32, tok: SpecialToken) -> bool {
+        match tok {
+            SpecialToken::EndOfSentence => self.found(state),
+            _ => false,
+        }
+    }
+
+    fn all_bytes_allowed(&self, _state: u32) -> bool {
+        true
+    }
+
+    fn save_state(&self, state: u32) -> Result<Vec<u8>> {
+        Ok(BlobWriter::new().u32(state).finish())
+    }
+
+    fn restore_state(&self, data: &[u8]) -> Result<u32> {
+        let mut r = BlobReader::new(data);
+        let state = r.u32()?;
+        r.finish()?;
+        if state as usize > self.needle.len() {
+            bail!("invalid Substring state {}", state);
+        }
+        Ok(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recognizer::push_str;
+    use crate::{Recognizer, TestTokEnv};
+
+    #[test]
+    fn matches_contains() {
+        for needle in ["abab", "aab", "a", ""] {
+            let rec = Substring::new(needle).to_recognizer();
+            let mut strings = vec![String::new()];
+            for _ in 0..7 {
+                let mut next = vec![];
+                for s in &strings {
+                    let mut r = rec.clone();
+                    assert!(push_str(&mut r, s));
+                    assert_eq!(
+                        r.special_allowed(SpecialToken::EndOfSentence),
+                        s.contains(needle),
+                        "{needle:?} in {s:?}"
+                    );
+                    next.push(format!("{s}a"));
+                    next.push(format!("{s}b"));
+                }
+                strings = next;
+            }
+        }
+    }
+
+    #[test]
+    fn bias_and_state() {
+        let env = TestTokEnv::with_words(&["ab", "ba", "bab", "abab"]);
+        let sub = Substring::new("abab");
+        let mut r = sub.clone().to_recognizer();
+        for b in "ababx".bytes() {
+            env.check_bias(&mut r).unwrap();
+            assert!(r.try_push_byte(b));
+            r.collapse();
+        }
+        let mut r = sub.clone().to_recognizer();
+        assert!(push_str(&mut r, "aba"));
+        let state = sub.restore_state(&r.save_state().unwrap()).unwrap();
+        assert_eq!(state, 3);
+        assert!(sub.restore_state(&5u32.to_le_bytes()).is_err());
+    }
+}
@@ -0,0 +1,326 @@
+use anyhow::{bail, Result};
+
+use crate::prelude::*;
+use crate::{FxHashMap, Recognizer, SimpleVob, TokTrie, TokenId};
+
+/// Constraint operating on whole tokens, rather than bytes.
+/// It is combined with a byte-level Recognizer by TokenLevel.
+pub trait TokenConstraint {
+    /// Adjust the set of tokens allowed by the byte-level recognizer.
+    /// Tokens can be removed (ban-lists) or added (see bypass()).
+    fn filter_tokens(&mut self, trie: &TokTrie, allowed: &mut SimpleVob);
+    /// If true, the token is not passed to the byte-level recognizer
+    /// (e.g., a special token whose bytes would not be accepted there).
+    fn bypass(&self, _tok: TokenId) -> bool {
+        false
+    }
+    /// Called after the token was appended; fail if it is not allowed.
+    fn token_appended(&mut self, tok: TokenId) -> Result<()>;
+}
+
+/// Only tokens from the set are allowed.
+/// With a limit, the restriction only applies to the next `limit` tokens.
+pub struct AllowTokens {
+    tokens: SimpleVob,
+    left: Option<usize>,
+}
+
+impl AllowTokens {
+    pub fn new(tokens: SimpleVob) -> Self {
+        AllowTokens { tokens, left: None }
+    }
+
+    pub fn with_limit(mut self, limit: usize) -> Self {
+        self.left = Some(limit);
+        self
+    }
+
+    fn active(&self) -> bool {
+        self.left != Some(0)
+    }
+}
+
+impl TokenConstraint for AllowTokens {
+    fn filter_tokens(&mut self, _trie: &TokTrie, allowed: &mut SimpleVob) {
+        if self.active() {
+            allowed.and(&self.tokens);
+        }
+    }
+
+    fn token_appended(&mut self, tok: TokenId) -> Result<()> {
+        if self.active() {
+            if !self.tokens.is_allowed(tok) {
+                bail!("token {} not in the allowed set", tok);
+            }
+            if let Some(left) = self.left.as_mut() {
+                *left -= 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Tokens from the set are never allowed.
+pub struct BanTokens {
+    tokens: SimpleVob,
+}
+
+impl BanTokens {
+    pub fn new(tokens: SimpleVob) -> Self {
+        BanTokens { tokens }
+    }
+}
+
+impl TokenConstraint for BanTokens {
+    fn filter_tokens(&mut self, _trie: &TokTrie, allowed: &mut SimpleVob) {
+        allowed.sub(&self.tokens);
+    }
+
+    fn token_appended(&mut self, tok: TokenId) -> Result<()> {
+        if self.tokens.is_allowed(tok) {
+            bail!("token {} is banned", tok);
+        }
+        Ok(())
+    }
+}
+
+/// Force the given tokens (typically special tokens), one at a time, and then
+/// defer to the byte-level recognizer.
+/// Forced tokens are not passed to the byte-level recognizer.
+pub struct ForceTokens {
+    tokens: Vec<TokenId>,
+    pos: usize,
+}
+
+impl ForceTokens {
+    pub fn new(tokens: &[TokenId]) -> Self {
+        ForceTokens {
+            tokens: tokens.to_vec(),
+            pos: 0,
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.pos >= self.tokens.len()
+    }
+}
+
+impl TokenConstraint for ForceTokens {
+    fn filter_tokens(&mut self, _trie: &TokTrie, allowed: &mut SimpleVob) {
+        if !self.is_done() {
+            allowed.set_all(false);
+            allowed.allow_token(self.tokens[self.pos]);
+        }
+    }
+
+    fn bypass(&self, tok: TokenId) -> bool {
+        !self.is_done() && self.tokens[self.pos] == tok
+    }
+
+    fn token_appended(&mut self, tok: TokenId) -> Result<()> {
+        if !self.is_done() {
+            if self.tokens[self.pos] != tok {
+                bail!("expected token {}, got {}", self.tokens[self.pos], tok);
+            }
+            self.pos += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Bans tokens that would complete an n-gram (of tokens) that already occurred,
+/// as in the usual no_repeat_ngram_size sampling option.
+/// Stop tokens are never banned.
+pub struct NoRepeatNgram {
+    n: usize,
+    history: Vec<TokenId>,
+    // (n-1)-gram -> tokens that followed it
+    seen: FxHashMap<Vec<TokenId>, Vec<TokenId>>,
+}
+
+impl NoRepeatNgram {
+    pub fn new(n: usize) -> Self {
+        assert!(n > 0);
+        NoRepeatNgram {
+            n,
+            history: vec![],
+            seen: FxHashMap::default(),
+        }
+    }
+
+    /// Add tokens to the history without checking them (e.g., the prompt).
+    pub fn with_history(mut self, tokens: &[TokenId]) -> Self {
+        for &tok in tokens {
+            self.push_token(tok);
+        }
+        self
+    }
+
+    pub fn push_token(&mut self, tok: TokenId) {
+        self.history.push(tok);
+        let len = self.history.len();
+        if len >= self.n {
+            let key = self.history[len - self.n..len - 1].to_vec();
+            let next = self.seen.entry(key).or_default();
+            if !next.contains(&tok) {
+                next.push(tok);
+            }
+        }
+    }
+
+    /// Tokens that would complete an n-gram already in the history.
+    pub fn banned_tokens(&self) -> &[TokenId] {
+        let len = self.history.len();
+        if len + 1 < self.n {
+            return &[];
+        }
+        match self.seen.get(&self.history[len + 1 - self.n..]) {
+            Some(toks) => toks,
+            None => &[],
+        }
+    }
+}
+
+impl TokenConstraint for NoRepeatNgram {
+    fn filter_tokens(&mut self, trie: &TokTrie, allowed: &mut SimpleVob) {
+        for &tok in self.banned_tokens() {
+            if !trie.is_stop_token(tok) {
+                allowed.disallow_token(tok);
+            }
+        }
+    }
+
+    // banned tokens may still end up appended (e.g., when forced), so they are just recorded
+    fn token_appended(&mut self, tok: TokenId) -> Result<()> {
+        self.push_token(tok);
+        Ok(())
+    }
+}
+
+/// Byte-level recognizer combined with a token-level constraint.
+/// The allowed set is computed by TokTrie::compute_bias() and then
+/// filtered by the constraint.
+pub struct TokenLevel<R: Recognizer, C: TokenConstraint> {
+    rec: R,
+    constraint: C,
+}
+
+impl<R: Recognizer, C: TokenConstraint> TokenLevel<R, C> {
+    pub fn new(rec: R, constraint: C) -> Self {
+        TokenLevel { rec, constraint }
+    }
+
+    pub fn recognizer(&mut self) -> &mut R {
+        &mut self.rec
+    }
+
+    pub fn constraint(&mut self) -> &mut C {
+        &mut self.constraint
+    }
+
+    pub fn compute_bias(&mut self, trie: &TokTrie, logits: &mut SimpleVob) {
+        trie.compute_bias(&mut self.rec, logits);
+        self.constraint.filter_tokens(trie, logits);
+    }
+
+    pub fn append_token(&mut self, trie: &TokTrie, tok: TokenId) -> Result<()> {
+        if !self.constraint.bypass(tok) {
+            trie.append_token(&mut self.rec, tok)?;
+        }
+        self.constraint.token_appended(tok)
+    }
+
+    pub fn append_tokens(&mut self, trie: &TokTrie, toks: &[TokenId]) -> Result<()> {
+        for &tok in toks {
+            self.append_token(trie, tok)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recognizer::{AnythingGoes, OneOf, StackRecognizer};
+    use crate::{TestTokEnv, TokenizerEnv};
+
+    fn env() -> TestTokEnv {
+        TestTokEnv::with_words(&["ab", "abc", "cd"])
+    }
+
+    fn allowed(
+        env: &TestTokEnv,
+        tl: &mut TokenLevel<impl Recognizer, impl TokenConstraint>,
+    ) -> Vec<String> {
+        let mut mask = env.tok_trie().alloc_token_set();
+        tl.compute_bias(env.tok_trie(), &mut mask);
+        env.allowed_names(&mask)
+    }
+
+    #[test]
+    fn allow_and_ban() {
+        let env = env();
+        let trie = env.tok_trie();
+        let ab = env.token("ab").unwrap();
+        let abc = env.token("abc").unwrap();
+        let set = SimpleVob::from_token_ids([ab, abc], trie.vocab_size());
+
+        let rec = OneOf::new(&["abcd", "ab"]).to_recognizer();
+        let mut tl = TokenLevel::new(rec, AllowTokens::new(set.clone()).with_limit(1));
+        assert_eq!(allowed(&env, &mut tl), ["\"ab\"", "\"abc\""]);
+        tl.append_token(trie, abc).unwrap();
+        // the limit is used up, so "d" from the byte-level recognizer is back
+        assert_eq!(allowed(&env, &mut tl), ["\"d\""]);
+
+        let mut tl = TokenLevel::new(StackRecognizer::from(AnythingGoes {}), BanTokens::new(set));
+        let names = allowed(&env, &mut tl);
+        assert!(!names.contains(&"\"ab\"".to_string()));
+        assert!(names.contains(&"\"cd\"".to_string()) && names.contains(&"EOS".to_string()));
+        assert!(tl.append_token(trie, ab).is_err());
+        tl.append_token(trie, env.token("cd").unwrap()).unwrap();
+    }
+
+    #[test]
+    fn force_tokens_bypass_recognizer() {
+        let env = env();
+        let trie = env.tok_trie();
+        let eos = trie.eos_token();
+        let force = || {
+            let rec = OneOf::new(&["cd"]).to_recognizer();
+            TokenLevel::new(rec, ForceTokens::new(&[eos, eos]))
+        };
+        assert!(force()
+            .append_token(trie, env.token("cd").unwrap())
+            .is_err());
+        let mut tl = force();
+        assert_eq!(allowed(&env, &mut tl), ["EOS"]);
+        tl.append_tokens(trie, &[eos, eos]).unwrap();
+        assert!(tl.constraint().is_done());
+        assert_eq!(allowed(&env, &mut tl), ["\"c\"", "\"cd\""]);
+    }
+
+    #[test]
+    fn no_repeat_ngram() {
+        let env = env();
+        let trie = env.tok_trie();
+        let [a, b, c] = [b'a', b'b', b'c'].map(|x| x as TokenId);
+        let eos = trie.eos_token();
+        let mut ngram = NoRepeatNgram::new(3).with_history(&[a, b, c, a, b, eos, a, b]);
+        let mut banned = ngram.banned_tokens().to_vec();
+        banned.sort();
+        assert_eq!(banned, vec![c, eos]);
+
+        let mut tl = TokenLevel::new(StackRecognizer::from(AnythingGoes {}), ngram);
+        let names = allowed(&env, &mut tl);
+        // stop tokens are never banned
+        assert!(names.contains(&"EOS".to_string()));
+        assert!(!names.contains(&"\"c\"".to_string()));
+        // banned tokens are still recorded when appended
+        tl.append_token(trie, c).unwrap();
+        assert_eq!(tl.constraint().banned_tokens(), &[a]);
+
+        ngram = NoRepeatNgram::new(1).with_history(&[a]);
+        assert_eq!(ngram.banned_tokens(), &[a]);
+        assert!(NoRepeatNgram::new(2).banned_tokens().is_empty());
+    }
+}
@@ -0,0 +1,410 @@
+use alloc::sync::Arc;
+
+use anyhow::{bail, Result};
+
+use super::{
+    blob::{BlobReader, BlobWriter},
+    EnumerableRecognizer, FunctionalRecognizer, StackRecognizer,
+};
+use crate::prelude::*;
+use crate::SpecialToken;
+
+/// Recognizer for a literal string, ignoring case.
+/// Each character can appear as itself, or its lower- or upper-case version,
+/// as long as that is a single character (so 'ß' doesn't match "SS").
+/// The state is a node in the graph of UTF-8 encodings of the variants.
+#[derive(Clone)]
+pub struct CaseInsensitive {
+    // children of every node; nodes 0..=num_chars are starts of characters
+    nodes: Vec<Vec<(u8, u32)>>,
+    num_chars: u32,
+}
+
+pub type CaseInsensitiveRecognizer = StackRecognizer<u32, CaseInsensitive>;
+
+fn single_char(mut it: impl Iterator<Item = char>) -> Option<char> {
+    let c = it.next()?;
+    if it.next().is_none() {
+        Some(c)
+    } else {
+        None
+    }
+}
+
+impl CaseInsensitive {
+    pub fn new(literal: &str) -> Self {
+        let chars: Vec<char> = literal.chars().collect();
+        // nodes 0..=chars.len() are the starts of characters
+        let mut nodes = vec![vec![]; chars.len() + 1];
+        for (idx, &c) in chars.iter().enumerate() {
+            let mut variants = vec![c];
+            variants.extend(single_char(c.to_lowercase()));
+            variants.extend(single_char(c.to_uppercase()));
+            variants.sort();
+            variants.dedup();
+            for v in variants {
+                let mut buf = [0; 4];
+                let bytes = v.encode_utf8(&mut buf).as_bytes();
+                let mut n = idx;
+                for (i, &b) in bytes.iter().enumerate() {
+                    let next = if i + 1 == bytes.len() {
+                        idx as u32 + 1
+                    } else {
+                        match nodes[n].iter().find(|(c, _)| *c == b) {
+                            Some((_, next)) => *next,
+                            None => {
+                                nodes.push(vec![]);
+                                (nodes.len() - 1) as u32
+                            }
+                        }
+                    };
+                    if !nodes[n].contains(&(b, next)) {
+                        nodes[n].push((b, next));
+                    }
+                    n = next as usize;
+                }
+            }
+        }
+        CaseInsensitive {
+            nodes,
+            num_chars: chars.len() as u32,
+        }
+    }
+
+    pub fn to_recognizer(self) -> CaseInsensitiveRecognizer {
+        StackRecognizer::from(self)
+    }
+}
+
+impl FunctionalRecognizer<u32> for CaseInsensitive {
+    fn initial(&self) -> u32 {
+        0
+    }
+
+    fn try_append(&self, state: u32, byte: u8) -> Option<u32> {
+        self.nodes[state as usize]
+            .iter()
+            .find(|(b, _)| *b == byte)
+            .map(|(_, next)| *next)
+    }
+
+    fn special_allowed(&self, state: u32, tok: SpecialToken) -> bool {
+        match tok {
+            SpecialToken::EndOfSentence => state == self.num_chars,
+            _ => false,
+        }
+    }
+
+    fn no_bytes_allowed(&self, state: u32) -> bool {
+        self.nodes[state as usize].is_empty()
+    }
+
+    fn save_state(&self, state: u32) -> Result<Vec<u8>> {
+        Ok(BlobWriter::new().u32(state).finish())
+    }
+
+    fn restore_state(&self, data: &[u8]) -> Result<u32> {
+        let mut r = BlobReader::new(data);
+        let state = r.u32()?;
+        r.finish()?;
+        if state as usize >= self.nodes.len() {
+            bail!("invalid CaseInsensitive state {}", state);
+        }
+        Ok(state)
+    }
+}
+
+impl EnumerableRecognizer for CaseInsensitive {
+    fn num_states(&self) -> usize {
+        self.nodes.len()
+    }
+}
+
+// set of code points, with rank for counting members in a range
+struct CodePointSet {
+    bits: Vec<u64>,
+    // number of members before each word
+    rank: Vec<u32>,
+}
+
+impl CodePointSet {
+    fn new(pred: impl Fn(char) -> bool) -> Self {
+        let num_words = (char::MAX as usize + 1).div_ceil(64);
+        let mut bits = vec![0u64; num_words];
+        for c in (0..=char::MAX as u32).filter_map(char::from_u32) {
+            if pred(c) {
+                bits[c as usize / 64] |= 1 << (c as u32 % 64);
+            }
+        }
+        let mut rank = Vec::with_capacity(num_words + 1);
+        let mut total = 0;
+        for w in &bits {
+            rank.push(total);
+            total += w.count_ones();
+        }
+        rank.push(total);
+        CodePointSet { bits, rank }
+    }
+
+    // number of members below cp
+    fn count_below(&self, cp: u32) -> u32 {
+        let (w, b) = (cp as usize / 64, cp % 64);
+        if w >= self.bits.len() {
+            return *self.rank.last().unwrap();
+        }
+        self.rank[w] + (self.bits[w] & ((1u64 << b) - 1)).count_ones()
+    }
+
+    fn any_in_range(&self, lo: u32, hi: u32) -> bool {
+        lo <= hi && self.count_below(hi + 1) > self.count_below(lo)
+    }
+}
+
+/// State of CharClass: number of complete characters, and the UTF-8 sequence in progress.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CharClassState {
+    count: u32,
+    // bits of the code point so far
+    partial: u32,
+    // length of the UTF-8 sequence in progress, and how many bytes of it are left
+    seq_len: u8,
+    left: u8,
+}
+
+/// Recognizer for between min and max (inclusive) characters from a Unicode class,
+/// working on UTF-8 bytes: a byte is only allowed if it can be completed
+/// to a character in the class.
+#[derive(Clone)]
+pub struct CharClass {
+    set: Arc<CodePointSet>,
+    min: u32,
+    max: u32,
+}
+
+pub type CharClassRecognizer = StackRecognizer<CharClassState, CharClass>;
+
+impl CharClass {
+    /// Characters for which pred returns true; this scans all code points once.
+    pub fn from_fn(pred: impl Fn(char) -> bool, min: u32, max: u32) -> Self {
+        assert!(min <= max);
+        CharClass {
+            set: Arc::new(CodePointSet::new(pred)),
+            min,
+            max,
+        }
+    }
+
+    /// Letters (char::is_alphabetic()).
+    pub fn letters(min: u32, max: u32) -> Self {
+        Self::from_fn(char::is_alphabetic, min, max)
+    }
+
+    /// Digits (char::is_numeric()).
+    pub fn digits(min: u32, max: u32) -> Self {
+        Self::from_fn(char::is_numeric, min, max)
+    }
+
+    /// Whitespace (char::is_whitespace()).
+    pub fn whitespace(min: u32, max: u32) -> Self {
+        Self::from_fn(char::is_whitespace, min, max)
+    }
+
+    pub fn to_recognizer(self) -> CharClassRecognizer {
+        StackRecognizer::from(self)
+    }
+
+    // range of code points starting with given bits, excluding overlong encodings
+    fn range(st: &CharClassState) -> (u32, u32) {
+        let lo = st.partial << (6 * st.left as u32);
+        let hi = lo | ((1 << (6 * st.left as u32)) - 1);
+        let min = match st.seq_len {
+            1 => 0,
+            2 => 0x80,
+            3 => 0x800,
+            _ => 0x10000,
+        };
+        (
+            core::cmp::max(lo, min),
+            core::cmp::min(hi, char::MAX as u32),
+        )
+    }
+}
+
+impl FunctionalRecognizer<CharClassState> for CharClass {
+    fn initial(&self) -> CharClassState {
+        CharClassState {
+            count: 0,
+            partial: 0,
+            seq_len: 0,
+            left: 0,
+        }
+    }
+
+    fn try_append(&self, st: CharClassState, byte: u8) -> Option<CharClassState> {
+        let next = if st.left == 0 {
+            if st.count >= self.max {
+                return None;
+            }
+            let (seq_len, bits) = match byte {
+                0x00..=0x7F => (1, byte as u32),
+                0xC0..=0xDF => (2, (byte & 0x1F) as u32),
+                0xE0..=0xEF => (3, (byte & 0x0F) as u32),
+                0xF0..=0xF7 => (4, (byte & 0x07) as u32),
+                _ => return None,
+            };
+            CharClassState {
+                partial: bits,
+                seq_len,
+                left: seq_len - 1,
+                ..st
+            }
+        } else {
+            if byte & 0xC0 != 0x80 {
+                return None;
+            }
+            CharClassState {
+                partial: (st.partial << 6) | (byte & 0x3F) as u32,
+                left: st.left - 1,
+                ..st
+            }
+        };
+        let (lo, hi) = Self::range(&next);
+        if !self.set.any_in_range(lo, hi) {
+            return None;
+        }
+        if next.left == 0 {
+            Some(CharClassState {
+                count: next.count + 1,
+                ..next
+            })
+        } else {
+            Some(next)
+        }
+    }
+
+    fn special_allowed(&self, st: CharClassState, tok: SpecialToken) -> bool {
+        match tok {
+            SpecialToken::EndOfSentence => st.left == 0 && st.count >= self.min,
+            _ => false,
+        }
+    }
+
+    fn no_bytes_allowed(&self, st: CharClassState) -> bool {
+        st.left == 0 && st.count >= self.max
+    }
+
+    fn save_state(&self, st: CharClassState) -> Result<Vec<u8>> {
+        Ok(BlobWriter::new()
+            .u32(st.count)
+            .u32(st.partial)
+            .u8(st.seq_len)
+            .u8(st.left)
+            .finish())
+    }
+
+    fn restore_state(&self, data: &[u8]) -> Result<CharClassState> {
+        let mut r = BlobReader::new(data);
+        let st = CharClassState {
+            count: r.u32()?,
+            partial: r.u32()?,
+            seq_len: r.u8()?,
+            left: r.u8()?,
+        };
+        r.finish()?;
+        if st.count > self.max || st.seq_len > 4 || st.left >= st.seq_len.max(1) {
+            bail!("invalid CharClass state");
+        }
+        Ok(st)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recognizer::push_str;
+    use crate::{Recognizer, TestTokEnv};
+
+    fn accepts(r: &(impl Recognizer + Clone), s: &[u8]) -> bool {
+        let mut r = r.clone();
+        s.iter().all(|&b| r.try_push_byte(b)) && r.special_allowed(SpecialToken::EndOfSentence)
+    }
+
+    #[test]
+    fn case_insensitive() {
+        let r = CaseInsensitive::new("Straße É").to_recognizer();
+        for s in ["straße é", "STRAßE É", "sTrAße é"] {
+            assert!(accepts(&r, s.as_bytes()), "{s}");
+        }
+        for s in ["STRASSE É", "straße e", "straße", "straße éé"] {
+            assert!(!accepts(&r, s.as_bytes()), "{s}");
+        }
+        let env = TestTokEnv::with_words(&["st", "ST", "ra", "Ra", "ße", "é", "É", "ss"]);
+        for prefix in ["", "s", "sT", "stra", "straß"] {
+            let mut r = r.clone();
+            assert!(push_str(&mut r, prefix));
+            env.check_bias(&mut r).unwrap();
+        }
+    }
+
+    #[test]
+    fn char_class_matches_predicate() {
+        let class = CharClass::from_fn(|c| c.is_numeric() || c == 'é', 1, 1).to_recognizer();
+        let samples = (0..=char::MAX as u32)
+            .step_by(61)
+            .chain([0x30, 0x39, 0xE9, 0x661, 0xFF10, 0x1D7CE, 0x10FFFF])
+            .filter_map(char::from_u32);
+        for c in samples {
+            let mut buf = [0; 4];
+            let bytes = c.encode_utf8(&mut buf).as_bytes();
+            assert_eq!(
+                accepts(&class, bytes),
+                c.is_numeric() || c == 'é',
+                "{:?}",
+                c
+            );
+        }
+        // overlong encodings, surrogates, and out of range code points
+        let any = CharClass::from_fn(|_| true, 0, 10).to_recognizer();
+        for bad in [
+            &b"\xC0\x80"[..],
+            b"\xED\xA0\x80",
+            b"\xF4\x90\x80\x80",
+            b"\x80",
+            b"\xF8",
+        ] {
+            assert!(!accepts(&any, bad), "{:?}", bad);
+        }
+        assert!(accepts(&any, "aé€😀".as_bytes()));
+    }
+
+    #[test]
+    fn char_class_counts_and_state() {
+        let r = CharClass::letters(2, 3).to_recognizer();
+        for s in ["ab", "éz", "abc", "Ωλψ"] {
+            assert!(accepts(&r, s.as_bytes()), "{s}");
+        }
+        for s in ["a", "abcd", "a1", ""] {
+            assert!(!accepts(&r, s.as_bytes()), "{s}");
+        }
+
+        let env = TestTokEnv::with_words(&["ab", "abc", "é", "éa", "1a", "Ωλ"]);
+        let mut r2 = r.clone();
+        env.check_bias(&mut r2).unwrap();
+        assert!(push_str(&mut r2, "a"));
+        env.check_bias(&mut r2).unwrap();
+        // in the middle of "é"
+        assert!(r2.try_push_byte(0xC3));
+        r2.collapse();
+        env.check_bias(&mut r2).unwrap();
+        let state = r2.save_state().unwrap();
+
+        let mut r3 = r.clone();
+        r3.restore_state(&state).unwrap();
+        assert!(!r3.try_push_byte(b'a'));
+        assert!(r3.try_push_byte(0xA9));
+        assert!(r3.special_allowed(SpecialToken::EndOfSentence));
+        let mut bad = state.clone();
+        *bad.last_mut().unwrap() = 7;
+        assert!(r3.restore_state(&bad).is_err());
+    }
+}
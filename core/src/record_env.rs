@@ -0,0 +1,257 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Mutex,
+};
+
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+
+use crate::{FxHashMap, TokEnv, TokError, TokTrie, TokenId, TokenizerEnv};
+
+/// Single tokenizer call with its result, see RecordingTokEnv.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum TokCall {
+    Tokenize {
+        input: Vec<u8>,
+        tokens: Vec<TokenId>,
+    },
+    TokenizeSpecial {
+        input: String,
+        tokens: Vec<TokenId>,
+    },
+    Decode {
+        tokens: Vec<TokenId>,
+        include_special: bool,
+        output: Vec<u8>,
+    },
+}
+
+/// Recorded tokenizer: the trie and all calls made, to be replayed with ReplayTokEnv.
+/// Serializes to JSON, with the trie in TokTrie::serialize() format (base64);
+/// added token metadata is not kept, but stop tokens are.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TokLog {
+    pub trie: String,
+    /// Same as TokTrie::stop_tokens() of the trie above, for reading the log.
+    pub stop_tokens: Vec<TokenId>,
+    pub canonical: bool,
+    pub calls: Vec<TokCall>,
+}
+
+/// TokenizerEnv that passes all calls to the base env, and records them with their results.
+pub struct RecordingTokEnv {
+    base_env: TokEnv,
+    calls: Mutex<Vec<TokCall>>,
+}
+
+impl RecordingTokEnv {
+    pub fn new(base_env: TokEnv) -> Self {
+        RecordingTokEnv {
+            base_env,
+            calls: Mutex::new(vec![]),
+        }
+    }
+
+    fn record(&self, call: TokCall) {
+        self.calls.lock().unwrap().push(call);
+    }
+
+    /// Snapshot of the trie and the calls recorded so far.
+    pub fn log(&self) -> TokLog {
+        let trie = self.base_env.tok_trie();
+        TokLog {
+            trie: base64::engine::general_purpose::STANDARD.encode(trie.serialize()),
+            stop_tokens: trie.stop_tokens().to_vec(),
+            canonical: self.base_env.tokenize_is_canonical(),
+            calls: self.calls.lock().unwrap().clone(),
+        }
+    }
+}
+
+impl TokenizerEnv for RecordingTokEnv {
+    fn tok_trie(&self) -> &TokTrie {
+        self.base_env.tok_trie()
+    }
+
+    fn tokenize_bytes(&self, s: &[u8]) -> Vec<TokenId> {
+        let tokens = self.base_env.tokenize_bytes(s);
+        self.record(TokCall::Tokenize {
+            input: s.to_vec(),
+            tokens: tokens.clone(),
+        });
+        tokens
+    }
+
+    fn tokenize_special(&self, s: &str) -> Vec<TokenId> {
+        let tokens = self.base_env.tokenize_special(s);
+        self.record(TokCall::TokenizeSpecial {
+            input: s.to_string(),
+            tokens: tokens.clone(),
+        });
+        tokens
+    }
+
+    fn decode_bytes_ext(&self, tokens: &[TokenId], include_special: bool) -> Vec<u8> {
+        let output = self.base_env.decode_bytes_ext(tokens, include_special);
+        self.record(TokCall::Decode {
+            tokens: tokens.to_vec(),
+            include_special,
+            output: output.clone(),
+        });
+        output
+    }
+
+    fn tokenize_is_canonical(&self) -> bool {
+        self.base_env.tokenize_is_canonical()
+    }
+}
+
+/// TokenizerEnv answering from a TokLog, without the original tokenizer.
+/// Inputs that were not recorded are tokenized greedily with the trie
+/// (and counted in num_misses()); try_tokenize_bytes() fails for them instead.
+pub struct ReplayTokEnv {
+    tok_trie: TokTrie,
+    canonical: bool,
+    tokenize: FxHashMap<Vec<u8>, Vec<TokenId>>,
+    tokenize_special: FxHashMap<String, Vec<TokenId>>,
+    decode: FxHashMap<(Vec<TokenId>, bool), Vec<u8>>,
+    misses: AtomicUsize,
+}
+
+impl ReplayTokEnv {
+    pub fn new(log: &TokLog) -> Result<Self> {
+        let bytes = base64::engine::general_purpose::STANDARD
+            .decode(&log.trie)
+            .map_err(|e| anyhow!("invalid trie in TokLog: {}", e))?;
+        // the log may come from anywhere, and the decoded bytes need not be aligned
+        let tok_trie = TokTrie::try_from_bytes(&bytes)
+            .map_err(|e| anyhow!("invalid trie in TokLog: {}", e))?;
+        let mut r = ReplayTokEnv {
+            // the serialized trie includes the stop tokens
+            tok_trie,
+            canonical: log.canonical,
+            tokenize: FxHashMap::default(),
+            tokenize_special: FxHashMap::default(),
+            decode: FxHashMap::default(),
+            misses: AtomicUsize::new(0),
+        };
+        for call in &log.calls {
+            match call {
+                TokCall::Tokenize { input, tokens } => {
+                    r.tokenize.insert(input.clone(), tokens.clone());
+                }
+                TokCall::TokenizeSpecial { input, tokens } => {
+                    r.tokenize_special.insert(input.clone(), tokens.clone());
+                }
+                TokCall::Decode {
+                    tokens,
+                    include_special,
+                    output,
+                } => {
+                    r.decode
+                        .insert((tokens.clone(), *include_special), output.clone());
+                }
+            }
+        }
+        Ok(r)
+    }
+
+    pub fn from_json(data: &[u8]) -> Result<Self> {
+        Self::new(&serde_json::from_slice(data)?)
+    }
+
+    /// Number of calls that were not found in the log.
+    pub fn num_misses(&self) -> usize {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    fn miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl TokenizerEnv for ReplayTokEnv {
+    fn tok_trie(&self) -> &TokTrie {
+        &self.tok_trie
+    }
+
+    fn tokenize_bytes(&self, s: &[u8]) -> Vec<TokenId> {
+        match self.tokenize.get(s) {
+            Some(t) => t.clone(),
+            None => {
+                self.miss();
+                self.tok_trie.greedy_tokenize(s)
+            }
+        }
+    }
+
+    fn try_tokenize_bytes(&self, s: &[u8]) -> Result<Vec<TokenId>> {
+        match self.tokenize.get(s) {
+            Some(t) => Ok(t.clone()),
+            None => {
+                self.miss();
+                Err(TokError::Backend(format!(
+                    "tokenization of {:?} not recorded",
+                    String::from_utf8_lossy(s)
+                ))
+                .into())
+            }
+        }
+    }
+
+    fn tokenize_special(&self, s: &str) -> Vec<TokenId> {
+        match self.tokenize_special.get(s) {
+            Some(t) => t.clone(),
+            None => self
+                .tok_trie
+                .tokenize_with_special(s.as_bytes(), |s| self.tokenize_bytes(s)),
+        }
+    }
+
+    fn decode_bytes_ext(&self, tokens: &[TokenId], include_special: bool) -> Vec<u8> {
+        match self.decode.get(&(tokens.to_vec(), include_special)) {
+            Some(b) => b.clone(),
+            None => self.tok_trie.decode_ext(tokens, include_special),
+        }
+    }
+
+    fn tokenize_is_canonical(&self) -> bool {
+        self.canonical
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TestTokEnv;
+    use std::sync::Arc;
+
+    #[test]
+    fn record_and_replay() {
+        let rec = RecordingTokEnv::new(Arc::new(TestTokEnv::with_words(&["ab", "abc"])));
+        let toks = rec.tokenize_bytes(b"abcab");
+        let log = rec.log();
+        assert_eq!(log.calls.len(), 1);
+
+        let json = serde_json::to_vec(&log).unwrap();
+        let replay = ReplayTokEnv::from_json(&json).unwrap();
+        assert_eq!(replay.tokenize_bytes(b"abcab"), toks);
+        assert_eq!(replay.num_misses(), 0);
+        assert_eq!(
+            replay.tok_trie().stop_tokens(),
+            rec.tok_trie().stop_tokens()
+        );
+        assert!(replay.try_tokenize_bytes(b"xyz").is_err());
+        assert_eq!(replay.num_misses(), 1);
+
+        let engine = base64::engine::general_purpose::STANDARD;
+        let trie = engine.decode(&log.trie).unwrap();
+        for bad in [&trie[..trie.len() - 1], &trie[..10]] {
+            let mut log = log.clone();
+            log.trie = engine.encode(bad);
+            assert!(ReplayTokEnv::new(&log).is_err());
+        }
+    }
+}
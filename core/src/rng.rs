@@ -1,7 +1,18 @@
+use crate::{math, SimpleVob, TokenId};
+
+#[derive(Clone, Debug)]
 pub struct Rng {
     state: usize,
 }
 
+// SplitMix64 finalizer; used to derive well-mixed seeds from related inputs
+fn splitmix64(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e3779b97f4a7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
 impl Rng {
     pub fn new(seed: usize) -> Self {
         Self {
@@ -9,6 +20,18 @@ impl Rng {
         }
     }
 
+    /// Generator for a sequence, given the seed provided by the host,
+    /// so that reruns with the same seed are reproducible.
+    pub fn for_sequence(seed: u64, seq_id: u64) -> Self {
+        Self::new(splitmix64(splitmix64(seed) ^ seq_id) as usize)
+    }
+
+    /// Independent, but reproducible, generator for given child (e.g., after a fork).
+    /// It only depends on the current state and child_idx, and does not advance self.
+    pub fn fork(&self, child_idx: u64) -> Self {
+        Self::new(splitmix64(splitmix64(self.state as u64) ^ child_idx) as usize)
+    }
+
     pub fn gen(&mut self) -> usize {
         // xor-shift algorithm
         #[cfg(target_pointer_width = "32")]
@@ -43,4 +66,206 @@ impl Rng {
             }
         }
     }
+
+    pub fn gen_u64(&mut self) -> u64 {
+        #[cfg(target_pointer_width = "32")]
+        {
+            ((self.gen() as u64) << 32) | (self.gen() as u64)
+        }
+        #[cfg(target_pointer_width = "64")]
+        {
+            self.gen() as u64
+        }
+    }
+
+    /// Uniform in [0.0, 1.0).
+    pub fn gen_f64(&mut self) -> f64 {
+        (self.gen_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Sample a token allowed by mask, with probabilities given by softmax(logits / temperature).
+    /// Temperature 0.0 means argmax.
+    /// Returns None if no token is allowed.
+    pub fn sample_categorical(
+        &mut self,
+        logits: &[f32],
+        mask: &SimpleVob,
+        temperature: f32,
+    ) -> Option<TokenId> {
+        let allowed = || {
+            mask.iter_set()
+                .filter(|&t| (t as usize) < logits.len())
+                .map(|t| (t, logits[t as usize]))
+        };
+        let max = allowed().map(|(_, l)| l).fold(f32::NEG_INFINITY, f32::max);
+        if temperature <= 0.0 {
+            return allowed().find(|(_, l)| *l == max).map(|(t, _)| t);
+        }
+        let weight = |l: f32| math::exp(((l - max) / temperature) as f64);
+        let total: f64 = allowed().map(|(_, l)| weight(l)).sum();
+        let mut point = self.gen_f64() * total;
+        let mut last = None;
+        for (t, l) in allowed() {
+            point -= weight(l);
+            last = Some(t);
+            if point < 0.0 {
+                break;
+            }
+        }
+        last
+    }
+
+    /// Same distribution as sample_categorical(), using the Gumbel-max trick:
+    /// argmax of logits / temperature plus Gumbel noise; no normalization is needed.
+    pub fn sample_gumbel(
+        &mut self,
+        logits: &[f32],
+        mask: &SimpleVob,
+        temperature: f32,
+    ) -> Option<TokenId> {
+        let mut best = None;
+        let mut best_score = f64::NEG_INFINITY;
+        for t in mask.iter_set() {
+            let l = match logits.get(t as usize) {
+                Some(&l) => l as f64,
+                None => continue,
+            };
+            let score = if temperature <= 0.0 {
+                l
+            } else {
+                // 1.0 - u is in (0.0, 1.0]
+                let u = 1.0 - self.gen_f64();
+                l / temperature as f64 - math::ln(-math::ln(u))
+            };
+            if best.is_none() || score > best_score {
+                best = Some(t);
+                best_score = score;
+            }
+        }
+        best
+    }
+}
+
+#[cfg(feature = "rand_core")]
+impl rand_core::RngCore for Rng {
+    fn next_u32(&mut self) -> u32 {
+        (self.gen_u64() >> 32) as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.gen_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        rand_core::impls::fill_bytes_via_next(self, dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "rand_core")]
+impl rand_core::SeedableRng for Rng {
+    type Seed = [u8; 8];
+
+    fn from_seed(seed: Self::Seed) -> Self {
+        Self::new(splitmix64(u64::from_le_bytes(seed)) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+
+    fn take(rng: &mut Rng, n: usize) -> Vec<u64> {
+        (0..n).map(|_| rng.gen_u64()).collect()
+    }
+
+    #[test]
+    fn sequence_streams() {
+        let a = take(&mut Rng::for_sequence(42, 0), 16);
+        assert_eq!(a, take(&mut Rng::for_sequence(42, 0), 16));
+        assert_ne!(a, take(&mut Rng::for_sequence(42, 1), 16));
+        assert_ne!(a, take(&mut Rng::for_sequence(43, 0), 16));
+        // seed 0 is mixed, not mapped to the all-zeros xor-shift state
+        assert!(take(&mut Rng::for_sequence(0, 0), 4)
+            .iter()
+            .all(|&x| x != 0));
+    }
+
+    #[test]
+    fn fork_is_stable() {
+        let mut parent = Rng::for_sequence(7, 3);
+        take(&mut parent, 5);
+        let c0 = take(&mut parent.fork(0), 16);
+        let c1 = take(&mut parent.fork(1), 16);
+        assert_ne!(c0, c1);
+
+        // forking doesn't advance the parent, and repeated forks agree
+        let mut p2 = parent.clone();
+        assert_eq!(take(&mut parent, 8), take(&mut p2, 8));
+        assert_ne!(c0, take(&mut parent.fork(0), 16));
+        let mut again = Rng::for_sequence(7, 3);
+        take(&mut again, 5);
+        assert_eq!(take(&mut again.fork(0), 16), c0);
+        assert_eq!(take(&mut again.fork(1), 16), c1);
+
+        // grandchildren only depend on the path
+        let g = take(&mut again.fork(1).fork(2), 4);
+        assert_eq!(g, take(&mut again.fork(1).fork(2), 4));
+        assert_ne!(g, take(&mut again.fork(2).fork(1), 4));
+    }
+
+    #[test]
+    fn masked_sampling() {
+        let logits = [0.0, 2.0, 1.0, 5.0, 1.0];
+        let mask = SimpleVob::from_token_ids([0, 1, 2, 4], 5);
+        let mut rng = Rng::new(1);
+        assert_eq!(rng.sample_categorical(&logits, &mask, 0.0), Some(1));
+        assert_eq!(rng.sample_gumbel(&logits, &mask, 0.0), Some(1));
+        let empty = SimpleVob::alloc(5);
+        assert_eq!(rng.sample_categorical(&logits, &empty, 1.0), None);
+        assert_eq!(rng.sample_gumbel(&logits, &empty, 1.0), None);
+
+        let n = 20000;
+        let mut cat = [0usize; 5];
+        let mut gum = [0usize; 5];
+        for _ in 0..n {
+            cat[rng.sample_categorical(&logits, &mask, 1.0).unwrap() as usize] += 1;
+            gum[rng.sample_gumbel(&logits, &mask, 1.0).unwrap() as usize] += 1;
+        }
+        assert_eq!(cat[3], 0);
+        assert_eq!(gum[3], 0);
+        let total: f64 = [0, 1, 2, 4]
+            .iter()
+            .map(|&i| math::exp(logits[i] as f64))
+            .sum();
+        for i in [0, 1, 2, 4] {
+            let p = math::exp(logits[i] as f64) / total;
+            for counts in [&cat, &gum] {
+                let freq = counts[i] as f64 / n as f64;
+                assert!((freq - p).abs() < 0.02, "token {i}: {freq} vs {p}");
+            }
+        }
+    }
+
+    #[cfg(feature = "rand_core")]
+    #[test]
+    fn rand_core_impl() {
+        use rand_core::{RngCore, SeedableRng};
+        let mut a = Rng::from_seed(*b"seedseed");
+        let mut b = Rng::seed_from_u64(0);
+        assert_ne!(a.next_u64(), b.next_u64());
+        let mut a2 = Rng::from_seed(*b"seedseed");
+        a2.next_u64();
+        let mut buf = [0u8; 13];
+        a.fill_bytes(&mut buf);
+        let mut buf2 = [0u8; 13];
+        a2.try_fill_bytes(&mut buf2).unwrap();
+        assert_eq!(buf, buf2);
+        assert!(buf.iter().any(|&x| x != 0));
+    }
 }
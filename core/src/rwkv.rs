@@ -0,0 +1,194 @@
+// Loader for the RWKV "world" vocabulary format (e.g., rwkv_vocab_v20230424.txt).
+// Each line is `<id> <python literal> <byte length>`, where the literal is
+// either a str ('...') or a bytes (b'...') literal.
+// Token 0 is not listed in the file; it is the end-of-text token.
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::prelude::*;
+use crate::{TokRxInfo, TokTrie, TokenId};
+
+fn hex_digits(chars: &mut core::str::Chars, n: usize) -> Result<u32> {
+    let s: String = chars.take(n).collect();
+    if s.len() != n {
+        bail!("truncated escape sequence");
+    }
+    Ok(u32::from_str_radix(&s, 16)?)
+}
+
+fn push_char(res: &mut Vec<u8>, c: char) {
+    let mut buf = [0u8; 4];
+    res.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+}
+
+/// Parse a Python str or bytes literal into raw bytes (str literals are UTF-8 encoded).
+fn parse_py_literal(lit: &str) -> Result<Vec<u8>> {
+    let (is_bytes, lit) = match lit.strip_prefix('b') {
+        Some(rest) => (true, rest),
+        None => (false, lit),
+    };
+    let quote = lit
+        .chars()
+        .next()
+        .filter(|&c| c == '\'' || c == '"')
+        .ok_or_else(|| anyhow!("expecting quoted literal: {}", lit))?;
+    let inner = lit[1..]
+        .strip_suffix(quote)
+        .ok_or_else(|| anyhow!("unterminated literal: {}", lit))?;
+
+    let mut res = Vec::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            push_char(&mut res, c);
+            continue;
+        }
+        let c = chars
+            .next()
+            .ok_or_else(|| anyhow!("trailing backslash: {}", lit))?;
+        match c {
+            'n' => res.push(b'\n'),
+            't' => res.push(b'\t'),
+            'r' => res.push(b'\r'),
+            '0' => res.push(0),
+            '\\' | '\'' | '"' => res.push(c as u8),
+            'x' => {
+                let v = hex_digits(&mut chars, 2)?;
+                if is_bytes {
+                    res.push(v as u8);
+                } else {
+                    push_char(&mut res, char::from_u32(v).unwrap());
+                }
+            }
+            'u' | 'U' if !is_bytes => {
+                let v = hex_digits(&mut chars, if c == 'u' { 4 } else { 8 })?;
+                let c = char::from_u32(v).ok_or_else(|| anyhow!("invalid char: {:#x}", v))?;
+                push_char(&mut res, c);
+            }
+            _ => bail!("unsupported escape \\{} in {}", c, lit),
+        }
+    }
+    Ok(res)
+}
+
+// Ids can leave gaps in the vocabulary, but not more than this many,
+// so that a single bogus id can't make us allocate gigabytes.
+const MAX_ID_GAP: usize = 1 << 16;
+
+impl TokTrie {
+    /// Build a trie from the text of an RWKV world vocabulary file.
+    /// Token 0 becomes the special <|endoftext|> token, which is also EOS.
+    pub fn from_rwkv_world(text: &str) -> Result<Self> {
+        let mut entries = Vec::new();
+        for (line_no, line) in text.lines().enumerate() {
+            let line = line.trim_end();
+            if line.is_empty() {
+                continue;
+            }
+            let err = || anyhow!("invalid line {}: {:?}", line_no + 1, line);
+            let (id, rest) = line.split_once(' ').ok_or_else(err)?;
+            let (lit, len) = rest.rsplit_once(' ').ok_or_else(err)?;
+            let id: TokenId = id.parse().map_err(|_| err())?;
+            let len: usize = len.parse().map_err(|_| err())?;
+            let bytes =
+                parse_py_literal(lit).map_err(|e| anyhow!("line {}: {}", line_no + 1, e))?;
+            if bytes.len() != len {
+                bail!(
+                    "line {}: length mismatch: {} vs {}",
+                    line_no + 1,
+                    bytes.len(),
+                    len
+                );
+            }
+            entries.push((id, bytes));
+        }
+
+        let max_id = entries.len() + MAX_ID_GAP;
+        if let Some((id, _)) = entries.iter().find(|(id, _)| *id as usize >= max_id) {
+            bail!("token id {} too large for {} entries", id, entries.len());
+        }
+        let vocab_size = entries.iter().map(|(id, _)| *id + 1).max().unwrap_or(1);
+        let mut words = vec![Vec::new(); vocab_size as usize];
+        for (id, bytes) in entries {
+            words[id as usize] = bytes;
+        }
+        let mut eos = b"<|endoftext|>".to_vec();
+        eos.insert(0, TokTrie::SPECIAL_TOKEN_MARKER);
+        words[0] = eos;
+
+        let info = TokRxInfo::new(vocab_size, 0);
+        Ok(TokTrie::from(&info, &words))
+    }
+
+    /// Build a trie from an RWKV world vocabulary file on disk.
+    #[cfg(feature = "std")]
+    pub fn from_rwkv_world_file(path: &str) -> Result<Self> {
+        let text =
+            std::fs::read_to_string(path).map_err(|e| anyhow!("error reading {}: {}", path, e))?;
+        Self::from_rwkv_world(&text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn py_literals() {
+        for (lit, expected) in [
+            ("'a b'", &b"a b"[..]),
+            ("b'a b'", b"a b"),
+            ("\"it's\"", b"it's"),
+            ("'\\'\\\\\\\"'", b"'\\\""),
+            ("'\\n\\t\\r\\0'", b"\n\t\r\0"),
+            ("b'\\xe4\\x00'", b"\xe4\x00"),
+            // in str literals, \x is a code point, encoded as UTF-8
+            ("'\\xe4'", "\u{e4}".as_bytes()),
+            ("'\\u00e4\\U0001f600'", "\u{e4}\u{1F600}".as_bytes()),
+            ("'\u{e4}'", "\u{e4}".as_bytes()),
+            ("''", b""),
+        ] {
+            assert_eq!(parse_py_literal(lit).unwrap(), expected, "{}", lit);
+        }
+        for lit in [
+            "abc",
+            "'abc",
+            "'abc\"",
+            "'\\'",
+            "'\\x4'",
+            "'\\q'",
+            "b'\\u00e4'",
+            "'\\U00110000'",
+            "",
+        ] {
+            assert!(parse_py_literal(lit).is_err(), "{}", lit);
+        }
+    }
+
+    #[test]
+    fn vocab_file() {
+        let trie =
+            TokTrie::from_rwkv_world("1 '\\x00' 1\n2 b'\\xff' 1\n4 'a b' 3\n\n5 'ä' 2\n").unwrap();
+        assert_eq!(trie.vocab_size(), 6);
+        assert_eq!(trie.eos_token(), 0);
+        assert_eq!(trie.get_special_token("<|endoftext|>"), Some(0));
+        assert_eq!(trie.token(1), b"\x00");
+        assert_eq!(trie.token(2), b"\xff");
+        assert_eq!(trie.token(3), b"");
+        assert_eq!(trie.token(4), b"a b");
+        assert_eq!(trie.token(5), "ä".as_bytes());
+
+        for text in [
+            "1 'a' 2",
+            "1 'a'",
+            "x 'a' 1",
+            "1 a 1",
+            "-1 'a' 1",
+            "4294967295 'a' 1",
+            "4000000000 'a' 1",
+            "1 'a' 1\n100000 'b' 1",
+        ] {
+            assert!(TokTrie::from_rwkv_world(text).is_err(), "{}", text);
+        }
+    }
+}
@@ -0,0 +1,293 @@
+// Loader for raw SentencePiece .model files (serialized ModelProto).
+// See https://github.com/google/sentencepiece/blob/master/src/sentencepiece_model.proto
+// Only the fields needed for the trie are decoded.
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::prelude::*;
+use crate::{
+    tokenizer_json::{token_to_bytes, DecoderKind},
+    TokRxInfo, TokTrie, TokenId,
+};
+
+// SentencePiece::Type; llama.cpp (and thus GGUF) uses the same values
+pub(crate) const TOKEN_TYPE_UNKNOWN: i32 = 2;
+pub(crate) const TOKEN_TYPE_CONTROL: i32 = 3;
+pub(crate) const TOKEN_TYPE_USER_DEFINED: i32 = 4;
+
+enum Field<'a> {
+    Varint(u64),
+    // fixed32/fixed64 (floats in our case), not needed
+    Fixed,
+    Bytes(&'a [u8]),
+}
+
+struct ProtoReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ProtoReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        ProtoReader { data, pos: 0 }
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut res = 0u64;
+        for shift in (0..64).step_by(7) {
+            let b = *self
+                .data
+                .get(self.pos)
+                .ok_or_else(|| anyhow!("truncated varint"))?;
+            self.pos += 1;
+            res |= ((b & 0x7f) as u64) << shift;
+            if b & 0x80 == 0 {
+                return Ok(res);
+            }
+        }
+        bail!("varint too long")
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.data.len() - self.pos < len {
+            bail!("truncated field");
+        }
+        let r = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(r)
+    }
+
+    fn next_field(&mut self) -> Result<Option<(u64, Field<'a>)>> {
+        if self.pos >= self.data.len() {
+            return Ok(None);
+        }
+        let key = self.varint()?;
+        let field = match key & 7 {
+            0 => Field::Varint(self.varint()?),
+            1 => {
+                self.take(8)?;
+                Field::Fixed
+            }
+            2 => {
+                let len = usize::try_from(self.varint()?)?;
+                Field::Bytes(self.take(len)?)
+            }
+            5 => {
+                self.take(4)?;
+                Field::Fixed
+            }
+            tp => bail!("unsupported protobuf wire type: {}", tp),
+        };
+        Ok(Some((key >> 3, field)))
+    }
+}
+
+struct Piece {
+    piece: String,
+    tp: i32,
+}
+
+fn parse_piece(data: &[u8]) -> Result<Piece> {
+    let mut r = ProtoReader::new(data);
+    let mut piece = Piece {
+        piece: String::new(),
+        tp: 1,
+    };
+    while let Some((tag, field)) = r.next_field()? {
+        match (tag, field) {
+            (1, Field::Bytes(b)) => piece.piece = String::from_utf8_lossy(b).to_string(),
+            (3, Field::Varint(v)) => piece.tp = v as i32,
+            _ => {}
+        }
+    }
+    Ok(piece)
+}
+
+impl TokTrie {
+    /// Build a trie from a raw SentencePiece .model file.
+    /// The ▁ prefix is mapped to space, byte-fallback pieces like <0x0A> to single bytes,
+    /// and control and unknown pieces are prefixed with SPECIAL_TOKEN_MARKER.
+    pub fn from_sentencepiece(bytes: &[u8]) -> Result<Self> {
+        let mut pieces = Vec::new();
+        // defaults from TrainerSpec
+        let mut unk_id = 0i64;
+        let mut bos_id = 1i64;
+        let mut eos_id = 2i64;
+        let mut pad_id = -1i64;
+
+        let mut r = ProtoReader::new(bytes);
+        while let Some((tag, field)) = r.next_field()? {
+            match (tag, field) {
+                (1, Field::Bytes(b)) => pieces.push(parse_piece(b)?),
+                (2, Field::Bytes(b)) => {
+                    let mut spec = ProtoReader::new(b);
+                    while let Some((tag, field)) = spec.next_field()? {
+                        if let Field::Varint(v) = field {
+                            // int32 fields; negative values are sign-extended
+                            let v = v as i64 as i32 as i64;
+                            match tag {
+                                40 => unk_id = v,
+                                41 => bos_id = v,
+                                42 => eos_id = v,
+                                43 => pad_id = v,
+                                _ => {}
+                            }
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if pieces.is_empty() {
+            bail!("no pieces found in SentencePiece model");
+        }
+
+        let vocab_size = pieces.len() as u32;
+        let id = |v: i64| {
+            if v >= 0 && (v as u32) < vocab_size {
+                Some(v as TokenId)
+            } else {
+                None
+            }
+        };
+
+        let mut info = TokRxInfo::new(vocab_size, id(eos_id).unwrap_or(0));
+        info.tok_bos = id(bos_id);
+        info.tok_unk = id(unk_id);
+        info.tok_pad = id(pad_id);
+
+        let kind = DecoderKind::ByteFallback { space_ch: '▁' };
+        let mut words = Vec::with_capacity(pieces.len());
+        for p in pieces {
+            let bytes = match p.tp {
+                TOKEN_TYPE_CONTROL | TOKEN_TYPE_UNKNOWN => {
+                    let mut bytes = p.piece.into_bytes();
+                    bytes.insert(0, TokTrie::SPECIAL_TOKEN_MARKER);
+                    bytes
+                }
+                TOKEN_TYPE_USER_DEFINED => p.piece.into_bytes(),
+                _ => token_to_bytes(&kind, &p.piece)
+                    .map_err(|e| anyhow!("error: {} for {:?}", e, p.piece))?,
+            };
+            words.push(bytes);
+        }
+
+        Ok(TokTrie::from(&info, &words))
+    }
+
+    /// Build a trie from a SentencePiece .model file on disk.
+    #[cfg(feature = "std")]
+    pub fn from_sentencepiece_file(path: &str) -> Result<Self> {
+        let bytes = std::fs::read(path).map_err(|e| anyhow!("error reading {}: {}", path, e))?;
+        Self::from_sentencepiece(&bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn varint(out: &mut Vec<u8>, mut v: u64) {
+        while v >= 0x80 {
+            out.push((v as u8) | 0x80);
+            v >>= 7;
+        }
+        out.push(v as u8);
+    }
+
+    fn bytes_field(out: &mut Vec<u8>, tag: u64, data: &[u8]) {
+        varint(out, (tag << 3) | 2);
+        varint(out, data.len() as u64);
+        out.extend_from_slice(data);
+    }
+
+    fn varint_field(out: &mut Vec<u8>, tag: u64, v: u64) {
+        varint(out, tag << 3);
+        varint(out, v);
+    }
+
+    fn piece(name: &str, tp: i32) -> Vec<u8> {
+        let mut p = Vec::new();
+        bytes_field(&mut p, 1, name.as_bytes());
+        // score, fixed32
+        varint(&mut p, (2 << 3) | 5);
+        p.extend_from_slice(&(-1.5f32).to_le_bytes());
+        varint_field(&mut p, 3, tp as u64);
+        p
+    }
+
+    fn model(pieces: &[(&str, i32)], spec: &[(u64, i64)]) -> Vec<u8> {
+        let mut m = Vec::new();
+        for (name, tp) in pieces {
+            bytes_field(&mut m, 1, &piece(name, *tp));
+        }
+        let mut s = Vec::new();
+        for (tag, v) in spec {
+            varint_field(&mut s, *tag, *v as u64);
+        }
+        bytes_field(&mut m, 2, &s);
+        m
+    }
+
+    const PIECES: &[(&str, i32)] = &[
+        ("<unk>", TOKEN_TYPE_UNKNOWN),
+        ("<s>", TOKEN_TYPE_CONTROL),
+        ("</s>", TOKEN_TYPE_CONTROL),
+        ("<0x0A>", 6),
+        ("<0xFF>", 6),
+        ("▁hello", 1),
+        ("<|user|>", TOKEN_TYPE_USER_DEFINED),
+    ];
+
+    #[test]
+    fn minimal_model() {
+        let trie = TokTrie::from_sentencepiece(&model(PIECES, &[])).unwrap();
+        assert_eq!(trie.vocab_size(), PIECES.len());
+        let info = trie.info();
+        assert_eq!(info.tok_eos, 2);
+        assert_eq!(info.tok_bos, Some(1));
+        assert_eq!(info.tok_unk, Some(0));
+        assert_eq!(info.tok_pad, None);
+        assert_eq!(trie.get_special_token("</s>"), Some(2));
+        assert_eq!(trie.token(3), b"\n");
+        assert_eq!(trie.token(4), b"\xFF");
+        assert_eq!(trie.token(5), b" hello");
+        assert_eq!(trie.token(6), b"<|user|>");
+
+        // -1 is sign-extended to 10 bytes, and means no token
+        let trie =
+            TokTrie::from_sentencepiece(&model(PIECES, &[(42, 5), (43, 0), (40, -1)])).unwrap();
+        let info = trie.info();
+        assert_eq!(info.tok_eos, 5);
+        assert_eq!(info.tok_pad, Some(0));
+        assert_eq!(info.tok_unk, None);
+    }
+
+    #[test]
+    fn malformed() {
+        let good = model(PIECES, &[]);
+        assert!(TokTrie::from_sentencepiece(&[]).is_err());
+        for len in 1..good.len() {
+            // truncated somewhere in the middle of a field
+            let _ = TokTrie::from_sentencepiece(&good[..len]);
+        }
+        assert!(TokTrie::from_sentencepiece(&good[..good.len() - 1]).is_err());
+
+        // truncated and overlong varints
+        assert!(TokTrie::from_sentencepiece(&[0x0a, 0x80]).is_err());
+        let mut long = vec![0x08];
+        long.extend_from_slice(&[0xff; 11]);
+        assert!(TokTrie::from_sentencepiece(&long).is_err());
+        // length beyond the end of the data, including one overflowing usize
+        assert!(TokTrie::from_sentencepiece(&[0x0a, 0x05, 0x0a, 0x01]).is_err());
+        let mut huge = vec![0x0a];
+        varint(&mut huge, u64::MAX);
+        assert!(TokTrie::from_sentencepiece(&huge).is_err());
+        // wire types 3 and 4 (groups)
+        assert!(TokTrie::from_sentencepiece(&[0x0b]).is_err());
+
+        // invalid byte-fallback piece
+        assert!(TokTrie::from_sentencepiece(&model(&[("<0xZZ>", 6)], &[])).is_err());
+    }
+}
@@ -0,0 +1,185 @@
+// Explicit SIMD versions of hot SimpleVob operations (enabled with the "simd" feature).
+// Only SSE2 is used, which is part of the x86_64 baseline, so no runtime detection is needed.
+
+use core::arch::x86_64::*;
+
+const LANES: usize = 4;
+
+#[inline(always)]
+fn binop(
+    dst: &mut [u32],
+    src: &[u32],
+    vec_op: impl Fn(__m128i, __m128i) -> __m128i,
+    scalar_op: impl Fn(u32, u32) -> u32,
+) {
+    let len = core::cmp::min(dst.len(), src.len());
+    let n = len / LANES * LANES;
+    for i in (0..n).step_by(LANES) {
+        unsafe {
+            let a = _mm_loadu_si128(dst.as_ptr().add(i) as *const __m128i);
+            let b = _mm_loadu_si128(src.as_ptr().add(i) as *const __m128i);
+            _mm_storeu_si128(dst.as_mut_ptr().add(i) as *mut __m128i, vec_op(a, b));
+        }
+    }
+    for i in n..len {
+        dst[i] = scalar_op(dst[i], src[i]);
+    }
+}
+
+pub fn or(dst: &mut [u32], src: &[u32]) {
+    binop(dst, src, |a, b| unsafe { _mm_or_si128(a, b) }, |a, b| a | b);
+}
+
+pub fn and(dst: &mut [u32], src: &[u32]) {
+    binop(
+        dst,
+        src,
+        |a, b| unsafe { _mm_and_si128(a, b) },
+        |a, b| a & b,
+    );
+}
+
+// dst &= !src
+pub fn sub(dst: &mut [u32], src: &[u32]) {
+    binop(
+        dst,
+        src,
+        |a, b| unsafe { _mm_andnot_si128(b, a) },
+        |a, b| a & !b,
+    );
+}
+
+pub fn xor(dst: &mut [u32], src: &[u32]) {
+    binop(
+        dst,
+        src,
+        |a, b| unsafe { _mm_xor_si128(a, b) },
+        |a, b| a ^ b,
+    );
+}
+
+pub fn popcount(data: &[u32]) -> usize {
+    let n = data.len() / LANES * LANES;
+    let mut total = 0u64;
+    unsafe {
+        let m1 = _mm_set1_epi8(0x55);
+        let m2 = _mm_set1_epi8(0x33);
+        let m4 = _mm_set1_epi8(0x0f);
+        let zero = _mm_setzero_si128();
+        for i in (0..n).step_by(LANES) {
+            let v = _mm_loadu_si128(data.as_ptr().add(i) as *const __m128i);
+            // per-byte bit counts
+            let v = _mm_sub_epi8(v, _mm_and_si128(_mm_srli_epi16(v, 1), m1));
+            let v = _mm_add_epi8(
+                _mm_and_si128(v, m2),
+                _mm_and_si128(_mm_srli_epi16(v, 2), m2),
+            );
+            let v = _mm_and_si128(_mm_add_epi8(v, _mm_srli_epi16(v, 4)), m4);
+            // horizontal sum of bytes in each 64-bit half
+            let s = _mm_sad_epu8(v, zero);
+            let mut halves = [0u64; 2];
+            _mm_storeu_si128(halves.as_mut_ptr() as *mut __m128i, s);
+            total += halves[0] + halves[1];
+        }
+    }
+    total as usize
+        + data[n..]
+            .iter()
+            .map(|x| x.count_ones() as usize)
+            .sum::<usize>()
+}
+
+// set logits[i] to neg_value for every unset bit i in words
+pub fn apply_to_logits(words: &[u32], logits: &mut [f32], neg_value: f32) {
+    assert!(logits.len() <= words.len() * 32);
+    unsafe {
+        let neg = _mm_set1_ps(neg_value);
+        let bits = _mm_set_epi32(8, 4, 2, 1);
+        let mut chunks = logits.chunks_exact_mut(LANES);
+        let mut idx = 0;
+        for chunk in &mut chunks {
+            let nibble = (words[idx / 32] >> (idx % 32)) & 0xf;
+            idx += LANES;
+            if nibble == 0xf {
+                continue;
+            }
+            // lane i is all ones if bit i is set
+            let allowed = _mm_castsi128_ps(_mm_cmpeq_epi32(
+                _mm_and_si128(_mm_set1_epi32(nibble as i32), bits),
+                bits,
+            ));
+            let p = chunk.as_mut_ptr();
+            let v = _mm_loadu_ps(p);
+            let r = _mm_or_ps(_mm_and_ps(allowed, v), _mm_andnot_ps(allowed, neg));
+            _mm_storeu_ps(p, r);
+        }
+        for x in chunks.into_remainder() {
+            if words[idx / 32] & (1 << (idx % 32)) == 0 {
+                *x = neg_value;
+            }
+            idx += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+
+    fn words(n: usize, seed: u32) -> Vec<u32> {
+        let mut x = seed | 1;
+        (0..n)
+            .map(|i| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                // some all-zero and all-one words, for the shortcuts
+                match i % 5 {
+                    1 => 0,
+                    3 => u32::MAX,
+                    _ => x,
+                }
+            })
+            .collect()
+    }
+
+    // SIMD operation, and its scalar equivalent
+    type OpCase = (fn(&mut [u32], &[u32]), fn(u32, u32) -> u32);
+
+    #[test]
+    fn binops_match_scalar() {
+        let ops: [OpCase; 4] = [
+            (or, |a, b| a | b),
+            (and, |a, b| a & b),
+            (sub, |a, b| a & !b),
+            (xor, |a, b| a ^ b),
+        ];
+        for len in 0..14 {
+            let a = words(len, 7);
+            let b = words(len, 11);
+            for (op, scalar) in ops {
+                let mut d = a.clone();
+                op(&mut d, &b);
+                let expected: Vec<u32> = a.iter().zip(&b).map(|(&x, &y)| scalar(x, y)).collect();
+                assert_eq!(d, expected, "len {}", len);
+            }
+            let expected: usize = a.iter().map(|x| x.count_ones() as usize).sum();
+            assert_eq!(popcount(&a), expected, "len {}", len);
+        }
+        assert_eq!(popcount(&[u32::MAX; 9]), 9 * 32);
+    }
+
+    #[test]
+    fn apply_to_logits_matches_scalar() {
+        let w = words(5, 3);
+        for len in (0..=w.len() * 32).filter(|l| l % 32 < 6 || l % 32 > 28) {
+            let mut logits: Vec<f32> = (0..len).map(|i| i as f32).collect();
+            apply_to_logits(&w, &mut logits, -1.0);
+            for (i, l) in logits.iter().enumerate() {
+                let allowed = w[i / 32] & (1 << (i % 32)) != 0;
+                assert_eq!(*l, if allowed { i as f32 } else { -1.0 }, "len {}", len);
+            }
+        }
+    }
+}
@@ -0,0 +1,81 @@
+#[cfg(feature = "std")]
+use std::{
+    backtrace::BacktraceStatus,
+    panic::{catch_unwind, AssertUnwindSafe},
+};
+
+#[cfg(feature = "std")]
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::prelude::*;
+
+/// Error (or panic) in controller code during a step, to be reported to the host,
+/// instead of taking down the whole sequence without diagnostics.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StepError {
+    pub message: String,
+    /// Only present if backtraces are enabled (RUST_BACKTRACE) and available.
+    #[serde(default)]
+    pub backtrace: Option<String>,
+    /// Index of the step that failed.
+    pub step: u32,
+    /// True if the error was a panic; the controller state may then be inconsistent.
+    #[serde(default)]
+    pub panic: bool,
+}
+
+impl core::fmt::Display for StepError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let kind = if self.panic { "panic" } else { "error" };
+        write!(f, "{} in step {}: {}", kind, self.step, self.message)
+    }
+}
+
+impl core::error::Error for StepError {}
+
+impl StepError {
+    pub fn from_error(step: u32, err: &anyhow::Error) -> Self {
+        #[cfg(feature = "std")]
+        let backtrace = {
+            let bt = err.backtrace();
+            if bt.status() == BacktraceStatus::Captured {
+                Some(bt.to_string())
+            } else {
+                None
+            }
+        };
+        #[cfg(not(feature = "std"))]
+        let backtrace = None;
+        StepError {
+            message: format!("{:#}", err),
+            backtrace,
+            step,
+            panic: false,
+        }
+    }
+}
+
+/// Run given step of the controller, turning both errors and panics into StepError.
+#[cfg(feature = "std")]
+pub fn catch_step<T>(step: u32, f: impl FnOnce() -> Result<T>) -> Result<T, StepError> {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(v)) => Ok(v),
+        Ok(Err(e)) => Err(StepError::from_error(step, &e)),
+        Err(payload) => {
+            let message = if let Some(s) = payload.downcast_ref::<&str>() {
+                s.to_string()
+            } else if let Some(s) = payload.downcast_ref::<String>() {
+                s.clone()
+            } else {
+                "unknown panic".to_string()
+            };
+            Err(StepError {
+                message,
+                backtrace: None,
+                step,
+                panic: true,
+            })
+        }
+    }
+}
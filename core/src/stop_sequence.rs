@@ -0,0 +1,184 @@
+use crate::prelude::*;
+use crate::{TokTrie, TokenId};
+
+/// Result of a stop sequence match.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StopMatch {
+    /// Index of the matched stop string.
+    pub stop_idx: usize,
+    /// Number of trailing bytes to remove, so that the output ends right before the stop string.
+    pub trim_bytes: usize,
+    /// Number of trailing tokens that contain bytes of the stop string
+    /// (suitable for Splice::backtrack).
+    pub trim_tokens: usize,
+    /// Bytes of the trimmed tokens that precede the stop string;
+    /// they have to be re-emitted after backtracking trim_tokens.
+    pub keep_bytes: Vec<u8>,
+}
+
+/// Detects user-specified stop strings in generated output,
+/// including ones that span several tokens.
+#[derive(Clone, Debug)]
+pub struct StopSequenceMatcher {
+    stops: Vec<Vec<u8>>,
+    max_stop_len: usize,
+    bytes: Vec<u8>,
+    // length in bytes of each token in `bytes`
+    token_lens: Vec<usize>,
+}
+
+impl StopSequenceMatcher {
+    pub fn new(stops: &[&str]) -> Self {
+        let stops: Vec<Vec<u8>> = stops
+            .iter()
+            .filter(|s| !s.is_empty())
+            .map(|s| s.as_bytes().to_vec())
+            .collect();
+        let max_stop_len = stops.iter().map(|s| s.len()).max().unwrap_or(0);
+        StopSequenceMatcher {
+            stops,
+            max_stop_len,
+            bytes: Vec::new(),
+            token_lens: Vec::new(),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.bytes.clear();
+        self.token_lens.clear();
+    }
+
+    /// Record a generated token; returns the match if a stop string was completed by it.
+    pub fn push_token(&mut self, trie: &TokTrie, tok: TokenId) -> Option<StopMatch> {
+        self.push_bytes(&trie.decode(&[tok]))
+    }
+
+    /// Record the bytes of a generated token; returns the match if a stop string was completed.
+    pub fn push_bytes(&mut self, bytes: &[u8]) -> Option<StopMatch> {
+        let prev_len = self.bytes.len();
+        self.bytes.extend_from_slice(bytes);
+        self.token_lens.push(bytes.len());
+
+        // find the earliest stop string occurrence ending in the new bytes
+        let mut best: Option<(usize, usize)> = None;
+        for (stop_idx, stop) in self.stops.iter().enumerate() {
+            let start = (prev_len + 1).saturating_sub(stop.len());
+            if let Some(pos) = self.bytes[start..]
+                .windows(stop.len())
+                .position(|w| w == stop.as_slice())
+            {
+                let pos = start + pos;
+                if best.is_none_or(|(_, p)| pos < p) {
+                    best = Some((stop_idx, pos));
+                }
+            }
+        }
+
+        let res = best.map(|(stop_idx, pos)| {
+            let mut trim_tokens = 0;
+            let mut tok_start = self.bytes.len();
+            for len in self.token_lens.iter().rev() {
+                if tok_start <= pos {
+                    break;
+                }
+                tok_start = tok_start.saturating_sub(*len);
+                trim_tokens += 1;
+            }
+            StopMatch {
+                stop_idx,
+                trim_bytes: self.bytes.len() - pos,
+                trim_tokens,
+                keep_bytes: self.bytes[tok_start..pos].to_vec(),
+            }
+        });
+
+        self.truncate();
+        res
+    }
+
+    /// Number of trailing bytes that form a prefix of some stop string.
+    /// Streaming output should hold these back until more tokens arrive.
+    pub fn partial_match_len(&self) -> usize {
+        self.stops
+            .iter()
+            .map(|stop| {
+                (1..stop.len())
+                    .rev()
+                    .find(|&k| self.bytes.ends_with(&stop[..k]))
+                    .unwrap_or(0)
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    // drop data not needed for future matches; keep whole tokens overlapping the window
+    fn truncate(&mut self) {
+        let mut keep = 0;
+        let mut num_tokens = 0;
+        for len in self.token_lens.iter().rev() {
+            if keep >= self.max_stop_len {
+                break;
+            }
+            keep += len;
+            num_tokens += 1;
+        }
+        let keep = core::cmp::min(keep, self.bytes.len());
+        self.bytes.drain(..self.bytes.len() - keep);
+        self.token_lens.drain(..self.token_lens.len() - num_tokens);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{TestTokEnv, TokenizerEnv};
+
+    #[test]
+    fn stop_spanning_three_tokens() {
+        let env = TestTokEnv::with_words(&["ab<", "/en", "d>x"]);
+        let trie = env.tok_trie();
+        let tok = |s| env.token(s).unwrap();
+        let mut m = StopSequenceMatcher::new(&["</end>", ""]);
+        assert_eq!(m.partial_match_len(), 0);
+        assert_eq!(m.push_token(trie, tok("ab<")), None);
+        assert_eq!(m.partial_match_len(), 1);
+        assert_eq!(m.push_token(trie, tok("/en")), None);
+        assert_eq!(m.partial_match_len(), 4);
+        assert_eq!(
+            m.push_token(trie, tok("d>x")),
+            Some(StopMatch {
+                stop_idx: 0,
+                trim_bytes: 7,
+                trim_tokens: 3,
+                keep_bytes: b"ab".to_vec(),
+            })
+        );
+        assert_eq!(m.partial_match_len(), 0);
+    }
+
+    #[test]
+    fn earliest_match_and_history() {
+        let mut m = StopSequenceMatcher::new(&["cd", "bcd!", "xyz"]);
+        assert_eq!(m.push_bytes(b"ab"), None);
+        // both "cd" and "bcd!" complete here; the earlier start wins
+        let r = m.push_bytes(b"cd!").unwrap();
+        assert_eq!((r.stop_idx, r.trim_bytes, r.trim_tokens), (1, 4, 2));
+        assert_eq!(r.keep_bytes, b"a");
+
+        // within a single token, with bytes before it in the same token
+        m.reset();
+        let r = m.push_bytes(b"..xyz..").unwrap();
+        assert_eq!((r.stop_idx, r.trim_bytes, r.trim_tokens), (2, 5, 1));
+        assert_eq!(r.keep_bytes, b"..");
+
+        // long outputs only keep the tail needed for matching
+        m.reset();
+        for _ in 0..100 {
+            assert_eq!(m.push_bytes(b"x"), None);
+        }
+        assert_eq!(m.partial_match_len(), 1);
+        assert!(m.bytes.len() <= 4);
+        let r = m.push_bytes(b"yz").unwrap();
+        assert_eq!((r.stop_idx, r.trim_bytes, r.trim_tokens), (2, 3, 2));
+    }
+}
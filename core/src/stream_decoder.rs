@@ -0,0 +1,114 @@
+use crate::prelude::*;
+use crate::{TokTrie, TokenId};
+
+/// Incremental detokenizer producing display-safe strings.
+/// Bytes of partial UTF-8 sequences (for example an emoji split across
+/// several byte-fallback tokens like <0xF0><0x9F>...) are buffered until
+/// the sequence is complete. Invalid bytes are replaced with U+FFFD.
+#[derive(Clone, Debug, Default)]
+pub struct StreamDecoder {
+    pending: Vec<u8>,
+}
+
+impl StreamDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a token and return the text that can be displayed now.
+    pub fn push_token(&mut self, trie: &TokTrie, tok: TokenId) -> String {
+        self.push_bytes(&trie.decode(&[tok]))
+    }
+
+    /// Append several tokens and return the text that can be displayed now.
+    pub fn push_tokens(&mut self, trie: &TokTrie, toks: &[TokenId]) -> String {
+        self.push_bytes(&trie.decode(toks))
+    }
+
+    /// Append raw bytes and return the text that can be displayed now.
+    pub fn push_bytes(&mut self, bytes: &[u8]) -> String {
+        self.pending.extend_from_slice(bytes);
+        let mut res = String::new();
+        let mut start = 0;
+        loop {
+            match core::str::from_utf8(&self.pending[start..]) {
+                Ok(s) => {
+                    res.push_str(s);
+                    start = self.pending.len();
+                    break;
+                }
+                Err(e) => {
+                    let valid = start + e.valid_up_to();
+                    res.push_str(core::str::from_utf8(&self.pending[start..valid]).unwrap());
+                    match e.error_len() {
+                        // invalid sequence; skip it
+                        Some(len) => {
+                            res.push('\u{FFFD}');
+                            start = valid + len;
+                        }
+                        // incomplete sequence at the end; wait for more bytes
+                        None => {
+                            start = valid;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        self.pending.drain(..start);
+        res
+    }
+
+    /// Whether there are buffered bytes of an incomplete UTF-8 sequence.
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Return any buffered bytes (lossily converted) and reset the decoder.
+    /// Call at the end of generation.
+    pub fn flush(&mut self) -> String {
+        let res = String::from_utf8_lossy(&self.pending).to_string();
+        self.pending.clear();
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{TestTokEnv, TokenizerEnv};
+
+    #[test]
+    fn emoji_split_across_byte_tokens() {
+        let env = TestTokEnv::with_words(&["hi"]);
+        let trie = env.tok_trie();
+        let hi = env.token("hi").unwrap();
+        let emoji = "\u{1F600}".as_bytes();
+        let mut dec = StreamDecoder::new();
+        assert_eq!(dec.push_token(trie, hi), "hi");
+        for &b in &emoji[..3] {
+            assert_eq!(dec.push_token(trie, b as TokenId), "");
+            assert!(dec.has_pending());
+        }
+        assert_eq!(
+            dec.push_tokens(trie, &[emoji[3] as TokenId, hi]),
+            "\u{1F600}hi"
+        );
+        assert!(!dec.has_pending());
+        assert_eq!(dec.flush(), "");
+    }
+
+    #[test]
+    fn invalid_bytes_and_flush() {
+        let mut dec = StreamDecoder::new();
+        // a stray continuation byte, and a lead byte followed by ASCII
+        assert_eq!(dec.push_bytes(b"a\x80b\xE2"), "a\u{FFFD}b");
+        assert_eq!(dec.push_bytes(b"x"), "\u{FFFD}x");
+        // an incomplete sequence at the end of generation
+        assert_eq!(dec.push_bytes(b"c\xF0\x9F\x98"), "c");
+        assert!(dec.has_pending());
+        assert_eq!(dec.flush(), "\u{FFFD}");
+        assert!(!dec.has_pending());
+        assert_eq!(dec.push_bytes("é".as_bytes()), "é");
+    }
+}
@@ -0,0 +1,217 @@
+// Index over token bytes answering "which tokens contain this byte sequence?"
+// All 1-, 2- and 3-grams of every token are indexed; longer needles are answered
+// by verifying the candidates from the needle's rarest trigram.
+
+use crate::prelude::*;
+use crate::{FxHashMap, SimpleVob, TokTrie, TokenId};
+
+const MAX_GRAM: usize = 3;
+
+fn gram_key(gram: &[u8]) -> u32 {
+    debug_assert!(!gram.is_empty() && gram.len() <= MAX_GRAM);
+    let mut key = gram.len() as u32;
+    for &b in gram {
+        key = (key << 8) | b as u32;
+    }
+    key
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+/// Substring index over the vocabulary of a trie.
+/// Special tokens are not indexed.
+pub struct SubstringIndex {
+    grams: FxHashMap<u32, Vec<TokenId>>,
+    all_tokens: Vec<TokenId>,
+    max_token_len: usize,
+}
+
+impl SubstringIndex {
+    pub fn new(trie: &TokTrie) -> Self {
+        let mut grams: FxHashMap<u32, Vec<TokenId>> = FxHashMap::default();
+        let mut all_tokens = Vec::new();
+        for tok in 0..trie.vocab_size() as TokenId {
+            let bytes = trie.token(tok);
+            if bytes.is_empty() || trie.is_special_token(tok) {
+                continue;
+            }
+            all_tokens.push(tok);
+            for len in 1..=MAX_GRAM {
+                for gram in bytes.windows(len) {
+                    let lst = grams.entry(gram_key(gram)).or_default();
+                    // tokens are visited in order, so this skips repeated grams within a token
+                    if lst.last() != Some(&tok) {
+                        lst.push(tok);
+                    }
+                }
+            }
+        }
+        SubstringIndex {
+            grams,
+            all_tokens,
+            max_token_len: trie.max_token_len(),
+        }
+    }
+
+    /// Sorted list of tokens containing `needle`.
+    /// `trie` has to be the one the index was built from.
+    pub fn tokens_containing(&self, trie: &TokTrie, needle: &[u8]) -> Vec<TokenId> {
+        if needle.is_empty() {
+            return self.all_tokens.clone();
+        }
+        if needle.len() > self.max_token_len {
+            return vec![];
+        }
+        if needle.len() <= MAX_GRAM {
+            return self
+                .grams
+                .get(&gram_key(needle))
+                .cloned()
+                .unwrap_or_default();
+        }
+        let mut best: &[TokenId] = &[];
+        for (idx, gram) in needle.windows(MAX_GRAM).enumerate() {
+            match self.grams.get(&gram_key(gram)) {
+                Some(lst) => {
+                    if idx == 0 || lst.len() < best.len() {
+                        best = lst;
+                    }
+                }
+                None => return vec![],
+            }
+        }
+        best.iter()
+            .copied()
+            .filter(|&tok| contains(trie.token(tok), needle))
+            .collect()
+    }
+
+    /// Set of tokens containing any of the `needles`.
+    pub fn token_set_containing(&self, trie: &TokTrie, needles: &[&[u8]]) -> SimpleVob {
+        let mut res = trie.alloc_token_set();
+        for needle in needles {
+            for tok in self.tokens_containing(trie, needle) {
+                res.allow_token(tok);
+            }
+        }
+        res
+    }
+
+    /// Set of tokens allowed after `tail` (the end of text generated so far),
+    /// excluding every token whose emission would complete one of the `banned` strings,
+    /// either fully within the token or starting in `tail` and ending in the token.
+    pub fn compute_ban_mask(&self, trie: &TokTrie, banned: &[&[u8]], tail: &[u8]) -> SimpleVob {
+        let mut ban = self.token_set_containing(trie, banned);
+        for w in banned {
+            for k in 1..w.len() {
+                if tail.ends_with(&w[..k]) {
+                    for tok in trie.tokens_with_prefix(&w[k..]) {
+                        if !trie.is_special_token(tok) {
+                            ban.allow_token(tok);
+                        }
+                    }
+                }
+            }
+        }
+        trie.apply_duplicates(&mut ban);
+        let mut res = trie.alloc_token_set();
+        res.set_all(true);
+        res.sub(&ban);
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{TestTokEnv, TokenizerEnv};
+
+    fn env() -> TestTokEnv {
+        TestTokEnv::with_words(&["hello", "yellow", "low", "lower", "owl", "world", "ello"])
+    }
+
+    #[test]
+    fn matches_linear_scan() {
+        let env = env();
+        let trie = env.tok_trie();
+        let idx = SubstringIndex::new(trie);
+        let needles: &[&[u8]] = &[
+            b"l",
+            b"lo",
+            b"llo",
+            b"ello",
+            b"llow",
+            b"lower",
+            b"owl",
+            b"xyz",
+            b"owlx",
+            b"hello world",
+            b"\xFF",
+            b"<|end|>",
+        ];
+        for needle in needles {
+            let expected: Vec<TokenId> = (0..trie.vocab_size() as TokenId)
+                .filter(|&t| !trie.is_special_token(t) && contains(trie.token(t), needle))
+                .collect();
+            assert_eq!(idx.tokens_containing(trie, needle), expected, "{needle:?}");
+        }
+        assert_eq!(
+            idx.tokens_containing(trie, b"").len(),
+            trie.vocab_size() - trie.special_tokens_iter().count()
+        );
+        assert!(idx
+            .tokens_containing(trie, b"low")
+            .contains(&env.token("yellow").unwrap()));
+    }
+
+    #[test]
+    fn token_set() {
+        let env = env();
+        let trie = env.tok_trie();
+        let idx = SubstringIndex::new(trie);
+        let set = idx.token_set_containing(trie, &[b"wor", b"owl"]);
+        let mut toks: Vec<_> = set.iter().collect();
+        toks.sort();
+        let expected = ["owl", "world"].map(|w| env.token(w).unwrap());
+        assert_eq!(toks, expected);
+    }
+
+    #[test]
+    fn ban_mask_across_tail() {
+        let env = env();
+        let trie = env.tok_trie();
+        let idx = SubstringIndex::new(trie);
+        let tok = |s| env.token(s).unwrap();
+        let banned = |tail: &[u8]| {
+            let mask = idx.compute_ban_mask(trie, &[b"low"], tail);
+            let mut toks: Vec<_> = (0..trie.vocab_size() as TokenId)
+                .filter(|&t| !mask.is_allowed(t))
+                .collect();
+            toks.sort();
+            toks
+        };
+
+        let mut expected = ["yellow", "low", "lower"].map(tok).to_vec();
+        expected.sort();
+        assert_eq!(banned(b"abc"), expected);
+
+        // "l" in the tail: anything starting with "ow" completes the word
+        let mut with_l = expected.clone();
+        with_l.push(tok("owl"));
+        with_l.sort();
+        assert_eq!(banned(b"yel"), with_l);
+
+        // "lo" in the tail: the bare "w" byte is banned too
+        let mut with_lo = expected.clone();
+        with_lo.extend([b'w' as TokenId, tok("world")]);
+        with_lo.sort();
+        assert_eq!(banned(b"yelo"), with_lo);
+
+        // special tokens are never banned
+        assert!(idx
+            .compute_ban_mask(trie, &[b"<|end|>"], b"")
+            .is_allowed(trie.eos_token()));
+    }
+}
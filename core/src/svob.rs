@@ -1,4 +1,7 @@
-use std::{fmt::Debug, hash::Hash, ops::Index};
+use core::{fmt::Debug, hash::Hash, ops::Index};
+use serde::{Deserialize, Serialize};
+
+use crate::prelude::*;
 
 pub type TokenId = u32;
 
@@ -9,7 +12,7 @@ pub struct SimpleVob {
 }
 
 impl Hash for SimpleVob {
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+    fn hash<H: core::hash::Hasher>(&self, state: &mut H) {
         self.size.hash(state);
         self.data.hash(state);
     }
@@ -24,7 +27,7 @@ impl PartialEq for SimpleVob {
 impl Eq for SimpleVob {}
 
 impl Debug for SimpleVob {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("SimpleVob")
             .field("len", &self.len())
             .finish()
@@ -43,6 +46,53 @@ impl Into<Vec<u32>> for SimpleVob {
     }
 }
 
+// Serialized form; runs are used when they are smaller than the dense words.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum SimpleVobRepr {
+    Dense { size: usize, data: Vec<u32> },
+    Runs { size: usize, runs: Vec<u32> },
+}
+
+impl Serialize for SimpleVob {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let runs = self.to_runs();
+        let repr = if runs.len() < self.data.len() {
+            SimpleVobRepr::Runs {
+                size: self.size,
+                runs,
+            }
+        } else {
+            SimpleVobRepr::Dense {
+                size: self.size,
+                data: self.data.clone(),
+            }
+        };
+        repr.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for SimpleVob {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match SimpleVobRepr::deserialize(deserializer)? {
+            SimpleVobRepr::Dense { size, mut data } => {
+                // masks with extra capacity (see alloc_with_capacity()) may have more words;
+                // anything past size is dropped, as it would be on the sending side
+                if data.len() < size / BITS + 1 {
+                    return Err(serde::de::Error::custom("invalid SimpleVob data length"));
+                }
+                data.truncate(size / BITS + 1);
+                let mut r = SimpleVob { data, size };
+                r.clear_excessive_bits();
+                Ok(r)
+            }
+            SimpleVobRepr::Runs { size, runs } => {
+                SimpleVob::from_runs(size, &runs).map_err(serde::de::Error::custom)
+            }
+        }
+    }
+}
+
 const BITS: usize = 32;
 
 impl SimpleVob {
@@ -61,6 +111,15 @@ impl SimpleVob {
         r
     }
 
+    /// Set of given size with given tokens allowed.
+    pub fn from_token_ids(toks: impl IntoIterator<Item = TokenId>, size: usize) -> Self {
+        let mut r = Self::alloc(size);
+        for tok in toks {
+            r.allow_token(tok);
+        }
+        r
+    }
+
     pub fn alloc(size: usize) -> Self {
         let mut r = Self::new();
         r.resize(size);
@@ -73,11 +132,13 @@ impl SimpleVob {
         r
     }
 
+    /// Set of given size, with room for bits up to capacity (exclusive).
+    /// alloc(size) already has room for bit size, so for capacity <= size + 1
+    /// this has the same words as alloc(size) (and compares equal to it).
     pub fn alloc_with_capacity(size: usize, capacity: usize) -> Self {
-        let mut r = Self::new();
         assert!(size <= capacity);
-        r.resize(capacity);
-        r.size = size;
+        let mut r = Self::alloc(size);
+        r.data.resize(r.data.len().max(capacity.div_ceil(BITS)), 0);
         r
     }
 
@@ -86,6 +147,9 @@ impl SimpleVob {
     }
 
     pub fn num_set(&self) -> usize {
+        #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+        return crate::simd::popcount(&self.data);
+        #[cfg(not(all(feature = "simd", target_arch = "x86_64")))]
         self.data.iter().map(|x| x.count_ones() as usize).sum()
     }
 
@@ -124,6 +188,30 @@ impl SimpleVob {
         &self.data
     }
 
+    /// The mask as a packed bitmap of len().div_ceil(8) bytes, without copying:
+    /// token i is bit (i % 8) of byte (i / 8), least significant bit first.
+    /// This is the layout of Arrow validity bitmaps, and of
+    /// numpy.packbits(mask, bitorder="little"), so in Python
+    /// numpy.unpackbits(bitmap, count=vocab_size, bitorder="little") gives the bool mask.
+    /// Bits past len() are zero.
+    #[cfg(target_endian = "little")]
+    pub fn as_bitmap(&self) -> &[u8] {
+        let bytes: &[u8] = bytemuck::cast_slice(&self.data);
+        &bytes[..self.size.div_ceil(8)]
+    }
+
+    /// Copy of the mask as an Arrow BooleanBuffer of len() elements.
+    #[cfg(all(feature = "arrow", target_endian = "little"))]
+    pub fn to_arrow(&self) -> arrow_buffer::BooleanBuffer {
+        self.clone().into_arrow()
+    }
+
+    /// The mask as an Arrow BooleanBuffer of len() elements, reusing the allocation.
+    #[cfg(all(feature = "arrow", target_endian = "little"))]
+    pub fn into_arrow(self) -> arrow_buffer::BooleanBuffer {
+        arrow_buffer::BooleanBuffer::new(arrow_buffer::Buffer::from_vec(self.data), 0, self.size)
+    }
+
     #[inline(always)]
     pub fn iter_set_entries(&self, mut f: impl FnMut(usize)) {
         let src = self.as_slice();
@@ -227,6 +315,23 @@ impl SimpleVob {
         self.set(tok as usize, false)
     }
 
+    pub fn allow_tokens(&mut self, toks: &[TokenId]) {
+        for &tok in toks {
+            self.allow_token(tok);
+        }
+    }
+
+    pub fn disallow_tokens(&mut self, toks: &[TokenId]) {
+        for &tok in toks {
+            self.disallow_token(tok);
+        }
+    }
+
+    /// List of allowed tokens (below len()).
+    pub fn to_token_ids(&self) -> Vec<TokenId> {
+        self.iter_set().collect()
+    }
+
     #[inline(always)]
     pub fn set(&mut self, idx: usize, val: bool) {
         let byte_idx = idx / BITS;
@@ -279,12 +384,115 @@ impl SimpleVob {
         }
     }
 
+    /// Set logits of disallowed tokens to neg_value (typically f32::NEG_INFINITY),
+    /// leaving allowed ones unchanged.
+    /// Logits past len() (e.g., padding of the model's vocabulary) are also set to neg_value.
+    pub fn apply_to_logits(&self, logits: &mut [f32], neg_value: f32) {
+        let limit = core::cmp::min(logits.len(), self.size);
+        let (inner, rest) = logits.split_at_mut(limit);
+        #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+        crate::simd::apply_to_logits(&self.data, inner, neg_value);
+        #[cfg(not(all(feature = "simd", target_arch = "x86_64")))]
+        for (chunk, v) in inner.chunks_mut(BITS).zip(self.data.iter()) {
+            match *v {
+                // the common cases are handled without branching per element
+                0 => chunk.fill(neg_value),
+                0xffff_ffff => {}
+                v => {
+                    for (bit_idx, x) in chunk.iter_mut().enumerate() {
+                        if v & (1 << bit_idx) == 0 {
+                            *x = neg_value;
+                        }
+                    }
+                }
+            }
+        }
+        rest.fill(neg_value);
+    }
+
+    /// Run-length encoding of the first len() bits: lengths of alternating
+    /// runs of unset and set bits, starting with unset (possibly zero-length) run.
+    pub fn to_runs(&self) -> Vec<u32> {
+        let mut runs = Vec::new();
+        let mut curr = false;
+        let mut len = 0u32;
+        let mut idx = 0;
+        while idx < self.size {
+            let word = self.data[idx / BITS];
+            if idx % BITS == 0 && idx + BITS <= self.size && (word == 0 || word == !0) {
+                // fast path for whole words
+                if (word != 0) != curr {
+                    runs.push(len);
+                    curr = !curr;
+                    len = 0;
+                }
+                len += BITS as u32;
+                idx += BITS;
+                continue;
+            }
+            if self.get(idx) != curr {
+                runs.push(len);
+                curr = !curr;
+                len = 0;
+            }
+            len += 1;
+            idx += 1;
+        }
+        if len > 0 {
+            runs.push(len);
+        }
+        runs
+    }
+
+    /// Inverse of to_runs().
+    pub fn from_runs(size: usize, runs: &[u32]) -> Result<Self, String> {
+        let mut r = Self::alloc(size);
+        let mut idx = 0;
+        for (i, &len) in runs.iter().enumerate() {
+            let end = idx + len as usize;
+            if end > size {
+                return Err(format!("runs exceed size {}", size));
+            }
+            if i % 2 == 1 {
+                for j in idx..end {
+                    r.set(j, true);
+                }
+            }
+            idx = end;
+        }
+        Ok(r)
+    }
+
     pub fn iter(&self) -> SimpleVobIter {
         SimpleVobIter { vob: self, idx: 0 }
     }
 
+    /// Iterate over allowed tokens below len().
+    /// Unlike iter(), this skips bits set in the extra capacity (see alloc_with_capacity()).
+    pub fn iter_set(&self) -> impl Iterator<Item = TokenId> + '_ {
+        let size = self.size;
+        self.iter().take_while(move |&t| (t as usize) < size)
+    }
+
+    /// First allowed token, if any.
+    pub fn first_allowed(&self) -> Option<TokenId> {
+        self.iter_set().next()
+    }
+
+    /// The allowed token, if exactly one token is allowed.
+    pub fn single_allowed(&self) -> Option<TokenId> {
+        let mut it = self.iter_set();
+        match (it.next(), it.next()) {
+            (Some(t), None) => Some(t),
+            _ => None,
+        }
+    }
+
     pub fn or(&mut self, other: &SimpleVob) {
         assert_eq!(self.size, other.size);
+        #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+        crate::simd::or(&mut self.data, &other.data);
+        #[cfg(not(all(feature = "simd", target_arch = "x86_64")))]
         for (idx, v) in self.data.iter_mut().zip(other.data.iter()) {
             *idx |= *v;
         }
@@ -306,6 +514,9 @@ impl SimpleVob {
 
     pub fn and(&mut self, other: &SimpleVob) {
         assert_eq!(self.size, other.size);
+        #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+        crate::simd::and(&mut self.data, &other.data);
+        #[cfg(not(all(feature = "simd", target_arch = "x86_64")))]
         for (idx, v) in self.data.iter_mut().zip(other.data.iter()) {
             *idx &= *v;
         }
@@ -323,13 +534,41 @@ impl SimpleVob {
             .all(|(a, b)| *a & *b == 0)
     }
 
+    /// self &= !other
     pub fn sub(&mut self, other: &SimpleVob) {
         assert_eq!(self.size, other.size);
+        #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+        crate::simd::sub(&mut self.data, &other.data);
+        #[cfg(not(all(feature = "simd", target_arch = "x86_64")))]
         for (idx, v) in self.data.iter_mut().zip(other.data.iter()) {
             *idx &= !*v;
         }
     }
 
+    pub fn xor(&mut self, other: &SimpleVob) {
+        assert_eq!(self.size, other.size);
+        #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+        crate::simd::xor(&mut self.data, &other.data);
+        #[cfg(not(all(feature = "simd", target_arch = "x86_64")))]
+        for (idx, v) in self.data.iter_mut().zip(other.data.iter()) {
+            *idx ^= *v;
+        }
+    }
+
+    /// Check if every element of self is also in other.
+    pub fn is_subset_of(&self, other: &SimpleVob) -> bool {
+        assert_eq!(self.size, other.size);
+        self.data
+            .iter()
+            .zip(other.data.iter())
+            .all(|(a, b)| *a & !*b == 0)
+    }
+
+    /// Check if self and other have any common elements.
+    pub fn intersects(&self, other: &SimpleVob) -> bool {
+        !self.and_is_zero(other)
+    }
+
     pub fn first_bit_set_here_and_in(&self, other: &SimpleVob) -> Option<usize> {
         assert_eq!(self.size, other.size);
         for (idx, (a, b)) in self.data.iter().zip(other.data.iter()).enumerate() {
@@ -389,3 +628,122 @@ impl Index<usize> for SimpleVob {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{TestTokEnv, TokenizerEnv};
+
+    fn roundtrip(v: &SimpleVob) -> SimpleVob {
+        serde_json::from_str(&serde_json::to_string(v).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn serde_roundtrip_trie_mask() {
+        // 256 bytes + 30 words + EOS = 287 tokens; 287 % 32 == 31,
+        // so the extra capacity bit of alloc_token_set() needs a word of its own
+        let words: Vec<String> = (0..30).map(|i| format!("w{i}")).collect();
+        let words: Vec<&str> = words.iter().map(|w| w.as_str()).collect();
+        let env = TestTokEnv::with_words(&words);
+        let trie = env.tok_trie();
+        assert_eq!(trie.vocab_size() % 32, 31);
+
+        let mut dense = trie.alloc_token_set();
+        for tok in (0..trie.vocab_size() as TokenId).step_by(3) {
+            dense.allow_token(tok);
+        }
+        assert!(serde_json::to_string(&dense).unwrap().contains("dense"));
+        assert_eq!(roundtrip(&dense), dense);
+
+        let mut runs = trie.alloc_token_set();
+        runs.allow_tokens(&[1, 2, 3, 100, 286]);
+        assert!(serde_json::to_string(&runs).unwrap().contains("runs"));
+        assert_eq!(roundtrip(&runs), runs);
+        assert_eq!(
+            SimpleVob::from_runs(runs.len(), &runs.to_runs()).unwrap(),
+            runs
+        );
+    }
+
+    #[test]
+    fn constructors_agree() {
+        for size in [0, 1, 31, 32, 33, 63, 64, 95] {
+            let a = SimpleVob::alloc(size);
+            let b = SimpleVob::alloc_with_capacity(size, size + 1);
+            assert_eq!(a, b);
+            assert_eq!(SimpleVob::from_runs(size, &[]).unwrap(), a);
+            assert_eq!(SimpleVob::from_token_ids([], size), a);
+            // bit size is still usable in the extra capacity
+            let mut b = b;
+            b.allow_token(size as TokenId);
+            assert!(b.get(size));
+        }
+        assert!(SimpleVob::alloc_with_capacity(10, 100).as_slice().len() >= 4);
+    }
+
+    #[test]
+    fn dense_excess_words_and_bits() {
+        let v: SimpleVob =
+            serde_json::from_str(r#"{"dense":{"size":31,"data":[4294967295,7,1]}}"#).unwrap();
+        assert_eq!(v.len(), 31);
+        assert_eq!(v.num_set(), 31);
+        assert_eq!(v, SimpleVob::alloc_ones(31));
+        assert!(serde_json::from_str::<SimpleVob>(r#"{"dense":{"size":32,"data":[1]}}"#).is_err());
+    }
+
+    // pseudo-random bits, with long runs of zeros and ones mixed in
+    fn bits(n: usize, seed: u32) -> Vec<bool> {
+        let mut x = seed | 1;
+        (0..n)
+            .map(|i| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                match i / 40 % 3 {
+                    0 => false,
+                    1 => x & 1 != 0,
+                    _ => true,
+                }
+            })
+            .collect()
+    }
+
+    // SimpleVob operation, and its per-bit equivalent
+    type OpCase = (fn(&mut SimpleVob, &SimpleVob), fn(bool, bool) -> bool);
+
+    // with the "simd" feature, this checks the SIMD versions against a per-bit reference
+    #[test]
+    fn ops_match_reference() {
+        for size in [0, 1, 3, 5, 31, 32, 33, 100, 127, 128, 130, 257, 300] {
+            let a = bits(size, 5);
+            let b = bits(size, 9);
+            let va = SimpleVob::from_slice(&a);
+            let vb = SimpleVob::from_slice(&b);
+            assert_eq!(va.num_set(), a.iter().filter(|&&x| x).count());
+
+            let ops: [OpCase; 4] = [
+                (SimpleVob::or, |x, y| x | y),
+                (SimpleVob::and, |x, y| x & y),
+                (SimpleVob::sub, |x, y| x & !y),
+                (SimpleVob::xor, |x, y| x ^ y),
+            ];
+            for (op, scalar) in ops {
+                let mut v = va.clone();
+                op(&mut v, &vb);
+                let expected: Vec<bool> = a.iter().zip(&b).map(|(&x, &y)| scalar(x, y)).collect();
+                assert_eq!(v, SimpleVob::from_slice(&expected), "size {}", size);
+                assert_eq!(v.num_set(), expected.iter().filter(|&&x| x).count());
+            }
+
+            // more logits than bits, as with padded vocabularies
+            for extra in [0, 3] {
+                let mut logits: Vec<f32> = (0..size + extra).map(|i| i as f32).collect();
+                va.apply_to_logits(&mut logits, f32::NEG_INFINITY);
+                for (i, l) in logits.iter().enumerate() {
+                    let allowed = i < size && a[i];
+                    assert_eq!(*l, if allowed { i as f32 } else { f32::NEG_INFINITY });
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,249 @@
+// Loader for Mistral's tekken.json tokenizer format.
+// Special tokens occupy the first ids, followed by the byte-level vocabulary
+// (base64-encoded token_bytes, ordered by rank).
+
+use anyhow::{anyhow, bail, Result};
+use base64::Engine;
+use serde_json::Value;
+
+use crate::prelude::*;
+use crate::{TokEnv, TokRxInfo, TokTrie, TokenId, TokenizerEnv};
+
+// used by tekken files that do not list their special tokens
+const DEFAULT_SPECIAL_TOKENS: &[&str] = &[
+    "<unk>",
+    "<s>",
+    "</s>",
+    "[INST]",
+    "[/INST]",
+    "[AVAILABLE_TOOLS]",
+    "[/AVAILABLE_TOOLS]",
+    "[TOOL_RESULTS]",
+    "[/TOOL_RESULTS]",
+    "[TOOL_CALLS]",
+    "<pad>",
+    "[PREFIX]",
+    "[MIDDLE]",
+    "[SUFFIX]",
+];
+
+// Ids of padding tokens (beyond the listed special tokens or vocab entries)
+// can't go further than this, so that a bogus size can't make us allocate gigabytes.
+const MAX_ID_GAP: usize = 1 << 16;
+
+/// TokenizerEnv for Mistral tekken.json tokenizers.
+/// Tokenization is greedy over the trie, so it is not canonical.
+pub struct TekkenTokenizerEnv {
+    tok_trie: TokTrie,
+}
+
+impl TekkenTokenizerEnv {
+    /// Build from the contents of a tekken.json file.
+    pub fn from_json(bytes: &[u8]) -> Result<Self> {
+        let json: Value = serde_json::from_slice(bytes)?;
+        let config = &json["config"];
+
+        let special: Vec<String> = match json["special_tokens"].as_array() {
+            Some(arr) => {
+                let mut res = Vec::new();
+                for (idx, t) in arr.iter().enumerate() {
+                    let rank = t["rank"].as_u64().unwrap_or(idx as u64) as usize;
+                    if rank != idx {
+                        bail!("special tokens out of order at {}", idx);
+                    }
+                    let name = t["token_str"]
+                        .as_str()
+                        .ok_or_else(|| anyhow!("invalid special token at {}", idx))?;
+                    res.push(name.to_string());
+                }
+                res
+            }
+            None => DEFAULT_SPECIAL_TOKENS
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+        };
+
+        let num_special = config["default_num_special_tokens"]
+            .as_u64()
+            .map(|n| n as usize)
+            .unwrap_or(special.len());
+        if num_special < special.len() {
+            bail!(
+                "too many special tokens: {} vs {}",
+                special.len(),
+                num_special
+            );
+        }
+        if num_special > special.len() + MAX_ID_GAP {
+            bail!("too many unnamed special tokens: {}", num_special);
+        }
+
+        let vocab = json["vocab"]
+            .as_array()
+            .ok_or_else(|| anyhow!("missing vocab"))?;
+        let vocab_size = config["default_vocab_size"]
+            .as_u64()
+            .map(|n| n as usize)
+            .unwrap_or(num_special + vocab.len());
+        if vocab_size < num_special {
+            bail!("vocab size smaller than number of special tokens");
+        }
+        if vocab_size > num_special + vocab.len() + MAX_ID_GAP {
+            bail!(
+                "vocab size {} too large for {} vocab entries",
+                vocab_size,
+                vocab.len()
+            );
+        }
+        let num_inner = core::cmp::min(vocab.len(), vocab_size - num_special);
+
+        let mut info = TokRxInfo::new(vocab_size as u32, 0);
+        let mut words = Vec::with_capacity(vocab_size);
+        for idx in 0..num_special {
+            let name = match special.get(idx) {
+                Some(n) => n.clone(),
+                None => format!("<SPECIAL_{}>", idx),
+            };
+            info.assign_role_by_name(&name, idx as TokenId)?;
+            let mut bytes = name.into_bytes();
+            bytes.insert(0, TokTrie::SPECIAL_TOKEN_MARKER);
+            words.push(bytes);
+        }
+
+        let b64 = base64::engine::general_purpose::STANDARD;
+        for (idx, t) in vocab[0..num_inner].iter().enumerate() {
+            let enc = t["token_bytes"]
+                .as_str()
+                .ok_or_else(|| anyhow!("missing token_bytes at {}", idx))?;
+            words.push(
+                b64.decode(enc)
+                    .map_err(|e| anyhow!("invalid token_bytes at {}: {}", idx, e))?,
+            );
+        }
+        words.resize(vocab_size, Vec::new());
+
+        Ok(TekkenTokenizerEnv {
+            tok_trie: TokTrie::from(&info, &words),
+        })
+    }
+
+    /// Build from a tekken.json file on disk.
+    #[cfg(feature = "std")]
+    pub fn from_file(path: &str) -> Result<Self> {
+        let bytes = std::fs::read(path).map_err(|e| anyhow!("error reading {}: {}", path, e))?;
+        Self::from_json(&bytes)
+    }
+
+    /// Like from_file(), but the trie is cached in cache_dir (see TokTrie::load_cached()),
+    /// so that subsequent loads of the same file skip building it.
+    #[cfg(feature = "std")]
+    pub fn from_file_cached(path: &str, cache_dir: &std::path::Path) -> Result<Self> {
+        let bytes = std::fs::read(path).map_err(|e| anyhow!("error reading {}: {}", path, e))?;
+        let tok_trie =
+            TokTrie::load_cached(cache_dir, &bytes, || Ok(Self::from_json(&bytes)?.tok_trie))?;
+        Ok(TekkenTokenizerEnv { tok_trie })
+    }
+
+    pub fn to_env(self) -> TokEnv {
+        alloc::sync::Arc::new(self)
+    }
+}
+
+impl TokenizerEnv for TekkenTokenizerEnv {
+    fn tok_trie(&self) -> &TokTrie {
+        &self.tok_trie
+    }
+
+    fn tokenize_bytes(&self, s: &[u8]) -> Vec<TokenId> {
+        self.tok_trie.greedy_tokenize(s)
+    }
+
+    fn count_tokens(&self, s: &[u8]) -> usize {
+        self.tok_trie.greedy_count(s)
+    }
+
+    fn tokenize_is_canonical(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SpecialToken;
+
+    // "a", "b", "ab", " ", "x"
+    const TEKKEN: &str = r#"{
+        "config": { "default_num_special_tokens": 6, "default_vocab_size": 11 },
+        "special_tokens": [
+            { "rank": 0, "token_str": "<unk>" },
+            { "rank": 1, "token_str": "<s>" },
+            { "rank": 2, "token_str": "</s>" },
+            { "rank": 3, "token_str": "[TOOL_CALLS]" },
+            { "rank": 4, "token_str": "[PREFIX]" }
+        ],
+        "vocab": [
+            { "rank": 0, "token_bytes": "YQ==" },
+            { "rank": 1, "token_bytes": "Yg==" },
+            { "rank": 2, "token_bytes": "YWI=" },
+            { "rank": 3, "token_bytes": "IA==" },
+            { "rank": 4, "token_bytes": "eA==" }
+        ]
+    }"#;
+
+    #[test]
+    fn special_and_inner_vocab() {
+        let env = TekkenTokenizerEnv::from_json(TEKKEN.as_bytes()).unwrap();
+        let trie = env.tok_trie();
+        assert_eq!(trie.vocab_size(), 11);
+        assert_eq!(trie.get_special_token("</s>"), Some(2));
+        assert_eq!(trie.get_special_token("<SPECIAL_5>"), Some(5));
+        assert_eq!(trie.token(6), b"a");
+        assert_eq!(trie.token(8), b"ab");
+        assert_eq!(trie.token(9), b" ");
+        assert_eq!(trie.token(10), b"x");
+        assert_eq!(env.tokenize_bytes(b"ab a"), vec![8, 9, 6]);
+
+        let info = trie.info();
+        assert_eq!(info.tok_eos, 2);
+        assert_eq!(info.tok_bos, Some(1));
+        assert_eq!(info.tok_unk, Some(0));
+        assert_eq!(info.role_token(SpecialToken::ToolCallBegin), Some(3));
+        assert_eq!(info.role_token(SpecialToken::FimPrefix), Some(4));
+
+        // only 2 inner tokens fit
+        let smaller = TEKKEN.replace(r#""default_vocab_size": 11"#, r#""default_vocab_size": 8"#);
+        let env = TekkenTokenizerEnv::from_json(smaller.as_bytes()).unwrap();
+        assert_eq!(env.tok_trie().vocab_size(), 8);
+        assert_eq!(env.tok_trie().token_id(b"ab"), None);
+    }
+
+    #[test]
+    fn bad_sizes() {
+        for (from, to) in [
+            (
+                r#""default_vocab_size": 11"#,
+                r#""default_vocab_size": 4000000000"#,
+            ),
+            (r#""default_vocab_size": 11"#, r#""default_vocab_size": 5"#),
+            (
+                r#""default_num_special_tokens": 6"#,
+                r#""default_num_special_tokens": 4"#,
+            ),
+            (
+                r#""default_num_special_tokens": 6"#,
+                r#""default_num_special_tokens": 4000000000"#,
+            ),
+            (r#""rank": 4, "token_str""#, r#""rank": 7, "token_str""#),
+            ("YWI=", "!!"),
+        ] {
+            let json = TEKKEN.replace(from, to);
+            assert!(
+                TekkenTokenizerEnv::from_json(json.as_bytes()).is_err(),
+                "{}",
+                to
+            );
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use anyhow::{bail, Result};
+
+use crate::prelude::*;
+use crate::{Recognizer, SimpleVob, SpecialToken, TokRxInfo, TokTrie, TokenId, TokenizerEnv};
+
+/// Small deterministic tokenizer, for testing constraints without real tokenizer files.
+/// Tokens 0..256 are the single bytes, followed by the given words,
+/// and the special EOS token <|end|> last.
+/// Tokenization is greedy (longest match), so any input can be tokenized
+/// and decoding gives back the input.
+pub struct TestTokEnv {
+    tok_trie: TokTrie,
+}
+
+impl TestTokEnv {
+    pub const EOS_NAME: &'static str = "<|end|>";
+
+    pub fn with_words(words: &[&str]) -> Self {
+        let mut all: Vec<Vec<u8>> = (0..=255u8).map(|b| vec![b]).collect();
+        for w in words {
+            let w = w.as_bytes();
+            if w.len() > 1 && !all.iter().any(|x| x == w) {
+                all.push(w.to_vec());
+            }
+        }
+        let mut eos = Self::EOS_NAME.as_bytes().to_vec();
+        eos.insert(0, TokTrie::SPECIAL_TOKEN_MARKER);
+        all.push(eos);
+        let n = all.len() as u32;
+        let mut info = TokRxInfo::new(n, n - 1);
+        info.assign_role_by_name(Self::EOS_NAME, n - 1).unwrap();
+        TestTokEnv {
+            tok_trie: TokTrie::from(&info, &all),
+        }
+    }
+
+    /// Token for given word (or single byte).
+    pub fn token(&self, word: &str) -> Option<TokenId> {
+        self.tok_trie.token_id_at_bytes(word.as_bytes())
+    }
+
+    /// Readable list of allowed tokens, like ["\"a\"", "\"ab\"", "EOS"].
+    pub fn allowed_names(&self, mask: &SimpleVob) -> Vec<String> {
+        self.allowed(mask)
+            .map(|t| {
+                if t == self.tok_trie.eos_token() {
+                    "EOS".to_string()
+                } else {
+                    self.tok_trie.token_dbg(t)
+                }
+            })
+            .collect()
+    }
+
+    /// Check that only tokens satisfying pred are allowed,
+    /// e.g., check_only_allowed(&mask, |t| t.starts_with(b"a")).
+    /// EOS is passed as its special token bytes.
+    pub fn check_only_allowed(&self, mask: &SimpleVob, pred: impl Fn(&[u8]) -> bool) -> Result<()> {
+        let bad: Vec<TokenId> = self
+            .allowed(mask)
+            .filter(|&t| !pred(self.tok_trie.token(t)))
+            .collect();
+        if !bad.is_empty() {
+            bail!(
+                "unexpected tokens allowed: {}",
+                self.tok_trie.tokens_dbg(&bad)
+            );
+        }
+        Ok(())
+    }
+
+    /// Check that exactly the tokens satisfying pred are allowed.
+    pub fn check_exactly_allowed(
+        &self,
+        mask: &SimpleVob,
+        pred: impl Fn(&[u8]) -> bool,
+    ) -> Result<()> {
+        self.check_only_allowed(mask, &pred)?;
+        let missing: Vec<TokenId> = (0..self.tok_trie.vocab_size() as TokenId)
+            .filter(|&t| !mask.is_allowed(t) && pred(self.tok_trie.token(t)))
+            .collect();
+        if !missing.is_empty() {
+            bail!(
+                "expected tokens not allowed: {}",
+                self.tok_trie.tokens_dbg(&missing)
+            );
+        }
+        Ok(())
+    }
+
+    /// Reference for TokTrie::compute_bias(): pushes the bytes of every token separately,
+    /// without the trie walk and the recognizer fast paths (all_bytes_allowed() etc.).
+    /// As in the trie walk, special tokens are allowed if the recognizer accepts
+    /// their bytes (with the marker); stop tokens also if special_allowed() says so.
+    pub fn compute_bias_slow(&self, r: &mut impl Recognizer) -> SimpleVob {
+        let trie = &self.tok_trie;
+        let info = trie.info();
+        let mut mask = trie.alloc_token_set();
+        if r.special_allowed(SpecialToken::EndOfSentence) {
+            mask.allow_token(info.tok_eos);
+            info.extra_stop_tokens().for_each(|t| mask.allow_token(t));
+        }
+        if r.special_allowed(SpecialToken::EndOfTurn) {
+            if let Some(t) = info.tok_end_of_turn {
+                mask.allow_token(t);
+            }
+        }
+        for tok in 0..trie.vocab_size() as TokenId {
+            let bytes = trie.token(tok);
+            if bytes.is_empty() {
+                continue;
+            }
+            r.trie_started();
+            let n = bytes.iter().take_while(|&&b| r.try_push_byte(b)).count();
+            if n == bytes.len() {
+                mask.allow_token(tok);
+            }
+            r.pop_bytes(n);
+            r.trie_finished();
+        }
+        mask
+    }
+
+    /// Check that TokTrie::compute_bias() agrees with compute_bias_slow().
+    pub fn check_bias(&self, r: &mut impl Recognizer) -> Result<()> {
+        let mut fast = self.tok_trie.alloc_token_set();
+        self.tok_trie.compute_bias(r, &mut fast);
+        let slow = self.compute_bias_slow(r);
+        if fast != slow {
+            bail!(
+                "compute_bias() allows {:?}, expected {:?}",
+                self.allowed_names(&fast),
+                self.allowed_names(&slow)
+            );
+        }
+        Ok(())
+    }
+
+    fn allowed<'a>(&'a self, mask: &'a SimpleVob) -> impl Iterator<Item = TokenId> + 'a {
+        (0..self.tok_trie.vocab_size() as TokenId).filter(|&t| mask.is_allowed(t))
+    }
+}
+
+impl TokenizerEnv for TestTokEnv {
+    fn tok_trie(&self) -> &TokTrie {
+        &self.tok_trie
+    }
+
+    fn tokenize_bytes(&self, s: &[u8]) -> Vec<TokenId> {
+        self.tok_trie.greedy_tokenize(s)
+    }
+
+    fn count_tokens(&self, s: &[u8]) -> usize {
+        self.tok_trie.greedy_count(s)
+    }
+}
@@ -0,0 +1,124 @@
+use std::{
+    cell::RefCell,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{FxHashMap, TokTrie, TokenId, TokenizerEnv};
+
+/// Tokenization function of a backend that can't be shared between threads.
+pub type Encoder = Box<dyn FnMut(&[u8]) -> Vec<TokenId>>;
+
+type EncoderFactory = Box<dyn Fn() -> Encoder + Send + Sync>;
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+thread_local! {
+    // encoders of all ThreadLocalTokEnv instances used on this thread, by instance id
+    static ENCODERS: RefCell<FxHashMap<usize, Encoder>> = RefCell::new(FxHashMap::default());
+}
+
+/// TokenizerEnv (and thus usable as TokEnv) for backends that are not Send or Sync.
+/// Each thread lazily creates its own encoder with the factory function,
+/// instead of all threads contending for a single encoder behind a Mutex.
+/// Encoders created on other threads are only freed when those threads exit.
+pub struct ThreadLocalTokEnv {
+    tok_trie: TokTrie,
+    id: usize,
+    factory: EncoderFactory,
+    canonical: bool,
+}
+
+impl ThreadLocalTokEnv {
+    pub fn new(tok_trie: TokTrie, factory: impl Fn() -> Encoder + Send + Sync + 'static) -> Self {
+        ThreadLocalTokEnv {
+            tok_trie,
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            factory: Box::new(factory),
+            canonical: true,
+        }
+    }
+
+    /// See TokenizerEnv::tokenize_is_canonical().
+    pub fn with_canonical(mut self, canonical: bool) -> Self {
+        self.canonical = canonical;
+        self
+    }
+}
+
+impl TokenizerEnv for ThreadLocalTokEnv {
+    fn tok_trie(&self) -> &TokTrie {
+        &self.tok_trie
+    }
+
+    fn tokenize_bytes(&self, s: &[u8]) -> Vec<TokenId> {
+        // the encoder is taken out of the map while running, in case it tokenizes recursively
+        let enc = ENCODERS.with(|m| m.borrow_mut().remove(&self.id));
+        let mut enc = enc.unwrap_or_else(|| (self.factory)());
+        let res = enc(s);
+        ENCODERS.with(|m| m.borrow_mut().insert(self.id, enc));
+        res
+    }
+
+    fn tokenize_is_canonical(&self) -> bool {
+        self.canonical
+    }
+}
+
+impl Drop for ThreadLocalTokEnv {
+    fn drop(&mut self) {
+        // may fail if the thread is being torn down
+        let _ = ENCODERS.try_with(|m| m.borrow_mut().remove(&self.id));
+    }
+}
+
+// All TokenizerEnv implementations in this crate can be shared between threads as TokEnv.
+const _: fn() = || {
+    fn check<T: TokenizerEnv + Sync>() {}
+    check::<crate::TokEnvWithTrie>();
+    check::<crate::TekkenTokenizerEnv>();
+    check::<ThreadLocalTokEnv>();
+    check::<crate::NormalizedTokEnv>();
+    check::<crate::TestTokEnv>();
+    check::<crate::FallbackTokEnv>();
+    check::<crate::RecordingTokEnv>();
+    check::<crate::ReplayTokEnv>();
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TestTokEnv;
+    use std::sync::{atomic::AtomicUsize, Arc};
+
+    #[test]
+    fn encoder_per_thread() {
+        let created = Arc::new(AtomicUsize::new(0));
+        let trie = TestTokEnv::with_words(&["ab"]).tok_trie().clone();
+        let created2 = created.clone();
+        let env = Arc::new(
+            ThreadLocalTokEnv::new(trie, move || {
+                created2.fetch_add(1, Ordering::Relaxed);
+                Box::new(|s: &[u8]| s.iter().map(|&b| b as TokenId).collect())
+            })
+            .with_canonical(false),
+        );
+        assert!(!env.tokenize_is_canonical());
+        assert_eq!(env.tokenize_bytes(b"ab"), vec![97, 98]);
+        assert_eq!(env.tokenize_bytes(b"c"), vec![99]);
+        assert_eq!(created.load(Ordering::Relaxed), 1);
+
+        let handles: Vec<_> = (0..3)
+            .map(|_| {
+                let env = env.clone();
+                std::thread::spawn(move || {
+                    assert_eq!(env.tokenize_bytes(b"xy"), vec![120, 121]);
+                    env.tokenize_bytes(b"z")
+                })
+            })
+            .collect();
+        for h in handles {
+            assert_eq!(h.join().unwrap(), vec![122]);
+        }
+        assert_eq!(created.load(Ordering::Relaxed), 4);
+    }
+}
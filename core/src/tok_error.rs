@@ -0,0 +1,33 @@
+use crate::prelude::*;
+use crate::TokenId;
+
+/// Error of the fallible (try_*) tokenization and decoding functions.
+/// These return anyhow::Result; the error can be downcast to TokError.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TokError {
+    /// Decoded bytes are not valid UTF-8; the prefix up to valid_up_to is.
+    InvalidUtf8 { valid_up_to: usize },
+    /// Special token name not present in the tokenizer.
+    UnknownSpecialToken(String),
+    /// Token id outside of the vocabulary.
+    VocabOverflow { token: TokenId, vocab_size: usize },
+    /// Error reported by the underlying tokenizer implementation.
+    Backend(String),
+}
+
+impl core::fmt::Display for TokError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            TokError::InvalidUtf8 { valid_up_to } => {
+                write!(f, "invalid UTF-8 after {} bytes", valid_up_to)
+            }
+            TokError::UnknownSpecialToken(name) => write!(f, "unknown special token {:?}", name),
+            TokError::VocabOverflow { token, vocab_size } => {
+                write!(f, "token {} out of vocabulary of {}", token, vocab_size)
+            }
+            TokError::Backend(msg) => write!(f, "tokenizer error: {}", msg),
+        }
+    }
+}
+
+impl core::error::Error for TokError {}
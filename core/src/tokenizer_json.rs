@@ -0,0 +1,387 @@
+// Loader for HuggingFace tokenizer.json files.
+// Only the parts needed to build the trie are interpreted:
+// the vocabulary, the added tokens, and the decoder (to figure out
+// how token strings map to bytes).
+
+use anyhow::{anyhow, bail, Result};
+use serde_json::Value;
+
+use crate::prelude::*;
+use crate::{bytes::byte_level_decode, AddedToken, TokRxInfo, TokTrie, TokenId};
+
+pub(crate) enum DecoderKind {
+    ByteLevel,
+    ByteFallback { space_ch: char },
+}
+
+fn decoder_kind(decoder: &Value) -> Result<DecoderKind> {
+    let mut is_byte_fallback = false;
+    let mut space_ch = ' ';
+    match decoder["type"].as_str() {
+        Some("ByteLevel") => return Ok(DecoderKind::ByteLevel),
+        Some("Metaspace") => {
+            if let Some(s) = decoder["replacement"].as_str() {
+                if let Some(c) = s.chars().next() {
+                    space_ch = c;
+                }
+            }
+            return Ok(DecoderKind::ByteFallback { space_ch });
+        }
+        Some("Sequence") => {
+            if let Some(decoders) = decoder["decoders"].as_array() {
+                for decoder in decoders {
+                    match decoder["type"].as_str() {
+                        Some("ByteLevel") => return Ok(DecoderKind::ByteLevel),
+                        Some("ByteFallback") => is_byte_fallback = true,
+                        Some("Replace") if decoder["content"].as_str() == Some(" ") => {
+                            if let Some(s) = decoder["pattern"]["String"].as_str() {
+                                let s: Vec<char> = s.chars().collect();
+                                if s.len() == 1 {
+                                    space_ch = s[0];
+                                }
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+    if is_byte_fallback {
+        Ok(DecoderKind::ByteFallback { space_ch })
+    } else {
+        bail!("can't determine decoder type: {}", decoder)
+    }
+}
+
+pub(crate) fn token_to_bytes(kind: &DecoderKind, tok_name: &str) -> Result<Vec<u8>> {
+    match kind {
+        DecoderKind::ByteFallback { space_ch } => {
+            if tok_name.len() == 6 && tok_name.starts_with("<0x") && tok_name.ends_with('>') {
+                let byte = u8::from_str_radix(&tok_name[3..5], 16)?;
+                Ok(vec![byte])
+            } else {
+                Ok(tok_name.replace(*space_ch, " ").into_bytes())
+            }
+        }
+        DecoderKind::ByteLevel => {
+            byte_level_decode(tok_name).ok_or_else(|| anyhow!("missing char in {:?}", tok_name))
+        }
+    }
+}
+
+fn vocab_entries(model: &Value) -> Result<Vec<(String, TokenId)>> {
+    match &model["vocab"] {
+        // BPE, WordPiece, ...
+        Value::Object(map) => map
+            .iter()
+            .map(|(k, v)| {
+                let id = v
+                    .as_u64()
+                    .and_then(|id| TokenId::try_from(id).ok())
+                    .ok_or_else(|| anyhow!("invalid token id for {:?}", k))?;
+                Ok((k.clone(), id))
+            })
+            .collect(),
+        // Unigram: [[piece, score], ...]
+        Value::Array(arr) => arr
+            .iter()
+            .enumerate()
+            .map(|(idx, e)| {
+                let piece = e[0]
+                    .as_str()
+                    .ok_or_else(|| anyhow!("invalid vocab entry at {}", idx))?;
+                Ok((piece.to_string(), idx as TokenId))
+            })
+            .collect(),
+        _ => bail!("missing model.vocab"),
+    }
+}
+
+// Token ids can leave gaps in the vocabulary, but not more than this many,
+// so that a single bogus id can't make us allocate gigabytes.
+const MAX_ID_GAP: usize = 1 << 16;
+
+// Trie of a tokenizer.json file, and whether an EOS token was found among the special tokens.
+fn load_tokenizer_json(bytes: &[u8]) -> Result<(TokTrie, bool)> {
+    let json: Value = serde_json::from_slice(bytes)?;
+    let kind = decoder_kind(&json["decoder"])?;
+
+    let vocab = vocab_entries(&json["model"])?;
+    let added = match json["added_tokens"].as_array() {
+        Some(a) => a.as_slice(),
+        None => &[],
+    };
+
+    let max_id = vocab.len() + added.len() + MAX_ID_GAP;
+    let mut added_ids = Vec::with_capacity(added.len());
+    for tok in added {
+        let id = tok["id"]
+            .as_u64()
+            .ok_or_else(|| anyhow!("added token without id: {}", tok))?;
+        added_ids.push(id);
+    }
+    let mut vocab_size: TokenId = 0;
+    for id in vocab
+        .iter()
+        .map(|(_, id)| *id as u64)
+        .chain(added_ids.iter().copied())
+    {
+        if id >= max_id as u64 {
+            bail!(
+                "token id {} too large for {} vocab entries",
+                id,
+                max_id - MAX_ID_GAP
+            );
+        }
+        // can't overflow, given the check above
+        vocab_size = core::cmp::max(vocab_size, id as TokenId + 1);
+    }
+
+    // tok_eos is set by assign_role_by_name() if one of the special tokens is the EOS
+    let mut info = TokRxInfo::new(vocab_size, TokenId::MAX);
+    let mut words = vec![Vec::new(); vocab_size as usize];
+
+    for (name, id) in vocab {
+        match token_to_bytes(&kind, &name) {
+            Ok(b) => words[id as usize] = b,
+            Err(e) => bail!("error: {} for {:?}", e, name),
+        }
+    }
+
+    let mut added_meta = Vec::new();
+    for (tok, id) in added.iter().zip(added_ids) {
+        let id = id as TokenId;
+        let content = tok["content"]
+            .as_str()
+            .ok_or_else(|| anyhow!("added token without content: {}", tok))?;
+        if tok["special"].as_bool().unwrap_or(false) {
+            info.assign_role_by_name(content, id)?;
+            let mut bytes = content.as_bytes().to_vec();
+            bytes.insert(0, TokTrie::SPECIAL_TOKEN_MARKER);
+            words[id as usize] = bytes;
+        } else {
+            words[id as usize] = content.as_bytes().to_vec();
+        }
+        added_meta.push(AddedToken {
+            id,
+            content: content.to_string(),
+            special: tok["special"].as_bool().unwrap_or(false),
+            normalized: tok["normalized"].as_bool().unwrap_or(false),
+            single_word: tok["single_word"].as_bool().unwrap_or(false),
+        });
+    }
+
+    let has_eos = info.tok_eos != TokenId::MAX;
+    if !has_eos {
+        // placeholder, to be replaced by the caller
+        info.tok_eos = 0;
+    }
+    Ok((
+        TokTrie::from(&info, &words).with_added_tokens(&added_meta),
+        has_eos,
+    ))
+}
+
+impl TokTrie {
+    /// Build a trie from the contents of a HuggingFace tokenizer.json file.
+    /// Special added tokens are prefixed with SPECIAL_TOKEN_MARKER,
+    /// and well-known names (</s>, <|eot_id|>, ...) are assigned roles in TokRxInfo.
+    /// Fails if none of the special tokens is a well-known EOS token;
+    /// use from_tokenizer_json_with_config() for such tokenizers.
+    pub fn from_tokenizer_json(bytes: &[u8]) -> Result<Self> {
+        let (trie, has_eos) = load_tokenizer_json(bytes)?;
+        if !has_eos {
+            bail!("no EOS token among special tokens; pass tokenizer_config.json to set it");
+        }
+        Ok(trie)
+    }
+
+    /// Build a trie from the contents of tokenizer.json and tokenizer_config.json files.
+    /// Roles set in the config take precedence, see with_tokenizer_config().
+    /// Fails if neither file specifies the EOS token.
+    pub fn from_tokenizer_json_with_config(bytes: &[u8], config: &[u8]) -> Result<Self> {
+        let (trie, has_eos) = load_tokenizer_json(bytes)?;
+        let json: Value = serde_json::from_slice(config)?;
+        if !has_eos && config_token_name(&json["eos_token"]).is_none() {
+            bail!("no EOS token among special tokens or in tokenizer_config.json");
+        }
+        trie.with_tokenizer_config(config)
+    }
+
+    /// Build a trie from a HuggingFace tokenizer.json file on disk.
+    #[cfg(feature = "std")]
+    pub fn from_tokenizer_json_file(path: &str) -> Result<Self> {
+        let bytes = std::fs::read(path).map_err(|e| anyhow!("error reading {}: {}", path, e))?;
+        Self::from_tokenizer_json(&bytes)
+    }
+}
+
+/// Extract the token name from a tokenizer_config.json entry,
+/// which is either a plain string or an object like {"content": "</s>", ...}.
+fn config_token_name(v: &Value) -> Option<&str> {
+    match v {
+        Value::String(s) => Some(s.as_str()),
+        Value::Object(_) => v["content"].as_str(),
+        _ => None,
+    }
+}
+
+impl TokTrie {
+    /// Look up a token by name, trying special tokens first and then regular tokens.
+    fn token_by_name(&self, name: &str) -> Option<TokenId> {
+        self.get_special_token(name)
+            .or_else(|| self.token_id_at_bytes(name.as_bytes()))
+    }
+
+    /// Return a copy of this trie with eos/bos/pad/unk roles set
+    /// from the contents of a HuggingFace tokenizer_config.json file.
+    /// Entries that are missing or null in the config are left unchanged.
+    pub fn with_tokenizer_config(&self, bytes: &[u8]) -> Result<Self> {
+        let json: Value = serde_json::from_slice(bytes)?;
+        let mut info = *self.info();
+        for key in ["eos_token", "bos_token", "pad_token", "unk_token"] {
+            let name = match config_token_name(&json[key]) {
+                Some(n) => n,
+                None => continue,
+            };
+            let id = self
+                .token_by_name(name)
+                .ok_or_else(|| anyhow!("{} {:?} not found in vocabulary", key, name))?;
+            match key {
+                "eos_token" => info.tok_eos = id,
+                "bos_token" => info.tok_bos = Some(id),
+                "pad_token" => info.tok_pad = Some(id),
+                "unk_token" => info.tok_unk = Some(id),
+                _ => unreachable!(),
+            }
+        }
+        Ok(self.with_info(info))
+    }
+
+    /// Same as with_tokenizer_config(), but reads the file from disk.
+    #[cfg(feature = "std")]
+    pub fn with_tokenizer_config_file(&self, path: &str) -> Result<Self> {
+        let bytes = std::fs::read(path).map_err(|e| anyhow!("error reading {}: {}", path, e))?;
+        self.with_tokenizer_config(&bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SpecialToken;
+
+    const BYTE_LEVEL: &str = r#"{
+        "model": { "type": "BPE", "vocab": { "a": 0, "b": 1, "Ġa": 2, "ab": 3 } },
+        "added_tokens": [
+            { "id": 4, "content": "<|endoftext|>", "special": true },
+            { "id": 5, "content": "<|user|>", "special": false, "single_word": true }
+        ],
+        "decoder": { "type": "ByteLevel" }
+    }"#;
+
+    const SENTENCEPIECE: &str = r#"{
+        "model": { "type": "Unigram", "vocab": [
+            ["<unk>", 0], ["<s>", 0], ["</s>", 0], ["<0x41>", 0], ["▁hi", -1.0], ["x", -2.0]
+        ] },
+        "added_tokens": [
+            { "id": 0, "content": "<unk>", "special": true },
+            { "id": 1, "content": "<s>", "special": true },
+            { "id": 2, "content": "</s>", "special": true }
+        ],
+        "decoder": { "type": "Sequence", "decoders": [
+            { "type": "Replace", "pattern": { "String": "▁" }, "content": " " },
+            { "type": "ByteFallback" }
+        ] }
+    }"#;
+
+    fn with_added(id: &str) -> String {
+        BYTE_LEVEL.replace(r#""id": 5"#, &format!(r#""id": {}"#, id))
+    }
+
+    #[test]
+    fn byte_level() {
+        let trie = TokTrie::from_tokenizer_json(BYTE_LEVEL.as_bytes()).unwrap();
+        assert_eq!(trie.vocab_size(), 6);
+        assert_eq!(trie.token(2), b" a");
+        assert_eq!(trie.token(3), b"ab");
+        assert_eq!(trie.eos_token(), 4);
+        assert_eq!(trie.get_special_token("<|endoftext|>"), Some(4));
+        let special: Vec<TokenId> = trie.special_tokens_iter().map(|t| t.id).collect();
+        assert_eq!(special, vec![4]);
+        assert_eq!(trie.token(5), b"<|user|>");
+        assert_eq!(trie.token_id(b"<|user|>"), Some(5));
+        let added = trie.added_token(5).unwrap();
+        assert!(!added.special && added.single_word);
+        assert_eq!(trie.greedy_tokenize(b" aab"), vec![2, 3]);
+    }
+
+    #[test]
+    fn sentencepiece() {
+        let trie = TokTrie::from_tokenizer_json(SENTENCEPIECE.as_bytes()).unwrap();
+        assert_eq!(trie.vocab_size(), 6);
+        assert_eq!(trie.token(3), b"A");
+        assert_eq!(trie.token(4), b" hi");
+        assert_eq!(trie.token(5), b"x");
+        let info = trie.info();
+        assert_eq!(info.tok_eos, 2);
+        assert_eq!(info.tok_bos, Some(1));
+        assert_eq!(info.tok_unk, Some(0));
+        assert_eq!(trie.get_special_token("</s>"), Some(2));
+    }
+
+    #[test]
+    fn bad_ids() {
+        for id in ["4294967295", "4000000000", "5000000000", "1000000", "-1"] {
+            assert!(TokTrie::from_tokenizer_json(with_added(id).as_bytes()).is_err());
+        }
+        let vocab_id = BYTE_LEVEL.replace(r#""ab": 3"#, r#""ab": 4294967296"#);
+        assert!(TokTrie::from_tokenizer_json(vocab_id.as_bytes()).is_err());
+        // gaps are fine, as long as they are not too big
+        let trie = TokTrie::from_tokenizer_json(with_added("100").as_bytes()).unwrap();
+        assert_eq!(trie.vocab_size(), 101);
+        assert_eq!(trie.token(50), b"");
+
+        let unknown_decoder = BYTE_LEVEL.replace("ByteLevel", "WordPiece");
+        assert!(TokTrie::from_tokenizer_json(unknown_decoder.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn missing_eos() {
+        let json = BYTE_LEVEL.replace("<|endoftext|>", "<|something|>");
+        assert!(TokTrie::from_tokenizer_json(json.as_bytes()).is_err());
+        assert!(TokTrie::from_tokenizer_json_with_config(json.as_bytes(), b"{}").is_err());
+        let trie = TokTrie::from_tokenizer_json_with_config(
+            json.as_bytes(),
+            br#"{ "eos_token": "<|something|>" }"#,
+        )
+        .unwrap();
+        assert_eq!(trie.eos_token(), 4);
+        assert_eq!(trie.special_token(SpecialToken::EndOfSentence), 4);
+    }
+
+    #[test]
+    fn roles_from_config() {
+        let trie = TokTrie::from_tokenizer_json(SENTENCEPIECE.as_bytes()).unwrap();
+        let config = br#"{
+            "eos_token": { "content": "x", "special": false },
+            "bos_token": "</s>",
+            "pad_token": "<unk>",
+            "unk_token": null
+        }"#;
+        let t2 = trie.with_tokenizer_config(config).unwrap();
+        let info = t2.info();
+        assert_eq!(info.tok_eos, 5);
+        assert_eq!(info.tok_bos, Some(2));
+        assert_eq!(info.tok_pad, Some(0));
+        assert_eq!(info.tok_unk, Some(0));
+        assert_eq!(t2.with_tokenizer_config(b"{}").unwrap().info(), info);
+
+        assert!(trie
+            .with_tokenizer_config(br#"{ "pad_token": "<pad>" }"#)
+            .is_err());
+        assert!(trie.with_tokenizer_config(b"not json").is_err());
+    }
+}
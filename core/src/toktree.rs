@@ -1,15 +1,16 @@
 // use 8:24 encoding - num_ch:tok_id (ch_byte:ch_off)* - 8 bytes per tree node
 // special case num_ch=0xff -> num_ch=0x100
 
-use std::sync::Arc;
+use alloc::sync::Arc;
 
-use anyhow::Result;
+use anyhow::{ensure, Result};
 use bytemuck_derive::{Pod, Zeroable};
-use rustc_hash::FxHashMap;
+use serde::{Deserialize, Serialize};
 
+use crate::prelude::*;
 use crate::{
-    bytes::{to_hex_string, vec_from_bytes},
-    SimpleVob,
+    bytes::{byte_level_decode, to_hex_string, vec_from_bytes, vec_from_unaligned_bytes},
+    FxHashMap, FxHashSet, SimpleVob, TokError,
 };
 
 pub type TokenId = u32;
@@ -19,9 +20,23 @@ pub type TokenId = u32;
 pub struct BinTokRxInfo {
     pub vocab_size: u32,
     pub tok_eos: TokenId,
+    /// BinTokRxInfo::NONE if not set.
+    pub tok_end_of_turn: TokenId,
+    /// TokRxInfo::tok_stop, padded with BinTokRxInfo::NONE.
+    pub tok_stop: [TokenId; TokRxInfo::MAX_STOP_TOKENS],
+    /// BinTokRxInfo::NONE if not set.
+    pub tok_bos: TokenId,
+    pub tok_pad: TokenId,
+    pub tok_unk: TokenId,
+    /// TokRxInfo::tok_roles, with BinTokRxInfo::NONE for unassigned roles.
+    pub tok_roles: [TokenId; SpecialToken::ALL.len()],
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+impl BinTokRxInfo {
+    pub const NONE: TokenId = u32::MAX;
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub struct TokRxInfo {
     pub vocab_size: u32,
     pub tok_eos: TokenId,
@@ -29,9 +44,17 @@ pub struct TokRxInfo {
     pub tok_pad: Option<TokenId>,
     pub tok_unk: Option<TokenId>,
     pub tok_end_of_turn: Option<TokenId>,
+    /// Additional tokens that end generation, besides tok_eos and tok_end_of_turn;
+    /// see add_stop_token().
+    pub tok_stop: [Option<TokenId>; TokRxInfo::MAX_STOP_TOKENS],
+    /// Tokens for roles not covered by the fields above (FIM, tool calls, chat delimiters),
+    /// indexed by the role; see role_token().
+    pub tok_roles: [Option<TokenId>; SpecialToken::ALL.len()],
 }
 
 impl TokRxInfo {
+    pub const MAX_STOP_TOKENS: usize = 8;
+
     pub fn new(vocab_size: u32, tok_eos: TokenId) -> Self {
         TokRxInfo {
             vocab_size,
@@ -40,29 +63,136 @@ impl TokRxInfo {
             tok_pad: None,
             tok_unk: None,
             tok_end_of_turn: None,
+            tok_stop: [None; TokRxInfo::MAX_STOP_TOKENS],
+            tok_roles: [None; SpecialToken::ALL.len()],
         }
     }
 
     pub fn from_bin(info: &BinTokRxInfo) -> Self {
+        let opt = |t: TokenId| {
+            if t == BinTokRxInfo::NONE {
+                None
+            } else {
+                Some(t)
+            }
+        };
         TokRxInfo {
-            vocab_size: info.vocab_size,
-            tok_eos: info.tok_eos,
-            tok_bos: None,
-            tok_pad: None,
-            tok_unk: None,
-            tok_end_of_turn: None,
+            tok_bos: opt(info.tok_bos),
+            tok_pad: opt(info.tok_pad),
+            tok_unk: opt(info.tok_unk),
+            tok_end_of_turn: opt(info.tok_end_of_turn),
+            tok_stop: info.tok_stop.map(opt),
+            tok_roles: info.tok_roles.map(opt),
+            ..TokRxInfo::new(info.vocab_size, info.tok_eos)
+        }
+    }
+
+    /// Add a token ending generation, unless it already does.
+    /// Fails if there are already MAX_STOP_TOKENS of them.
+    pub fn add_stop_token(&mut self, id: TokenId) -> Result<()> {
+        if id == self.tok_eos
+            || self.tok_end_of_turn == Some(id)
+            || self.tok_stop.contains(&Some(id))
+        {
+            return Ok(());
+        }
+        match self.tok_stop.iter_mut().find(|t| t.is_none()) {
+            Some(slot) => *slot = Some(id),
+            None => anyhow::bail!("too many stop tokens (max {})", TokRxInfo::MAX_STOP_TOKENS),
+        }
+        Ok(())
+    }
+
+    /// Replace tok_stop with given tokens (minus tok_eos and tok_end_of_turn).
+    pub fn set_stop_tokens(&mut self, ids: &[TokenId]) -> Result<()> {
+        self.tok_stop = [None; TokRxInfo::MAX_STOP_TOKENS];
+        for &id in ids {
+            self.add_stop_token(id)?;
+        }
+        Ok(())
+    }
+
+    /// The tokens in tok_stop.
+    pub fn extra_stop_tokens(&self) -> impl Iterator<Item = TokenId> + '_ {
+        self.tok_stop.iter().flatten().copied()
+    }
+
+    /// Roles assigned in tok_roles, with their tokens.
+    pub fn extra_role_tokens(&self) -> impl Iterator<Item = (SpecialToken, TokenId)> + '_ {
+        SpecialToken::ALL
+            .iter()
+            .zip(self.tok_roles.iter())
+            .filter_map(|(role, id)| id.map(|id| (*role, id)))
+    }
+
+    /// Token id assigned to given role, if any.
+    pub fn role_token(&self, tok: SpecialToken) -> Option<TokenId> {
+        match tok {
+            SpecialToken::EndOfSentence => Some(self.tok_eos),
+            SpecialToken::BeginningOfSentence => self.tok_bos,
+            SpecialToken::Padding => self.tok_pad,
+            SpecialToken::Unknown => self.tok_unk,
+            SpecialToken::EndOfTurn => self.tok_end_of_turn,
+            _ => self.tok_roles[tok as usize],
+        }
+    }
+
+    /// Assign a token id to given role, replacing the previous one.
+    pub fn set_role_token(&mut self, tok: SpecialToken, id: TokenId) {
+        match tok {
+            SpecialToken::EndOfSentence => self.tok_eos = id,
+            SpecialToken::BeginningOfSentence => self.tok_bos = Some(id),
+            SpecialToken::Padding => self.tok_pad = Some(id),
+            SpecialToken::Unknown => self.tok_unk = Some(id),
+            SpecialToken::EndOfTurn => self.tok_end_of_turn = Some(id),
+            _ => self.tok_roles[tok as usize] = Some(id),
         }
     }
 
+    /// Assign a role to a special token based on its well-known name (</s>, <|eot_id|>, ...).
+    /// Unknown names are ignored.
+    /// Fails if the name ends generation, and there are too many such tokens already.
+    pub fn assign_role_by_name(&mut self, name: &str, id: TokenId) -> Result<()> {
+        let role = match name {
+            "</s>" | "<|endoftext|>" | "<|end_of_text|>" => SpecialToken::EndOfSentence,
+            "<s>" | "<|begin_of_text|>" | "<|startoftext|>" => SpecialToken::BeginningOfSentence,
+            "<|end|>" | "<|eot_id|>" | "<end_of_turn>" => SpecialToken::EndOfTurn,
+            "<unk>" | "<|unk|>" => SpecialToken::Unknown,
+            "<pad>" | "<|pad|>" => SpecialToken::Padding,
+            "<|im_start|>" | "<start_of_turn>" | "<|start|>" => SpecialToken::BeginningOfTurn,
+            "<|start_header_id|>" => SpecialToken::BeginningOfHeader,
+            "<|end_header_id|>" => SpecialToken::EndOfHeader,
+            "<|fim_prefix|>" | "<fim_prefix>" | "<PRE>" | "[PREFIX]" => SpecialToken::FimPrefix,
+            "<|fim_middle|>" | "<fim_middle>" | "<MID>" | "[MIDDLE]" => SpecialToken::FimMiddle,
+            "<|fim_suffix|>" | "<fim_suffix>" | "<SUF>" | "[SUFFIX]" => SpecialToken::FimSuffix,
+            "<tool_call>" | "[TOOL_CALLS]" | "<|python_tag|>" => SpecialToken::ToolCallBegin,
+            "</tool_call>" => SpecialToken::ToolCallEnd,
+            // <|return|> and <|call|> end the final and tool call messages in Harmony (gpt-oss)
+            "<|eom_id|>" | "<|im_end|>" | "<|return|>" | "<|call|>" => {
+                return self.add_stop_token(id);
+            }
+            _ => return Ok(()),
+        };
+        self.set_role_token(role, id);
+        Ok(())
+    }
+
     pub fn to_bin(&self) -> BinTokRxInfo {
+        let bin = |t: Option<TokenId>| t.unwrap_or(BinTokRxInfo::NONE);
         BinTokRxInfo {
             vocab_size: self.vocab_size,
             tok_eos: self.tok_eos,
+            tok_end_of_turn: bin(self.tok_end_of_turn),
+            tok_stop: self.tok_stop.map(bin),
+            tok_bos: bin(self.tok_bos),
+            tok_pad: bin(self.tok_pad),
+            tok_unk: bin(self.tok_unk),
+            tok_roles: self.tok_roles.map(bin),
         }
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum SpecialToken {
     Unknown,
     Padding,
@@ -70,8 +200,113 @@ pub enum SpecialToken {
     BeginningOfSentence,
     EndOfSentence,
     EndOfTurn,
+    /// Start of a chat turn, like <|im_start|>.
+    BeginningOfTurn,
+    /// Start and end of a chat role header, like <|start_header_id|> and <|end_header_id|>.
+    BeginningOfHeader,
+    EndOfHeader,
+    /// Fill-in-the-middle markers.
+    FimPrefix,
+    FimMiddle,
+    FimSuffix,
+    /// Start and end of a tool call.
+    ToolCallBegin,
+    ToolCallEnd,
+}
+
+impl SpecialToken {
+    /// All roles, in declaration order (so that ALL[tok as usize] == tok).
+    pub const ALL: [SpecialToken; 14] = [
+        SpecialToken::Unknown,
+        SpecialToken::Padding,
+        SpecialToken::Separator,
+        SpecialToken::BeginningOfSentence,
+        SpecialToken::EndOfSentence,
+        SpecialToken::EndOfTurn,
+        SpecialToken::BeginningOfTurn,
+        SpecialToken::BeginningOfHeader,
+        SpecialToken::EndOfHeader,
+        SpecialToken::FimPrefix,
+        SpecialToken::FimMiddle,
+        SpecialToken::FimSuffix,
+        SpecialToken::ToolCallBegin,
+        SpecialToken::ToolCallEnd,
+    ];
+}
+
+/// Why a recognizer rejected a byte, see Recognizer::try_push_byte_ext().
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rejection {
+    pub byte: u8,
+    /// Bytes that would have been allowed instead, in increasing order.
+    pub expected: Vec<u8>,
+    pub eos_allowed: bool,
+    /// See Recognizer::state_description().
+    pub description: Option<String>,
 }
 
+impl Rejection {
+    pub fn new<R: Recognizer + ?Sized>(r: &mut R, byte: u8) -> Self {
+        Rejection {
+            byte,
+            expected: (0..=255).filter(|&b| r.byte_allowed(b)).collect(),
+            eos_allowed: r.special_allowed(SpecialToken::EndOfSentence),
+            description: r.state_description(),
+        }
+    }
+
+    /// Expected bytes as a character class, like [0-9a-z].
+    pub fn expected_class(&self) -> String {
+        let mut res = "[".to_string();
+        let mut i = 0;
+        while i < self.expected.len() {
+            let start = self.expected[i];
+            while i + 1 < self.expected.len() && self.expected[i + 1] == self.expected[i] + 1 {
+                i += 1;
+            }
+            let class_char = |b: u8| {
+                if b"-]^".contains(&b) {
+                    format!("\\{}", b as char)
+                } else {
+                    [b].escape_ascii().to_string()
+                }
+            };
+            res.push_str(&class_char(start));
+            if self.expected[i] > start {
+                if self.expected[i] > start + 1 {
+                    res.push('-');
+                }
+                res.push_str(&class_char(self.expected[i]));
+            }
+            i += 1;
+        }
+        res.push(']');
+        res
+    }
+}
+
+impl core::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "byte {:?} not allowed; expecting ", self.byte as char)?;
+        match (self.expected.is_empty(), self.eos_allowed) {
+            (true, true) => write!(f, "EOS")?,
+            (true, false) => write!(f, "nothing")?,
+            (false, eos) => {
+                write!(f, "{}", self.expected_class())?;
+                if eos {
+                    write!(f, " or EOS")?;
+                }
+            }
+        }
+        if let Some(d) = &self.description {
+            write!(f, " ({})", d)?;
+        }
+        Ok(())
+    }
+}
+
+impl core::error::Error for Rejection {}
+
 pub trait Recognizer {
     /// for _ in 0..num { stack.pop() }
     fn pop_bytes(&mut self, num: usize);
@@ -98,10 +333,104 @@ pub trait Recognizer {
     fn trie_started(&mut self) {}
     /// This combines `push_byte` and `byte_allowed` into one function for performance.
     fn try_push_byte(&mut self, byte: u8) -> bool;
+    /// If true, any byte sequence is allowed from the current state,
+    /// and compute_bias() allows all tokens without walking the trie.
+    fn all_bytes_allowed(&mut self) -> bool {
+        false
+    }
+    /// If true, no byte is allowed in the current state,
+    /// and compute_bias() only considers stop tokens without walking the trie.
+    fn no_bytes_allowed(&mut self) -> bool {
+        false
+    }
+    /// Weight of appending given byte in the current state; called before try_push_byte().
+    /// Used by TokTrie::compute_bias_f32(); the weight of a token is the sum over its bytes.
+    fn byte_weight(&mut self, _byte: u8) -> f32 {
+        0.0
+    }
     /// Check if there are any errors to be reported to the user.
     fn get_error(&mut self) -> Option<String> {
         None
     }
+    /// Description of stack.top() for error messages, e.g., the grammar rules in progress.
+    fn state_description(&mut self) -> Option<String> {
+        None
+    }
+    /// Like try_push_byte(), but explains why the byte is not allowed.
+    fn try_push_byte_ext(&mut self, byte: u8) -> core::result::Result<(), Rejection> {
+        if self.try_push_byte(byte) {
+            Ok(())
+        } else {
+            Err(Rejection::new(self, byte))
+        }
+    }
+    /// Named spans of the output so far, in order (see recognizer::Capture).
+    /// After collapse() or trie_finished() these cover committed bytes only.
+    fn captures(&mut self) -> Vec<(String, Vec<u8>)> {
+        vec![]
+    }
+    /// Serialize stack.top() into an opaque blob, to be passed to restore_state()
+    /// of the same recognizer (or its clone), e.g., after backtracking.
+    fn save_state(&mut self) -> Result<Vec<u8>> {
+        anyhow::bail!("save_state() not supported by this recognizer")
+    }
+    /// Restore state saved with save_state().
+    /// The stack then consists only of that state, as after collapse().
+    fn restore_state(&mut self, _state: &[u8]) -> Result<()> {
+        anyhow::bail!("restore_state() not supported by this recognizer")
+    }
+}
+
+// Allows Box<dyn Recognizer> to be used wherever a Recognizer is expected.
+impl<R: Recognizer + ?Sized> Recognizer for Box<R> {
+    fn pop_bytes(&mut self, num: usize) {
+        (**self).pop_bytes(num)
+    }
+    fn collapse(&mut self) {
+        (**self).collapse()
+    }
+    fn byte_allowed(&mut self, byte: u8) -> bool {
+        (**self).byte_allowed(byte)
+    }
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        (**self).special_allowed(tok)
+    }
+    fn trie_finished(&mut self) {
+        (**self).trie_finished()
+    }
+    fn trie_started(&mut self) {
+        (**self).trie_started()
+    }
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        (**self).try_push_byte(byte)
+    }
+    fn all_bytes_allowed(&mut self) -> bool {
+        (**self).all_bytes_allowed()
+    }
+    fn no_bytes_allowed(&mut self) -> bool {
+        (**self).no_bytes_allowed()
+    }
+    fn byte_weight(&mut self, byte: u8) -> f32 {
+        (**self).byte_weight(byte)
+    }
+    fn get_error(&mut self) -> Option<String> {
+        (**self).get_error()
+    }
+    fn state_description(&mut self) -> Option<String> {
+        (**self).state_description()
+    }
+    fn try_push_byte_ext(&mut self, byte: u8) -> core::result::Result<(), Rejection> {
+        (**self).try_push_byte_ext(byte)
+    }
+    fn captures(&mut self) -> Vec<(String, Vec<u8>)> {
+        (**self).captures()
+    }
+    fn save_state(&mut self) -> Result<Vec<u8>> {
+        (**self).save_state()
+    }
+    fn restore_state(&mut self, state: &[u8]) -> Result<()> {
+        (**self).restore_state(state)
+    }
 }
 
 pub trait TokenizerEnv: Send {
@@ -113,38 +442,85 @@ pub trait TokenizerEnv: Send {
     fn tokenize_bytes(&self, s: &[u8]) -> Vec<TokenId>;
 
     /// Tokenize a given byte sequence.
-    /// It will interpret text starting with SPECIAL_TOKEN_MARKER as special tokens.
+    /// It will interpret text starting with the trie's special token marker
+    /// (SPECIAL_TOKEN_MARKER unless configured otherwise) as special tokens,
+    /// see MarkerOptions::default() for details.
     fn tokenize_bytes_marker(&self, s: &[u8]) -> Vec<TokenId> {
-        let mut idx = 0;
-        let ff = TokTrie::SPECIAL_TOKEN_MARKER;
-        let mut result = Vec::new();
+        self.tok_trie()
+            .tokenize_with_marker(s, &MarkerOptions::default(), |s| Ok(self.tokenize_bytes(s)))
+            .expect("unknown markers are dropped")
+    }
+
+    /// Like tokenize_bytes(), but reports tokenizer failures instead of panicking.
+    /// Implementations whose backend can fail should override it,
+    /// and implement tokenize_bytes() in terms of it.
+    fn try_tokenize_bytes(&self, s: &[u8]) -> Result<Vec<TokenId>> {
+        Ok(self.tokenize_bytes(s))
+    }
+
+    /// Like try_tokenize_bytes() for a string.
+    fn try_tokenize(&self, s: &str) -> Result<Vec<TokenId>> {
+        self.try_tokenize_bytes(s.as_bytes())
+    }
+
+    /// Like tokenize_bytes_marker(), but fails with TokError::UnknownSpecialToken
+    /// when the marker is followed by <name> that is not a special token.
+    fn try_tokenize_bytes_marker(&self, s: &[u8]) -> Result<Vec<TokenId>> {
+        let opts = MarkerOptions::default().with_unknown(UnknownMarker::Error);
+        self.tokenize_bytes_marker_ext(s, &opts)
+    }
+
+    /// Like try_tokenize_bytes_marker(), with given special token detection options.
+    fn tokenize_bytes_marker_ext(&self, s: &[u8], opts: &MarkerOptions) -> Result<Vec<TokenId>> {
+        self.tok_trie()
+            .tokenize_with_marker(s, opts, |s| self.try_tokenize_bytes(s))
+    }
+
+    /// Like tokenize_bytes(), but appends to out, which can be reused across calls.
+    fn tokenize_bytes_into(&self, s: &[u8], out: &mut Vec<TokenId>) {
+        out.extend_from_slice(&self.tokenize_bytes(s));
+    }
+
+    /// Tokenize several inputs with tokenize_bytes().
+    fn tokenize_batch(&self, inputs: &[&[u8]]) -> Vec<Vec<TokenId>> {
+        inputs.iter().map(|s| self.tokenize_bytes(s)).collect()
+    }
+
+    /// Like tokenize_batch(), but inputs are processed in parallel.
+    #[cfg(feature = "rayon")]
+    fn tokenize_batch_par(&self, inputs: &[&[u8]]) -> Vec<Vec<TokenId>>
+    where
+        Self: Sync,
+    {
+        use rayon::prelude::*;
+        inputs.par_iter().map(|s| self.tokenize_bytes(s)).collect()
+    }
+
+    /// Like tokenize_bytes(), but also returns the span of input bytes of each token.
+    /// By default, spans are computed from token lengths (special tokens count with their name),
+    /// which is exact as long as the tokenizer doesn't normalize its input;
+    /// spans are clamped to the input length.
+    fn tokenize_bytes_with_offsets(&self, s: &[u8]) -> Vec<(TokenId, core::ops::Range<usize>)> {
         let trie = self.tok_trie();
-        while idx < s.len() {
-            let normal_len = s[idx..]
-                .iter()
-                .position(|&x| x == ff)
-                .unwrap_or(s.len() - idx);
-            if normal_len != 0 {
-                result.extend_from_slice(&self.tokenize_bytes(&s[idx..idx + normal_len]));
-                idx += normal_len;
-            }
-            idx += 1; // skip ff
-            if idx + 3 < s.len() && s[idx] == '<' as u8 {
-                let spec_len = s[idx..std::cmp::min(s.len(), idx + 100)]
-                    .iter()
-                    .position(|&x| x == '>' as u8);
-                if let Some(mut spec_len) = spec_len {
-                    spec_len += 1;
-                    let spec_token = &s[idx - 1..idx + spec_len];
-                    if let Some(id) = trie.token_id_at_bytes(spec_token) {
-                        result.push(id);
-                        idx += spec_len;
-                    }
-                }
-            }
-        }
+        let mut pos = 0;
+        self.tokenize_bytes(s)
+            .into_iter()
+            .map(|tok| {
+                let len = match trie.special_token_name(tok) {
+                    Some(name) => name.len(),
+                    None => trie.token(tok).len(),
+                };
+                let start = pos;
+                pos = core::cmp::min(pos + len, s.len());
+                (tok, start..pos)
+            })
+            .collect()
+    }
 
-        result
+    /// Number of tokens tokenize_bytes() would return.
+    /// Implementations that can count without building the token list should override it.
+    fn count_tokens(&self, s: &[u8]) -> usize {
+        self.tokenize_bytes(s).len()
     }
 
     /// Tokenize a string coming from user. It may or may not interpret <|special_tokens|> as special.
@@ -153,8 +529,53 @@ pub trait TokenizerEnv: Send {
     }
 
     /// Tokenize a string. It will interpret <|special_tokens|> as special.
+    /// By default, special token names found in the trie are recognized
+    /// and the remaining text is tokenized with tokenize_bytes().
     fn tokenize_special(&self, s: &str) -> Vec<TokenId> {
-        self.tokenize(s)
+        self.tok_trie()
+            .tokenize_with_special(s.as_bytes(), |s| self.tokenize_bytes(s))
+    }
+
+    /// Tokenize a prompt and apply token healing to its end.
+    /// Returns the prompt tokens and the bytes the generation has to start with
+    /// (see TokTrie::heal_prompt()).
+    fn tokenize_with_healing(&self, s: &str) -> (Vec<TokenId>, Vec<u8>) {
+        let tokens = self.tokenize(s);
+        self.tok_trie().heal_prompt(&tokens, &[])
+    }
+
+    /// Inverse of tokenize_special(): special tokens are rendered by name.
+    /// Engines with their own decoder (e.g., handling normalization) can override it.
+    fn decode_bytes(&self, tokens: &[TokenId]) -> Vec<u8> {
+        self.decode_bytes_ext(tokens, true)
+    }
+
+    /// Like decode_bytes(), but special tokens are skipped unless include_special is set.
+    fn decode_bytes_ext(&self, tokens: &[TokenId], include_special: bool) -> Vec<u8> {
+        self.tok_trie().decode_ext(tokens, include_special)
+    }
+
+    /// Like decode_bytes(), but fails with TokError::VocabOverflow on unknown tokens,
+    /// instead of silently skipping them.
+    fn try_decode_bytes(&self, tokens: &[TokenId]) -> Result<Vec<u8>> {
+        self.tok_trie().check_tokens(tokens)?;
+        Ok(self.decode_bytes(tokens))
+    }
+
+    /// Like try_decode_bytes(), but also fails with TokError::InvalidUtf8
+    /// if the result is not valid UTF-8 (see decode_str_lossy() for the lenient version).
+    fn try_decode_str(&self, tokens: &[TokenId]) -> Result<String> {
+        String::from_utf8(self.try_decode_bytes(tokens)?).map_err(|e| {
+            TokError::InvalidUtf8 {
+                valid_up_to: e.utf8_error().valid_up_to(),
+            }
+            .into()
+        })
+    }
+
+    /// decode_bytes(), with invalid UTF-8 replaced by U+FFFD.
+    fn decode_str_lossy(&self, tokens: &[TokenId]) -> String {
+        String::from_utf8_lossy(&self.decode_bytes(tokens)).to_string()
     }
 
     /// End of sentence token
@@ -170,6 +591,8 @@ pub trait TokenizerEnv: Send {
     }
 }
 
+/// Shared tokenizer environment; TokenizerEnv requires Send, so this is Send + Sync.
+/// For backends that are not thread-safe, see ThreadLocalTokEnv.
 pub type TokEnv = Arc<dyn TokenizerEnv + Sync + 'static>;
 
 pub struct TokEnvWithTrie {
@@ -191,6 +614,10 @@ impl TokenizerEnv for TokEnvWithTrie {
     fn tokenize_bytes(&self, s: &[u8]) -> Vec<TokenId> {
         self.base_env.tokenize_bytes(s)
     }
+
+    fn count_tokens(&self, s: &[u8]) -> usize {
+        self.base_env.count_tokens(s)
+    }
 }
 
 #[derive(Clone)]
@@ -201,6 +628,141 @@ pub struct TokTrie {
     nodes: Vec<TrieNode>,
     max_token_len: usize,
     token_duplicates: FxHashMap<TokenId, Vec<TokenId>>,
+    added_tokens: Vec<AddedToken>,
+    escape: SpecialTokenEscape,
+    // tokens the trie walk can reach (including duplicates);
+    // allowed when the recognizer allows all bytes
+    trie_tokens: SimpleVob,
+    // names of IdTable special tokens, laid out like nodes
+    special_nodes: Vec<TrieNode>,
+    // see stop_tokens(); recomputed whenever info changes
+    stop_tokens: Vec<TokenId>,
+}
+
+/// How special tokens are kept apart from regular byte sequences in the trie.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SpecialTokenEscape {
+    /// Special tokens are stored as the given byte followed by the token name.
+    /// The default is Prefix(TokTrie::SPECIAL_TOKEN_MARKER).
+    Prefix(u8),
+    /// Special tokens are given by explicit ids (out-of-band id ranges can be listed here too).
+    /// Their bytes are just the token name, and they are not inserted in the trie,
+    /// so no byte sequence produces them.
+    /// Use this for vocabularies where the marker byte is a legitimate token byte.
+    IdTable(Vec<TokenId>),
+}
+
+impl Default for SpecialTokenEscape {
+    fn default() -> Self {
+        SpecialTokenEscape::Prefix(TokTrie::SPECIAL_TOKEN_MARKER)
+    }
+}
+
+/// How special token names are recognized after the marker byte,
+/// see TokTrie::tokenize_with_marker().
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MarkerDetection {
+    /// The longest special token name of the trie that follows the marker.
+    Exact,
+    /// Text starting with open and ending with close (both included),
+    /// of at most max_len bytes, looked up with get_special_token().
+    Delimited {
+        open: Vec<u8>,
+        close: Vec<u8>,
+        max_len: usize,
+    },
+}
+
+// Whether s (following a marker byte) is shaped like a special token name:
+// open...close for Delimited, and starting with a printable ASCII character for Exact.
+fn looks_like_special_name(s: &[u8], detection: &MarkerDetection) -> bool {
+    match detection {
+        MarkerDetection::Exact => s.first().is_some_and(|b| b.is_ascii_graphic()),
+        MarkerDetection::Delimited {
+            open,
+            close,
+            max_len,
+        } => {
+            let window = &s[..core::cmp::min(s.len(), *max_len)];
+            window.starts_with(open)
+                && !close.is_empty()
+                && window[open.len()..]
+                    .windows(close.len())
+                    .any(|w| w == &close[..])
+        }
+    }
+}
+
+/// What to do with a marker byte that is not followed by a known special token.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnknownMarker {
+    /// Skip the marker byte.
+    Drop,
+    /// Tokenize the marker byte as part of the surrounding text.
+    Keep,
+    /// Fail with TokError::UnknownSpecialToken if the marker is followed by something
+    /// that looks like a special token name (see MarkerDetection); otherwise the marker
+    /// byte is kept, as with Keep.
+    Error,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MarkerOptions {
+    pub detection: MarkerDetection,
+    pub unknown: UnknownMarker,
+}
+
+impl Default for MarkerOptions {
+    /// <name> of up to 100 bytes; unknown markers are dropped.
+    fn default() -> Self {
+        MarkerOptions {
+            detection: MarkerDetection::Delimited {
+                open: b"<".to_vec(),
+                close: b">".to_vec(),
+                max_len: 100,
+            },
+            unknown: UnknownMarker::Drop,
+        }
+    }
+}
+
+impl MarkerOptions {
+    pub fn with_detection(mut self, detection: MarkerDetection) -> Self {
+        self.detection = detection;
+        self
+    }
+
+    pub fn with_unknown(mut self, unknown: UnknownMarker) -> Self {
+        self.unknown = unknown;
+        self
+    }
+}
+
+/// Metadata about an added (typically special) token.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AddedToken {
+    pub id: TokenId,
+    /// Surface string of the token, without SPECIAL_TOKEN_MARKER.
+    pub content: String,
+    /// Special tokens are prefixed with SPECIAL_TOKEN_MARKER in the trie.
+    pub special: bool,
+    /// Whether the tokenizer matches this token after normalization.
+    pub normalized: bool,
+    /// Whether the token only matches whole words.
+    pub single_word: bool,
+}
+
+/// Size of a TokTrie, see TokTrie::stats().
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TrieStats {
+    pub num_nodes: usize,
+    pub num_token_nodes: usize,
+    /// Total length of all tokens.
+    pub token_bytes: usize,
+    pub max_token_len: usize,
+    /// Heap and inline memory used by the trie (nodes, token table, duplicates,
+    /// added token metadata, precomputed masks), in bytes.
+    pub memory_bytes: usize,
 }
 
 #[derive(Clone, Copy, Zeroable, Pod)]
@@ -208,15 +770,31 @@ pub struct TokTrie {
 pub struct TokTrieHeader {
     magic: u32,
     hd_size: u32,
+    version: u32,
     trie_bytes: u32,
     token_offset_bytes: u32,
+    // ids of SpecialTokenEscape::IdTable, stored after the token offsets
+    special_ids_bytes: u32,
+    // (id, ADDED_* flags) pairs of added tokens, stored after the special ids
+    added_tokens_bytes: u32,
     token_data_bytes: u32,
+    // SpecialTokenEscape::Prefix marker byte, or ESCAPE_ID_TABLE
+    escape: u32,
     info: BinTokRxInfo,
     align: [u32; 0],
 }
 
 impl TokTrieHeader {
     const MAGIC: u32 = 0x558b6fd3;
+    // 2 - added version, special_ids_bytes and escape
+    // 3 - added tok_end_of_turn and tok_stop to info
+    // 4 - added added_tokens_bytes
+    // 5 - added tok_bos, tok_pad, tok_unk and tok_roles to info
+    const VERSION: u32 = 5;
+    const ESCAPE_ID_TABLE: u32 = 0x100;
+    const ADDED_SPECIAL: u32 = 1;
+    const ADDED_NORMALIZED: u32 = 2;
+    const ADDED_SINGLE_WORD: u32 = 4;
 }
 
 #[derive(Clone, Copy, Zeroable, Pod)]
@@ -263,6 +841,13 @@ impl TrieNode {
     }
 }
 
+// which kinds of stop tokens a recognizer allows in its current state
+#[derive(Clone, Copy, Default)]
+struct StopAllowed {
+    eos: bool,
+    eot: bool,
+}
+
 // max length of token is 1023 bytes
 const LEN_BITS: u32 = 10;
 
@@ -271,12 +856,44 @@ impl TokTrie {
     pub const SPECIAL_TOKEN_MARKER: u8 = 0xff;
 
     pub fn from(info: &TokRxInfo, words: &Vec<Vec<u8>>) -> Self {
-        let mut trie = TrieHash::new(0xff);
-        let mut token_offsets = Vec::new();
-        let mut token_data = Vec::new();
+        Self::from_with_escape(info, words, SpecialTokenEscape::default())
+    }
+
+    /// Build a trie from a GPT-2-style vocabulary, where token strings use
+    /// the byte-level alphabet (e.g., "Ġhello" for " hello").
+    /// Words that are not valid byte-level strings (e.g., special tokens
+    /// starting with SPECIAL_TOKEN_MARKER) are kept as is.
+    pub fn from_byte_level(info: &TokRxInfo, words: &[Vec<u8>]) -> Self {
+        let words: Vec<Vec<u8>> = words
+            .iter()
+            .map(|w| {
+                core::str::from_utf8(w)
+                    .ok()
+                    .and_then(byte_level_decode)
+                    .unwrap_or_else(|| w.clone())
+            })
+            .collect();
+        Self::from(info, &words)
+    }
+
+    /// Build a trie with given special token escaping scheme.
+    /// With SpecialTokenEscape::Prefix(b), special tokens in `words` start with `b`.
+    /// With SpecialTokenEscape::IdTable(ids), `words` for listed ids are plain token names.
+    pub fn from_with_escape(
+        info: &TokRxInfo,
+        words: &[Vec<u8>],
+        escape: SpecialTokenEscape,
+    ) -> Self {
+        let mut trie = TrieBuilder::new();
+        let mut token_offsets = Vec::with_capacity(words.len());
+        let mut token_data = Vec::with_capacity(words.iter().map(|w| w.len()).sum());
         assert!(info.vocab_size == words.len() as u32);
+        let out_of_band = match &escape {
+            SpecialTokenEscape::IdTable(ids) => ids.iter().copied().collect(),
+            SpecialTokenEscape::Prefix(_) => FxHashSet::default(),
+        };
         for (idx, word) in words.iter().enumerate() {
-            if word.len() > 0 {
+            if !word.is_empty() && !out_of_band.contains(&(idx as TokenId)) {
                 trie.insert(word, idx as u32);
             }
             assert!(word.len() < (1 << LEN_BITS));
@@ -285,15 +902,21 @@ impl TokTrie {
             token_offsets.push(desc);
             token_data.extend_from_slice(word);
         }
-        let mut nodes = Vec::new();
-        trie.serialize(&mut nodes, 0);
+        let mut nodes = Vec::with_capacity(trie.nodes.len());
+        trie.serialize(0, &mut nodes, 0);
+        drop(trie);
         let mut r = TokTrie {
-            info: info.clone(),
+            info: *info,
             token_offsets,
             token_data,
             nodes,
             max_token_len: 0,
             token_duplicates: FxHashMap::default(),
+            added_tokens: vec![],
+            escape,
+            trie_tokens: SimpleVob::new(),
+            special_nodes: vec![],
+            stop_tokens: vec![],
         };
         r.finalize_ctor();
         r
@@ -302,25 +925,114 @@ impl TokTrie {
     pub fn with_eos_token(&self, eos_token: TokenId) -> Self {
         self.with_info(TokRxInfo {
             tok_eos: eos_token,
-            ..self.info.clone()
+            ..self.info
         })
     }
 
     pub fn with_info(&self, info: TokRxInfo) -> Self {
         let mut r = self.clone();
-        r.info = info.clone();
+        r.info = info;
+        r.stop_tokens = r.compute_stop_tokens();
         r
     }
 
+    /// Return a copy of this trie with tok_stop set to given tokens.
+    /// Fails if there are more than TokRxInfo::MAX_STOP_TOKENS of them.
+    pub fn with_stop_tokens(&self, stop_tokens: &[TokenId]) -> Result<Self> {
+        let mut info = self.info;
+        info.set_stop_tokens(stop_tokens)?;
+        Ok(self.with_info(info))
+    }
+
     pub fn build_chat_mode_trie(&self) -> Self {
         self.with_eos_token(self.info.tok_end_of_turn.unwrap_or(self.info.tok_eos))
     }
 
+    /// Return a copy of this trie with given added token metadata.
+    /// Entries replace the ones derived from the special token escape.
+    /// The metadata is kept by serialize(), except for content,
+    /// which is restored from the token bytes.
+    pub fn with_added_tokens(&self, added_tokens: &[AddedToken]) -> Self {
+        let mut r = self.clone();
+        r.set_added_tokens(added_tokens);
+        r
+    }
+
+    fn set_added_tokens(&mut self, added_tokens: &[AddedToken]) {
+        for t in added_tokens {
+            assert!(t.id < self.info.vocab_size);
+            match self.added_tokens.binary_search_by_key(&t.id, |e| e.id) {
+                Ok(idx) => self.added_tokens[idx] = t.clone(),
+                Err(idx) => self.added_tokens.insert(idx, t.clone()),
+            }
+        }
+        self.build_special_nodes();
+    }
+
+    fn added_tokens_to_bin(&self) -> Vec<u32> {
+        let mut r = Vec::with_capacity(self.added_tokens.len() * 2);
+        for t in &self.added_tokens {
+            let flags = (t.special as u32 * TokTrieHeader::ADDED_SPECIAL)
+                | (t.normalized as u32 * TokTrieHeader::ADDED_NORMALIZED)
+                | (t.single_word as u32 * TokTrieHeader::ADDED_SINGLE_WORD);
+            r.push(t.id);
+            r.push(flags);
+        }
+        r
+    }
+
+    // called after finalize_ctor(), so the escaped names are known
+    fn added_tokens_from_bin(&mut self, data: &[u32]) -> Result<()> {
+        let all_flags = TokTrieHeader::ADDED_SPECIAL
+            | TokTrieHeader::ADDED_NORMALIZED
+            | TokTrieHeader::ADDED_SINGLE_WORD;
+        let mut added = Vec::with_capacity(data.len() / 2);
+        for (i, pair) in data.chunks_exact(2).enumerate() {
+            let (id, flags) = (pair[0], pair[1]);
+            ensure!(
+                id < self.info.vocab_size && flags & !all_flags == 0,
+                "invalid added token entry {}",
+                i
+            );
+            ensure!(
+                i == 0 || data[2 * i - 2] < id,
+                "added tokens not sorted at {}",
+                i
+            );
+            let content = match self.escaped_name(id) {
+                Some(name) => name,
+                None => self.token(id),
+            };
+            added.push(AddedToken {
+                id,
+                content: String::from_utf8_lossy(content).to_string(),
+                special: flags & TokTrieHeader::ADDED_SPECIAL != 0,
+                normalized: flags & TokTrieHeader::ADDED_NORMALIZED != 0,
+                single_word: flags & TokTrieHeader::ADDED_SINGLE_WORD != 0,
+            });
+        }
+        self.set_added_tokens(&added);
+        Ok(())
+    }
+
     fn finalize_ctor(&mut self) {
         for tok_id in 0..self.info.vocab_size {
+            if let Some(name) = self.escaped_name(tok_id) {
+                self.added_tokens.push(AddedToken {
+                    id: tok_id,
+                    content: String::from_utf8_lossy(name).to_string(),
+                    special: true,
+                    normalized: false,
+                    single_word: false,
+                });
+                if matches!(self.escape, SpecialTokenEscape::IdTable(_)) {
+                    // not in the trie, so it can't have duplicates
+                    continue;
+                }
+            }
             let bytes = self.token(tok_id);
             let tok_ids = self.greedy_tokenize(bytes);
-            self.max_token_len = std::cmp::max(self.max_token_len, bytes.len());
+            self.max_token_len = core::cmp::max(self.max_token_len, bytes.len());
             if tok_ids.len() == 1 && tok_ids[0] != tok_id {
                 self.token_duplicates
                     .entry(tok_ids[0])
@@ -329,6 +1041,85 @@ impl TokTrie {
             }
         }
         self.validate();
+        let mut trie_tokens = self.alloc_token_set();
+        for n in &self.nodes {
+            if let Some(tok) = n.token_id() {
+                trie_tokens.allow_token(tok);
+            }
+        }
+        self.apply_duplicates(&mut trie_tokens);
+        self.trie_tokens = trie_tokens;
+        self.stop_tokens = self.compute_stop_tokens();
+        self.build_special_nodes();
+    }
+
+    fn compute_stop_tokens(&self) -> Vec<TokenId> {
+        let mut res = vec![self.info.tok_eos];
+        res.extend(self.info.tok_end_of_turn);
+        for t in self.info.extra_stop_tokens() {
+            if !res.contains(&t) {
+                res.push(t);
+            }
+        }
+        res
+    }
+
+    fn build_special_nodes(&mut self) {
+        self.special_nodes.clear();
+        if matches!(self.escape, SpecialTokenEscape::IdTable(_)) {
+            let mut names = TrieBuilder::new();
+            for t in self.special_tokens_iter() {
+                if !t.content.is_empty() {
+                    names.insert(t.content.as_bytes(), t.id);
+                }
+            }
+            names.serialize(0, &mut self.special_nodes, 0);
+        }
+    }
+
+    /// Name of the token if it is special according to the escape scheme.
+    fn escaped_name(&self, tok: TokenId) -> Option<&[u8]> {
+        let bytes = self.token(tok);
+        match &self.escape {
+            SpecialTokenEscape::Prefix(m) => {
+                if bytes.len() > 1 && bytes[0] == *m {
+                    Some(&bytes[1..])
+                } else {
+                    None
+                }
+            }
+            SpecialTokenEscape::IdTable(ids) => {
+                if ids.contains(&tok) {
+                    Some(bytes)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    pub fn special_token_escape(&self) -> &SpecialTokenEscape {
+        &self.escape
+    }
+
+    /// The byte prefixing special tokens in the trie, if the Prefix scheme is used.
+    pub fn special_token_marker(&self) -> Option<u8> {
+        match self.escape {
+            SpecialTokenEscape::Prefix(m) => Some(m),
+            SpecialTokenEscape::IdTable(_) => None,
+        }
+    }
+
+    /// Name of given special token (without the marker), or None if the token is not special.
+    pub fn special_token_name(&self, tok: TokenId) -> Option<&[u8]> {
+        if !self.is_special_token(tok) {
+            return None;
+        }
+        let bytes = self.token(tok);
+        match self.escape {
+            SpecialTokenEscape::Prefix(m) if bytes.first() == Some(&m) => Some(&bytes[1..]),
+            _ => Some(bytes),
+        }
     }
 
     fn node_offset(&self, n: &TrieNode) -> usize {
@@ -348,9 +1139,9 @@ impl TokTrie {
     }
 
     pub fn special_token(&self, tok: SpecialToken) -> TokenId {
-        match tok {
-            SpecialToken::EndOfSentence => self.info.tok_eos,
-            _ => panic!("non-EOS special_token() called"), // TODO?
+        match self.info.role_token(tok) {
+            Some(id) => id,
+            None => panic!("no token for {:?}", tok),
         }
     }
 
@@ -358,6 +1149,15 @@ impl TokTrie {
         self.info.tok_eos
     }
 
+    /// All tokens that end generation: EOS, end-of-turn (if any), and tok_stop.
+    pub fn stop_tokens(&self) -> &[TokenId] {
+        &self.stop_tokens
+    }
+
+    pub fn is_stop_token(&self, tok: TokenId) -> bool {
+        self.stop_tokens.contains(&tok)
+    }
+
     pub fn vocab_size(&self) -> usize {
         self.info.vocab_size as usize
     }
@@ -379,7 +1179,7 @@ impl TokTrie {
         let use_neg = ts_neg.num_set() * 10 < ts.num_set();
         let ts1 = if use_neg { &ts_neg } else { &ts };
         let num_set = ts1.num_set();
-        let max_tok = std::cmp::min(max_examples, num_set);
+        let max_tok = core::cmp::min(max_examples, num_set);
         let mut token_names = Vec::new();
         // make sure we include EOS first if it's allowed
         if ts1.is_allowed(self.info.tok_eos) {
@@ -460,8 +1260,8 @@ impl TokTrie {
         } else {
             // format!("{:?}[{}]", self.token_str(idx), idx)
             let bytes = self.token(idx);
-            if bytes.len() > 1 && bytes[0] == TokTrie::SPECIAL_TOKEN_MARKER {
-                String::from_utf8_lossy(&bytes[1..]).to_string()
+            if let Some(name) = self.special_token_name(idx) {
+                String::from_utf8_lossy(name).to_string()
             } else {
                 let s = String::from_utf8_lossy(bytes);
                 if s.len() == 0 {
@@ -490,12 +1290,25 @@ impl TokTrie {
         &self.token_data[off..(off + len as usize)]
     }
 
+    /// Decode tokens to bytes; special tokens are rendered as their names.
     pub fn decode(&self, tokens: &[TokenId]) -> Vec<u8> {
-        let mut bytes = self.decode_raw(tokens);
-        if bytes.contains(&TokTrie::SPECIAL_TOKEN_MARKER) {
-            bytes.retain(|&b| b != TokTrie::SPECIAL_TOKEN_MARKER);
+        self.decode_ext(tokens, true)
+    }
+
+    /// Like decode(), but special tokens are skipped unless include_special is set.
+    pub fn decode_ext(&self, tokens: &[TokenId], include_special: bool) -> Vec<u8> {
+        let mut res = Vec::with_capacity(tokens.len() * 6 + 32);
+        for &tok in tokens {
+            match self.special_token_name(tok) {
+                Some(name) => {
+                    if include_special {
+                        res.extend_from_slice(name)
+                    }
+                }
+                None => res.extend_from_slice(self.token(tok)),
+            }
         }
-        bytes
+        res
     }
 
     pub fn decode_raw(&self, tokens: &[TokenId]) -> Vec<u8> {
@@ -512,17 +1325,91 @@ impl TokTrie {
     }
 
     pub fn get_special_token(&self, name: &str) -> Option<TokenId> {
-        self.child_at_byte(self.root(), TokTrie::SPECIAL_TOKEN_MARKER)
-            .and_then(|n| {
-                self.child_at_bytes(n, name.as_bytes())
-                    .and_then(|n| n.token_id())
-            })
+        match self.longest_special_name(name.as_bytes()) {
+            Some((tok, len)) if len == name.len() => Some(tok),
+            _ => None,
+        }
+    }
+
+    // Nodes of special token names (without the marker), and the offset of their root.
+    fn special_names_root(&self) -> Option<(&[TrieNode], usize)> {
+        match self.escape {
+            SpecialTokenEscape::Prefix(m) => self
+                .child_at_byte(self.root(), m)
+                .map(|n| (&self.nodes[..], self.node_offset(n))),
+            SpecialTokenEscape::IdTable(_) if !self.special_nodes.is_empty() => {
+                Some((&self.special_nodes[..], 0))
+            }
+            SpecialTokenEscape::IdTable(_) => None,
+        }
+    }
+
+    /// Longest special token name that `s` starts with, and its length.
+    fn longest_special_name(&self, s: &[u8]) -> Option<(TokenId, usize)> {
+        let (nodes, mut p) = self.special_names_root()?;
+        let mut last = None;
+        for (off, &b) in s.iter().enumerate() {
+            let endp = p + nodes[p].subtree_size();
+            let mut c = p + 1;
+            while c < endp && nodes[c].byte() != b {
+                c += nodes[c].subtree_size();
+            }
+            if c >= endp {
+                break;
+            }
+            p = c;
+            if let Some(tok) = nodes[p].token_id() {
+                last = Some((tok, off + 1));
+            }
+        }
+        last
+    }
+
+    /// Like get_special_token(), but fails with TokError::UnknownSpecialToken.
+    pub fn try_special_token(&self, name: &str) -> Result<TokenId> {
+        self.get_special_token(name)
+            .ok_or_else(|| TokError::UnknownSpecialToken(name.to_string()).into())
+    }
+
+    /// Fail with TokError::VocabOverflow if any of the tokens is outside of the vocabulary.
+    pub fn check_tokens(&self, tokens: &[TokenId]) -> Result<()> {
+        match tokens.iter().find(|&&t| t as usize >= self.vocab_size()) {
+            Some(&token) => Err(TokError::VocabOverflow {
+                token,
+                vocab_size: self.vocab_size(),
+            }
+            .into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Metadata for given token, if it is an added token.
+    pub fn added_token(&self, tok: TokenId) -> Option<&AddedToken> {
+        self.added_tokens
+            .binary_search_by_key(&tok, |e| e.id)
+            .ok()
+            .map(|idx| &self.added_tokens[idx])
+    }
+
+    pub fn is_special_token(&self, tok: TokenId) -> bool {
+        self.added_token(tok).is_some_and(|t| t.special)
+    }
+
+    /// Iterate over metadata of all special tokens, in token id order.
+    pub fn special_tokens_iter(&self) -> impl Iterator<Item = &AddedToken> {
+        self.added_tokens.iter().filter(|t| t.special)
     }
 
     pub fn get_special_tokens(&self) -> Vec<TokenId> {
+        let marker = match self.escape {
+            SpecialTokenEscape::Prefix(m) => m,
+            SpecialTokenEscape::IdTable(_) => {
+                return self.special_tokens_iter().map(|t| t.id).collect();
+            }
+        };
         let mut res = Vec::new();
         let pref_node = self
-            .child_at_byte(self.root(), TokTrie::SPECIAL_TOKEN_MARKER)
+            .child_at_byte(self.root(), marker)
             .expect("missing special token prefix");
         let mut stack = vec![pref_node];
         while let Some(n) = stack.pop() {
@@ -542,33 +1429,45 @@ impl TokTrie {
 
     pub fn greedy_tokenize(&self, bytes: &[u8]) -> Vec<TokenId> {
         let mut r = Vec::new();
+        self.greedy_for_each(bytes, |tok| r.push(tok));
+        r
+    }
+
+    /// Number of tokens greedy_tokenize() would return, without allocating them.
+    pub fn greedy_count(&self, bytes: &[u8]) -> usize {
+        let mut n = 0;
+        self.greedy_for_each(bytes, |_| n += 1);
+        n
+    }
+
+    fn greedy_for_each(&self, bytes: &[u8], mut f: impl FnMut(TokenId)) {
         if bytes.len() == 0 {
-            return r;
+            return;
         }
 
         let mut n = self.root();
         let mut last_tok = None;
         let mut last_idx = 0;
         let mut idx = 0;
-        while idx < bytes.len() {
-            match self.child_at_byte(n, bytes[idx]) {
-                Some(c) => {
-                    if let Some(tok) = c.token_id() {
-                        last_tok = Some(tok);
-                        last_idx = idx;
-                    }
-                    n = c;
-                }
-                None => {
-                    r.push(last_tok.unwrap());
-                    idx = last_idx;
-                    n = self.root();
+        loop {
+            if let Some(c) = bytes.get(idx).and_then(|&b| self.child_at_byte(n, b)) {
+                if let Some(tok) = c.token_id() {
+                    last_tok = Some(tok);
+                    last_idx = idx;
                 }
+                n = c;
+                idx += 1;
+                continue;
+            }
+            // no longer match; also at the end of input, where the bytes after
+            // the last token (a prefix of a longer token) still need to be tokenized
+            f(last_tok.take().unwrap());
+            idx = last_idx + 1;
+            if idx >= bytes.len() {
+                break;
             }
-            idx = idx + 1;
+            n = self.root();
         }
-        r.push(last_tok.unwrap());
-        r
     }
 
     pub fn tokenize_with_greedy_fallback(
@@ -576,6 +1475,16 @@ impl TokTrie {
         s: &[u8],
         str_tokenize: impl FnOnce(&str) -> Vec<TokenId>,
     ) -> Vec<TokenId> {
+        self.try_tokenize_with_greedy_fallback(s, |s| Ok(str_tokenize(s)))
+            .unwrap()
+    }
+
+    /// Like tokenize_with_greedy_fallback(), but returns the error of str_tokenize, if any.
+    pub fn try_tokenize_with_greedy_fallback(
+        &self,
+        s: &[u8],
+        str_tokenize: impl FnOnce(&str) -> Result<Vec<TokenId>>,
+    ) -> Result<Vec<TokenId>> {
         let utf8_str = String::from_utf8_lossy(s);
         // if the string ends with a replacement character, remove them
         let to_tokenize = if utf8_str.ends_with('\u{FFFD}') {
@@ -583,7 +1492,7 @@ impl TokTrie {
         } else {
             &utf8_str
         };
-        let mut r = str_tokenize(to_tokenize);
+        let mut r = str_tokenize(to_tokenize)?;
         // if we didn't tokenize everything (because of the replacement character)
         // we tokenize the suffix using greedy tokenizer that is happy with bytes
         let last_tokenized = to_tokenize.len();
@@ -591,6 +1500,136 @@ impl TokTrie {
             let mut added = self.greedy_tokenize(&s[last_tokenized..]);
             r.append(&mut added);
         }
+        Ok(r)
+    }
+
+    /// Special token starting at s, which follows a marker byte, and its length.
+    fn special_token_after_marker(
+        &self,
+        s: &[u8],
+        detection: &MarkerDetection,
+    ) -> Option<(TokenId, usize)> {
+        match detection {
+            MarkerDetection::Exact => self.longest_special_name(s),
+            MarkerDetection::Delimited {
+                open,
+                close,
+                max_len,
+            } => {
+                if !s.starts_with(open) || close.is_empty() {
+                    return None;
+                }
+                let window = &s[..core::cmp::min(s.len(), *max_len)];
+                let end = window
+                    .get(open.len()..)?
+                    .windows(close.len())
+                    .position(|w| w == &close[..])?
+                    + open.len()
+                    + close.len();
+                let name = core::str::from_utf8(&s[..end]).ok()?;
+                self.get_special_token(name).map(|tok| (tok, end))
+            }
+        }
+    }
+
+    /// Tokenize `s`, where special tokens are written as the special token marker
+    /// (SPECIAL_TOKEN_MARKER unless configured otherwise) followed by their name,
+    /// recognized according to opts.
+    /// The text in between special tokens is passed to `tokenize`.
+    pub fn tokenize_with_marker(
+        &self,
+        s: &[u8],
+        opts: &MarkerOptions,
+        mut tokenize: impl FnMut(&[u8]) -> Result<Vec<TokenId>>,
+    ) -> Result<Vec<TokenId>> {
+        let ff = self
+            .special_token_marker()
+            .unwrap_or(TokTrie::SPECIAL_TOKEN_MARKER);
+        let mut result = Vec::new();
+        // text not tokenized yet
+        let mut text = Vec::new();
+        let mut idx = 0;
+        while idx < s.len() {
+            let normal_len = s[idx..]
+                .iter()
+                .position(|&x| x == ff)
+                .unwrap_or(s.len() - idx);
+            text.extend_from_slice(&s[idx..idx + normal_len]);
+            idx += normal_len;
+            if idx == s.len() {
+                break;
+            }
+            idx += 1; // skip ff
+            let special = self.special_token_after_marker(&s[idx..], &opts.detection);
+            let unknown = match opts.unknown {
+                UnknownMarker::Error if !looks_like_special_name(&s[idx..], &opts.detection) => {
+                    UnknownMarker::Keep
+                }
+                u => u,
+            };
+            if special.is_none() && unknown == UnknownMarker::Keep {
+                text.push(ff);
+                continue;
+            }
+            if !text.is_empty() {
+                result.extend_from_slice(&tokenize(&text)?);
+                text.clear();
+            }
+            match special {
+                Some((tok, len)) => {
+                    result.push(tok);
+                    idx += len;
+                }
+                None if unknown == UnknownMarker::Error => {
+                    let name_len = s[idx..]
+                        .iter()
+                        .take(100)
+                        .position(|b| b.is_ascii_whitespace() || *b == ff)
+                        .unwrap_or(core::cmp::min(100, s.len() - idx));
+                    let name = String::from_utf8_lossy(&s[idx..idx + name_len]);
+                    return Err(TokError::UnknownSpecialToken(name.to_string()).into());
+                }
+                None => {}
+            }
+        }
+        if !text.is_empty() {
+            result.extend_from_slice(&tokenize(&text)?);
+        }
+        Ok(result)
+    }
+
+    /// Tokenize `s`, emitting a single token for every special token name
+    /// (like `</s>` or `<|eot_id|>`) that appears literally in the input.
+    /// The text in between special tokens is passed to `tokenize`.
+    /// The longest matching special token name wins.
+    pub fn tokenize_with_special(
+        &self,
+        s: &[u8],
+        mut tokenize: impl FnMut(&[u8]) -> Vec<TokenId>,
+    ) -> Vec<TokenId> {
+        if self.special_names_root().is_none() {
+            return tokenize(s);
+        }
+
+        let mut r = Vec::new();
+        let mut start = 0;
+        let mut idx = 0;
+        while idx < s.len() {
+            match self.longest_special_name(&s[idx..]) {
+                Some((tok, len)) => {
+                    if start < idx {
+                        r.extend_from_slice(&tokenize(&s[start..idx]));
+                    }
+                    r.push(tok);
+                    idx += len;
+                    start = idx;
+                }
+                None => idx += 1,
+            }
+        }
+        if start < s.len() {
+            r.extend_from_slice(&tokenize(&s[start..]));
+        }
         r
     }
 
@@ -628,28 +1667,199 @@ impl TokTrie {
     }
 
     pub fn from_bytes(bytes: &[u8]) -> Self {
-        let pref = std::mem::size_of::<TokTrieHeader>();
+        let pref = core::mem::size_of::<TokTrieHeader>();
         let hd: &TokTrieHeader = bytemuck::from_bytes(&bytes[0..pref]);
 
         assert!(hd.magic == TokTrieHeader::MAGIC);
         assert!(hd.hd_size as usize == pref);
+        assert!(hd.version == TokTrieHeader::VERSION);
 
         let trie_end = pref + hd.trie_bytes as usize;
         let nodes = vec_from_bytes(&bytes[pref..trie_end]);
         let offsets_end = trie_end + hd.token_offset_bytes as usize;
         let token_offsets = vec_from_bytes(&bytes[trie_end..offsets_end]);
-        let token_data = vec_from_bytes(&bytes[offsets_end..]);
+        let ids_end = offsets_end + hd.special_ids_bytes as usize;
+        let special_ids = vec_from_bytes(&bytes[offsets_end..ids_end]);
+        let added_end = ids_end + hd.added_tokens_bytes as usize;
+        let added: Vec<u32> = vec_from_bytes(&bytes[ids_end..added_end]);
+        let token_data =
+            vec_from_bytes(&bytes[added_end..added_end + hd.token_data_bytes as usize]);
+        let mut r = Self::from_parts(hd, nodes, token_offsets, special_ids, token_data).unwrap();
+        r.finalize_ctor();
+        r.added_tokens_from_bin(&added).unwrap();
+        r
+    }
 
-        let mut r = TokTrie {
+    /// Like from_bytes(), but fails instead of panicking on malformed data,
+    /// and doesn't require the data to be aligned.
+    /// The structure of the trie is checked against the token table,
+    /// so this can be used on untrusted data.
+    pub fn try_from_bytes(bytes: &[u8]) -> Result<Self> {
+        let pref = core::mem::size_of::<TokTrieHeader>();
+        if bytes.len() < pref {
+            anyhow::bail!("trie data too short");
+        }
+        let hd: TokTrieHeader = bytemuck::pod_read_unaligned(&bytes[0..pref]);
+        if hd.magic != TokTrieHeader::MAGIC || hd.hd_size as usize != pref {
+            anyhow::bail!("invalid trie header");
+        }
+        if hd.version != TokTrieHeader::VERSION {
+            anyhow::bail!("unsupported trie format version {}", hd.version);
+        }
+        let trie_end = pref + hd.trie_bytes as usize;
+        let offsets_end = trie_end + hd.token_offset_bytes as usize;
+        let ids_end = offsets_end + hd.special_ids_bytes as usize;
+        let added_end = ids_end + hd.added_tokens_bytes as usize;
+        if added_end + hd.token_data_bytes as usize != bytes.len()
+            || !(hd.trie_bytes as usize).is_multiple_of(core::mem::size_of::<TrieNode>())
+            || !hd.token_offset_bytes.is_multiple_of(4)
+            || !hd.special_ids_bytes.is_multiple_of(4)
+            || !hd.added_tokens_bytes.is_multiple_of(8)
+        {
+            anyhow::bail!("invalid trie data size");
+        }
+        let mut r = Self::from_parts(
+            &hd,
+            vec_from_unaligned_bytes(&bytes[pref..trie_end]),
+            vec_from_unaligned_bytes(&bytes[trie_end..offsets_end]),
+            vec_from_unaligned_bytes(&bytes[offsets_end..ids_end]),
+            bytes[added_end..].to_vec(),
+        )?;
+        r.check_structure()?;
+        r.finalize_ctor();
+        r.added_tokens_from_bin(&vec_from_unaligned_bytes(&bytes[ids_end..added_end]))?;
+        Ok(r)
+    }
+
+    fn from_parts(
+        hd: &TokTrieHeader,
+        nodes: Vec<TrieNode>,
+        token_offsets: Vec<u32>,
+        special_ids: Vec<TokenId>,
+        token_data: Vec<u8>,
+    ) -> Result<Self> {
+        let escape = match hd.escape {
+            TokTrieHeader::ESCAPE_ID_TABLE => SpecialTokenEscape::IdTable(special_ids),
+            m => {
+                ensure!(
+                    m <= 0xff && special_ids.is_empty(),
+                    "invalid special token escape"
+                );
+                SpecialTokenEscape::Prefix(m as u8)
+            }
+        };
+        Ok(TokTrie {
             info: TokRxInfo::from_bin(&hd.info),
             token_offsets,
             token_data,
             nodes,
             max_token_len: 0,
             token_duplicates: FxHashMap::default(),
+            added_tokens: vec![],
+            escape,
+            trie_tokens: SimpleVob::new(),
+            special_nodes: vec![],
+            stop_tokens: vec![],
+        })
+    }
+
+    // Everything finalize_ctor() and validate() assume about deserialized data:
+    // the token table is in bounds, the nodes nest properly with sorted children
+    // and num_parents as built by TrieBuilder::serialize(), and every token
+    // is reachable in the trie under its bytes.
+    fn check_structure(&self) -> Result<()> {
+        let vocab_size = self.info.vocab_size as usize;
+        ensure!(
+            self.token_offsets.len() == vocab_size,
+            "token table size mismatch"
+        );
+        ensure!(
+            self.info.tok_eos < self.info.vocab_size,
+            "invalid EOS token"
+        );
+        ensure!(
+            self.info
+                .tok_end_of_turn
+                .into_iter()
+                .chain(self.info.extra_stop_tokens())
+                .all(|t| t < self.info.vocab_size),
+            "invalid stop token"
+        );
+        let out_of_band: &[TokenId] = match &self.escape {
+            SpecialTokenEscape::IdTable(ids) => ids,
+            SpecialTokenEscape::Prefix(_) => &[],
         };
-        r.finalize_ctor();
-        r
+        ensure!(
+            out_of_band.iter().all(|&t| t < self.info.vocab_size),
+            "invalid special token id"
+        );
+        for &desc in &self.token_offsets {
+            let len = (desc & ((1 << LEN_BITS) - 1)) as usize;
+            let off = (desc >> LEN_BITS) as usize;
+            ensure!(
+                off + len <= self.token_data.len(),
+                "token data out of bounds"
+            );
+        }
+
+        ensure!(
+            !self.nodes.is_empty() && self.root().subtree_size() == self.nodes.len(),
+            "invalid trie root"
+        );
+        let mut used = vec![false; vocab_size];
+        let mut path = Vec::new();
+        // (node offset, expected num_parents, depth)
+        let mut stack = vec![(0, 0u8, 0)];
+        while let Some((p, num_parents, depth)) = stack.pop() {
+            let n = &self.nodes[p];
+            ensure!(n.num_parents() == num_parents as usize, "invalid trie node");
+            path.truncate(depth);
+            if p != 0 {
+                path.push(n.byte());
+            }
+            if let Some(tok) = n.token_id() {
+                ensure!(
+                    tok < self.info.vocab_size && !used[tok as usize],
+                    "invalid token in trie"
+                );
+                used[tok as usize] = true;
+                ensure!(self.token(tok) == path, "token bytes don't match trie");
+            }
+            let endp = p + n.subtree_size();
+            let mut c = p + 1;
+            let mut prev_byte = None;
+            while c < endp {
+                let ch = &self.nodes[c];
+                let ch_end = c + ch.subtree_size();
+                ensure!(
+                    ch.subtree_size() > 0
+                        && ch_end <= endp
+                        && prev_byte.is_none_or(|b| b < ch.byte()),
+                    "invalid trie node"
+                );
+                prev_byte = Some(ch.byte());
+                let ch_parents = if ch_end == endp {
+                    num_parents.wrapping_add(1)
+                } else {
+                    1
+                };
+                stack.push((c, ch_parents, path.len()));
+                c = ch_end;
+            }
+        }
+
+        for &tok in out_of_band {
+            ensure!(!used[tok as usize], "special token in trie");
+            used[tok as usize] = true;
+        }
+        for tok in 0..self.info.vocab_size {
+            let bytes = self.token(tok);
+            ensure!(
+                used[tok as usize] || bytes.is_empty() || self.token_id_at_bytes(bytes).is_some(),
+                "token missing from trie"
+            );
+        }
+        Ok(())
     }
 
     pub fn max_token_len(&self) -> usize {
@@ -685,12 +1895,26 @@ impl TokTrie {
         let token_offsets: &[u8] = bytemuck::cast_slice(&self.token_offsets);
         let token_data: &[u8] = bytemuck::cast_slice(&self.token_data);
 
+        let (escape, special_ids): (u32, &[u8]) = match &self.escape {
+            SpecialTokenEscape::Prefix(m) => (*m as u32, &[]),
+            SpecialTokenEscape::IdTable(ids) => {
+                (TokTrieHeader::ESCAPE_ID_TABLE, bytemuck::cast_slice(ids))
+            }
+        };
+
+        let added = self.added_tokens_to_bin();
+        let added: &[u8] = bytemuck::cast_slice(&added);
+
         let hd = TokTrieHeader {
             magic: TokTrieHeader::MAGIC,
-            hd_size: std::mem::size_of::<TokTrieHeader>() as u32,
+            hd_size: core::mem::size_of::<TokTrieHeader>() as u32,
+            version: TokTrieHeader::VERSION,
             trie_bytes: trie_data.len() as u32,
             token_offset_bytes: token_offsets.len() as u32,
-            token_data_bytes: trie_data.len() as u32,
+            special_ids_bytes: special_ids.len() as u32,
+            added_tokens_bytes: added.len() as u32,
+            token_data_bytes: token_data.len() as u32,
+            escape,
             info: self.info.to_bin(),
             align: [],
         };
@@ -698,6 +1922,8 @@ impl TokTrie {
         let mut bytes = bytemuck::bytes_of(&hd).to_vec();
         bytes.extend_from_slice(trie_data);
         bytes.extend_from_slice(token_offsets);
+        bytes.extend_from_slice(special_ids);
+        bytes.extend_from_slice(added);
         bytes.extend_from_slice(token_data);
         bytes
     }
@@ -771,6 +1997,19 @@ impl TokTrie {
         Some(n)
     }
 
+    /// Enumerate tokens whose bytes start with `prefix` (including the token equal to `prefix`).
+    /// Only walks the subtree of `prefix`. Duplicate tokens are not included.
+    pub fn tokens_with_prefix(&self, prefix: &[u8]) -> impl Iterator<Item = TokenId> + '_ {
+        let nodes = match self.child_at_bytes(self.root(), prefix) {
+            Some(n) => {
+                let off = self.node_offset(n);
+                &self.nodes[off..off + n.subtree_size()]
+            }
+            None => &self.nodes[0..0],
+        };
+        nodes.iter().filter_map(|n| n.token_id())
+    }
+
     pub fn token_id_at_bytes(&self, bytes: &[u8]) -> Option<TokenId> {
         self.child_at_bytes(self.root(), bytes)
             .and_then(|n| n.token_id())
@@ -782,16 +2021,257 @@ impl TokTrie {
 
     pub fn compute_bias_ext(&self, r: &mut impl Recognizer, logits: &mut SimpleVob, start: &[u8]) {
         logits.set_all(false);
-        if start.is_empty() {
-            // EOS is only allowed if there is no forced byte prefix
-            for tok in vec![SpecialToken::EndOfSentence] {
-                if r.special_allowed(tok) {
-                    logits.allow_token(self.special_token(tok))
+        // EOS is only allowed if there is no forced byte prefix
+        let stop_allowed = if start.is_empty() {
+            Self::stop_allowed(r)
+        } else {
+            StopAllowed::default()
+        };
+        if !(start.is_empty() && self.trivial_bias(r, logits)) {
+            self.add_bias(r, logits, start);
+            self.apply_duplicates(logits);
+        }
+        self.allow_stop_tokens(logits, stop_allowed);
+    }
+
+    fn stop_allowed(r: &mut impl Recognizer) -> StopAllowed {
+        StopAllowed {
+            eos: r.special_allowed(SpecialToken::EndOfSentence),
+            eot: r.special_allowed(SpecialToken::EndOfTurn),
+        }
+    }
+
+    // EOS and tok_stop are gated on EndOfSentence, the end-of-turn token on EndOfTurn
+    fn is_allowed_stop_token(&self, tok: TokenId, stop: StopAllowed) -> bool {
+        (stop.eos && (tok == self.info.tok_eos || self.info.tok_stop.contains(&Some(tok))))
+            || (stop.eot && self.info.tok_end_of_turn == Some(tok))
+    }
+
+    // after apply_duplicates(), so that tokens with the same bytes
+    // as a stop token are not allowed along with it
+    fn allow_stop_tokens(&self, logits: &mut SimpleVob, stop: StopAllowed) {
+        for &tok in &self.stop_tokens {
+            if self.is_allowed_stop_token(tok, stop) {
+                logits.allow_token(tok)
+            }
+        }
+    }
+
+    // fast paths for recognizers declaring that everything or nothing is allowed;
+    // returns true if logits are complete
+    fn trivial_bias(&self, r: &mut impl Recognizer, logits: &mut SimpleVob) -> bool {
+        if r.no_bytes_allowed() {
+            true
+        } else if r.all_bytes_allowed() {
+            // same as walking the trie: empty tokens and the extra capacity stay disallowed
+            logits.or(&self.trie_tokens);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Like compute_bias(), but produces a soft bias: disallowed tokens get -inf,
+    /// allowed tokens get the sum of Recognizer::byte_weight() over their bytes,
+    /// and allowed stop tokens get 0.0.
+    /// `bias` has to have at least vocab_size() elements (see alloc_logits()).
+    pub fn compute_bias_f32(&self, r: &mut impl Recognizer, bias: &mut [f32]) {
+        assert!(bias.len() >= self.vocab_size());
+        bias.iter_mut().for_each(|x| *x = f32::NEG_INFINITY);
+
+        let n = self.root();
+        r.trie_started();
+        let mut weights = vec![0.0f32; self.max_token_len() + 2];
+        let mut depth = 0;
+        let off = self.node_offset(n);
+        let mut p = off + 1;
+        let endp = off + n.subtree_size();
+        let mut next_pop = 0;
+        while p < endp {
+            r.pop_bytes(next_pop);
+            depth -= next_pop;
+            let n = &self.nodes[p];
+            let b = n.byte();
+            let w = weights[depth] + r.byte_weight(b);
+            if r.try_push_byte(b) {
+                depth += 1;
+                weights[depth] = w;
+                if let Some(tok) = n.token_id() {
+                    bias[tok as usize] = w;
+                }
+                next_pop = if n.subtree_size() == 1 {
+                    n.num_parents()
+                } else {
+                    0
+                };
+                p += 1;
+            } else {
+                p += n.subtree_size();
+                next_pop = n.num_parents() - 1;
+            }
+        }
+        r.pop_bytes(next_pop);
+        r.trie_finished();
+
+        for (tok, dups) in &self.token_duplicates {
+            for &dup in dups {
+                bias[dup as usize] = bias[*tok as usize];
+            }
+        }
+        // after duplicates, which could otherwise overwrite stop tokens or copy them
+        let stop_allowed = Self::stop_allowed(r);
+        for &tok in &self.stop_tokens {
+            if self.is_allowed_stop_token(tok, stop_allowed) {
+                bias[tok as usize] = 0.0;
+            }
+        }
+    }
+
+    /// Compute masks for several recognizers at once (e.g., sequences in a batch).
+    /// The trie is walked once; a subtree is only visited by the recognizers that
+    /// allow its prefix. Results are the same as calling compute_bias() for each recognizer.
+    pub fn compute_bias_batch<R: Recognizer>(&self, rs: &mut [R], masks: &mut [SimpleVob]) {
+        assert!(rs.len() == masks.len());
+        let defl_tok = self.vocab_size() as u32;
+        let mut walked = Vec::new();
+        let mut stop_allowed = Vec::with_capacity(rs.len());
+        for (idx, (r, mask)) in rs.iter_mut().zip(masks.iter_mut()).enumerate() {
+            mask.set_all(false);
+            stop_allowed.push(Self::stop_allowed(r));
+            if !self.trivial_bias(r, mask) {
+                walked.push(idx);
+            }
+            r.trie_started();
+        }
+
+        // levels[d] are the recognizers that accepted the first d bytes of the current path;
+        // the vectors are reused, only levels[0..depth] are valid
+        let mut levels: Vec<Vec<usize>> = vec![walked];
+        let mut depth = 1;
+        fn close_levels<R: Recognizer>(
+            rs: &mut [R],
+            levels: &[Vec<usize>],
+            depth: &mut usize,
+            num: usize,
+        ) {
+            for _ in 0..num {
+                *depth -= 1;
+                for &i in &levels[*depth] {
+                    rs[i].pop_bytes(1);
+                }
+            }
+        }
+
+        let n = self.root();
+        let off = self.node_offset(n);
+        let mut p = off + 1;
+        let endp = off + n.subtree_size();
+        while p < endp {
+            let n = &self.nodes[p];
+            let b = n.byte();
+            if levels.len() <= depth {
+                levels.push(Vec::new());
+            }
+            let (parents, curr) = levels.split_at_mut(depth);
+            let curr = &mut curr[0];
+            curr.clear();
+            for &i in &parents[depth - 1] {
+                if rs[i].try_push_byte(b) {
+                    curr.push(i);
+                    masks[i].allow_token(n.token_id().unwrap_or(defl_tok));
+                }
+            }
+            if curr.is_empty() {
+                p += n.subtree_size();
+                close_levels(rs, &levels, &mut depth, n.num_parents() - 1);
+            } else {
+                depth += 1;
+                if n.subtree_size() == 1 {
+                    close_levels(rs, &levels, &mut depth, n.num_parents());
                 }
+                p += 1;
+            }
+        }
+
+        for ((r, mask), stop) in rs.iter_mut().zip(masks.iter_mut()).zip(stop_allowed) {
+            r.trie_finished();
+            mask.disallow_token(defl_tok);
+            self.apply_duplicates(mask);
+            self.allow_stop_tokens(mask, stop);
+        }
+    }
+
+    /// Like compute_bias_batch(), but splits the batch into chunks processed in parallel.
+    #[cfg(feature = "rayon")]
+    pub fn compute_bias_batch_par<R: Recognizer + Send>(
+        &self,
+        rs: &mut [R],
+        masks: &mut [SimpleVob],
+        chunk_size: usize,
+    ) {
+        use rayon::prelude::*;
+        assert!(rs.len() == masks.len());
+        rs.par_chunks_mut(chunk_size)
+            .zip(masks.par_chunks_mut(chunk_size))
+            .for_each(|(rs, masks)| self.compute_bias_batch(rs, masks));
+    }
+
+    /// Like compute_bias(), but the subtrees of the trie root are split
+    /// into groups processed in parallel, each with its own clone of the recognizer.
+    #[cfg(feature = "rayon")]
+    pub fn compute_bias_par<R: Recognizer + Clone + Send + Sync>(
+        &self,
+        r: &mut R,
+        logits: &mut SimpleVob,
+    ) {
+        use rayon::prelude::*;
+
+        logits.set_all(false);
+        let stop_allowed = Self::stop_allowed(r);
+        if self.trivial_bias(r, logits) {
+            self.allow_stop_tokens(logits, stop_allowed);
+            return;
+        }
+
+        let root = self.root();
+        let off = self.node_offset(root);
+        let endp = off + root.subtree_size();
+        let num_groups = rayon::current_num_threads() * 2;
+        let target = root.subtree_size() / num_groups + 1;
+        let mut groups = Vec::new();
+        let mut start = off + 1;
+        for child in self.node_children(root) {
+            let child_end = self.node_offset(child) + child.subtree_size();
+            if child_end - start >= target || child_end == endp {
+                groups.push((start, child_end));
+                start = child_end;
             }
         }
-        self.add_bias(r, logits, start);
+
+        let proto: &R = r;
+        let res = groups
+            .into_par_iter()
+            .map(|(p, endp)| {
+                let mut r = proto.clone();
+                let mut toks = self.alloc_token_set();
+                r.trie_started();
+                let next_pop = self.add_bias_range(&mut r, &mut toks, p, endp);
+                r.pop_bytes(next_pop);
+                r.trie_finished();
+                toks
+            })
+            .reduce(
+                || self.alloc_token_set(),
+                |mut a, b| {
+                    a.or(&b);
+                    a
+                },
+            );
+
+        logits.or(&res);
+        logits.disallow_token(self.vocab_size() as u32);
         self.apply_duplicates(logits);
+        self.allow_stop_tokens(logits, stop_allowed);
     }
 
     pub fn apply_duplicates(&self, logits: &mut SimpleVob) {
@@ -811,13 +2291,21 @@ impl TokTrie {
         Ok(())
     }
 
+    /// Append token to the recognizer, and collapse() it.
+    /// On failure, the error can be downcast to the Rejection of the offending byte.
     pub fn append_token(&self, r: &mut impl Recognizer, t: TokenId) -> Result<()> {
         // println!("append_token: {}", self.token_dbg(t));
         let bytes = self.token(t);
-        for &byte in bytes {
-            if !r.try_push_byte(byte) {
+        for (idx, &byte) in bytes.iter().enumerate() {
+            if let Err(rejection) = r.try_push_byte_ext(byte) {
                 r.collapse();
-                return Err(anyhow::anyhow!("byte {:?} not allowed", byte as char));
+                let msg = format!(
+                    "token {} rejected at byte {}: {}",
+                    self.token_dbg(t),
+                    idx,
+                    rejection
+                );
+                return Err(anyhow::Error::new(rejection).context(msg));
             }
         }
         r.collapse();
@@ -861,6 +2349,118 @@ impl TokTrie {
         (chop_tokens, chop_bytes)
     }
 
+    /// Compute bytes forced by the recognizer in its current state, that is
+    /// as long as exactly one byte is allowed and EOS is not, up to max_len bytes.
+    /// The recognizer is left in its original state.
+    pub fn compute_ff_bytes(&self, r: &mut impl Recognizer, max_len: usize) -> Vec<u8> {
+        let mut res = Vec::new();
+        while res.len() < max_len && !r.special_allowed(SpecialToken::EndOfSentence) {
+            let mut forced = None;
+            for b in 0..=255u8 {
+                if r.byte_allowed(b) {
+                    if forced.is_some() {
+                        forced = None;
+                        break;
+                    }
+                    forced = Some(b);
+                }
+            }
+            match forced {
+                Some(b) => {
+                    let ok = r.try_push_byte(b);
+                    assert!(ok);
+                    res.push(b);
+                }
+                None => break,
+            }
+        }
+        r.pop_bytes(res.len());
+        res
+    }
+
+    /// Compute tokens forced by the recognizer in its current state,
+    /// suitable for Splice::ff_tokens.
+    /// The forced bytes are tokenized with `tokenize` (e.g., TokenizerEnv::tokenize_bytes()),
+    /// and trailing tokens that could be extended by a longer allowed token are dropped.
+    /// The recognizer is left in its original state.
+    pub fn compute_ff_tokens(
+        &self,
+        r: &mut impl Recognizer,
+        tokenize: impl FnOnce(&[u8]) -> Vec<TokenId>,
+    ) -> Vec<TokenId> {
+        let bytes = self.compute_ff_bytes(r, 100);
+        if bytes.is_empty() {
+            return vec![];
+        }
+        let mut tokens = tokenize(&bytes);
+        for &b in &bytes {
+            let ok = r.try_push_byte(b);
+            assert!(ok);
+        }
+        let mut suff = Vec::new();
+        let mut chop = 0;
+        for (idx, t) in tokens.iter().rev().enumerate() {
+            suff.splice(0..0, self.token(*t).iter().cloned());
+            if suff.len() > self.max_token_len() {
+                break;
+            }
+            if let Some(n) = self.child_at_bytes(self.root(), &suff) {
+                if self.node_children(n).any(|c| r.byte_allowed(c.byte())) {
+                    chop = idx + 1;
+                }
+            }
+        }
+        r.pop_bytes(bytes.len());
+        tokens.truncate(tokens.len() - chop);
+        tokens
+    }
+
+    /// Token healing: back off the final prompt tokens that could be
+    /// extended by a longer token, so that the tokenization boundary
+    /// doesn't constrain the first generated token.
+    /// The prompt is `tokens` followed by `suffix` bytes (which may be empty).
+    /// Returns the tokens to keep and the bytes the generation has to start with;
+    /// use token_healing_mask() to constrain the first generated token.
+    pub fn heal_prompt(&self, tokens: &[TokenId], suffix: &[u8]) -> (Vec<TokenId>, Vec<u8>) {
+        let mut prefix = suffix.to_vec();
+        let mut keep = tokens.len();
+        while keep > 0 {
+            let t = tokens[keep - 1];
+            if self.is_special_token(t) {
+                break;
+            }
+            let mut candidate = self.token(t).to_vec();
+            candidate.extend_from_slice(&prefix);
+            if candidate.len() > self.max_token_len() || !self.has_extensions(&candidate) {
+                break;
+            }
+            prefix = candidate;
+            keep -= 1;
+        }
+        (tokens[0..keep].to_vec(), prefix)
+    }
+
+    /// Set of tokens that can start a generation forced to begin with `prefix`:
+    /// tokens extending `prefix` and tokens that are prefixes of `prefix`.
+    pub fn token_healing_mask(&self, prefix: &[u8]) -> SimpleVob {
+        let mut toks = self.alloc_token_set();
+        let mut n = self.root();
+        for &b in prefix {
+            n = match self.child_at_byte(n, b) {
+                Some(n) => n,
+                None => break,
+            };
+            if let Some(tok) = n.token_id() {
+                toks.allow_token(tok);
+            }
+        }
+        for tok in self.tokens_with_prefix(prefix) {
+            toks.allow_token(tok);
+        }
+        self.apply_duplicates(&mut toks);
+        toks
+    }
+
     /// Check if add_bias() would have returned any tokens.
     #[inline(never)]
     pub fn has_valid_extensions(&self, r: &mut impl Recognizer, start: &[u8]) -> bool {
@@ -933,10 +2533,20 @@ impl TokTrie {
 
     #[inline(never)]
     fn add_bias_inner(&self, r: &mut impl Recognizer, toks: &mut SimpleVob, n: &TrieNode) -> usize {
-        let defl_tok = self.vocab_size() as u32;
         let off = self.node_offset(n);
-        let mut p = off + 1;
-        let endp = off + n.subtree_size();
+        self.add_bias_range(r, toks, off + 1, off + n.subtree_size())
+    }
+
+    // walk sibling subtrees in nodes[p..endp]; returns the number of bytes left to pop
+    #[inline(always)]
+    fn add_bias_range(
+        &self,
+        r: &mut impl Recognizer,
+        toks: &mut SimpleVob,
+        mut p: usize,
+        endp: usize,
+    ) -> usize {
+        let defl_tok = self.vocab_size() as u32;
         let mut next_pop = 0;
         while p < endp {
             r.pop_bytes(next_pop);
@@ -1004,6 +2614,41 @@ impl TokTrie {
         (count, num_tokens)
     }
 
+    /// Size of the trie, including its memory footprint.
+    pub fn stats(&self) -> TrieStats {
+        let duplicates = self.token_duplicates.capacity()
+            * core::mem::size_of::<(TokenId, Vec<TokenId>)>()
+            + self
+                .token_duplicates
+                .values()
+                .map(|v| v.capacity() * core::mem::size_of::<TokenId>())
+                .sum::<usize>();
+        let added = self.added_tokens.capacity() * core::mem::size_of::<AddedToken>()
+            + self
+                .added_tokens
+                .iter()
+                .map(|t| t.content.capacity())
+                .sum::<usize>();
+        let escape = match &self.escape {
+            SpecialTokenEscape::IdTable(ids) => ids.capacity() * core::mem::size_of::<TokenId>(),
+            SpecialTokenEscape::Prefix(_) => 0,
+        } + self.special_nodes.capacity() * core::mem::size_of::<TrieNode>();
+        TrieStats {
+            num_nodes: self.nodes.len(),
+            num_token_nodes: self.nodes.iter().filter(|n| n.token_id().is_some()).count(),
+            token_bytes: self.token_data.len(),
+            max_token_len: self.max_token_len,
+            memory_bytes: core::mem::size_of::<Self>()
+                + self.nodes.capacity() * core::mem::size_of::<TrieNode>()
+                + self.token_offsets.capacity() * core::mem::size_of::<u32>()
+                + self.token_data.capacity()
+                + duplicates
+                + added
+                + escape
+                + core::mem::size_of_val(self.trie_tokens.as_slice()),
+        }
+    }
+
     pub fn trie_stats(&self) -> String {
         let mut nodes_histogram = vec![0; 256];
 
@@ -1030,7 +2675,7 @@ impl TokTrie {
                 num_children += 1;
             }
 
-            nodes_histogram[std::cmp::min(9, num_children)] += 1;
+            nodes_histogram[core::cmp::min(9, num_children)] += 1;
 
             p += 1;
         }
@@ -1104,67 +2749,520 @@ impl<'a> Iterator for NodeChildren<'a> {
     }
 }
 
-struct TrieHash {
+const NO_NODE: u32 = u32::MAX;
+
+// Node of TrieBuilder; children are a linked list sorted by byte,
+// or a table of all 256 children once there are many of them.
+struct BuilderNode {
     token_id: u32,
+    first_child: u32,
+    next_sibling: u32,
+    dense: u32,
+    num_children: u16,
     byte: u8,
-    children: Vec<TrieHash>,
 }
 
-impl TrieHash {
-    fn new(byte: u8) -> TrieHash {
-        TrieHash {
+// Trie under construction, with all nodes in one arena, referring to each other by index
+// (the root is node 0). serialize() lays them out in pre-order as TrieNodes.
+struct TrieBuilder {
+    nodes: Vec<BuilderNode>,
+    dense: Vec<[u32; 256]>,
+}
+
+impl TrieBuilder {
+    fn new() -> Self {
+        let mut r = TrieBuilder {
+            nodes: Vec::new(),
+            dense: Vec::new(),
+        };
+        r.add_node(0xff, NO_NODE);
+        r
+    }
+
+    fn add_node(&mut self, byte: u8, next_sibling: u32) -> u32 {
+        self.nodes.push(BuilderNode {
             token_id: NO_TOKEN,
+            first_child: NO_NODE,
+            next_sibling,
+            dense: NO_NODE,
+            num_children: 0,
             byte,
-            children: Vec::new(),
-        }
+        });
+        (self.nodes.len() - 1) as u32
     }
+
     fn insert(&mut self, word: &[u8], token_id: u32) {
-        if word.len() == 0 {
-            // Some tokenizers have duplicate tokens...
-            // we just override
-            // assert!(self.token_id == NO_TOKEN);
-            self.token_id = token_id;
-        } else {
-            if self.children.len() == 0x100 {
-                // assert!(self.children[word[0] as usize].byte == word[0]);
-                self.children[word[0] as usize].insert(&word[1..], token_id);
-                return;
-            }
+        let mut n = 0;
+        for &b in word {
+            n = self.child_or_insert(n, b);
+        }
+        // Some tokenizers have duplicate tokens...
+        // we just override
+        self.nodes[n as usize].token_id = token_id;
+    }
 
-            for ch in &mut self.children {
-                if ch.byte == word[0] {
-                    ch.insert(&word[1..], token_id);
-                    return;
-                }
-            }
+    fn child_or_insert(&mut self, n: u32, byte: u8) -> u32 {
+        let node = &self.nodes[n as usize];
+        if node.dense != NO_NODE {
+            return self.dense[node.dense as usize][byte as usize];
+        }
 
-            let mut ch = TrieHash::new(word[0]);
-            ch.insert(&word[1..], token_id);
-            self.children.push(ch);
+        let mut prev = NO_NODE;
+        let mut ch = node.first_child;
+        while ch != NO_NODE && self.nodes[ch as usize].byte < byte {
+            prev = ch;
+            ch = self.nodes[ch as usize].next_sibling;
+        }
+        if ch != NO_NODE && self.nodes[ch as usize].byte == byte {
+            return ch;
+        }
 
-            // if it's getting dense, make it full
-            // for cl100k threshold 60->15 nodes, 50->22, 40->45 30->94
-            // for llama (32k) 50->5, 40->15
-            // TODO remove this?
-            if self.children.len() > 250 {
-                let mut v2 = (0..=255).map(TrieHash::new).collect::<Vec<_>>();
-                for ch in self.children.drain(..) {
-                    let idx = ch.byte as usize;
-                    v2[idx] = ch;
+        let new_ch = self.add_node(byte, ch);
+        if prev == NO_NODE {
+            self.nodes[n as usize].first_child = new_ch;
+        } else {
+            self.nodes[prev as usize].next_sibling = new_ch;
+        }
+        self.nodes[n as usize].num_children += 1;
+
+        // if it's getting dense, make it full
+        // for cl100k threshold 60->15 nodes, 50->22, 40->45 30->94
+        // for llama (32k) 50->5, 40->15
+        // TODO remove this?
+        if self.nodes[n as usize].num_children > 250 {
+            let mut table = [NO_NODE; 256];
+            let mut ch = self.nodes[n as usize].first_child;
+            while ch != NO_NODE {
+                table[self.nodes[ch as usize].byte as usize] = ch;
+                ch = self.nodes[ch as usize].next_sibling;
+            }
+            for (b, entry) in table.iter_mut().enumerate() {
+                if *entry == NO_NODE {
+                    *entry = self.add_node(b as u8, NO_NODE);
                 }
-                self.children = v2;
             }
+            self.dense.push(table);
+            self.nodes[n as usize].dense = (self.dense.len() - 1) as u32;
         }
+
+        new_ch
     }
-    fn serialize(&mut self, data: &mut Vec<TrieNode>, num_parents: u8) {
+
+    fn serialize(&self, n: u32, data: &mut Vec<TrieNode>, num_parents: u8) {
         let idx = data.len();
-        let mut num_ch = self.children.len();
-        data.push(TrieNode::new(self.byte, self.token_id, num_parents));
-        self.children.sort_by_key(|e| e.byte);
-        for entry in &mut self.children {
-            num_ch -= 1;
-            entry.serialize(data, if num_ch == 0 { num_parents + 1 } else { 1 });
+        let node = &self.nodes[n as usize];
+        data.push(TrieNode::new(node.byte, node.token_id, num_parents));
+        if node.dense != NO_NODE {
+            let table = &self.dense[node.dense as usize];
+            for (b, &ch) in table.iter().enumerate() {
+                self.serialize(ch, data, if b == 0xff { num_parents + 1 } else { 1 });
+            }
+        } else {
+            let mut ch = node.first_child;
+            while ch != NO_NODE {
+                let next = self.nodes[ch as usize].next_sibling;
+                self.serialize(ch, data, if next == NO_NODE { num_parents + 1 } else { 1 });
+                ch = next;
+            }
         }
         data[idx].bits2 |= ((data.len() - idx) as u32) << 8;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recognizer::{AnythingGoes, FunctionalRecognizer, StackRecognizer};
+    use crate::{TestTokEnv, TokenizerEnv};
+
+    // single bytes, then given words, then EOS
+    fn trie_with(words: &[&[u8]]) -> TokTrie {
+        let mut all: Vec<Vec<u8>> = (0..=255u8).map(|b| vec![b]).collect();
+        all.extend(words.iter().map(|w| w.to_vec()));
+        all.push(b"\xFF<|end|>".to_vec());
+        let n = all.len() as u32;
+        TokTrie::from(&TokRxInfo::new(n, n - 1), &all)
+    }
+
+    // allows everything, but without the all_bytes_allowed() fast path
+    struct AllBytesSlow;
+
+    impl FunctionalRecognizer<()> for AllBytesSlow {
+        fn initial(&self) {}
+
+        fn try_append(&self, state: (), _byte: u8) -> Option<()> {
+            Some(state)
+        }
+
+        fn special_allowed(&self, _state: (), _tok: SpecialToken) -> bool {
+            true
+        }
+    }
+
+    // no bytes, only the given special token
+    struct OnlySpecial(SpecialToken);
+
+    impl FunctionalRecognizer<()> for OnlySpecial {
+        fn initial(&self) {}
+
+        fn try_append(&self, _state: (), _byte: u8) -> Option<()> {
+            None
+        }
+
+        fn special_allowed(&self, _state: (), tok: SpecialToken) -> bool {
+            tok == self.0
+        }
+    }
+
+    #[test]
+    fn stop_tokens_serialize_and_gating() {
+        let trie = trie_with(&[b"\xFF<|eot_id|>", b"\xFF<|im_end|>"]);
+        let mut info = *trie.info();
+        info.tok_end_of_turn = Some(256);
+        info.add_stop_token(257).unwrap();
+        info.add_stop_token(258).unwrap();
+        let trie = trie.with_info(info);
+        assert_eq!(trie.stop_tokens(), &[258, 256, 257]);
+        assert!(trie.is_stop_token(257) && !trie.is_stop_token(255));
+
+        let t2 = TokTrie::try_from_bytes(&trie.serialize()).unwrap();
+        assert_eq!(t2.info().tok_end_of_turn, Some(256));
+        assert_eq!(t2.stop_tokens(), trie.stop_tokens());
+
+        let mut logits = trie.alloc_token_set();
+        let mut bias = trie.alloc_logits();
+        for (tok, expected) in [
+            (SpecialToken::EndOfSentence, vec![257, 258]),
+            (SpecialToken::EndOfTurn, vec![256]),
+        ] {
+            let mut r = StackRecognizer::from(OnlySpecial(tok));
+            trie.compute_bias(&mut r, &mut logits);
+            assert_eq!(logits.iter_set().collect::<Vec<_>>(), expected);
+            trie.compute_bias_f32(&mut r, &mut bias);
+            let allowed: Vec<TokenId> = (0..trie.vocab_size() as TokenId)
+                .filter(|&t| bias[t as usize] == 0.0)
+                .collect();
+            assert_eq!(allowed, expected);
+        }
+    }
+
+    #[test]
+    fn too_many_stop_tokens() {
+        let trie = trie_with(&[]);
+        let ids: Vec<TokenId> = (0..=TokRxInfo::MAX_STOP_TOKENS as TokenId).collect();
+        let mut info = *trie.info();
+        for &id in &ids[..TokRxInfo::MAX_STOP_TOKENS] {
+            info.add_stop_token(id).unwrap();
+        }
+        // already there, or EOS
+        info.add_stop_token(0).unwrap();
+        info.add_stop_token(info.tok_eos).unwrap();
+        assert!(info.add_stop_token(100).is_err());
+        assert!(info.assign_role_by_name("<|im_end|>", 100).is_err());
+        assert_eq!(info.extra_stop_tokens().count(), TokRxInfo::MAX_STOP_TOKENS);
+
+        assert!(trie.with_stop_tokens(&ids).is_err());
+        let t2 = trie.with_stop_tokens(&ids[1..]).unwrap();
+        assert_eq!(t2.stop_tokens().len(), TokRxInfo::MAX_STOP_TOKENS + 1);
+    }
+
+    #[test]
+    fn roles_serialize() {
+        let trie = trie_with(&[
+            b"\xFF<s>",
+            b"\xFF<pad>",
+            b"\xFF<|fim_prefix|>",
+            b"\xFF<tool_call>",
+        ]);
+        let mut info = *trie.info();
+        info.set_role_token(SpecialToken::BeginningOfSentence, 256);
+        info.set_role_token(SpecialToken::Padding, 257);
+        info.set_role_token(SpecialToken::Unknown, 0);
+        info.set_role_token(SpecialToken::FimPrefix, 258);
+        info.set_role_token(SpecialToken::ToolCallBegin, 259);
+        let trie = trie.with_info(info);
+        let bytes = trie.serialize();
+
+        let mut aligned = vec![0u32; bytes.len().div_ceil(4)];
+        bytemuck::cast_slice_mut::<u32, u8>(&mut aligned)[..bytes.len()].copy_from_slice(&bytes);
+        for t2 in [
+            TokTrie::try_from_bytes(&bytes).unwrap(),
+            TokTrie::from_bytes(bytemuck::cast_slice(&aligned)),
+        ] {
+            assert_eq!(t2.info(), trie.info());
+            for role in SpecialToken::ALL {
+                assert_eq!(t2.info().role_token(role), info.role_token(role));
+            }
+            assert_eq!(t2.info().role_token(SpecialToken::FimSuffix), None);
+        }
+    }
+
+    #[test]
+    fn unknown_marker_error_only_for_names() {
+        let env = TestTokEnv::with_words(&["ab"]);
+        let trie = env.tok_trie();
+        let eos = trie.eos_token();
+        let ab = env.token("ab").unwrap();
+        let tokenize = |s: &[u8]| Ok(trie.greedy_tokenize(s));
+        for detection in [MarkerOptions::default().detection, MarkerDetection::Exact] {
+            let opts = MarkerOptions {
+                detection,
+                unknown: UnknownMarker::Error,
+            };
+            let run = |s: &[u8]| trie.tokenize_with_marker(s, &opts, tokenize);
+            assert_eq!(run(b"ab\xFF<|end|>").unwrap(), vec![ab, eos]);
+            assert!(run(b"ab\xFF<|foo|>").is_err());
+            // stray marker bytes, not followed by a name
+            assert_eq!(run(b"ab\xFF ab").unwrap(), vec![ab, 255, 32, ab]);
+            assert_eq!(run(b"ab\xFF").unwrap(), vec![ab, 255]);
+            assert_eq!(run(b"\xFF\xFE").unwrap(), vec![255, 254]);
+        }
+    }
+
+    #[test]
+    fn greedy_fallback_for_trailing_bytes() {
+        let env = TestTokEnv::with_words(&["ab", "\u{1F600}"]);
+        let trie = env.tok_trie();
+        let ab = env.token("ab").unwrap();
+        let mut seen = String::new();
+        let toks = trie
+            .try_tokenize_with_greedy_fallback(b"abab\xF0\x9F", |s| {
+                seen = s.to_string();
+                Ok(trie.greedy_tokenize(s.as_bytes()))
+            })
+            .unwrap();
+        assert_eq!(seen, "abab");
+        assert_eq!(toks, vec![ab, ab, 0xF0, 0x9F]);
+        assert_eq!(
+            trie.tokenize_with_greedy_fallback(b"abab\xF0\x9F", |s| trie
+                .greedy_tokenize(s.as_bytes())),
+            toks
+        );
+        assert!(trie
+            .try_tokenize_with_greedy_fallback(b"ab\xF0", |_| anyhow::bail!("failed"))
+            .is_err());
+    }
+
+    #[test]
+    fn greedy_tokenize_partial_token_at_end() {
+        let env = TestTokEnv::with_words(&["abc", "\u{1F600}"]);
+        let trie = env.tok_trie();
+        for input in [&b"xab"[..], b"ab", b"abcab", b"\xF0\x9F", b"a\xF0\x9F\x98"] {
+            let toks = trie.greedy_tokenize(input);
+            assert_eq!(trie.decode(&toks), input);
+            assert_eq!(trie.greedy_count(input), toks.len());
+        }
+        assert_eq!(
+            trie.greedy_tokenize(b"abcab"),
+            vec![env.token("abc").unwrap(), b'a' as TokenId, b'b' as TokenId]
+        );
+    }
+
+    #[test]
+    fn try_from_bytes_rejects_corrupted_trie() {
+        let env = TestTokEnv::with_words(&["ab", "abc"]);
+        let trie = env.tok_trie();
+        let bytes = trie.serialize();
+        let t2 = TokTrie::try_from_bytes(&bytes).unwrap();
+        assert_eq!(t2.serialize(), bytes);
+
+        let node = |i: usize| core::mem::size_of::<TokTrieHeader>() + i * 8;
+        let set_u32 = |b: &mut Vec<u8>, off: usize, v: u32| {
+            b[off..off + 4].copy_from_slice(&v.to_le_bytes());
+        };
+        let get_u32 =
+            |b: &[u8], off: usize| u32::from_le_bytes(b[off..off + 4].try_into().unwrap());
+
+        // token bytes don't match the path in the trie
+        let mut b = bytes.clone();
+        *b.last_mut().unwrap() ^= 1;
+        assert!(TokTrie::try_from_bytes(&b).is_err());
+
+        // token id out of range
+        let mut b = bytes.clone();
+        let byte = get_u32(&b, node(1)) & 0xff;
+        set_u32(
+            &mut b,
+            node(1),
+            ((trie.vocab_size() as u32 + 5) << 8) | byte,
+        );
+        assert!(TokTrie::try_from_bytes(&b).is_err());
+
+        // wrong subtree size of the root
+        let mut b = bytes.clone();
+        let bits2 = get_u32(&b, node(0) + 4);
+        set_u32(&mut b, node(0) + 4, bits2 - (1 << 8));
+        assert!(TokTrie::try_from_bytes(&b).is_err());
+    }
+
+    #[test]
+    fn all_bytes_fast_path_matches_trie_walk() {
+        // "" is not in the trie, the second "ab" is a duplicate
+        let trie = trie_with(&[b"ab", b"", b"ab", b"abc"]);
+        let mut fast = trie.alloc_token_set();
+        trie.compute_bias(&mut StackRecognizer::from(AnythingGoes {}), &mut fast);
+        let mut slow = trie.alloc_token_set();
+        trie.compute_bias(&mut StackRecognizer::from(AllBytesSlow), &mut slow);
+        assert_eq!(fast, slow);
+        assert!(!fast.is_allowed(257));
+        assert!(fast.is_allowed(258));
+        assert!(!fast.get(trie.vocab_size()));
+        assert_eq!(fast.num_set(), trie.vocab_size() - 1);
+    }
+
+    fn id_table_trie() -> TokTrie {
+        let mut words: Vec<Vec<u8>> = (0..=255u8).map(|b| vec![b]).collect();
+        for w in ["<|", "<|a|>", "<|a|>x", "<|end|>"] {
+            words.push(w.as_bytes().to_vec());
+        }
+        // "<|a|>", "<|a|>x" and "<|end|>" are special
+        let n = words.len() as u32;
+        TokTrie::from_with_escape(
+            &TokRxInfo::new(n, n - 1),
+            &words,
+            SpecialTokenEscape::IdTable(vec![257, 258, 259]),
+        )
+    }
+
+    #[test]
+    fn id_table_serialize_roundtrip() {
+        let trie = id_table_trie();
+        let bytes = trie.serialize();
+
+        let mut aligned = vec![0u32; bytes.len().div_ceil(4)];
+        bytemuck::cast_slice_mut::<u32, u8>(&mut aligned)[..bytes.len()].copy_from_slice(&bytes);
+        for t2 in [
+            TokTrie::try_from_bytes(&bytes).unwrap(),
+            TokTrie::from_bytes(bytemuck::cast_slice(&aligned)),
+        ] {
+            assert_eq!(t2.special_token_escape(), trie.special_token_escape());
+            assert_eq!(t2.get_special_tokens(), vec![257, 258, 259]);
+            assert!(t2.is_special_token(259));
+            assert_eq!(t2.get_special_token("<|end|>"), Some(259));
+            assert_eq!(t2.get_special_token("<|a|"), None);
+            // special token names are not reachable by greedy tokenization
+            assert_eq!(t2.greedy_tokenize(b"<|end|>")[0], 256);
+            assert_eq!(t2.serialize(), bytes);
+        }
+
+        let mut b = bytes.clone();
+        b[8] = 1; // version
+        assert!(TokTrie::try_from_bytes(&b).is_err());
+    }
+
+    #[test]
+    fn id_table_tokenize_with_special() {
+        let trie = id_table_trie();
+        let toks = trie.tokenize_with_special(b"x<|a|>xy<|a|><|end|><|", |s| {
+            s.iter().map(|&b| b as TokenId).collect()
+        });
+        assert_eq!(
+            toks,
+            vec![
+                b'x' as TokenId,
+                258,
+                b'y' as TokenId,
+                257,
+                259,
+                b'<' as TokenId,
+                b'|' as TokenId
+            ]
+        );
+
+        let opts = MarkerOptions::default();
+        let toks = trie
+            .tokenize_with_marker(b"a\xFF<|end|>", &opts, |s| {
+                Ok(s.iter().map(|&b| b as TokenId).collect())
+            })
+            .unwrap();
+        assert_eq!(toks, vec![b'a' as TokenId, 259]);
+    }
+
+    // only allows the byte 'x', and EOS
+    struct OnlyX;
+
+    impl FunctionalRecognizer<()> for OnlyX {
+        fn initial(&self) {}
+
+        fn try_append(&self, state: (), byte: u8) -> Option<()> {
+            (byte == b'x').then_some(state)
+        }
+
+        fn special_allowed(&self, _state: (), tok: SpecialToken) -> bool {
+            tok == SpecialToken::EndOfSentence
+        }
+    }
+
+    #[test]
+    fn compute_bias_f32_duplicated_eos() {
+        // EOS has the same bytes as another token, "ab";
+        // the later one is in the trie, and the other one is its duplicate
+        let mut words: Vec<Vec<u8>> = (0..=255u8).map(|b| vec![b]).collect();
+        words.push(b"ab".to_vec());
+        words.push(b"ab".to_vec());
+        for eos in [256, 257] {
+            let trie = TokTrie::from(&TokRxInfo::new(258, eos), &words);
+            let other = 256 + 257 - eos as usize;
+
+            let mut r = StackRecognizer::from(OnlyX);
+            let mut bias = trie.alloc_logits();
+            trie.compute_bias_f32(&mut r, &mut bias);
+            assert_eq!(bias[eos as usize], 0.0);
+            assert_eq!(bias[b'x' as usize], 0.0);
+            assert_eq!(bias[other], f32::NEG_INFINITY);
+
+            let mut mask = trie.alloc_token_set();
+            trie.compute_bias(&mut r, &mut mask);
+            for (tok, &b) in bias.iter().enumerate().take(trie.vocab_size()) {
+                assert_eq!(mask.get(tok), b > f32::NEG_INFINITY, "token {tok}");
+            }
+            let mut masks = vec![trie.alloc_token_set()];
+            trie.compute_bias_batch(core::slice::from_mut(&mut r), &mut masks);
+            assert_eq!(masks[0], mask);
+        }
+    }
+
+    #[test]
+    fn added_token_metadata_serialized() {
+        let trie = id_table_trie().with_added_tokens(&[
+            AddedToken {
+                id: 258,
+                content: "<|a|>x".to_string(),
+                special: true,
+                normalized: true,
+                single_word: true,
+            },
+            AddedToken {
+                id: 256,
+                content: "<|".to_string(),
+                special: false,
+                normalized: true,
+                single_word: false,
+            },
+        ]);
+        let bytes = trie.serialize();
+        let mut aligned = vec![0u32; bytes.len().div_ceil(4)];
+        bytemuck::cast_slice_mut::<u32, u8>(&mut aligned)[..bytes.len()].copy_from_slice(&bytes);
+        for t2 in [
+            TokTrie::try_from_bytes(&bytes).unwrap(),
+            TokTrie::from_bytes(bytemuck::cast_slice(&aligned)),
+        ] {
+            for id in [256, 257, 258, 259] {
+                assert_eq!(t2.added_token(id), trie.added_token(id));
+            }
+            assert_eq!(t2.added_token(255), None);
+            assert_eq!(t2.serialize(), bytes);
+        }
+
+        // flags of the first added token (256) are right after the header
+        let flags = core::mem::size_of::<TokTrieHeader>()
+            + trie.nodes.len() * 8
+            + (trie.vocab_size() + 3) * 4
+            + 4;
+        assert_eq!(bytes[flags - 4..flags], 256u32.to_le_bytes());
+        assert_eq!(bytes[flags], 2);
+        let mut b = bytes.clone();
+        b[flags] = 0x80;
+        assert!(TokTrie::try_from_bytes(&b).is_err());
+    }
+}
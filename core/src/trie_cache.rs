@@ -0,0 +1,164 @@
+// On-disk cache of built tries, keyed by the source data (e.g., the vocabulary file).
+// File layout (little endian):
+//   magic u32, version u32, key length u64, trie length u64, info length u64,
+//   key, TokTrie::serialize() data, TokRxInfo as JSON, SHA-256 of everything before.
+// Files are named after the SHA-256 of the key, and the full key is compared on load.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+use sha2::{Digest, Sha256};
+
+use crate::{TokRxInfo, TokTrie};
+
+const MAGIC: u32 = 0x48434b54; // "TKCH"
+                               // 4 - SHA-256 instead of FxHash, full key stored
+const VERSION: u32 = 4;
+const HEADER_SIZE: usize = 32;
+const DIGEST_SIZE: usize = 32;
+
+fn sha256(data: &[u8]) -> [u8; DIGEST_SIZE] {
+    Sha256::digest(data).into()
+}
+
+fn read_u64(data: &[u8], off: usize) -> u64 {
+    u64::from_le_bytes(data[off..off + 8].try_into().unwrap())
+}
+
+fn cache_path(cache_dir: &Path, key: &[u8]) -> PathBuf {
+    let hex: String = sha256(key).iter().map(|b| format!("{:02x}", b)).collect();
+    cache_dir.join(format!("toktrie-{}.bin", hex))
+}
+
+fn encode(trie: &TokTrie, key: &[u8]) -> Result<Vec<u8>> {
+    let trie_data = trie.serialize();
+    let info = serde_json::to_vec(trie.info())?;
+    let mut r =
+        Vec::with_capacity(HEADER_SIZE + key.len() + trie_data.len() + info.len() + DIGEST_SIZE);
+    r.extend_from_slice(&MAGIC.to_le_bytes());
+    r.extend_from_slice(&VERSION.to_le_bytes());
+    r.extend_from_slice(&(key.len() as u64).to_le_bytes());
+    r.extend_from_slice(&(trie_data.len() as u64).to_le_bytes());
+    r.extend_from_slice(&(info.len() as u64).to_le_bytes());
+    r.extend_from_slice(key);
+    r.extend_from_slice(&trie_data);
+    r.extend_from_slice(&info);
+    let checksum = sha256(&r);
+    r.extend_from_slice(&checksum);
+    Ok(r)
+}
+
+fn decode(data: &[u8], key: &[u8]) -> Result<TokTrie> {
+    if data.len() < HEADER_SIZE + DIGEST_SIZE {
+        bail!("cache file too short");
+    }
+    let (body, checksum) = data.split_at(data.len() - DIGEST_SIZE);
+    if sha256(body) != checksum {
+        bail!("cache checksum mismatch");
+    }
+    let magic = u32::from_le_bytes(data[0..4].try_into().unwrap());
+    let version = u32::from_le_bytes(data[4..8].try_into().unwrap());
+    if magic != MAGIC || version != VERSION {
+        bail!("unsupported cache file");
+    }
+    let key_len = read_u64(data, 8) as usize;
+    let trie_len = read_u64(data, 16) as usize;
+    let info_len = read_u64(data, 24) as usize;
+    let key_end = HEADER_SIZE.saturating_add(key_len);
+    let trie_end = key_end.saturating_add(trie_len);
+    if trie_end.saturating_add(info_len) != body.len() {
+        bail!("invalid cache file size");
+    }
+    if &body[HEADER_SIZE..key_end] != key {
+        bail!("cache file for different data");
+    }
+    let trie = TokTrie::try_from_bytes(&body[key_end..trie_end])?;
+    let info: TokRxInfo = serde_json::from_slice(&body[trie_end..])?;
+    if info.vocab_size != trie.info().vocab_size {
+        bail!("vocab size mismatch in cache file");
+    }
+    Ok(trie.with_info(info))
+}
+
+fn store(trie: &TokTrie, key: &[u8], cache_dir: &Path, path: &Path) -> Result<()> {
+    let data = encode(trie, key)?;
+    std::fs::create_dir_all(cache_dir)?;
+    // write to a temporary file first, so readers never see partial data
+    let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+    std::fs::write(&tmp, data)?;
+    if let Err(e) = std::fs::rename(&tmp, path) {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+impl TokTrie {
+    /// Load the trie cached in cache_dir for given key (typically the contents
+    /// of the vocabulary file, plus any options affecting the trie), or build it
+    /// and store it in the cache. Failures to read or write the cache are ignored.
+    /// The cache keeps TokRxInfo and special tokens, but not other added token metadata.
+    pub fn load_cached(
+        cache_dir: &Path,
+        key: &[u8],
+        build: impl FnOnce() -> Result<TokTrie>,
+    ) -> Result<TokTrie> {
+        let path = cache_path(cache_dir, key);
+        if let Ok(data) = std::fs::read(&path) {
+            if let Ok(trie) = decode(&data, key) {
+                return Ok(trie);
+            }
+        }
+        let trie = build()?;
+        let _ = store(&trie, key, cache_dir, &path);
+        Ok(trie)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{TestTokEnv, TokenizerEnv};
+
+    fn hex(d: [u8; DIGEST_SIZE]) -> String {
+        d.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn sha256_vectors() {
+        assert_eq!(
+            hex(sha256(b"")),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            hex(sha256(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            hex(sha256(
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            )),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+        assert_eq!(
+            hex(sha256(&[b'a'; 1000])),
+            "41edece42d63e8d9bf515a9ba6932e1c20cbc9f5a5d134645adb5db1b9737ea3"
+        );
+    }
+
+    #[test]
+    fn cache_roundtrip() {
+        let trie = TestTokEnv::with_words(&["ab", "abc"]).tok_trie().clone();
+        let data = encode(&trie, b"key").unwrap();
+        let t2 = decode(&data, b"key").unwrap();
+        assert_eq!(t2.serialize(), trie.serialize());
+        assert_eq!(t2.info(), trie.info());
+
+        assert!(decode(&data, b"kez").is_err());
+        assert!(decode(&data, b"key2").is_err());
+        let mut bad = data.clone();
+        bad[HEADER_SIZE + 5] ^= 1;
+        assert!(decode(&bad, b"key").is_err());
+        assert!(decode(&data[..data.len() - 1], b"key").is_err());
+    }
+}
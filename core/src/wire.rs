@@ -0,0 +1,134 @@
+use anyhow::{bail, Result};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::prelude::*;
+
+/// Encoding of StepArg/StepResult and other messages exchanged with the controller.
+/// JSON is always available; the binary encoding needs the "postcard" feature.
+/// Both sides have to agree on the format, e.g., when the controller is loaded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WireFormat {
+    Json,
+    Postcard,
+}
+
+impl WireFormat {
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "json" => Ok(WireFormat::Json),
+            "postcard" => Ok(WireFormat::Postcard),
+            _ => bail!("unknown wire format {:?}", name),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            WireFormat::Json => "json",
+            WireFormat::Postcard => "postcard",
+        }
+    }
+
+    /// Check if this build supports the format.
+    pub fn is_supported(&self) -> bool {
+        match self {
+            WireFormat::Json => true,
+            WireFormat::Postcard => cfg!(feature = "postcard"),
+        }
+    }
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        match self {
+            WireFormat::Json => Ok(serde_json::to_vec(value)?),
+            #[cfg(feature = "postcard")]
+            WireFormat::Postcard => Ok(postcard::to_allocvec(value)?),
+            #[cfg(not(feature = "postcard"))]
+            WireFormat::Postcard => bail!("postcard wire format not enabled"),
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T> {
+        match self {
+            WireFormat::Json => Ok(serde_json::from_slice(data)?),
+            #[cfg(feature = "postcard")]
+            WireFormat::Postcard => Ok(postcard::from_bytes(data)?),
+            #[cfg(not(feature = "postcard"))]
+            WireFormat::Postcard => bail!("postcard wire format not enabled"),
+        }
+    }
+}
+
+// Binary formats can't deserialize arbitrary JSON values, so these are sent as JSON text there.
+pub(crate) mod json_value {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        value: &Option<serde_json::Value>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            value.serialize(serializer)
+        } else {
+            value.as_ref().map(|v| v.to_string()).serialize(serializer)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<serde_json::Value>, D::Error> {
+        if deserializer.is_human_readable() {
+            Option::<serde_json::Value>::deserialize(deserializer)
+        } else {
+            match Option::<String>::deserialize(deserializer)? {
+                Some(s) => serde_json::from_str(&s)
+                    .map(Some)
+                    .map_err(serde::de::Error::custom),
+                None => Ok(None),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Branch, StepArg};
+
+    #[test]
+    fn names() {
+        for fmt in [WireFormat::Json, WireFormat::Postcard] {
+            assert_eq!(WireFormat::from_name(fmt.name()).unwrap(), fmt);
+        }
+        assert!(WireFormat::from_name("yaml").is_err());
+        assert!(WireFormat::Json.is_supported());
+    }
+
+    #[test]
+    fn roundtrip() {
+        let arg = StepArg {
+            backtrack: 1,
+            tokens: vec![3, 4],
+            sampled: Some(4),
+            logprobs: None,
+        };
+        let payload = serde_json::json!({ "name": "x", "n": [1, 2] });
+        let branch: Branch<Vec<u32>> = Branch::stop_with("done", Some(payload.clone()));
+        for fmt in [WireFormat::Json, WireFormat::Postcard] {
+            if !fmt.is_supported() {
+                assert!(fmt.encode(&arg).is_err());
+                continue;
+            }
+            let arg2: StepArg = fmt.decode(&fmt.encode(&arg).unwrap()).unwrap();
+            assert_eq!(arg2.backtrack, 1);
+            assert_eq!(arg2.tokens, vec![3, 4]);
+            assert_eq!(arg2.sampled, Some(4));
+
+            let branch2: Branch<Vec<u32>> = fmt.decode(&fmt.encode(&branch).unwrap()).unwrap();
+            assert!(branch2.is_stop());
+            let info = branch2.stop_info.unwrap();
+            assert_eq!(info.reason.as_deref(), Some("done"));
+            assert_eq!(info.payload, Some(payload.clone()));
+        }
+        assert!(WireFormat::Json.decode::<StepArg>(b"{").is_err());
+    }
+}
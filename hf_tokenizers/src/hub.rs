@@ -0,0 +1,70 @@
+// Loading tokenizers from the HuggingFace Hub (feature "hub").
+// Files are cached by hf-hub in the HuggingFace cache (~/.cache/huggingface/hub by default),
+// so a model is only downloaded once, and pinned revisions load without network access.
+
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
+use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
+
+use crate::{ByteTokenizer, HfTokenizerEnv};
+
+#[derive(Clone, Debug, Default)]
+pub struct HubOptions {
+    /// Branch, tag or commit hash; defaults to "main".
+    /// Pin a commit hash for reproducible tokenization.
+    pub revision: Option<String>,
+    /// Cache directory instead of the default HuggingFace cache.
+    pub cache_dir: Option<PathBuf>,
+    /// Access token for gated or private models; defaults to the token saved
+    /// in the cache by huggingface-cli login.
+    pub token: Option<String>,
+    /// Vocabulary size of the model, see HfTokenizerEnv::new().
+    pub n_vocab: Option<usize>,
+}
+
+impl HfTokenizerEnv {
+    /// Download tokenizer.json and tokenizer_config.json of a model like "org/model"
+    /// (or take them from the cache), and build the env.
+    /// Token roles (eos, bos, pad, unk) from tokenizer_config.json override
+    /// the ones guessed from token names; the config is optional.
+    pub fn from_hub(model: &str, opts: &HubOptions) -> Result<HfTokenizerEnv> {
+        let mut builder = ApiBuilder::new().with_progress(false);
+        if let Some(dir) = &opts.cache_dir {
+            builder = builder.with_cache_dir(dir.clone());
+        }
+        if opts.token.is_some() {
+            builder = builder.with_token(opts.token.clone());
+        }
+        let api = builder.build()?;
+        let revision = opts.revision.clone().unwrap_or_else(|| "main".to_string());
+        let repo = api.repo(Repo::with_revision(
+            model.to_string(),
+            RepoType::Model,
+            revision.clone(),
+        ));
+
+        let path = repo.get("tokenizer.json").map_err(|e| {
+            anyhow!(
+                "error fetching tokenizer.json of {}@{}: {}",
+                model,
+                revision,
+                e
+            )
+        })?;
+        let path = path
+            .to_str()
+            .ok_or_else(|| anyhow!("invalid path: {:?}", path))?;
+        let mut env = HfTokenizerEnv::new(ByteTokenizer::from_file(path)?, opts.n_vocab)?;
+
+        match repo.get("tokenizer_config.json") {
+            Ok(path) => {
+                let bytes = std::fs::read(&path)?;
+                env.tok_trie = env.tok_trie.with_tokenizer_config(&bytes)?;
+            }
+            Err(e) => log::warn!("no tokenizer_config.json for {}: {}", model, e),
+        }
+
+        Ok(env)
+    }
+}
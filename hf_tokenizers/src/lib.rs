@@ -1,8 +1,16 @@
 use anyhow::{anyhow, bail, Result};
-use rustc_hash::FxHashMap;
 use std::{collections::BTreeMap, sync::Arc};
 use tokenizers::{normalizers::Sequence, FromPretrainedParameters, NormalizerWrapper, Tokenizer};
-use toktrie::{TokEnv, TokRxInfo, TokTrie, TokenId, TokenizerEnv};
+use toktrie::{
+    bytes::byte_level_decode, AddedToken, TokEnv, TokError, TokRxInfo, TokTrie, TokenId,
+    TokenizerEnv,
+};
+
+#[cfg(feature = "hub")]
+mod hub;
+
+#[cfg(feature = "hub")]
+pub use hub::HubOptions;
 
 pub struct ByteTokenizer {
     pub hf_model: String,
@@ -12,30 +20,6 @@ pub struct ByteTokenizer {
     pub special: BTreeMap<String, u32>,
 }
 
-// useful when debugging this: https://www.cogsci.ed.ac.uk/~richard/utf-8.cgi
-
-fn is_self_mapped(c: char) -> bool {
-    match c {
-        '!'..='~' | '\u{00A1}'..='\u{00AC}' | '\u{00AE}'..='\u{00FF}' => true,
-        _ => false,
-    }
-}
-
-fn build_char_map() -> FxHashMap<char, u8> {
-    let mut res = FxHashMap::default();
-    let mut k = 0x100u32;
-    for byte in 0..=255u8 {
-        let c = byte as char;
-        if is_self_mapped(c) {
-            res.insert(c, byte);
-        } else {
-            res.insert(char::from_u32(k).unwrap(), byte);
-            k += 1;
-        }
-    }
-    res
-}
-
 fn strip_suffix(sep: &str, s: &mut String) -> Option<String> {
     let mut parts = s.splitn(2, sep);
     let core = parts.next().unwrap().to_string();
@@ -134,21 +118,13 @@ impl ByteTokenizer {
 
         for (id, info) in added.iter() {
             if info.special {
-                match info.content.as_str() {
-                    "</s>" | "<|endoftext|>" | "<|end_of_text|>" => res.info.tok_eos = *id,
-                    "<|end|>" | "<|eot_id|>" => res.info.tok_end_of_turn = Some(*id),
-                    "<unk>" | "<|unk|>" => res.info.tok_unk = Some(*id),
-                    "<pad>" | "<|pad|>" => res.info.tok_pad = Some(*id),
-                    _ => {}
-                }
+                res.info.assign_role_by_name(&info.content, *id)?;
                 res.special.insert(info.content.clone(), *id);
             } else {
                 res.token_bytes[*id as usize] = info.content.clone().into_bytes();
             }
         }
 
-        let char_map = build_char_map();
-
         for tok_id in 0..vocab_size {
             if let Some(tok_name) = res.hf_tokenizer.id_to_token(tok_id) {
                 let bytes = if added.contains_key(&tok_id) {
@@ -168,19 +144,10 @@ impl ByteTokenizer {
                         tok_name.as_bytes().to_vec()
                     }
                 } else if is_byte_level {
-                    let bytes: Result<Vec<u8>> = tok_name
-                        .chars()
-                        .map(|c| {
-                            char_map
-                                .get(&c)
-                                .map(|c| *c)
-                                .ok_or_else(|| anyhow!("missing char: {}", c))
-                        })
-                        .collect();
-                    match bytes {
-                        Ok(b) => b,
-                        Err(e) => {
-                            log::warn!("error: {} for {:?}", e, tok_name);
+                    match byte_level_decode(&tok_name) {
+                        Some(b) => b,
+                        None => {
+                            log::warn!("missing char in {:?}", tok_name);
                             continue;
                         }
                     }
@@ -197,7 +164,7 @@ impl ByteTokenizer {
     }
 
     pub fn tokrx_info(&self) -> TokRxInfo {
-        self.info.clone()
+        self.info
     }
     pub fn token_bytes(&self) -> Vec<Vec<u8>> {
         self.token_bytes.clone()
@@ -217,18 +184,30 @@ impl ByteTokenizer {
     }
 }
 
-pub struct ByteTokenizerEnv {
+/// TokenizerEnv backed by HuggingFace tokenizers: the trie (including added
+/// and special tokens) is built from the tokenizer's vocabulary,
+/// and text is encoded with the tokenizer itself.
+pub struct HfTokenizerEnv {
     pub tokenizer: ByteTokenizer,
     pub tok_trie: TokTrie,
 }
 
-impl ByteTokenizerEnv {
-    pub fn from_name(name: &str, n_vocab: Option<usize>) -> Result<ByteTokenizerEnv> {
+#[deprecated(note = "use HfTokenizerEnv")]
+pub type ByteTokenizerEnv = HfTokenizerEnv;
+
+impl HfTokenizerEnv {
+    pub fn from_name(name: &str, n_vocab: Option<usize>) -> Result<HfTokenizerEnv> {
         let tokenizer = ByteTokenizer::from_name(name)?;
-        ByteTokenizerEnv::new(tokenizer, n_vocab)
+        HfTokenizerEnv::new(tokenizer, n_vocab)
     }
 
-    pub fn new(tokenizer: ByteTokenizer, n_vocab: Option<usize>) -> Result<ByteTokenizerEnv> {
+    /// Load from a tokenizer.json file, with vocabulary size taken from the tokenizer.
+    pub fn from_file(name: &str) -> Result<HfTokenizerEnv> {
+        let tokenizer = ByteTokenizer::from_file(name)?;
+        HfTokenizerEnv::new(tokenizer, None)
+    }
+
+    pub fn new(tokenizer: ByteTokenizer, n_vocab: Option<usize>) -> Result<HfTokenizerEnv> {
         let mut info = tokenizer.tokrx_info();
         let mut token_bytes = tokenizer.token_bytes();
         if let Some(n_vocab) = n_vocab {
@@ -240,8 +219,21 @@ impl ByteTokenizerEnv {
             }
             info.vocab_size = n_vocab as u32;
         }
-        let tok_trie = TokTrie::from(&info, &token_bytes);
-        Ok(ByteTokenizerEnv {
+        let added: Vec<AddedToken> = tokenizer
+            .hf_tokenizer
+            .get_added_tokens_decoder()
+            .iter()
+            .filter(|(id, _)| (**id as usize) < token_bytes.len())
+            .map(|(id, t)| AddedToken {
+                id: *id,
+                content: t.content.clone(),
+                special: t.special,
+                normalized: t.normalized,
+                single_word: t.single_word,
+            })
+            .collect();
+        let tok_trie = TokTrie::from(&info, &token_bytes).with_added_tokens(&added);
+        Ok(HfTokenizerEnv {
             tokenizer,
             tok_trie,
         })
@@ -252,19 +244,48 @@ impl ByteTokenizerEnv {
     }
 }
 
-impl TokenizerEnv for ByteTokenizerEnv {
+impl TokenizerEnv for HfTokenizerEnv {
     fn tok_trie(&self) -> &TokTrie {
         &self.tok_trie
     }
 
     fn tokenize_bytes(&self, s: &[u8]) -> Vec<TokenId> {
-        self.tok_trie.tokenize_with_greedy_fallback(s, |s| {
-            self.tokenizer
+        self.try_tokenize_bytes(s).expect("tokenizer error")
+    }
+
+    fn try_tokenize_bytes(&self, s: &[u8]) -> Result<Vec<TokenId>> {
+        self.tok_trie.try_tokenize_with_greedy_fallback(s, |s| {
+            let enc = self
+                .tokenizer
                 .hf_tokenizer
                 .encode(s, false)
-                .expect("tokenizer error")
-                .get_ids()
-                .to_vec()
+                .map_err(|e| TokError::Backend(e.to_string()))?;
+            Ok(enc.get_ids().to_vec())
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // "[UNK]" is not in the vocabulary, so encoding unknown words fails
+    const TOKENIZER: &str = r#"{
+        "version": "1.0",
+        "added_tokens": [],
+        "normalizer": null,
+        "pre_tokenizer": {"type": "Whitespace"},
+        "post_processor": null,
+        "decoder": {"type": "ByteLevel", "add_prefix_space": false, "trim_offsets": true, "use_regex": true},
+        "model": {"type": "WordLevel", "vocab": {"a": 0, "b": 1, "ab": 2}, "unk_token": "[UNK]"}
+    }"#;
+
+    #[test]
+    fn encode_errors_are_returned() {
+        let hft: Tokenizer = TOKENIZER.parse().unwrap();
+        let env = HfTokenizerEnv::new(ByteTokenizer::from_tokenizer(hft).unwrap(), None).unwrap();
+        assert_eq!(env.try_tokenize_bytes(b"ab a").unwrap(), vec![2, 0]);
+        let err = env.try_tokenize_bytes(b"a c").unwrap_err();
+        assert!(err.downcast_ref::<TokError>().is_some());
+    }
+}
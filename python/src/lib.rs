@@ -0,0 +1,280 @@
+// Python bindings: trie construction, mask computation and streaming decoding.
+// Masks are returned as numpy bool arrays of vocab_size elements, or packed bitmaps.
+
+use numpy::PyArray1;
+use pyo3::{
+    exceptions::PyValueError,
+    prelude::*,
+    types::{PyBytes, PyString},
+};
+use toktrie::{recognizer::OneOf, Recognizer, SimpleVob, SpecialToken, TokTrie, TokenId};
+
+#[pyclass(name = "TokTrie", frozen)]
+struct PyTokTrie {
+    trie: TokTrie,
+}
+
+fn mask_to_numpy<'py>(
+    py: Python<'py>,
+    trie: &TokTrie,
+    mask: &SimpleVob,
+) -> Bound<'py, PyArray1<bool>> {
+    let v: Vec<bool> = (0..trie.vocab_size() as TokenId)
+        .map(|t| mask.is_allowed(t))
+        .collect();
+    PyArray1::from_vec(py, v)
+}
+
+impl PyTokTrie {
+    fn compute_mask(&self, rec: &Bound<'_, PyAny>) -> PyResult<SimpleVob> {
+        let mut mask = self.trie.alloc_token_set();
+        if let Ok(rec) = rec.cast::<PyRecognizer>() {
+            let mut rec = rec.borrow_mut();
+            self.trie.compute_bias(&mut rec.rec, &mut mask);
+        } else {
+            let mut rec = PythonRecognizer {
+                obj: rec.clone().unbind(),
+                stack: vec![rec.call_method0("initial")?.unbind()],
+                error: None,
+            };
+            self.trie.compute_bias(&mut rec, &mut mask);
+            if let Some(e) = rec.error {
+                return Err(e);
+            }
+        }
+        Ok(mask)
+    }
+}
+
+#[pymethods]
+impl PyTokTrie {
+    /// Load from a HuggingFace tokenizer.json file.
+    #[staticmethod]
+    fn from_tokenizer_json(path: &str) -> PyResult<Self> {
+        Ok(PyTokTrie {
+            trie: TokTrie::from_tokenizer_json_file(path)?,
+        })
+    }
+
+    /// Load from the contents of a HuggingFace tokenizer.json file.
+    #[staticmethod]
+    fn from_tokenizer_json_bytes(data: &[u8]) -> PyResult<Self> {
+        Ok(PyTokTrie {
+            trie: TokTrie::from_tokenizer_json(data)?,
+        })
+    }
+
+    /// Build from a list of token byte strings, with given EOS token.
+    #[staticmethod]
+    fn from_words(words: Vec<Vec<u8>>, eos_token: TokenId) -> PyResult<Self> {
+        if eos_token as usize >= words.len() {
+            return Err(PyValueError::new_err("eos_token out of range"));
+        }
+        let info = toktrie::TokRxInfo::new(words.len() as u32, eos_token);
+        Ok(PyTokTrie {
+            trie: TokTrie::from(&info, &words),
+        })
+    }
+
+    #[getter]
+    fn vocab_size(&self) -> usize {
+        self.trie.vocab_size()
+    }
+
+    #[getter]
+    fn eos_token(&self) -> TokenId {
+        self.trie.eos_token()
+    }
+
+    fn token<'py>(&self, py: Python<'py>, tok: TokenId) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, self.trie.token(tok))
+    }
+
+    fn decode<'py>(&self, py: Python<'py>, tokens: Vec<TokenId>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, &self.trie.decode(&tokens))
+    }
+
+    fn greedy_tokenize(&self, data: &[u8]) -> Vec<TokenId> {
+        self.trie.greedy_tokenize(data)
+    }
+
+    /// Tokens allowed by the recognizer, as a numpy bool array.
+    /// rec is either a built-in Recognizer, or a Python object with methods
+    /// initial() -> state (the state after the output so far),
+    /// append(state, byte) -> state or None (if the byte is not allowed),
+    /// and allows_eos(state) -> bool.
+    fn compute_bias<'py>(
+        &self,
+        py: Python<'py>,
+        rec: &Bound<'py, PyAny>,
+    ) -> PyResult<Bound<'py, PyArray1<bool>>> {
+        let mask = self.compute_mask(rec)?;
+        Ok(mask_to_numpy(py, &self.trie, &mask))
+    }
+
+    /// Like compute_bias(), but returns the mask as a packed numpy uint8 bitmap
+    /// (token i is bit i % 8 of byte i // 8, least significant bit first),
+    /// e.g., for copying to the GPU; numpy.unpackbits(bitmap, count=vocab_size,
+    /// bitorder="little") gives the bool mask.
+    fn compute_bias_packed<'py>(
+        &self,
+        py: Python<'py>,
+        rec: &Bound<'py, PyAny>,
+    ) -> PyResult<Bound<'py, PyArray1<u8>>> {
+        let mask = self.compute_mask(rec)?;
+        Ok(PyArray1::from_slice(py, mask.as_bitmap()))
+    }
+
+    /// Advance a built-in recognizer by given token; raises if the token is not allowed.
+    fn append_token(&self, rec: &mut PyRecognizer, tok: TokenId) -> PyResult<()> {
+        Ok(self.trie.append_token(&mut rec.rec, tok)?)
+    }
+}
+
+/// Built-in recognizer.
+#[pyclass(name = "Recognizer", unsendable)]
+struct PyRecognizer {
+    rec: Box<dyn Recognizer>,
+}
+
+#[pymethods]
+impl PyRecognizer {
+    /// Text matching given regex.
+    #[staticmethod]
+    fn regex(rx: &str) -> PyResult<Self> {
+        Ok(PyRecognizer {
+            rec: Box::new(toktrie::recognizer::RegexDfa::new(rx)?.to_recognizer()),
+        })
+    }
+
+    /// One of the given strings.
+    #[staticmethod]
+    fn one_of(options: Vec<String>) -> Self {
+        let options: Vec<&str> = options.iter().map(|s| s.as_str()).collect();
+        PyRecognizer {
+            rec: Box::new(OneOf::new(&options).to_recognizer()),
+        }
+    }
+
+    /// Check if EOS is allowed in the current state.
+    fn is_accepting(&mut self) -> bool {
+        self.rec.special_allowed(SpecialToken::EndOfSentence)
+    }
+}
+
+// Recognizer calling into a Python object; the stack holds Python states.
+// The first Python exception is kept and raised after compute_bias().
+struct PythonRecognizer {
+    obj: Py<PyAny>,
+    stack: Vec<Py<PyAny>>,
+    error: Option<PyErr>,
+}
+
+impl Recognizer for PythonRecognizer {
+    fn pop_bytes(&mut self, num: usize) {
+        self.stack.truncate(self.stack.len() - num);
+    }
+
+    fn collapse(&mut self) {
+        let top = self.stack.pop().unwrap();
+        self.stack.clear();
+        self.stack.push(top);
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        if tok != SpecialToken::EndOfSentence || self.error.is_some() {
+            return false;
+        }
+        Python::attach(|py| {
+            let top = self.stack.last().unwrap();
+            match self
+                .obj
+                .call_method1(py, "allows_eos", (top.clone_ref(py),))
+                .and_then(|r| r.extract::<bool>(py))
+            {
+                Ok(r) => r,
+                Err(e) => {
+                    self.error = Some(e);
+                    false
+                }
+            }
+        })
+    }
+
+    fn trie_finished(&mut self) {
+        self.stack.truncate(1);
+    }
+
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        if self.error.is_some() {
+            return false;
+        }
+        Python::attach(|py| {
+            let top = self.stack.last().unwrap();
+            match self
+                .obj
+                .call_method1(py, "append", (top.clone_ref(py), byte))
+            {
+                Ok(r) if r.is_none(py) => false,
+                Ok(r) => {
+                    self.stack.push(r);
+                    true
+                }
+                Err(e) => {
+                    self.error = Some(e);
+                    false
+                }
+            }
+        })
+    }
+}
+
+/// Incremental decoding of tokens to text, holding back incomplete UTF-8.
+#[pyclass(name = "StreamDecoder")]
+struct PyStreamDecoder {
+    dec: toktrie::StreamDecoder,
+}
+
+#[pymethods]
+impl PyStreamDecoder {
+    #[new]
+    fn new() -> Self {
+        PyStreamDecoder {
+            dec: toktrie::StreamDecoder::new(),
+        }
+    }
+
+    fn push_token<'py>(
+        &mut self,
+        py: Python<'py>,
+        trie: &PyTokTrie,
+        tok: TokenId,
+    ) -> Bound<'py, PyString> {
+        PyString::new(py, &self.dec.push_token(&trie.trie, tok))
+    }
+
+    fn push_tokens<'py>(
+        &mut self,
+        py: Python<'py>,
+        trie: &PyTokTrie,
+        tokens: Vec<TokenId>,
+    ) -> Bound<'py, PyString> {
+        PyString::new(py, &self.dec.push_tokens(&trie.trie, &tokens))
+    }
+
+    fn has_pending(&self) -> bool {
+        self.dec.has_pending()
+    }
+
+    fn flush<'py>(&mut self, py: Python<'py>) -> Bound<'py, PyString> {
+        PyString::new(py, &self.dec.flush())
+    }
+}
+
+#[pymodule]
+fn toktrie_py(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyTokTrie>()?;
+    m.add_class::<PyRecognizer>()?;
+    m.add_class::<PyStreamDecoder>()?;
+    Ok(())
+}
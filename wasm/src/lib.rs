@@ -0,0 +1,158 @@
+// JavaScript bindings (wasm-bindgen), for token counting and mask previews in the browser or Node,
+// using the same trie logic as the server. Build with e.g.:
+//   wasm-pack build --target web wasm
+// Byte arrays are passed as Uint8Array, token lists and masks as Uint32Array.
+
+use toktrie::{
+    recognizer::{JsonSyntax, RegexDfa},
+    PreTokenizer, Recognizer as TokRecognizer, SpecialToken, TokenId,
+};
+use wasm_bindgen::prelude::*;
+
+fn js_err(e: anyhow::Error) -> JsError {
+    JsError::new(&format!("{:#}", e))
+}
+
+#[wasm_bindgen]
+pub struct TokTrie {
+    trie: toktrie::TokTrie,
+    pre: Option<PreTokenizer>,
+}
+
+#[wasm_bindgen]
+impl TokTrie {
+    /// Load from the contents of a HuggingFace tokenizer.json file,
+    /// e.g., new Uint8Array(await (await fetch(url)).arrayBuffer()).
+    #[wasm_bindgen(js_name = fromTokenizerJson)]
+    pub fn from_tokenizer_json(data: &[u8]) -> Result<TokTrie, JsError> {
+        Ok(TokTrie {
+            trie: toktrie::TokTrie::from_tokenizer_json(data).map_err(js_err)?,
+            pre: PreTokenizer::from_tokenizer_json(data).map_err(js_err)?,
+        })
+    }
+
+    /// Load a trie in TokTrie::serialize() format (see serialize()).
+    /// Tokenization then doesn't use a pre-tokenizer.
+    #[wasm_bindgen(js_name = fromBytes)]
+    pub fn from_bytes(data: &[u8]) -> Result<TokTrie, JsError> {
+        Ok(TokTrie {
+            trie: toktrie::TokTrie::try_from_bytes(data).map_err(js_err)?,
+            pre: None,
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        self.trie.serialize()
+    }
+
+    #[wasm_bindgen(getter, js_name = vocabSize)]
+    pub fn vocab_size(&self) -> usize {
+        self.trie.vocab_size()
+    }
+
+    #[wasm_bindgen(getter, js_name = eosToken)]
+    pub fn eos_token(&self) -> TokenId {
+        self.trie.eos_token()
+    }
+
+    /// Bytes of given token (special tokens start with 0xFF).
+    #[wasm_bindgen(js_name = tokenBytes)]
+    pub fn token_bytes(&self, token: TokenId) -> Vec<u8> {
+        self.trie.token(token).to_vec()
+    }
+
+    /// Tokenize text: pre-tokenization (if the tokenizer.json uses a known pattern),
+    /// followed by greedy (longest-match) tokenization of each piece.
+    pub fn tokenize(&self, text: &str) -> Vec<TokenId> {
+        self.tokenize_bytes(text.as_bytes())
+    }
+
+    #[wasm_bindgen(js_name = tokenizeBytes)]
+    pub fn tokenize_bytes(&self, data: &[u8]) -> Vec<TokenId> {
+        match self.pre {
+            Some(pre) => self.trie.greedy_tokenize_split(data, pre),
+            None => self.trie.greedy_tokenize(data),
+        }
+    }
+
+    /// Number of tokens tokenize() would return, without allocating them.
+    #[wasm_bindgen(js_name = countTokens)]
+    pub fn count_tokens(&self, text: &str) -> usize {
+        let data = text.as_bytes();
+        match self.pre {
+            Some(pre) => pre
+                .split_bytes(data)
+                .into_iter()
+                .map(|r| self.trie.greedy_count(&data[r]))
+                .sum(),
+            None => self.trie.greedy_count(data),
+        }
+    }
+
+    /// Decode tokens to text (invalid UTF-8 is replaced); special tokens are skipped.
+    pub fn decode(&self, tokens: &[TokenId]) -> Result<String, JsError> {
+        self.trie.check_tokens(tokens).map_err(js_err)?;
+        Ok(self.trie.decode_str(tokens))
+    }
+
+    #[wasm_bindgen(js_name = decodeBytes)]
+    pub fn decode_bytes(&self, tokens: &[TokenId]) -> Result<Vec<u8>, JsError> {
+        self.trie.check_tokens(tokens).map_err(js_err)?;
+        Ok(self.trie.decode(tokens))
+    }
+
+    /// Mask of tokens allowed by the recognizer, as vocabSize bits
+    /// (bit i % 32 of word i / 32 is set if token i is allowed).
+    #[wasm_bindgen(js_name = computeMask)]
+    pub fn compute_mask(&self, rec: &mut Recognizer) -> Vec<u32> {
+        let mut set = self.trie.alloc_token_set();
+        self.trie.compute_bias(&mut rec.rec, &mut set);
+        set.as_slice()[..self.trie.vocab_size().div_ceil(32)].to_vec()
+    }
+
+    /// List of tokens allowed by the recognizer.
+    #[wasm_bindgen(js_name = allowedTokens)]
+    pub fn allowed_tokens(&self, rec: &mut Recognizer) -> Vec<TokenId> {
+        let mut set = self.trie.alloc_token_set();
+        self.trie.compute_bias(&mut rec.rec, &mut set);
+        let mut r = Vec::new();
+        set.iter_set_entries(|t| r.push(t as TokenId));
+        r
+    }
+
+    /// Advance the recognizer by given token; throws if the token is not allowed.
+    #[wasm_bindgen(js_name = appendToken)]
+    pub fn append_token(&self, rec: &mut Recognizer, token: TokenId) -> Result<(), JsError> {
+        self.trie.check_tokens(&[token]).map_err(js_err)?;
+        self.trie.append_token(&mut rec.rec, token).map_err(js_err)
+    }
+}
+
+/// Constraint on the generated bytes, used to compute token masks.
+#[wasm_bindgen]
+pub struct Recognizer {
+    rec: Box<dyn TokRecognizer>,
+}
+
+#[wasm_bindgen]
+impl Recognizer {
+    /// Bytes matching given regex (whole match).
+    pub fn regex(pattern: &str) -> Result<Recognizer, JsError> {
+        Ok(Recognizer {
+            rec: Box::new(RegexDfa::new(pattern).map_err(js_err)?.to_recognizer()),
+        })
+    }
+
+    /// A single well-formed JSON value, nested up to maxDepth levels.
+    pub fn json(max_depth: usize) -> Recognizer {
+        Recognizer {
+            rec: Box::new(JsonSyntax::new(max_depth).to_recognizer()),
+        }
+    }
+
+    /// Check if end of sequence is allowed in the current state.
+    #[wasm_bindgen(js_name = isAccepting)]
+    pub fn is_accepting(&mut self) -> bool {
+        self.rec.special_allowed(SpecialToken::EndOfSentence)
+    }
+}